@@ -1,9 +1,13 @@
 use crate::{background_process, local_env::LocalEnv};
 use anyhow::anyhow;
 use camino::Utf8PathBuf;
-use serde::{Deserialize, Serialize};
+use pageserver_api::models::{TimelineCreateRequest, TimelineInfo};
+use pageserver_api::shard::{ShardCount, ShardNumber, TenantShardId};
+use postgres_backend::AuthType;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{path::PathBuf, process::Child};
-use utils::id::{NodeId, TenantId};
+use utils::auth::{Claims, Scope};
+use utils::id::{NodeId, TenantId, TimelineId};
 
 pub struct AttachmentService {
     env: LocalEnv,
@@ -16,23 +20,332 @@ const COMMAND: &str = "attachment_service";
 
 #[derive(Serialize, Deserialize)]
 pub struct AttachHookRequest {
-    pub tenant_id: TenantId,
+    /// A TenantId for an unsharded tenant, which older clients send as `tenant_id`
+    #[serde(alias = "tenant_id")]
+    pub tenant_shard_id: TenantShardId,
     pub node_id: Option<NodeId>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AttachHookResponse {
     pub gen: Option<u32>,
+    /// The sequence of the tenant shard after the change, see [`InspectResponse::sequence`]
+    pub sequence: u64,
+}
+
+/// Several attach hook calls in one, applied in order, and all or none of them
+#[derive(Serialize, Deserialize)]
+pub struct AttachHookBatchRequest {
+    pub attachments: Vec<AttachHookRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AttachHookBatchResponse {
+    /// In the order of the request's attachments
+    pub attachments: Vec<AttachHookResponse>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct InspectRequest {
-    pub tenant_id: TenantId,
+    #[serde(alias = "tenant_id")]
+    pub tenant_shard_id: TenantShardId,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct InspectResponse {
     pub attachment: Option<(u32, NodeId)>,
+    /// Increases with every change of the tenant shard, zero if it is unknown. Pass it in an
+    /// `If-Match` header to make a change conditional on the shard being unchanged since.
+    pub sequence: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NodeWeightRequest {
+    pub weight: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeAvailability {
+    #[default]
+    Active,
+    /// Down, e.g. for maintenance, as registered by the node or an operator: the node keeps its
+    /// attachments, but gets no new ones. Heartbeats don't change it, a node that doesn't
+    /// answer them is `unreachable` instead
+    Offline,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NodeRegisterRequest {
+    pub node_id: NodeId,
+    /// `host:port` of the management API
+    pub listen_http_addr: String,
+    /// `host:port` of the page service
+    pub listen_pg_addr: String,
+    #[serde(default)]
+    pub availability: NodeAvailability,
+    /// The availability zone of the node, for the [`TenantPlacement`] of tenants
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub availability_zone: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NodeDescribeResponse {
+    pub node_id: NodeId,
+    /// None until the node registers
+    pub listen_http_addr: Option<String>,
+    pub listen_pg_addr: Option<String>,
+    pub availability: Option<NodeAvailability>,
+    /// Whether the heartbeats failed to reach the node, which then takes no new attachments
+    /// whatever its availability
+    #[serde(default)]
+    pub unreachable: bool,
+    /// When the node last answered a heartbeat, None if it hasn't since the attachment
+    /// service started
+    pub last_seen_millis_since_epoch: Option<u64>,
+    pub weight: u32,
+    pub cordoned: bool,
+    pub availability_zone: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeDrainStatus {
+    Running,
+    Complete,
+    /// The node was uncordoned before all its shards were migrated
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NodeDrainProgress {
+    pub status: NodeDrainStatus,
+    /// The shards that were attached to the node when the drain started
+    pub total: usize,
+    pub migrated: usize,
+    /// The shards that failed to migrate, they remain attached to the node
+    pub failed: Vec<NodeDrainFailure>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NodeDrainFailure {
+    pub tenant_shard_id: TenantShardId,
+    pub error: String,
+}
+
+/// The automatic failover of the tenant shards of nodes that stay offline
+#[derive(Serialize, Deserialize)]
+pub struct FailoverStatus {
+    /// Whether the failover is on: may be turned off at runtime, e.g. during maintenance
+    pub enabled: bool,
+    /// How long a node is offline before its shards fail over, None without failover
+    pub grace_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FailoverRequest {
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TenantMigrateRequest {
+    /// The destination pageserver
+    pub node_id: NodeId,
+    /// Detach the tenant from the origin pageserver, instead of leaving a secondary location
+    #[serde(default)]
+    pub detach_origin: bool,
+}
+
+/// Where to place a tenant shard, by the availability zones of the pageservers. Only applies to
+/// the attachments that are scheduled or migrated afterwards: existing ones stay where they are.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TenantPlacement {
+    /// Prefer the pageservers in this AZ, as long as one of them is active and uncordoned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub az_affinity: Option<String>,
+    /// Never place the shard on the pageservers in these AZs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub az_anti_affinity: Vec<String>,
+}
+
+impl TenantPlacement {
+    pub fn is_empty(&self) -> bool {
+        self.az_affinity.is_none() && self.az_anti_affinity.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TenantMigrateResponse {
+    /// The generation in which the destination attached the tenant
+    pub gen: u32,
+    /// The pageserver that the tenant was attached to before
+    pub origin: Option<NodeId>,
+    pub sequence: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TenantDescribeResponse {
+    pub tenant_id: TenantId,
+    /// Sorted by shard number. A single unsharded entry for an unsharded tenant.
+    pub shards: Vec<TenantShardDescribeResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TenantShardDescribeResponse {
+    pub tenant_shard_id: TenantShardId,
+    pub shard_number: ShardNumber,
+    pub shard_count: ShardCount,
+    /// None while detached
+    pub node_id: Option<NodeId>,
+    pub gen: u32,
+    pub secondaries: Vec<NodeId>,
+    pub sequence: u64,
+    pub placement: TenantPlacement,
+}
+
+/// What `GET /tenant/:tenant_id` returns about a tenant shard, for debugging e.g. a failed
+/// migration: what the state intends, what its pageserver has, and how it got there.
+#[derive(Serialize, Deserialize)]
+pub struct TenantShardInspectResponse {
+    /// The attachment, secondary locations and placement that the state has
+    pub intent: TenantShardDescribeResponse,
+    /// What the pageserver of the intent's attachment has, None while detached
+    pub observed: Option<ObservedAttachment>,
+    /// The most recent notification of a pageserver about the tenant, i.e., our last attempt
+    /// to make a pageserver follow the intent
+    pub last_notification: Option<NotificationRecord>,
+    /// The most recent changes of the shard's attachment, oldest first
+    pub history: Vec<TenantHistoryEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ObservedAttachment {
+    pub node_id: NodeId,
+    /// The generation that the pageserver has the shard attached in, None if it doesn't have
+    /// the shard, or if it couldn't be asked
+    pub generation: Option<u32>,
+    /// How the pageserver differs from the intent, like in [`ConsistencyReport::mismatches`]
+    pub mismatch: Option<ConsistencyMismatch>,
+    /// Why the pageserver couldn't be asked
+    pub error: Option<String>,
+}
+
+/// A notification of a pageserver about a generation change, see `--notify`, as
+/// `GET /notifications` lists them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NotificationRecord {
+    pub id: u64,
+    pub tenant_id: TenantId,
+    pub node_id: NodeId,
+    /// The generation that the pageserver is told to attach in, or None to detach
+    pub generation: Option<u32>,
+    pub status: NotificationStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at_millis_since_epoch: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationStatus {
+    /// Not delivered yet, and still being retried
+    Pending,
+    Succeeded,
+    /// Gave up after `--notify-max-retries`
+    Failed,
+    /// Dropped because a later generation change made it obsolete
+    Superseded,
+}
+
+/// A change of the attachment of a tenant shard, in the history that
+/// `GET /tenant/:tenant_id/history` returns: a new generation, a detach, or both.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TenantHistoryEntry {
+    pub at_millis_since_epoch: u64,
+    pub tenant_shard_id: TenantShardId,
+    /// What made the change: an endpoint, e.g. `attach-hook`, a background task, e.g.
+    /// `drain`, or a `state` subcommand
+    pub actor: String,
+    /// None while detached
+    pub old_node_id: Option<NodeId>,
+    pub new_node_id: Option<NodeId>,
+    pub old_gen: u32,
+    pub new_gen: u32,
+}
+
+/// What the attachment service POSTs to its `--compute-hook-url` after a change of the
+/// tenant's attachments.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ComputeHookRequest {
+    pub tenant_id: TenantId,
+    /// All shards that we issued generations for, sorted by shard number
+    pub shards: Vec<ComputeHookShard>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ComputeHookShard {
+    pub shard_number: ShardNumber,
+    pub shard_count: ShardCount,
+    /// None while detached
+    pub node_id: Option<NodeId>,
+    pub gen: u32,
+    /// `host:port` of the page service to connect to, None while detached or if the node
+    /// didn't register
+    pub listen_pg_addr: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TenantLocateResponse {
+    /// The pageserver that the tenant is attached to
+    pub node_id: NodeId,
+    pub gen: u32,
+    pub sequence: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    /// The registered pageservers whose attachments were compared with the state
+    pub checked_nodes: Vec<NodeId>,
+    /// The registered pageservers that failed to list their tenants, and why
+    pub unreachable_nodes: Vec<(NodeId, String)>,
+    pub mismatches: Vec<ConsistencyMismatch>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ConsistencyMismatch {
+    pub kind: MismatchKind,
+    pub tenant_shard_id: TenantShardId,
+    /// The pageserver that has the shard attached, or for a missing tenant, that should have
+    pub node_id: NodeId,
+    /// The generation that the pageserver has the shard attached in, if any
+    pub pageserver_generation: Option<u32>,
+    /// The generation and pageserver that the state has the shard attached to, like
+    /// [`InspectResponse::attachment`]
+    pub expected: Option<(u32, NodeId)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+    /// Attached to the pageserver of the state, but not in the latest generation
+    StaleGeneration,
+    /// Attached to a pageserver that the state doesn't have it attached to
+    UnexpectedAttachment,
+    /// Not attached to the pageserver that the state has it attached to
+    MissingTenant,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimelineGenerationRequest {
+    pub safekeepers: Vec<NodeId>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimelineGenerationResponse {
+    pub generation: u32,
+    pub safekeepers: Vec<NodeId>,
+    pub pageserver_attachment: Option<(u32, NodeId)>,
 }
 
 impl AttachmentService {
@@ -66,12 +379,26 @@ impl AttachmentService {
 
     pub async fn start(&self) -> anyhow::Result<Child> {
         let path_str = self.path.to_string_lossy();
+        let jwt;
+        let mut args: Vec<&str> = vec!["-l", &self.listen, "-p", &path_str];
+        // the attachment service calls the pageservers, e.g. to migrate tenants
+        if self
+            .env
+            .pageservers
+            .iter()
+            .any(|ps| ps.http_auth_type == AuthType::NeonJWT)
+        {
+            jwt = self
+                .env
+                .generate_auth_token(&Claims::new(None, Scope::PageServerApi))?;
+            args.extend(["--pageserver-jwt", &jwt]);
+        }
 
         background_process::start_process(
             COMMAND,
             &self.env.base_data_dir,
             &self.env.attachment_service_bin(),
-            ["-l", &self.listen, "-p", &path_str],
+            args,
             [],
             background_process::InitialPidFile::Create(self.pid_file()),
             // TODO: a real status check
@@ -101,7 +428,7 @@ impl AttachmentService {
             .unwrap();
 
         let request = AttachHookRequest {
-            tenant_id,
+            tenant_shard_id: TenantShardId::unsharded(tenant_id),
             node_id: Some(pageserver_id),
         };
 
@@ -125,7 +452,9 @@ impl AttachmentService {
             .join("inspect")
             .unwrap();
 
-        let request = InspectRequest { tenant_id };
+        let request = InspectRequest {
+            tenant_shard_id: TenantShardId::unsharded(tenant_id),
+        };
 
         let response = self.client.post(url).json(&request).send().await?;
         if response.status() != StatusCode::OK {
@@ -135,4 +464,153 @@ impl AttachmentService {
         let response = response.json::<InspectResponse>().await?;
         Ok(response.attachment)
     }
+
+    /// Register a pageserver with its addresses, or update its registration
+    pub async fn node_register(&self, request: &NodeRegisterRequest) -> anyhow::Result<()> {
+        use hyper::StatusCode;
+
+        let url = self
+            .env
+            .control_plane_api
+            .clone()
+            .unwrap()
+            .join("node")
+            .unwrap();
+
+        let response = self.client.post(url).json(request).send().await?;
+        if response.status() != StatusCode::OK {
+            return Err(anyhow!("Unexpected status {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    pub async fn tenant_list(&self) -> anyhow::Result<Vec<TenantDescribeResponse>> {
+        use hyper::StatusCode;
+
+        let url = self
+            .env
+            .control_plane_api
+            .clone()
+            .unwrap()
+            .join("tenants")
+            .unwrap();
+
+        let response = self.client.get(url).send().await?;
+        if response.status() != StatusCode::OK {
+            return Err(anyhow!("Unexpected status {}", response.status()));
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// Sends a request to `path` of the API, with `body` as JSON, and decodes the response. The
+    /// error of a failed request includes the response body, which explains the failure.
+    async fn request<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> anyhow::Result<R> {
+        let url = self
+            .env
+            .control_plane_api
+            .clone()
+            .unwrap()
+            .join(path)
+            .unwrap();
+
+        let mut builder = self.client.request(method, url);
+        if let Some(body) = body {
+            builder = builder.json(body);
+        }
+        let response = builder.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Unexpected status {status}: {text}"));
+        }
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn tenant_inspect(
+        &self,
+        tenant_shard_id: TenantShardId,
+    ) -> anyhow::Result<InspectResponse> {
+        let request = InspectRequest { tenant_shard_id };
+        self.request(reqwest::Method::POST, "inspect", Some(&request))
+            .await
+    }
+
+    /// Move the tenant shard's attachment to another pageserver, see [`TenantMigrateRequest`]
+    pub async fn tenant_migrate(
+        &self,
+        tenant_shard_id: TenantShardId,
+        request: &TenantMigrateRequest,
+    ) -> anyhow::Result<TenantMigrateResponse> {
+        self.request(
+            reqwest::Method::PUT,
+            &format!("tenant/{tenant_shard_id}/migrate"),
+            Some(request),
+        )
+        .await
+    }
+
+    /// Start draining the node, or if it is already draining, get the progress
+    pub async fn node_drain(&self, node_id: NodeId) -> anyhow::Result<NodeDrainProgress> {
+        self.request::<(), _>(reqwest::Method::PUT, &format!("node/{node_id}/drain"), None)
+            .await
+    }
+
+    pub async fn node_drain_progress(&self, node_id: NodeId) -> anyhow::Result<NodeDrainProgress> {
+        self.request::<(), _>(reqwest::Method::GET, &format!("node/{node_id}/drain"), None)
+            .await
+    }
+
+    /// Create a timeline on the pageserver that the tenant is attached to
+    pub async fn timeline_create(
+        &self,
+        tenant_id: TenantId,
+        request: &TimelineCreateRequest,
+    ) -> anyhow::Result<TimelineInfo> {
+        self.request(
+            reqwest::Method::POST,
+            &format!("tenant/{tenant_id}/timeline"),
+            Some(request),
+        )
+        .await
+    }
+
+    pub async fn timeline_info(
+        &self,
+        tenant_shard_id: TenantShardId,
+        timeline_id: TimelineId,
+    ) -> anyhow::Result<TimelineInfo> {
+        self.request::<(), _>(
+            reqwest::Method::GET,
+            &format!("tenant/{tenant_shard_id}/timeline/{timeline_id}"),
+            None,
+        )
+        .await
+    }
+
+    /// Start deleting a timeline on the pageserver that the tenant shard is attached to
+    pub async fn timeline_delete(
+        &self,
+        tenant_shard_id: TenantShardId,
+        timeline_id: TimelineId,
+    ) -> anyhow::Result<()> {
+        self.request::<(), _>(
+            reqwest::Method::DELETE,
+            &format!("tenant/{tenant_shard_id}/timeline/{timeline_id}"),
+            None,
+        )
+        .await
+    }
+
+    pub async fn consistency_check(&self) -> anyhow::Result<ConsistencyReport> {
+        self.request::<(), _>(reqwest::Method::POST, "debug/consistency_check", None)
+            .await
+    }
 }
@@ -4,54 +4,309 @@
 /// This enables running & testing pageservers without a full-blown
 /// deployment of the Neon cloud platform.
 ///
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
+use futures::StreamExt;
 use hex::FromHex;
-use hyper::StatusCode;
-use hyper::{Body, Request, Response};
+use hyper::{Body, Response};
+use metrics::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+};
+use once_cell::sync::Lazy;
+use pageserver_api::models::{
+    LocationConfig, LocationConfigMode, LocationConfigSecondary, TenantConfig,
+};
 use pageserver_api::shard::TenantShardId;
+use pageserver_client::mgmt_api::{self, RetryPolicy};
+use routerify::RequestInfo;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant, SystemTime};
 use std::{collections::HashMap, sync::Arc};
-use utils::http::endpoint::request_span;
 use utils::logging::{self, LogFormat};
-use utils::signals::{ShutdownSignals, Signal};
 
 use utils::{
-    http::{
-        endpoint::{self},
-        error::ApiError,
-        json::{json_request, json_response},
-        RequestExt, RouterBuilder,
-    },
-    id::{NodeId, TenantId},
+    auth::{JwtAuth, SwappableJwtAuth},
+    http::{error::ApiError, RequestExt},
+    id::{NodeId, TenantId, TimelineId},
     tcp_listener,
 };
 
-use pageserver_api::control_api::{
-    ReAttachRequest, ReAttachResponse, ReAttachResponseTenant, ValidateRequest, ValidateResponse,
-    ValidateResponseTenant,
+use control_plane::attachment_service::{
+    AttachHookResponse, ComputeHookRequest, ComputeHookShard, NodeAvailability, NodeDrainProgress,
+    NotificationRecord, NotificationStatus, TenantMigrateResponse, TenantPlacement,
 };
 
-use control_plane::attachment_service::{
-    AttachHookRequest, AttachHookResponse, InspectRequest, InspectResponse,
+mod background;
+mod http;
+mod persistence;
+mod scheduler;
+
+use crate::background::{chaos_loop, heartbeat_loop, lease_loop, Lease};
+use crate::http::make_router;
+use crate::persistence::{
+    load_existing, load_or_new, open_persistence, Changes, Persistence, SCHEMA_VERSION,
 };
+use crate::scheduler::Failover;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(arg_required_else_help(true))]
 struct Cli {
-    /// Host and port to listen on, like `127.0.0.1:1234`
+    /// Host and port to listen on, like `127.0.0.1:1234`. Required unless running a subcommand.
     #[arg(short, long)]
-    listen: std::net::SocketAddr,
+    listen: Option<std::net::SocketAddr>,
 
-    /// Path to the .json file to store state (will be created if it doesn't exist)
-    #[arg(short, long)]
-    path: PathBuf,
+    /// Path to the .json file to store state, or to the database with `--sqlite` (will be
+    /// created if it doesn't exist)
+    #[arg(short, long, required_unless_present = "database_url")]
+    path: Option<Utf8PathBuf>,
+
+    /// Store the state at `--path` in an SQLite database rather than a JSON file, for setups
+    /// with many tenants that don't run a Postgres for `--database-url`: only the changed rows
+    /// are written. The tables are created or migrated on startup.
+    #[arg(long, requires = "path")]
+    sqlite: bool,
+
+    /// Store the state in this Postgres database instead of a file, for setups with many
+    /// tenants: the file is rewritten on every change, the database only writes the changed
+    /// rows. The tables are created or migrated on startup. Unlike the file, the database is
+    /// not locked: only one attachment service may use it at a time, unless they elect a
+    /// leader with `--leader-lease-ms`.
+    #[arg(long, conflicts_with = "path")]
+    database_url: Option<String>,
+
+    /// Elect a leader among the attachment services on the `--database-url` database, with a
+    /// lease of this many milliseconds. The leader makes all changes, the others serve reads
+    /// from the state they reload and reject changes with 503, naming the leader. They take
+    /// over once the leader fails to renew its lease within this long.
+    #[arg(long, requires = "database_url", value_parser = clap::value_parser!(u64).range(1..))]
+    leader_lease_ms: Option<u64>,
+
+    /// The URL that the other attachment services name for this one when it's the leader, see
+    /// `--leader-lease-ms`. Defaults to `http://<listen>`, or `https://<listen>` with
+    /// `--ssl-cert`. Must be unique among them.
+    #[arg(long)]
+    advertise_url: Option<String>,
+
+    /// Before serving, reconcile the state with the attachments of this pageserver,
+    /// given as `<node_id>=<mgmt_api_url>`. May be repeated.
+    #[arg(long, value_parser = parse_node_target)]
+    reconcile_with: Vec<(NodeId, String)>,
+
+    /// If there is no state yet, seed it with the attachments of this pageserver, given as
+    /// `<node_id>=<mgmt_api_url>`, to introduce the attachment service into an existing
+    /// environment. May be repeated. Fails if a pageserver can't list its tenants, so that no
+    /// generation is forgotten. Ignored once the state exists.
+    #[arg(long, value_parser = parse_node_target)]
+    import_from_pageservers: Vec<(NodeId, String)>,
+
+    /// Push generation changes to this pageserver, given as `<node_id>=<mgmt_api_url>`, so that
+    /// it attaches or detaches tenants right away instead of on its next re-attach. May be
+    /// repeated. Only for setups where nothing else configures the pageserver's tenant
+    /// locations: the pushed configurations would race with e.g. `neon_local tenant migrate`.
+    #[arg(long, value_parser = parse_node_target)]
+    notify: Vec<(NodeId, String)>,
+
+    /// After every change of a tenant's attachments, POST the pageservers of its shards to this
+    /// URL, e.g. for tests to reconfigure their computes. Retried like `--notify`.
+    #[arg(long)]
+    compute_hook_url: Option<String>,
+
+    /// Answer the re-attaches of a pageserver within this many milliseconds of its previous
+    /// one with the same generations, if its attachments haven't changed since, instead of
+    /// issuing new ones: a crash-looping pageserver then doesn't inflate the generations of its
+    /// tenants. Zero issues new generations on every re-attach.
+    #[arg(long, default_value = "0")]
+    re_attach_window_ms: u64,
+
+    /// How long a drain waits after each migration, in milliseconds, to spread the load of the
+    /// attaches on the other pageservers
+    #[arg(long, default_value = "1000")]
+    drain_interval_ms: u64,
+
+    /// Require a JWT signed with this public key, or one of the keys in this directory, on all
+    /// endpoints: with the `generations_api` scope for the pageservers' calls (`/re-attach`,
+    /// `/validate`, and registering at `POST /node`), with the `admin` scope for all others. An
+    /// `admin` token passes everywhere.
+    #[arg(long)]
+    public_key: Option<Utf8PathBuf>,
+
+    /// Authenticate to the pageservers' management APIs with this JWT, for pageservers that
+    /// require one. It needs the `pageserverapi` scope.
+    #[arg(long)]
+    pageserver_jwt: Option<String>,
+
+    /// Serve HTTPS instead of HTTP, with the certificate chain in this PEM file
+    #[arg(long, requires = "ssl_key")]
+    ssl_cert: Option<Utf8PathBuf>,
+
+    /// The PKCS#8 private key of `--ssl-cert`, in a PEM file
+    #[arg(long, requires = "ssl_cert")]
+    ssl_key: Option<Utf8PathBuf>,
+
+    /// How many times to retry a failed notification before giving up on it
+    #[arg(long, default_value = "5")]
+    notify_max_retries: u32,
+
+    /// How often to poll the status of the registered pageservers, in milliseconds
+    #[arg(long, default_value = "5000", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_interval_ms: u64,
+
+    /// Treat a registered pageserver as offline after this many consecutive failed heartbeats,
+    /// until one succeeds. Its registered availability is kept, for an operator who took it
+    /// offline.
+    #[arg(long, default_value = "3")]
+    heartbeat_max_failures: u32,
+
+    /// Once a pageserver has been offline for this many milliseconds, attach its tenant shards
+    /// to other pageservers in new generations, notifying those pageservers and the
+    /// `--compute-hook-url`. Without it, the shards of an offline pageserver stay attached to
+    /// it. May be turned off and on at runtime with PUT /failover.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    failover_grace_ms: Option<u64>,
+
+    /// For tests of the pageservers' generation handling only: this often, in milliseconds,
+    /// pick a random tenant shard and attach it in a new generation, detach it, or attach it
+    /// to another pageserver, notifying the pageservers like the attach hook does.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    chaos_interval_ms: Option<u64>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Inspect or edit the state without serving, e.g. to repair or set up a test
+    /// environment. Fails while a server uses the state file. Pageservers are not notified
+    /// of the changes, they pick them up on their next re-attach.
+    #[command(subcommand)]
+    State(StateCommand),
+}
+
+#[derive(clap::Subcommand)]
+enum StateCommand {
+    /// Print the state, or only that of one tenant shard
+    Get {
+        #[arg(long)]
+        tenant_id: Option<TenantShardId>,
+    },
+    /// Replace the state with the one read from stdin, which may be of an older schema version.
+    /// If there is a state already, the new one starts a new epoch, see `bump-epoch`.
+    Set {
+        /// Start an epoch after this one even if the existing state can't be read, which is
+        /// refused otherwise: pass at least the epoch of the existing state.
+        #[arg(long)]
+        min_epoch: Option<u32>,
+    },
+    /// Start a new epoch: every tenant shard's generation is raised above all generations of
+    /// the previous epochs, so that a state restored from an old copy doesn't hand out a
+    /// generation again. The pageservers' current generations turn invalid until they re-attach.
+    /// A server refuses to start with a state file older than the last epoch it stored.
+    BumpEpoch,
+    /// Increment the generation of a tenant shard, keeping its pageserver, and print it
+    BumpGeneration { tenant_id: TenantShardId },
+    /// Attach a tenant to a pageserver in a new generation, like the attach hook does, or
+    /// detach it if no node is given, and print the generation
+    Assign {
+        tenant_id: TenantShardId,
+        #[arg(long)]
+        node_id: Option<u64>,
+    },
+}
+
+/// The TLS configuration of `--ssl-cert` and `--ssl-key`.
+fn load_tls_config(
+    cert_path: &Utf8Path,
+    key_path: &Utf8Path,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let key_bytes = std::fs::read(key_path)
+        .with_context(|| format!("Failed to read TLS key at '{key_path}'"))?;
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &key_bytes[..])
+        .with_context(|| format!("Failed to parse TLS key at '{key_path}'"))?;
+    if keys.len() != 1 {
+        anyhow::bail!(
+            "Expected one PKCS#8 private key in '{key_path}', found {}",
+            keys.len()
+        );
+    }
+
+    let cert_bytes = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read TLS certificate at '{cert_path}'"))?;
+    let cert_chain = rustls_pemfile::certs(&mut &cert_bytes[..])
+        .with_context(|| format!("Failed to parse TLS certificate chain at '{cert_path}'"))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, rustls::PrivateKey(keys.remove(0)))
+        .context("Invalid TLS certificate or key")
+}
+
+fn parse_node_target(s: &str) -> anyhow::Result<(NodeId, String)> {
+    let (node_id, url) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected <node_id>=<mgmt_api_url>, got '{s}'"))?;
+    Ok((NodeId(node_id.parse()?), url.to_owned()))
 }
 
-// The persistent state of each Tenant
+// Served on /metrics by endpoint::make_router
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "attachment_service_requests_total",
+        "Calls of the generations API, by endpoint",
+        &["endpoint"]
+    )
+    .expect("failed to define a metric")
+});
+
+static GENERATIONS_ISSUED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "attachment_service_generations_issued_total",
+        "Tenant shard generations issued, by attaches and re-attaches"
+    )
+    .expect("failed to define a metric")
+});
+
+static RE_ATTACH_REPLAYS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "attachment_service_re_attach_replays_total",
+        "Re-attaches answered with the generations of the node's previous one, see --re-attach-window-ms"
+    )
+    .expect("failed to define a metric")
+});
+
+static PERSISTENCE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "attachment_service_persistence_seconds",
+        "Time to store a change of the state, by backend",
+        &["backend"],
+        vec![0.0001, 0.001, 0.01, 0.1, 1.0, 10.0]
+    )
+    .expect("failed to define a metric")
+});
+
+static NODE_ATTACHED_SHARDS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "attachment_service_node_attached_shards",
+        "Tenant shards attached to each pageserver",
+        &["node_id"]
+    )
+    .expect("failed to define a metric")
+});
+
+// The persistent state of each tenant shard, or of an unsharded tenant
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 struct TenantState {
     // Currently attached pageserver
     pageserver: Option<NodeId>,
@@ -59,6 +314,90 @@ struct TenantState {
     // Latest generation number: next time we attach, increment this
     // and use the incremented number when attaching
     generation: u32,
+
+    // Pageservers with a secondary location of the shard, sorted, never the attached one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    secondaries: Vec<NodeId>,
+
+    // Incremented on every change of the above, for the If-Match preconditions of requests
+    #[serde(default)]
+    sequence: u64,
+
+    // The AZs for the scheduler and migrations, see PersistentState::schedule
+    #[serde(default, skip_serializing_if = "TenantPlacement::is_empty")]
+    placement: TenantPlacement,
+
+    // The tenant config overrides that its attachments get, see PUT /tenant/:tenant_id/config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config: Option<TenantConfig>,
+}
+
+// The persistent state of each pageserver node, as configured by the operator
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct NodeState {
+    // Relative capacity of the node for scheduling: a node with weight 2 is meant
+    // to hold twice as many attachments as a node with weight 1.
+    #[serde(default = "NodeState::default_weight")]
+    weight: u32,
+
+    // A cordoned node receives no new attachments, but keeps its existing ones
+    #[serde(default)]
+    cordoned: bool,
+
+    // Set once the node registers itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    registration: Option<NodeRegistration>,
+}
+
+// What a pageserver node reports about itself when it registers
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct NodeRegistration {
+    listen_http_addr: String,
+    listen_pg_addr: String,
+    availability: NodeAvailability,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    availability_zone: Option<String>,
+
+    // Set by the heartbeats after `--heartbeat-max-failures` failures in a row, cleared by the
+    // next success. Unlike the availability, which only a registration changes, e.g. by an
+    // operator taking the node offline.
+    #[serde(default)]
+    unreachable: bool,
+}
+
+impl NodeRegistration {
+    /// Whether the node takes new attachments, as far as its availability goes.
+    fn is_active(&self) -> bool {
+        self.availability == NodeAvailability::Active && !self.unreachable
+    }
+}
+
+impl NodeState {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+impl Default for NodeState {
+    fn default() -> Self {
+        Self {
+            weight: Self::default_weight(),
+            cordoned: false,
+            registration: None,
+        }
+    }
+}
+
+// The persistent state of each Timeline's safekeeper membership, as driven by tests
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct TimelineState {
+    // Incremented on each membership change
+    generation: u32,
+
+    safekeepers: Vec<NodeId>,
 }
 
 fn to_hex_map<S, V>(input: &HashMap<TenantId, V>, serializer: S) -> Result<S::Ok, S::Error>
@@ -89,212 +428,1014 @@ where
         .collect()
 }
 
+/// The generations of epoch `e` start at `e << EPOCH_GENERATION_BITS`, which leaves a tenant
+/// shard about a million generations per epoch.
+const EPOCH_GENERATION_BITS: u32 = 20;
+
 // Top level state available to all HTTP handlers
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 struct PersistentState {
+    // Always SCHEMA_VERSION once loaded: older files are migrated in [`Self::decode`]
+    version: u32,
+
+    tenants: HashMap<TenantShardId, TenantState>,
+
+    // Nodes only appear here once they have registered or been configured, e.g. cordoned:
+    // all other nodes implicitly have the default NodeState.
+    nodes: HashMap<NodeId, NodeState>,
+
     #[serde(serialize_with = "to_hex_map", deserialize_with = "from_hex_map")]
-    tenants: HashMap<TenantId, TenantState>,
+    timelines: HashMap<TenantId, HashMap<TimelineId, TimelineState>>,
+
+    // Incremented on every change of a tenant shard's generation or attachment, and on its
+    // removal, for the sequence of /validate responses
+    #[serde(default)]
+    sequence: u64,
 
-    #[serde(skip)]
-    path: PathBuf,
+    // The generations that we issue are in the range of the epoch, see generation_floor
+    #[serde(default)]
+    epoch: u32,
 }
 
 impl PersistentState {
-    async fn save(&self) -> anyhow::Result<()> {
-        let bytes = serde_json::to_vec(self)?;
-        tokio::fs::write(&self.path, &bytes).await?;
+    fn new() -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            tenants: HashMap::new(),
+            nodes: HashMap::new(),
+            timelines: HashMap::new(),
+            sequence: 0,
+            epoch: 0,
+        }
+    }
+
+    /// The lowest generation of the current epoch: a tenant shard's generations are above
+    /// all generations of the earlier epochs.
+    fn generation_floor(&self) -> u32 {
+        self.epoch << EPOCH_GENERATION_BITS
+    }
+
+    /// Starts an epoch after both ours and `after`, e.g. the epoch of the state that this one
+    /// replaces, raising the generation of every tenant shard to the new floor.
+    fn bump_epoch(&mut self, after: u32) -> anyhow::Result<()> {
+        let epoch = self.epoch.max(after) + 1;
+        if epoch >= 1 << (u32::BITS - EPOCH_GENERATION_BITS) {
+            anyhow::bail!("Out of epochs, the last one is {}", epoch - 1);
+        }
+        self.epoch = epoch;
+        let floor = self.generation_floor();
+        for tenant_state in self.tenants.values_mut() {
+            if tenant_state.generation < floor {
+                tenant_state.generation = floor;
+                tenant_state.sequence += 1;
+            }
+        }
+        self.sequence += 1;
+        Ok(())
+    }
 
+    /// Checks the invariants that the handlers maintain, which the schema doesn't capture.
+    fn validate(&self) -> anyhow::Result<()> {
+        for (node_id, node) in &self.nodes {
+            if node.weight == 0 {
+                anyhow::bail!("node {node_id} has weight zero");
+            }
+        }
         Ok(())
     }
 
-    async fn load(path: &Path) -> anyhow::Result<Self> {
-        let bytes = tokio::fs::read(path).await?;
-        let mut decoded = serde_json::from_slice::<Self>(&bytes)?;
-        decoded.path = path.to_owned();
-        Ok(decoded)
+    /// Attaches the tenant shard to `node_id` in a new generation, or detaches it if None.
+    /// Returns the previous pageserver and the generation.
+    fn attach(
+        &mut self,
+        tenant_shard_id: TenantShardId,
+        node_id: Option<NodeId>,
+    ) -> (Option<NodeId>, u32) {
+        let generation_floor = self.generation_floor();
+        let tenant_state = self
+            .tenants
+            .entry(tenant_shard_id)
+            .or_insert_with(|| TenantState {
+                pageserver: node_id,
+                generation: generation_floor,
+                secondaries: Vec::new(),
+                sequence: 0,
+                placement: TenantPlacement::default(),
+                config: None,
+            });
+        let previous_pageserver = tenant_state.pageserver;
+        tenant_state.sequence += 1;
+        self.sequence += 1;
+
+        if let Some(attaching_pageserver) = node_id.as_ref() {
+            tenant_state.generation += 1;
+            GENERATIONS_ISSUED.inc();
+            tracing::info!(
+                %tenant_shard_id,
+                ps_id = %attaching_pageserver,
+                generation = %tenant_state.generation,
+                "issuing",
+            );
+        } else if let Some(ps_id) = tenant_state.pageserver {
+            tracing::info!(
+                %tenant_shard_id,
+                %ps_id,
+                generation = %tenant_state.generation,
+                "dropping",
+            );
+        } else {
+            tracing::info!(
+                %tenant_shard_id,
+                "no-op: tenant already has no pageserver");
+        }
+        tenant_state.pageserver = node_id;
+        // attaching a location replaces its secondary
+        tenant_state.secondaries.retain(|s| Some(*s) != node_id);
+
+        (previous_pageserver, tenant_state.generation)
     }
 
-    async fn load_or_new(path: &Path) -> Self {
-        match Self::load(path).await {
-            Ok(s) => {
-                tracing::info!("Loaded state file at {}", path.display());
-                s
+    /// Records a secondary location of the tenant shard on `node_id`, if it still exists.
+    fn add_secondary(&mut self, tenant_shard_id: TenantShardId, node_id: NodeId) {
+        if let Some(tenant_state) = self.tenants.get_mut(&tenant_shard_id) {
+            if let Err(i) = tenant_state.secondaries.binary_search(&node_id) {
+                tenant_state.secondaries.insert(i, node_id);
+                tenant_state.sequence += 1;
             }
-            Err(e)
-                if e.downcast_ref::<std::io::Error>()
-                    .map(|e| e.kind() == std::io::ErrorKind::NotFound)
-                    .unwrap_or(false) =>
-            {
-                tracing::info!("Will create state file at {}", path.display());
-                Self {
-                    tenants: HashMap::new(),
-                    path: path.to_owned(),
-                }
+        }
+    }
+
+    /// Records that `node_id` has the tenant shard attached, unless another pageserver was
+    /// already found to have it attached in a later generation. Returns whether it did.
+    fn import_attachment(
+        &mut self,
+        node_id: NodeId,
+        tenant: &pageserver_api::models::TenantInfo,
+    ) -> bool {
+        let Some(generation) = tenant.generation else {
+            tracing::warn!(%node_id, tenant_id = %tenant.id, "import: attached without a generation");
+            return false;
+        };
+        if let Some(existing) = self.tenants.get(&tenant.id) {
+            // the pageserver with the older generation is stale, it fails its validations
+            if existing.generation >= generation {
+                tracing::warn!(
+                    %node_id, tenant_id = %tenant.id, generation,
+                    "import: also attached to {:?} in generation {}, keeping that",
+                    existing.pageserver, existing.generation
+                );
+                return false;
+            }
+        }
+        self.sequence += 1;
+        self.tenants.insert(
+            tenant.id,
+            TenantState {
+                pageserver: Some(node_id),
+                generation,
+                secondaries: Vec::new(),
+                sequence: 0,
+                placement: TenantPlacement::default(),
+                config: None,
+            },
+        );
+        true
+    }
+
+    /// Reconciles the state with an attachment that `node_id` reports, see [`reconcile`].
+    ///
+    /// An adopted attachment gets the generation after the reported one, at least the floor of
+    /// the epoch: the state may have lost track of generations that were issued after the
+    /// reported one, and must not issue them again.
+    fn reconcile_attachment(
+        &mut self,
+        node_id: NodeId,
+        tenant: &pageserver_api::models::TenantInfo,
+    ) -> Reconciled {
+        let Some(generation) = tenant.generation else {
+            tracing::warn!(
+                %node_id, tenant_id = %tenant.id,
+                "reconcile: attached without a generation"
+            );
+            return Reconciled::Flagged;
+        };
+
+        let generation_floor = self.generation_floor();
+        let tenant_state = self.tenants.entry(tenant.id).or_insert(TenantState {
+            pageserver: None,
+            generation: 0,
+            secondaries: Vec::new(),
+            sequence: 0,
+            placement: TenantPlacement::default(),
+            config: None,
+        });
+
+        match tenant_state.pageserver {
+            Some(ps) if ps != node_id => {
+                tracing::warn!(
+                    %node_id, tenant_id = %tenant.id, generation,
+                    "reconcile: also attached to {ps} (generation {})",
+                    tenant_state.generation
+                );
+                Reconciled::Flagged
+            }
+            // already adopted, or attached since: the pageserver is yet to re-attach
+            Some(_) if tenant_state.generation > generation => Reconciled::Unchanged,
+            None if tenant_state.generation > generation => {
+                tracing::warn!(
+                    %node_id, tenant_id = %tenant.id, generation,
+                    "reconcile: stale generation, latest is {}",
+                    tenant_state.generation
+                );
+                Reconciled::Flagged
+            }
+            _ => {
+                let adopted = (generation + 1).max(generation_floor);
+                tracing::info!(
+                    %node_id, tenant_id = %tenant.id, generation,
+                    "reconcile: adopting in generation {adopted}"
+                );
+                tenant_state.pageserver = Some(node_id);
+                tenant_state.generation = adopted;
+                tenant_state.sequence += 1;
+                self.sequence += 1;
+                Reconciled::Adopted
             }
+        }
+    }
+
+    /// Checks the `If-Match` precondition of a request that changes the tenant shard, see
+    /// [`parse_if_match`]: a client that read an older sequence of the shard would overwrite
+    /// a change it didn't see.
+    fn check_sequence(
+        &self,
+        tenant_shard_id: TenantShardId,
+        expected: Option<u64>,
+    ) -> Result<(), ApiError> {
+        let sequence = self.tenants.get(&tenant_shard_id).map_or(0, |t| t.sequence);
+        match expected {
+            Some(expected) if expected != sequence => Err(ApiError::Conflict(format!(
+                "Tenant shard {tenant_shard_id} changed: it is at sequence {sequence}, \
+                 not {expected}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The tenant config of the tenant shard's locations: its overrides, if any.
+    fn tenant_config(&self, tenant_shard_id: TenantShardId) -> TenantConfig {
+        self.tenants
+            .get(&tenant_shard_id)
+            .and_then(|t| t.config.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// How many times a call to a pageserver is attempted on transient errors, see
+/// [`mgmt_api_client`].
+const PAGESERVER_MAX_ATTEMPTS: u32 = 3;
+
+/// A client of the management API of a pageserver at `url`, authenticating with `jwt`, see
+/// `--pageserver-jwt`. It retries transient errors: our calls to pageservers are idempotent,
+/// they read, or configure or delete a tenant shard.
+fn mgmt_api_client(url: String, jwt: Option<&str>) -> mgmt_api::Client {
+    mgmt_api::Client::new(url, jwt).with_retry_policy(RetryPolicy::transient(
+        NonZeroU32::new(PAGESERVER_MAX_ATTEMPTS).unwrap(),
+    ))
+}
+
+/// Seeds a new state with the attachments that the pageservers list, see
+/// `--import-from-pageservers`.
+async fn import_attachments(
+    state: &mut PersistentState,
+    targets: &[(NodeId, String)],
+    pageserver_jwt: Option<&str>,
+) -> anyhow::Result<()> {
+    for (node_id, url) in targets {
+        let client = mgmt_api_client(url.clone(), pageserver_jwt);
+        let tenants = client
+            .list_tenants()
+            .await
+            .with_context(|| format!("Failed to list the tenants of node {node_id} at {url}"))?;
+        let mut imported = 0;
+        for tenant in &tenants {
+            if state.import_attachment(*node_id, tenant) {
+                imported += 1;
+            }
+        }
+        tracing::info!(
+            %node_id,
+            "Imported {imported} of {} attachments",
+            tenants.len()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reconciled {
+    Adopted,
+    Flagged,
+    Unchanged,
+}
+
+/// Adopt the attachments that pageservers report into the state, so that a state file that was
+/// lost or recreated doesn't hand out generations older than the ones the pageservers hold:
+/// that would fail all their deletion validations.
+///
+/// Attachments that contradict the state (attached elsewhere, or detached since in a later
+/// generation) are only logged: the pageserver will pick up the state's view on its next
+/// re-attach.
+async fn reconcile(
+    state: &mut PersistentState,
+    targets: &[(NodeId, String)],
+    pageserver_jwt: Option<&str>,
+) {
+    let mut adopted = 0;
+    let mut flagged = 0;
+
+    for (node_id, url) in targets {
+        let client = mgmt_api_client(url.clone(), pageserver_jwt);
+        let tenants = match client.list_tenants().await {
+            Ok(tenants) => tenants,
             Err(e) => {
-                panic!("Failed to load state from '{}': {e:#} (maybe your .neon/ dir was written by an older version?)", path.display())
+                tracing::warn!(%node_id, "reconcile: failed to list tenants at {url}: {e}");
+                continue;
             }
+        };
+
+        for tenant in &tenants {
+            match state.reconcile_attachment(*node_id, tenant) {
+                Reconciled::Adopted => adopted += 1,
+                Reconciled::Flagged => flagged += 1,
+                Reconciled::Unchanged => {}
+            }
+        }
+    }
+
+    tracing::info!(
+        "Reconciled with {} pageservers: adopted {adopted}, flagged {flagged}",
+        targets.len()
+    );
+}
+
+/// Sets NODE_ATTACHED_SHARDS from the state. Nodes that no longer have attachments report zero
+/// until we restart.
+fn update_node_metrics(state: &PersistentState) {
+    let mut attached: HashMap<NodeId, i64> = HashMap::new();
+    for tenant in state.tenants.values() {
+        if let Some(node_id) = tenant.pageserver {
+            *attached.entry(node_id).or_default() += 1;
         }
     }
+    for node_id in state.nodes.keys() {
+        attached.entry(*node_id).or_default();
+    }
+    for (node_id, count) in attached {
+        NODE_ATTACHED_SHARDS
+            .with_label_values(&[&node_id.to_string()])
+            .set(count);
+    }
 }
 
 /// State available to HTTP request handlers
-#[derive(Clone)]
 struct State {
     inner: Arc<tokio::sync::RwLock<PersistentState>>,
+    persistence: Box<dyn Persistence>,
+
+    // The most recently handled requests, oldest first, served by /debug/requests
+    recent_requests: std::sync::Mutex<VecDeque<RequestRecord>>,
+
+    // The mgmt API URLs of the pageservers to notify of generation changes, see Cli::notify
+    notify_targets: HashMap<NodeId, String>,
+    notify_max_retries: u32,
+
+    // See Cli::compute_hook_url
+    compute_hook_url: Option<String>,
+
+    // The most recent notifications, oldest first, served by /notifications
+    notifications: std::sync::Mutex<Notifications>,
+
+    // The heartbeats of the registered nodes since we started, see heartbeat_loop
+    liveness: std::sync::Mutex<HashMap<NodeId, Liveness>>,
+
+    // None if auth is disabled, see Cli::public_key
+    auth: Option<SwappableJwtAuth>,
+
+    // See Cli::pageserver_jwt
+    pageserver_jwt: Option<String>,
+
+    // The progress of the most recent drain of each node since we started, see drain_node
+    drains: std::sync::Mutex<HashMap<NodeId, NodeDrainProgress>>,
+    drain_interval: Duration,
+
+    // The most recent re-attach of each node since we started, see Cli::re_attach_window_ms
+    re_attaches: std::sync::Mutex<HashMap<NodeId, RecentReAttach>>,
+    re_attach_window: Duration,
+
+    // None without leader election, see Cli::leader_lease_ms
+    lease: Option<Lease>,
+
+    // None without failover, see Cli::failover_grace_ms
+    failover: Option<Failover>,
 }
 
 impl State {
-    fn new(persistent_state: PersistentState) -> State {
+    /// The management API of a pageserver: from its registration, or else its notify target.
+    fn node_mgmt_api_url(&self, locked: &PersistentState, node_id: NodeId) -> Option<String> {
+        locked
+            .nodes
+            .get(&node_id)
+            .and_then(|n| n.registration.as_ref())
+            .map(|r| format!("http://{}", r.listen_http_addr))
+            .or_else(|| self.notify_targets.get(&node_id).cloned())
+    }
+
+    /// A client of the management API of a pageserver at `url`, see [`mgmt_api_client`].
+    fn mgmt_api_client(&self, url: String) -> mgmt_api::Client {
+        mgmt_api_client(url, self.pageserver_jwt.as_deref())
+    }
+
+    fn new(
+        persistent_state: PersistentState,
+        persistence: Box<dyn Persistence>,
+        notify_targets: HashMap<NodeId, String>,
+        notify_max_retries: u32,
+        compute_hook_url: Option<String>,
+        auth: Option<SwappableJwtAuth>,
+        pageserver_jwt: Option<String>,
+        drain_interval: Duration,
+        re_attach_window: Duration,
+        lease: Option<Lease>,
+        failover_grace: Option<Duration>,
+    ) -> State {
         Self {
             inner: Arc::new(tokio::sync::RwLock::new(persistent_state)),
+            persistence,
+            recent_requests: std::sync::Mutex::new(VecDeque::with_capacity(RECENT_REQUESTS)),
+            notify_targets,
+            notify_max_retries,
+            compute_hook_url,
+            notifications: std::sync::Mutex::new(Notifications {
+                next_id: 0,
+                recent: VecDeque::with_capacity(RECENT_NOTIFICATIONS),
+            }),
+            liveness: std::sync::Mutex::new(HashMap::new()),
+            auth,
+            pageserver_jwt,
+            drains: std::sync::Mutex::new(HashMap::new()),
+            drain_interval,
+            re_attaches: std::sync::Mutex::new(HashMap::new()),
+            re_attach_window,
+            lease,
+            failover: failover_grace.map(|grace| Failover {
+                grace,
+                enabled: std::sync::atomic::AtomicBool::new(true),
+            }),
         }
     }
-}
 
-#[inline(always)]
-fn get_state(request: &Request<Body>) -> &State {
-    request
-        .data::<Arc<State>>()
-        .expect("unknown state type")
-        .as_ref()
-}
+    /// Whether we make the changes: always without leader election.
+    fn is_leader(&self) -> bool {
+        self.lease.as_ref().map_or(true, Lease::is_held)
+    }
 
-/// Pageserver calls into this on startup, to learn which tenants it should attach
-async fn handle_re_attach(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
-    let reattach_req = json_request::<ReAttachRequest>(&mut req).await?;
+    /// Stores the `changes` that a handler made to `locked`.
+    async fn save(&self, locked: &PersistentState, changes: Changes) -> Result<(), ApiError> {
+        self.persist(locked, &changes)
+            .await
+            .map_err(ApiError::InternalServerError)
+    }
 
-    let state = get_state(&req).inner.clone();
-    let mut locked = state.write().await;
+    async fn persist(&self, locked: &PersistentState, changes: &Changes) -> anyhow::Result<()> {
+        let timer = PERSISTENCE_SECONDS
+            .with_label_values(&[self.persistence.backend()])
+            .start_timer();
+        self.persistence.save(locked, changes).await?;
+        timer.observe_duration();
 
-    let mut response = ReAttachResponse {
-        tenants: Vec::new(),
-    };
-    for (t, state) in &mut locked.tenants {
-        if state.pageserver == Some(reattach_req.node_id) {
-            state.generation += 1;
-            response.tenants.push(ReAttachResponseTenant {
-                // TODO(sharding): make this shard-aware
-                id: TenantShardId::unsharded(*t),
-                gen: state.generation,
+        update_node_metrics(locked);
+        Ok(())
+    }
+
+    /// Tell the pageserver `node_id` to attach the tenant in `generation`, or to detach it if
+    /// `generation` is None, in the background. A no-op unless `node_id` is a notify target.
+    fn notify(
+        self: &Arc<Self>,
+        tenant_shard_id: TenantShardId,
+        node_id: NodeId,
+        generation: Option<u32>,
+    ) {
+        let Some(url) = self.notify_targets.get(&node_id) else {
+            return;
+        };
+        self.notify_at(tenant_shard_id, node_id, url.clone(), generation);
+    }
+
+    /// Like [`Self::notify`], but for any pageserver, at the mgmt API `url`.
+    fn notify_at(
+        self: &Arc<Self>,
+        tenant_shard_id: TenantShardId,
+        node_id: NodeId,
+        url: String,
+        generation: Option<u32>,
+    ) {
+        // TODO(sharding): the location config of a shard needs its stripe size, which we
+        // don't know
+        if !tenant_shard_id.is_unsharded() {
+            tracing::info!(%tenant_shard_id, %node_id, "not notifying about a shard");
+            return;
+        }
+        let tenant_id = tenant_shard_id.tenant_id;
+
+        let id = {
+            let mut notifications = self.notifications.lock().unwrap();
+            let id = notifications.next_id;
+            notifications.next_id += 1;
+            if notifications.recent.len() == RECENT_NOTIFICATIONS {
+                notifications.recent.pop_front();
+            }
+            notifications.recent.push_back(NotificationRecord {
+                id,
+                tenant_id,
+                node_id,
+                generation,
+                status: NotificationStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                created_at_millis_since_epoch: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
             });
+            id
+        };
+
+        tokio::spawn(deliver_notification(
+            Arc::clone(self),
+            id,
+            // that retries, a retry of each of its attempts would multiply them
+            self.mgmt_api_client(url.clone())
+                .with_retry_policy(RetryPolicy::never()),
+        ));
+    }
+
+    /// Notifies the pageservers of an attach hook call: the new one of the attachment, and the
+    /// previous one that it replaced, if any.
+    fn notify_attachment(
+        self: &Arc<Self>,
+        tenant_shard_id: TenantShardId,
+        node_id: Option<NodeId>,
+        previous: Option<NodeId>,
+        generation: u32,
+    ) {
+        if let Some(node_id) = node_id {
+            self.notify(tenant_shard_id, node_id, Some(generation));
+        }
+        if let Some(previous) = previous.filter(|ps| Some(*ps) != node_id) {
+            self.notify(tenant_shard_id, previous, None);
+        }
+    }
+
+    /// Tells the `--compute-hook-url` about the attachments of the tenant's shards in `locked`,
+    /// after a change of them.
+    fn notify_compute(self: &Arc<Self>, locked: &PersistentState, tenant_id: TenantId) {
+        let Some(url) = &self.compute_hook_url else {
+            return;
+        };
+        tokio::spawn(deliver_compute_hook(
+            Arc::clone(self),
+            url.clone(),
+            compute_hook_request(locked, tenant_id),
+        ));
+    }
+
+    /// Updates the record of a notification, unless it has already been evicted.
+    fn update_notification(&self, id: u64, f: impl FnOnce(&mut NotificationRecord)) {
+        let mut notifications = self.notifications.lock().unwrap();
+        if let Some(record) = notifications.recent.iter_mut().find(|r| r.id == id) {
+            f(record);
         }
     }
 
-    locked.save().await.map_err(ApiError::InternalServerError)?;
+    fn record_request(&self, response: &Response<Body>, info: &RequestInfo) {
+        if info.uri().path() == "/debug/requests" {
+            return;
+        }
+        let Some(start) = info.context::<RequestStart>() else {
+            return;
+        };
+        let record = RequestRecord {
+            request_id: response
+                .headers()
+                .get("x-request-id")
+                .and_then(|id| id.to_str().ok())
+                .unwrap_or_default()
+                .to_owned(),
+            method: info.method().to_string(),
+            path: info.uri().path().to_owned(),
+            status: response.status().as_u16(),
+            started_at_millis_since_epoch: start
+                .wall
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            duration_micros: start.at.elapsed().as_micros() as u64,
+        };
 
-    json_response(StatusCode::OK, response)
+        let mut recent = self.recent_requests.lock().unwrap();
+        if recent.len() == RECENT_REQUESTS {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
 }
 
-/// Pageserver calls into this before doing deletions, to confirm that it still
-/// holds the latest generation for the tenants with deletions enqueued
-async fn handle_validate(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
-    let validate_req = json_request::<ValidateRequest>(&mut req).await?;
+/// How many requests /debug/requests remembers.
+const RECENT_REQUESTS: usize = 256;
 
-    let locked = get_state(&req).inner.read().await;
+// Set on each request before it is routed, for its RequestRecord
+#[derive(Clone, Copy)]
+struct RequestStart {
+    at: Instant,
+    wall: SystemTime,
+}
 
-    let mut response = ValidateResponse {
-        tenants: Vec::new(),
-    };
+// A handled request. The request_id is the one in the request's tracing span and in the
+// x-request-id header of the response, taken from the request's header if the client set it.
+#[derive(Serialize, Clone)]
+struct RequestRecord {
+    request_id: String,
+    method: String,
+    path: String,
+    status: u16,
+    started_at_millis_since_epoch: u64,
+    duration_micros: u64,
+}
 
-    for req_tenant in validate_req.tenants {
-        // TODO(sharding): make this shard-aware
-        if let Some(tenant_state) = locked.tenants.get(&req_tenant.id.tenant_id) {
-            let valid = tenant_state.generation == req_tenant.gen;
-            tracing::info!(
-                "handle_validate: {}(gen {}): valid={valid} (latest {})",
-                req_tenant.id,
-                req_tenant.gen,
-                tenant_state.generation
-            );
-            response.tenants.push(ValidateResponseTenant {
-                id: req_tenant.id,
-                valid,
-            });
-        }
+/// The generations that a re-attach of a node handed out.
+struct RecentReAttach {
+    at: Instant,
+    // Sorted by tenant shard
+    generations: Vec<(TenantShardId, u32)>,
+}
+
+impl RecentReAttach {
+    /// Whether a re-attach of the node at `now` gets the same generations: if it's within
+    /// `window`, and the node's attachments are still these `generations`.
+    fn replays(
+        &self,
+        now: Instant,
+        window: Duration,
+        generations: &[(TenantShardId, u32)],
+    ) -> bool {
+        now.saturating_duration_since(self.at) < window && self.generations == generations
     }
+}
 
-    json_response(StatusCode::OK, response)
+/// The generations of the tenant shards attached to `node_id`, sorted by tenant shard.
+fn attached_generations(locked: &PersistentState, node_id: NodeId) -> Vec<(TenantShardId, u32)> {
+    let mut generations: Vec<_> = locked
+        .tenants
+        .iter()
+        .filter(|(_, t)| t.pageserver == Some(node_id))
+        .map(|(id, t)| (*id, t.generation))
+        .collect();
+    generations.sort();
+    generations
 }
-/// Call into this before attaching a tenant to a pageserver, to acquire a generation number
-/// (in the real control plane this is unnecessary, because the same program is managing
-///  generation numbers and doing attachments).
-async fn handle_attach_hook(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
-    let attach_req = json_request::<AttachHookRequest>(&mut req).await?;
 
-    let state = get_state(&req).inner.clone();
-    let mut locked = state.write().await;
+#[derive(Default)]
+struct Liveness {
+    last_seen: Option<SystemTime>,
+    consecutive_failures: u32,
+    // When the failed heartbeats reached the maximum, for the failover's grace period
+    offline_since: Option<Instant>,
+}
 
-    let tenant_state = locked
-        .tenants
-        .entry(attach_req.tenant_id)
-        .or_insert_with(|| TenantState {
-            pageserver: attach_req.node_id,
-            generation: 0,
-        });
+/// How many notifications /notifications remembers.
+const RECENT_NOTIFICATIONS: usize = 256;
 
-    if let Some(attaching_pageserver) = attach_req.node_id.as_ref() {
-        tenant_state.generation += 1;
-        tracing::info!(
-            tenant_id = %attach_req.tenant_id,
-            ps_id = %attaching_pageserver,
-            generation = %tenant_state.generation,
-            "issuing",
-        );
-    } else if let Some(ps_id) = tenant_state.pageserver {
-        tracing::info!(
-            tenant_id = %attach_req.tenant_id,
-            %ps_id,
-            generation = %tenant_state.generation,
-            "dropping",
+const NOTIFY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const NOTIFY_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+struct Notifications {
+    next_id: u64,
+    recent: VecDeque<NotificationRecord>,
+}
+
+/// Delivers a notification with retries, until it succeeds, runs out of retries, or a later
+/// generation change supersedes it: that one comes with its own notification.
+async fn deliver_notification(state: Arc<State>, id: u64, client: mgmt_api::Client) {
+    let Some((tenant_id, node_id, generation)) = state
+        .notifications
+        .lock()
+        .unwrap()
+        .recent
+        .iter()
+        .find(|r| r.id == id)
+        .map(|r| (r.tenant_id, r.node_id, r.generation))
+    else {
+        return;
+    };
+
+    let mut backoff = NOTIFY_INITIAL_BACKOFF;
+    for attempt in 1.. {
+        let (superseded, tenant_conf) = {
+            let locked = state.inner.read().await;
+            let tenant_shard_id = TenantShardId::unsharded(tenant_id);
+            let tenant_state = locked.tenants.get(&tenant_shard_id);
+            let superseded = match generation {
+                Some(generation) => !tenant_state
+                    .is_some_and(|s| s.pageserver == Some(node_id) && s.generation == generation),
+                None => tenant_state.is_some_and(|s| s.pageserver == Some(node_id)),
+            };
+            (superseded, locked.tenant_config(tenant_shard_id))
+        };
+        if superseded {
+            tracing::info!(%tenant_id, %node_id, ?generation, "notification superseded");
+            state.update_notification(id, |r| r.status = NotificationStatus::Superseded);
+            return;
+        }
+
+        let config = location_config(
+            match generation {
+                Some(_) => LocationConfigMode::AttachedSingle,
+                None => LocationConfigMode::Detached,
+            },
+            generation,
+            None,
+            tenant_conf,
         );
-    } else {
-        tracing::info!(
-            tenant_id = %attach_req.tenant_id,
-            "no-op: tenant already has no pageserver");
+        match client.location_config(tenant_id, config, None).await {
+            Ok(()) => {
+                tracing::info!(%tenant_id, %node_id, ?generation, attempt, "notified");
+                state.update_notification(id, |r| {
+                    r.status = NotificationStatus::Succeeded;
+                    r.attempts = attempt;
+                });
+                return;
+            }
+            Err(e) => {
+                let failed = attempt > state.notify_max_retries;
+                state.update_notification(id, |r| {
+                    if failed {
+                        r.status = NotificationStatus::Failed;
+                    }
+                    r.attempts = attempt;
+                    r.last_error = Some(e.to_string());
+                });
+                if failed {
+                    tracing::warn!(
+                        %tenant_id, %node_id, ?generation, attempt,
+                        "giving up on notification: {e}"
+                    );
+                    return;
+                }
+                tracing::info!(
+                    %tenant_id, %node_id, ?generation, attempt,
+                    "notification failed, retrying in {backoff:?}: {e}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(NOTIFY_MAX_BACKOFF);
+            }
+        }
     }
-    tenant_state.pageserver = attach_req.node_id;
-    let generation = tenant_state.generation;
+}
 
-    tracing::info!(
-        "handle_attach_hook: tenant {} set generation {}, pageserver {}",
-        attach_req.tenant_id,
-        tenant_state.generation,
-        attach_req.node_id.unwrap_or(utils::id::NodeId(0xfffffff))
-    );
+/// The attachments of the tenant's shards, for the `--compute-hook-url`.
+fn compute_hook_request(locked: &PersistentState, tenant_id: TenantId) -> ComputeHookRequest {
+    let mut shards: Vec<ComputeHookShard> = locked
+        .tenants
+        .iter()
+        .filter(|(tenant_shard_id, _)| tenant_shard_id.tenant_id == tenant_id)
+        .map(|(tenant_shard_id, tenant_state)| ComputeHookShard {
+            shard_number: tenant_shard_id.shard_number,
+            shard_count: tenant_shard_id.shard_count,
+            node_id: tenant_state.pageserver,
+            gen: tenant_state.generation,
+            listen_pg_addr: tenant_state
+                .pageserver
+                .and_then(|node_id| locked.nodes.get(&node_id)?.registration.as_ref())
+                .map(|r| r.listen_pg_addr.clone()),
+        })
+        .collect();
+    shards.sort_by_key(|s| s.shard_number);
+    ComputeHookRequest { tenant_id, shards }
+}
 
-    locked.save().await.map_err(ApiError::InternalServerError)?;
+/// How long to wait for the `--compute-hook-url` to respond, before retrying.
+const COMPUTE_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
 
-    json_response(
-        StatusCode::OK,
-        AttachHookResponse {
-            gen: attach_req.node_id.map(|_| generation),
-        },
-    )
+/// Delivers a compute hook request with retries, like [`deliver_notification`], until a later
+/// change of the tenant's attachments supersedes it.
+async fn deliver_compute_hook(state: Arc<State>, url: String, request: ComputeHookRequest) {
+    let tenant_id = request.tenant_id;
+    let client = reqwest::Client::new();
+    let mut backoff = NOTIFY_INITIAL_BACKOFF;
+    for attempt in 1.. {
+        if compute_hook_request(&*state.inner.read().await, tenant_id) != request {
+            tracing::info!(%tenant_id, "compute hook superseded");
+            return;
+        }
+
+        let result = client
+            .post(&url)
+            .json(&request)
+            .timeout(COMPUTE_HOOK_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                tracing::info!(%tenant_id, attempt, "notified the compute hook");
+                return;
+            }
+            Err(e) if attempt > state.notify_max_retries => {
+                tracing::warn!(%tenant_id, attempt, "giving up on the compute hook: {e}");
+                return;
+            }
+            Err(e) => {
+                tracing::info!(
+                    %tenant_id, attempt,
+                    "compute hook failed, retrying in {backoff:?}: {e}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(NOTIFY_MAX_BACKOFF);
+            }
+        }
+    }
 }
 
-async fn handle_inspect(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
-    let inspect_req = json_request::<InspectRequest>(&mut req).await?;
+// TODO(sharding): make this shard-aware
+fn location_config(
+    mode: LocationConfigMode,
+    generation: Option<u32>,
+    secondary_conf: Option<LocationConfigSecondary>,
+    tenant_conf: TenantConfig,
+) -> LocationConfig {
+    LocationConfig {
+        mode,
+        generation,
+        secondary_conf,
+        tenant_conf,
+        shard_number: 0,
+        shard_count: 0,
+        shard_stripe_size: 0,
+    }
+}
 
-    let state = get_state(&req).inner.clone();
-    let locked = state.write().await;
-    let tenant_state = locked.tenants.get(&inspect_req.tenant_id);
+/// The migration of [`handle_tenant_migrate`], also used by drains. The `actor` is for the
+/// tenant's history.
+async fn migrate_tenant(
+    state: &Arc<State>,
+    tenant_shard_id: TenantShardId,
+    dest: NodeId,
+    detach_origin: bool,
+    expected_sequence: Option<u64>,
+    actor: &str,
+) -> Result<TenantMigrateResponse, ApiError> {
+    // TODO(sharding): the location config of a shard needs its stripe size, which we don't know
+    if !tenant_shard_id.is_unsharded() {
+        return Err(ApiError::BadRequest(anyhow!(
+            "Migrating a shard is not supported, only unsharded tenants"
+        )));
+    }
+    let tenant_id = tenant_shard_id.tenant_id;
 
-    json_response(
-        StatusCode::OK,
-        InspectResponse {
-            attachment: tenant_state.and_then(|s| s.pageserver.map(|ps| (s.generation, ps))),
-        },
-    )
-}
+    let (origin, dest_url, origin_url, generation, mut sequence, tenant_conf) = {
+        let mut locked = state.inner.write().await;
+        locked.check_sequence(tenant_shard_id, expected_sequence)?;
+
+        let Some(tenant_state) = locked.tenants.get(&tenant_shard_id) else {
+            return Err(ApiError::NotFound(
+                anyhow!("Tenant {tenant_id} not found").into(),
+            ));
+        };
+        let origin = tenant_state
+            .pageserver
+            .map(|ps| (ps, tenant_state.generation));
+        if locked.nodes.get(&dest).is_some_and(|n| n.cordoned) {
+            return Err(ApiError::Conflict(format!(
+                "Node {dest} is cordoned, cannot migrate tenant {tenant_id} to it"
+            )));
+        }
+        let dest_url = state.node_mgmt_api_url(&locked, dest).ok_or_else(|| {
+            ApiError::BadRequest(anyhow!(
+                "Node {dest} has no known management API address, register it first"
+            ))
+        })?;
+        let origin_url = origin
+            .filter(|(ps, _)| *ps != dest)
+            .and_then(|(ps, _)| state.node_mgmt_api_url(&locked, ps));
+
+        let (previous, generation) = locked.attach(tenant_shard_id, Some(dest));
+        state
+            .save(
+                &locked,
+                Changes::attachment(actor, tenant_shard_id, previous, Some(dest), generation),
+            )
+            .await?;
+        let sequence = locked.tenants[&tenant_shard_id].sequence;
+        let tenant_conf = locked.tenant_config(tenant_shard_id);
+        (
+            origin,
+            dest_url,
+            origin_url,
+            generation,
+            sequence,
+            tenant_conf,
+        )
+    };
+
+    let origin_client = origin_url.map(|url| state.mgmt_api_client(url));
+    if let (Some(client), Some((origin_id, origin_generation))) = (&origin_client, origin) {
+        // An origin that is down can't be made stale, but it can't write either
+        let stale = location_config(
+            LocationConfigMode::AttachedStale,
+            Some(origin_generation),
+            None,
+            tenant_conf.clone(),
+        );
+        if let Err(e) = client
+            .location_config(tenant_id, stale, Some(Duration::from_secs(10)))
+            .await
+        {
+            tracing::warn!(%tenant_id, %origin_id, "failed to make origin stale: {e}");
+        }
+    }
+
+    let attach = location_config(
+        LocationConfigMode::AttachedSingle,
+        Some(generation),
+        None,
+        tenant_conf.clone(),
+    );
+    state
+        .mgmt_api_client(dest_url)
+        .location_config(tenant_id, attach, None)
+        .await
+        .map_err(|e| {
+            ApiError::InternalServerError(anyhow!(
+                "Failed to attach tenant {tenant_id} to node {dest}: {e}"
+            ))
+        })?;
+    // the computes can switch over now, the origin keeps serving them until it's reconfigured
+    state.notify_compute(&*state.inner.read().await, tenant_id);
+
+    if let (Some(client), Some((origin_id, _))) = (&origin_client, origin) {
+        let config = if detach_origin {
+            location_config(
+                LocationConfigMode::Detached,
+                None,
+                None,
+                TenantConfig::default(),
+            )
+        } else {
+            location_config(
+                LocationConfigMode::Secondary,
+                None,
+                Some(LocationConfigSecondary { warm: true }),
+                tenant_conf,
+            )
+        };
+        client
+            .location_config(tenant_id, config, None)
+            .await
+            .map_err(|e| {
+                ApiError::InternalServerError(anyhow!(
+                    "Attached tenant {tenant_id} to node {dest}, but failed to reconfigure \
+                     the origin node {origin_id}: {e}"
+                ))
+            })?;
+
+        if !detach_origin {
+            let mut locked = state.inner.write().await;
+            locked.add_secondary(tenant_shard_id, origin_id);
+            state
+                .save(&locked, Changes::tenants([tenant_shard_id]))
+                .await?;
+            sequence = locked
+                .tenants
+                .get(&tenant_shard_id)
+                .map_or(0, |t| t.sequence);
+        }
+    }
+
+    tracing::info!(
+        %tenant_id,
+        origin = ?origin.map(|(ps, _)| ps),
+        %dest,
+        generation,
+        "migrated tenant"
+    );
 
-fn make_router(persistent_state: PersistentState) -> RouterBuilder<hyper::Body, ApiError> {
-    endpoint::make_router()
-        .data(Arc::new(State::new(persistent_state)))
-        .post("/re-attach", |r| request_span(r, handle_re_attach))
-        .post("/validate", |r| request_span(r, handle_validate))
-        .post("/attach-hook", |r| request_span(r, handle_attach_hook))
-        .post("/inspect", |r| request_span(r, handle_inspect))
+    Ok(TenantMigrateResponse {
+        gen: generation,
+        origin: origin.map(|(ps, _)| ps),
+        sequence,
+    })
 }
 
 #[tokio::main]
@@ -306,32 +1447,1087 @@ async fn main() -> anyhow::Result<()> {
     )?;
 
     let args = Cli::parse();
+    if let Some(Command::State(command)) = args.command {
+        let (persistence, _lock) = open_persistence(
+            args.path.as_deref(),
+            args.sqlite,
+            args.database_url.as_deref(),
+            None,
+        )
+        .await?;
+        return state_command(persistence.as_ref(), command).await;
+    }
+    let listen = args
+        .listen
+        .context("--listen is required to serve, see --help")?;
+    let lease_holder = args.leader_lease_ms.map(|_| {
+        args.advertise_url.clone().unwrap_or_else(|| {
+            let scheme = if args.ssl_cert.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            format!("{scheme}://{listen}")
+        })
+    });
+    // The lock is held until we exit
+    let (persistence, _lock) = open_persistence(
+        args.path.as_deref(),
+        args.sqlite,
+        args.database_url.as_deref(),
+        lease_holder.as_deref(),
+    )
+    .await?;
     tracing::info!(
-        "Starting, state at {}, listening on {}",
-        args.path.to_string_lossy(),
-        args.listen
+        "Starting, state in {}, listening on {}",
+        persistence.describe(),
+        listen
     );
 
-    let persistent_state = PersistentState::load_or_new(&args.path).await;
+    let lease = match (lease_holder, args.leader_lease_ms) {
+        (Some(holder), Some(lease_ms)) => {
+            let duration = Duration::from_millis(lease_ms);
+            let leader = persistence.acquire_lease(&holder, duration).await?;
+            tracing::info!("Electing a leader as {holder}, the leader is {leader}");
+            Some(Lease {
+                holder,
+                duration,
+                leader: std::sync::Mutex::new(Some(leader)),
+            })
+        }
+        _ => None,
+    };
+
+    let persistent_state = if lease.as_ref().map_or(true, Lease::is_held) {
+        let mut persistent_state = load_or_new(
+            persistence.as_ref(),
+            &args.import_from_pageservers,
+            args.pageserver_jwt.as_deref(),
+        )
+        .await?;
+        if !args.reconcile_with.is_empty() {
+            reconcile(
+                &mut persistent_state,
+                &args.reconcile_with,
+                args.pageserver_jwt.as_deref(),
+            )
+            .await;
+            persistence.save_all(&persistent_state).await?;
+        }
+        persistent_state
+    } else {
+        // The leader creates, reconciles and imports the state, and we reload it
+        persistence
+            .load()
+            .await
+            .with_context(|| format!("Failed to load state from {}", persistence.describe()))?
+            .unwrap_or_else(PersistentState::new)
+    };
+
+    let auth = match &args.public_key {
+        Some(public_key) => {
+            tracing::info!("Requiring JWTs signed with {public_key}");
+            Some(SwappableJwtAuth::new(JwtAuth::from_key_path(public_key)?))
+        }
+        None => None,
+    };
+    let tls_config = match (&args.ssl_cert, &args.ssl_key) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+        _ => None,
+    };
 
-    let http_listener = tcp_listener::bind(args.listen)?;
-    let router = make_router(persistent_state)
+    let http_listener = tcp_listener::bind(listen)?;
+    update_node_metrics(&persistent_state);
+    let state = Arc::new(State::new(
+        persistent_state,
+        persistence,
+        args.notify.into_iter().collect(),
+        args.notify_max_retries,
+        args.compute_hook_url,
+        auth,
+        args.pageserver_jwt,
+        Duration::from_millis(args.drain_interval_ms),
+        Duration::from_millis(args.re_attach_window_ms),
+        lease,
+        args.failover_grace_ms.map(Duration::from_millis),
+    ));
+    if state.lease.is_some() {
+        tokio::task::spawn(lease_loop(Arc::clone(&state)));
+    }
+    tokio::task::spawn(heartbeat_loop(
+        Arc::clone(&state),
+        Duration::from_millis(args.heartbeat_interval_ms),
+        args.heartbeat_max_failures,
+    ));
+    if let Some(interval_ms) = args.chaos_interval_ms {
+        tracing::warn!("Chaos mode: changing a random attachment every {interval_ms}ms");
+        tokio::task::spawn(chaos_loop(
+            Arc::clone(&state),
+            Duration::from_millis(interval_ms),
+        ));
+    }
+    let router = make_router(Arc::clone(&state))
         .build()
         .map_err(|err| anyhow!(err))?;
-    let service = utils::http::RouterService::new(router).unwrap();
-    let server = hyper::Server::from_tcp(http_listener)?.serve(service);
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let shutdown = async {
+        shutdown_rx.await.ok();
+    };
+    let server = match tls_config {
+        None => {
+            let service = utils::http::RouterService::new(router).unwrap();
+            let server = hyper::Server::from_tcp(http_listener)?
+                .serve(service)
+                .with_graceful_shutdown(shutdown);
+            tracing::info!("Serving on {0}", listen);
+            tokio::task::spawn(server)
+        }
+        Some(tls_config) => {
+            // RouterService only serves plain TCP connections, so build the per-connection
+            // services ourselves
+            let services = routerify::RequestServiceBuilder::new(router).unwrap();
+            http_listener.set_nonblocking(true)?;
+            let incoming = hyper::server::conn::AddrIncoming::from_listener(
+                tokio::net::TcpListener::from_std(http_listener)?,
+            )?;
+            let tls_listener = tls_listener::TlsListener::new(
+                tokio_rustls::TlsAcceptor::from(Arc::new(tls_config)),
+                incoming,
+            )
+            .filter(|conn| {
+                if let Err(e) = conn {
+                    tracing::warn!("Failed to accept a TLS connection: {e:?}");
+                }
+                futures::future::ready(conn.is_ok())
+            });
+            let make_service = hyper::service::make_service_fn(
+                move |stream: &tokio_rustls::server::TlsStream<hyper::server::conn::AddrStream>| {
+                    let service = services.build(stream.get_ref().0.remote_addr());
+                    async move { Ok::<_, std::convert::Infallible>(service) }
+                },
+            );
+            let server = hyper::Server::builder(hyper::server::accept::from_stream(tls_listener))
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown);
+            tracing::info!("Serving HTTPS on {0}", listen);
+            tokio::task::spawn(server)
+        }
+    };
+
+    let signal = shutdown_signal().await?;
+    if signal == "SIGQUIT" {
+        // An immediate stop: every change was persisted before it was acknowledged
+        tracing::info!("Got {signal}. Terminating");
+        std::process::exit(0);
+    }
+    tracing::info!("Got {signal}. Shutting down");
+    shutdown_tx.send(()).ok();
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, server).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => tracing::warn!("HTTP server failed: {e}"),
+        Ok(Err(e)) => tracing::warn!("HTTP server task failed: {e}"),
+        Err(_) => tracing::warn!(
+            "Requests still in flight after {SHUTDOWN_TIMEOUT:?}, shutting down anyway"
+        ),
+    }
+
+    // Every change is persisted before it's acknowledged, so there is nothing to flush. The
+    // background tasks, e.g. drains, persist their changes under the lock: once we hold it, none
+    // of them is half-done, and none starts before we exit.
+    let _locked = state.inner.write().await;
+    tracing::info!("Exiting");
 
-    tracing::info!("Serving on {0}", args.listen);
+    Ok(())
+}
 
-    tokio::task::spawn(server);
+/// How long to wait for in-flight requests on shutdown, before exiting anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits for SIGINT, SIGTERM or SIGQUIT, and returns its name.
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut quit = signal(SignalKind::quit())?;
+    Ok(tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+        _ = quit.recv() => "SIGQUIT",
+    })
+}
 
-    ShutdownSignals::handle(|signal| match signal {
-        Signal::Interrupt | Signal::Terminate | Signal::Quit => {
-            tracing::info!("Got {}. Terminating", signal.name());
-            // We're just a test helper: no graceful shutdown.
-            std::process::exit(0);
+async fn state_command(persistence: &dyn Persistence, command: StateCommand) -> anyhow::Result<()> {
+    match command {
+        StateCommand::Get { tenant_id } => {
+            let state = load_existing(persistence).await?;
+            let output = match tenant_id {
+                Some(tenant_id) => serde_json::to_string_pretty(
+                    state
+                        .tenants
+                        .get(&tenant_id)
+                        .ok_or_else(|| anyhow!("tenant {tenant_id} not found"))?,
+                )?,
+                None => serde_json::to_string_pretty(&state)?,
+            };
+            println!("{output}");
+        }
+        StateCommand::Set { min_epoch } => {
+            let mut bytes = Vec::new();
+            std::io::stdin().read_to_end(&mut bytes)?;
+            let mut state = PersistentState::decode(&bytes).context("Invalid state on stdin")?;
+            // The state on stdin may be an old copy of the one it replaces, whose generations
+            // the pageservers have seen
+            let last_epoch = persistence.last_epoch().await?.max(min_epoch.unwrap_or(0));
+            match persistence.load().await {
+                Ok(None) if last_epoch == 0 => {}
+                Ok(None) => state.bump_epoch(last_epoch)?,
+                Ok(Some(existing)) => state.bump_epoch(existing.epoch.max(last_epoch))?,
+                Err(e) if min_epoch.is_some() => {
+                    eprintln!("Failed to load the existing state, replacing it anyway: {e:#}");
+                    state.bump_epoch(last_epoch)?;
+                }
+                Err(e) => {
+                    return Err(e.context(
+                        "Failed to load the existing state, whose epoch the new one must \
+                         follow: pass --min-epoch with at least its epoch to replace it anyway",
+                    ))
+                }
+            }
+            if state.epoch > 0 {
+                eprintln!("Starting epoch {}", state.epoch);
+            }
+            persistence.save_all(&state).await?;
+        }
+        StateCommand::BumpEpoch => {
+            let mut state = load_existing(persistence).await?;
+            state.bump_epoch(persistence.last_epoch().await?)?;
+            persistence.save_all(&state).await?;
+            println!("{}", state.epoch);
+        }
+        StateCommand::BumpGeneration { tenant_id } => {
+            let mut state = load_existing(persistence).await?;
+            let tenant_state = state
+                .tenants
+                .get_mut(&tenant_id)
+                .ok_or_else(|| anyhow!("tenant {tenant_id} not found"))?;
+            tenant_state.generation += 1;
+            tenant_state.sequence += 1;
+            state.sequence += 1;
+            let response = AttachHookResponse {
+                gen: Some(tenant_state.generation),
+                sequence: tenant_state.sequence,
+            };
+            let changes = Changes::attachment(
+                "state bump-generation",
+                tenant_id,
+                tenant_state.pageserver,
+                tenant_state.pageserver,
+                tenant_state.generation,
+            );
+            persistence.save(&state, &changes).await?;
+            println!("{}", serde_json::to_string(&response)?);
+        }
+        StateCommand::Assign { tenant_id, node_id } => {
+            let mut state = load_or_new(persistence, &[], None).await?;
+            let node_id = node_id.map(NodeId);
+            if let Some(node_id) = node_id {
+                if state.nodes.get(&node_id).is_some_and(|n| n.cordoned) {
+                    anyhow::bail!("Node {node_id} is cordoned, cannot attach tenant {tenant_id}");
+                }
+            }
+            let (previous, generation) = state.attach(tenant_id, node_id);
+            let changes =
+                Changes::attachment("state assign", tenant_id, previous, node_id, generation);
+            persistence.save(&state, &changes).await?;
+            let response = AttachHookResponse {
+                gen: node_id.map(|_| generation),
+                sequence: state.tenants[&tenant_id].sequence,
+            };
+            println!("{}", serde_json::to_string(&response)?);
         }
-    })?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    use crate::background::ChaosAction;
+    use crate::http::diff_attachments;
+    use crate::persistence::{history_entry, JsonFile, Sqlite};
+    use control_plane::attachment_service::MismatchKind;
+    use pageserver_api::shard::{ShardCount, ShardNumber};
+
+    const TENANT_ID: &str = "1f359dd625e519a1a4e8d7509690f6fc";
+
+    fn decode(json: serde_json::Value) -> anyhow::Result<PersistentState> {
+        PersistentState::decode(&serde_json::to_vec(&json).unwrap())
+    }
+
+    /// Registers the node, active, reachable and in no AZ, and returns its registration to
+    /// change.
+    fn test_node(state: &mut PersistentState, node_id: u64) -> &mut NodeRegistration {
+        let node = state.nodes.entry(NodeId(node_id)).or_default();
+        node.registration.insert(NodeRegistration {
+            listen_http_addr: format!("localhost:{node_id}"),
+            listen_pg_addr: format!("localhost:{node_id}"),
+            availability: NodeAvailability::Active,
+            availability_zone: None,
+            unreachable: false,
+        })
+    }
+
+    /// The tenant shard as a pageserver lists it, attached in `generation`.
+    fn listed(id: TenantShardId, generation: Option<u32>) -> pageserver_api::models::TenantInfo {
+        pageserver_api::models::TenantInfo {
+            id,
+            state: pageserver_api::models::TenantState::Active,
+            current_physical_size: None,
+            attachment_status: pageserver_api::models::TenantAttachmentStatus::Attached,
+            generation,
+        }
+    }
+
+    #[tokio::test]
+    async fn json_file_keeps_the_previous_state() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("attachments.json");
+        let persistence = JsonFile { path: path.clone() };
+        let tenant_id: TenantShardId = TENANT_ID.parse().unwrap();
+
+        let mut state = PersistentState::new();
+        persistence.save_all(&state).await.unwrap();
+        state.attach(tenant_id, Some(NodeId(1)));
+        persistence.save_all(&state).await.unwrap();
+
+        let files = |names: &[&str]| {
+            names
+                .iter()
+                .filter(|name| dir.path().join(name).exists())
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            files(&[
+                "attachments.json",
+                "attachments.json.prev",
+                "attachments.json.tmp"
+            ]),
+            ["attachments.json", "attachments.json.prev"]
+        );
+        let prev = PersistentState::decode(
+            &std::fs::read(dir.path().join("attachments.json.prev")).unwrap(),
+        )
+        .unwrap();
+        assert!(prev.tenants.is_empty());
+        let current = persistence.load().await.unwrap().unwrap();
+        assert_eq!(current.tenants[&tenant_id].pageserver, Some(NodeId(1)));
+    }
+
+    #[tokio::test]
+    async fn json_file_refuses_an_old_copy() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("attachments.json");
+        let persistence = JsonFile { path: path.clone() };
+
+        let mut state = PersistentState::new();
+        persistence.save_all(&state).await.unwrap();
+        let old_copy = std::fs::read(&path).unwrap();
+        assert_eq!(persistence.last_epoch().await.unwrap(), 0);
+        state.bump_epoch(0).unwrap();
+        persistence.save_all(&state).await.unwrap();
+        assert_eq!(persistence.last_epoch().await.unwrap(), 1);
+
+        std::fs::write(&path, old_copy).unwrap();
+        let err = load_or_new(&persistence, &[], None).await.unwrap_err();
+        assert!(format!("{err:#}").contains("old copy"), "{err:#}");
+
+        // a new state doesn't start over at epoch 0 either
+        std::fs::remove_file(&path).unwrap();
+        let state = load_or_new(&persistence, &[], None).await.unwrap();
+        assert_eq!(state.epoch, 2);
+    }
+
+    #[tokio::test]
+    async fn sqlite_writes_the_changed_rows() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("attachments.db");
+        let persistence = Sqlite::open(&path).await.unwrap();
+        assert!(persistence.load().await.unwrap().is_none());
+        let tenant_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let timeline_id = TimelineId::generate();
+
+        let mut state = load_or_new(&persistence, &[], None).await.unwrap();
+        let (previous, generation) = state.attach(tenant_id, Some(NodeId(1)));
+        let changes = Changes::attachment("test", tenant_id, previous, Some(NodeId(1)), generation);
+        persistence.save(&state, &changes).await.unwrap();
+        state.nodes.entry(NodeId(1)).or_default().cordoned = true;
+        persistence
+            .save(&state, &Changes::node(NodeId(1)))
+            .await
+            .unwrap();
+        state
+            .timelines
+            .entry(tenant_id.tenant_id)
+            .or_default()
+            .insert(timeline_id, TimelineState::default());
+        persistence
+            .save(&state, &Changes::timeline(tenant_id.tenant_id, timeline_id))
+            .await
+            .unwrap();
+
+        // and the rows of a removed tenant shard go
+        let other_id = TenantShardId {
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(2),
+            ..tenant_id
+        };
+        state.attach(other_id, Some(NodeId(2)));
+        persistence
+            .save(&state, &Changes::tenants([other_id]))
+            .await
+            .unwrap();
+        state.tenants.remove(&other_id);
+        persistence
+            .save(&state, &Changes::tenants([other_id]))
+            .await
+            .unwrap();
+
+        drop(persistence);
+        let persistence = Sqlite::open(&path).await.unwrap();
+        let loaded = persistence.load().await.unwrap().unwrap();
+        assert_eq!(loaded.sequence, state.sequence);
+        assert_eq!(loaded.tenants.len(), 1);
+        assert_eq!(loaded.tenants[&tenant_id].pageserver, Some(NodeId(1)));
+        assert_eq!(loaded.tenants[&tenant_id].generation, generation);
+        assert!(loaded.nodes[&NodeId(1)].cordoned);
+        assert!(loaded.timelines[&tenant_id.tenant_id].contains_key(&timeline_id));
+        let history = persistence.history(tenant_id.tenant_id).await.unwrap();
+        assert_eq!(history, changes.history);
+    }
+
+    #[tokio::test]
+    async fn sqlite_refuses_an_old_copy() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("attachments.db");
+        let persistence = Sqlite::open(&path).await.unwrap();
+
+        let mut state = load_or_new(&persistence, &[], None).await.unwrap();
+        assert_eq!(persistence.last_epoch().await.unwrap(), 0);
+        state.bump_epoch(0).unwrap();
+        persistence.save_all(&state).await.unwrap();
+        assert_eq!(persistence.last_epoch().await.unwrap(), 1);
+
+        // like restoring a dump of the tables from before the epoch
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute("UPDATE state_version SET epoch = 0", [])
+            .unwrap();
+        let err = load_or_new(&persistence, &[], None).await.unwrap_err();
+        assert!(format!("{err:#}").contains("old copy"), "{err:#}");
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        // written by builds before the nodes and timelines sections
+        let state = decode(serde_json::json!({
+            "tenants": { TENANT_ID: { "pageserver": 1, "generation": 3 } },
+        }))
+        .unwrap();
+        assert_eq!(state.version, SCHEMA_VERSION);
+        let tenant = &state.tenants[&TENANT_ID.parse::<TenantShardId>().unwrap()];
+        assert_eq!(tenant.pageserver, Some(NodeId(1)));
+        assert_eq!(tenant.generation, 3);
+        assert!(state.nodes.is_empty());
+        assert!(state.timelines.is_empty());
+
+        // written by builds with the nodes section, but before versioning
+        let state = decode(serde_json::json!({
+            "tenants": {},
+            "nodes": { "1": { "weight": 2 } },
+        }))
+        .unwrap();
+        assert_eq!(state.nodes[&NodeId(1)].weight, 2);
+        assert!(!state.nodes[&NodeId(1)].cordoned);
+
+        assert!(decode(serde_json::json!({ "nodes": {} })).is_err());
+    }
+
+    #[test]
+    fn migrate_v2() {
+        // written by builds before node registration
+        let state = decode(serde_json::json!({
+            "version": 2,
+            "tenants": {},
+            "nodes": { "1": { "weight": 2, "cordoned": true } },
+            "timelines": {},
+        }))
+        .unwrap();
+        assert_eq!(state.version, SCHEMA_VERSION);
+        assert_eq!(state.nodes[&NodeId(1)].weight, 2);
+        assert!(state.nodes[&NodeId(1)].registration.is_none());
+    }
+
+    #[test]
+    fn roundtrip_current() {
+        let mut state = PersistentState::new();
+        state.tenants.insert(
+            TENANT_ID.parse().unwrap(),
+            TenantState {
+                pageserver: None,
+                generation: 1,
+                secondaries: vec![NodeId(2)],
+                sequence: 3,
+                placement: TenantPlacement {
+                    az_affinity: Some("az-1".to_owned()),
+                    az_anti_affinity: Vec::new(),
+                },
+                config: Some(TenantConfig {
+                    pitr_interval: Some("1h".to_owned()),
+                    ..Default::default()
+                }),
+            },
+        );
+        state.nodes.insert(NodeId(1), NodeState::default());
+        state.nodes.insert(
+            NodeId(2),
+            NodeState {
+                registration: Some(NodeRegistration {
+                    listen_http_addr: "127.0.0.1:9898".to_owned(),
+                    listen_pg_addr: "127.0.0.1:64000".to_owned(),
+                    availability: NodeAvailability::Offline,
+                    availability_zone: Some("az-1".to_owned()),
+                    unreachable: true,
+                }),
+                ..Default::default()
+            },
+        );
+
+        let decoded = PersistentState::decode(&serde_json::to_vec(&state).unwrap()).unwrap();
+        assert_eq!(decoded.version, SCHEMA_VERSION);
+        assert_eq!(decoded.tenants.len(), 1);
+        assert_eq!(decoded.nodes.len(), 2);
+        assert!(decoded.nodes[&NodeId(1)].registration.is_none());
+        let registration = decoded.nodes[&NodeId(2)].registration.as_ref().unwrap();
+        assert_eq!(registration.listen_pg_addr, "127.0.0.1:64000");
+        assert_eq!(registration.availability, NodeAvailability::Offline);
+        assert_eq!(registration.availability_zone.as_deref(), Some("az-1"));
+        assert!(registration.unreachable);
+        let tenant_state = decoded.tenants.values().next().unwrap();
+        assert_eq!(tenant_state.placement.az_affinity.as_deref(), Some("az-1"));
+        let config = tenant_state.config.as_ref().unwrap();
+        assert_eq!(config.pitr_interval.as_deref(), Some("1h"));
+        assert_eq!(config.min_resident_size_override, None);
+    }
+
+    #[test]
+    fn shards() {
+        let unsharded: TenantShardId = TENANT_ID.parse().unwrap();
+        let shard = TenantShardId {
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(2),
+            ..unsharded
+        };
+        let mut state = PersistentState::new();
+        assert_eq!(
+            state.attach(unsharded, Some(NodeId(1))),
+            (Some(NodeId(1)), 1)
+        );
+        // each shard has its own generation
+        assert_eq!(state.attach(shard, Some(NodeId(2))), (Some(NodeId(2)), 1));
+        assert_eq!(state.attach(shard, Some(NodeId(2))), (Some(NodeId(2)), 2));
+
+        // an unsharded tenant is keyed by its TenantId, as in earlier versions
+        let encoded = serde_json::to_value(&state).unwrap();
+        assert_eq!(encoded["tenants"][TENANT_ID]["generation"], 1);
+        assert_eq!(
+            encoded["tenants"][format!("{TENANT_ID}-0102")]["generation"],
+            2
+        );
+
+        let decoded = PersistentState::decode(&serde_json::to_vec(&state).unwrap()).unwrap();
+        assert_eq!(decoded.tenants[&unsharded].pageserver, Some(NodeId(1)));
+        assert_eq!(decoded.tenants[&shard].pageserver, Some(NodeId(2)));
+        assert_eq!(decoded.tenants[&shard].generation, 2);
+    }
+
+    #[test]
+    fn attach() {
+        let tenant_id = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+
+        assert_eq!(
+            state.attach(tenant_id, Some(NodeId(1))),
+            (Some(NodeId(1)), 1)
+        );
+        assert_eq!(
+            state.attach(tenant_id, Some(NodeId(2))),
+            (Some(NodeId(1)), 2)
+        );
+        // detaching keeps the generation, the next attach increments it
+        assert_eq!(state.attach(tenant_id, None), (Some(NodeId(2)), 2));
+        assert_eq!(state.tenants[&tenant_id].pageserver, None);
+        assert_eq!(state.attach(tenant_id, Some(NodeId(1))), (None, 3));
+    }
+
+    #[test]
+    fn secondaries() {
+        let tenant_id = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+
+        // no secondaries of unknown tenants
+        state.add_secondary(tenant_id, NodeId(3));
+        assert!(state.tenants.is_empty());
+
+        state.attach(tenant_id, Some(NodeId(1)));
+        state.add_secondary(tenant_id, NodeId(3));
+        state.add_secondary(tenant_id, NodeId(2));
+        state.add_secondary(tenant_id, NodeId(3));
+        assert_eq!(
+            state.tenants[&tenant_id].secondaries,
+            [NodeId(2), NodeId(3)]
+        );
+
+        // attaching a secondary's node replaces it
+        state.attach(tenant_id, Some(NodeId(2)));
+        assert_eq!(state.tenants[&tenant_id].secondaries, [NodeId(3)]);
+    }
+
+    #[test]
+    fn sequence() {
+        let tenant_id = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+
+        // an unknown tenant is at zero
+        state.check_sequence(tenant_id, Some(0)).unwrap();
+        state.check_sequence(tenant_id, Some(1)).unwrap_err();
+
+        state.attach(tenant_id, Some(NodeId(1)));
+        state.add_secondary(tenant_id, NodeId(2));
+        // already a secondary, no change
+        state.add_secondary(tenant_id, NodeId(2));
+        state.attach(tenant_id, None);
+        assert_eq!(state.tenants[&tenant_id].sequence, 3);
+        state.check_sequence(tenant_id, Some(3)).unwrap();
+        state.check_sequence(tenant_id, Some(2)).unwrap_err();
+        state.check_sequence(tenant_id, None).unwrap();
+        // the state's sequence only counts the attachments
+        assert_eq!(state.sequence, 2);
+    }
+
+    #[test]
+    fn bump_epoch() {
+        let tenant_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let other = TenantShardId {
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(2),
+            ..tenant_id
+        };
+        let mut state = PersistentState::new();
+        assert_eq!(state.attach(tenant_id, Some(NodeId(1))).1, 1);
+
+        state.bump_epoch(0).unwrap();
+        assert_eq!(state.epoch, 1);
+        let floor = 1 << EPOCH_GENERATION_BITS;
+        assert_eq!(state.tenants[&tenant_id].generation, floor);
+        assert_eq!(state.attach(tenant_id, Some(NodeId(1))).1, floor + 1);
+        // a new tenant shard starts in the epoch too
+        assert_eq!(state.attach(other, Some(NodeId(1))).1, floor + 1);
+
+        // after the epoch of the state that is replaced
+        state.bump_epoch(5).unwrap();
+        assert_eq!(state.epoch, 6);
+        assert_eq!(state.tenants[&other].generation, 6 << EPOCH_GENERATION_BITS);
+
+        state.epoch = (1 << (u32::BITS - EPOCH_GENERATION_BITS)) - 1;
+        state.bump_epoch(0).unwrap_err();
+    }
+
+    #[test]
+    fn re_attach_replays() {
+        let tenant_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+        state.attach(tenant_id, Some(NodeId(1)));
+        let generations = attached_generations(&state, NodeId(1));
+        assert_eq!(generations, vec![(tenant_id, 1)]);
+        assert!(attached_generations(&state, NodeId(2)).is_empty());
+
+        let now = Instant::now();
+        let window = Duration::from_secs(10);
+        let recent = RecentReAttach {
+            at: now,
+            generations: generations.clone(),
+        };
+        assert!(recent.replays(now + Duration::from_secs(5), window, &generations));
+        // too late
+        assert!(!recent.replays(now + window, window, &generations));
+        // disabled
+        assert!(!recent.replays(now, Duration::ZERO, &generations));
+
+        // attached elsewhere in the meantime
+        state.attach(tenant_id, Some(NodeId(2)));
+        state.attach(tenant_id, Some(NodeId(1)));
+        let generations = attached_generations(&state, NodeId(1));
+        assert!(!recent.replays(now, window, &generations));
+    }
+
+    #[test]
+    fn schedule() {
+        let unsharded: TenantShardId = TENANT_ID.parse().unwrap();
+        let shard = |n| TenantShardId {
+            shard_number: ShardNumber(n),
+            shard_count: ShardCount(8),
+            ..unsharded
+        };
+        let mut state = PersistentState::new();
+        assert_eq!(state.schedule(unsharded), None);
+
+        for node_id in 1..=3 {
+            test_node(&mut state, node_id);
+        }
+        test_node(&mut state, 4).availability = NodeAvailability::Offline;
+        // failed its heartbeats
+        test_node(&mut state, 6).unreachable = true;
+        state.nodes.get_mut(&NodeId(2)).unwrap().weight = 2;
+        state.nodes.get_mut(&NodeId(3)).unwrap().cordoned = true;
+        // unregistered nodes are never picked
+        state.attach(shard(0), Some(NodeId(5)));
+
+        // node 2 takes twice as many shards as node 1, ties go to the lower node id
+        let mut picked = Vec::new();
+        for n in 1..7 {
+            let node_id = state.schedule(shard(n)).unwrap();
+            state.attach(shard(n), Some(node_id));
+            picked.push(node_id.0);
+        }
+        assert_eq!(picked, [1, 2, 2, 1, 2, 2]);
+    }
+
+    #[test]
+    fn fail_over() {
+        let unsharded: TenantShardId = TENANT_ID.parse().unwrap();
+        let shard = |n| TenantShardId {
+            shard_number: ShardNumber(n),
+            shard_count: ShardCount(4),
+            ..unsharded
+        };
+        let mut state = PersistentState::new();
+        for node_id in 1..=3 {
+            test_node(&mut state, node_id);
+        }
+        for n in 0..4 {
+            state.attach(shard(n), Some(NodeId(1)));
+        }
+        let set_availability = |state: &mut PersistentState, node_id, availability| {
+            let node = state.nodes.get_mut(&NodeId(node_id)).unwrap();
+            node.registration.as_mut().unwrap().availability = availability;
+        };
+        set_availability(&mut state, 1, NodeAvailability::Offline);
+        set_availability(&mut state, 3, NodeAvailability::Offline);
+
+        let (moved, stuck) = state.fail_over(NodeId(1));
+        assert!(stuck.is_empty());
+        assert_eq!(moved.len(), 4);
+        for (tenant_shard_id, dest, generation) in moved {
+            assert_eq!(dest, NodeId(2));
+            assert_eq!(generation, 2);
+            assert_eq!(state.tenants[&tenant_shard_id].pageserver, Some(NodeId(2)));
+        }
+        // nothing left to move
+        assert_eq!(state.fail_over(NodeId(1)), (Vec::new(), Vec::new()));
+
+        // no node to move to: the shards stay
+        set_availability(&mut state, 2, NodeAvailability::Offline);
+        let (moved, stuck) = state.fail_over(NodeId(2));
+        assert!(moved.is_empty());
+        assert_eq!(stuck, (0..4).map(shard).collect::<Vec<_>>());
+        assert_eq!(state.tenants[&shard(0)].pageserver, Some(NodeId(2)));
+    }
+
+    #[test]
+    fn placement() {
+        let tenant_shard_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+        for (node_id, az) in [(1, "az-a"), (2, "az-a"), (3, "az-b")] {
+            test_node(&mut state, node_id).availability_zone = Some(az.to_owned());
+        }
+        let place = |state: &mut PersistentState, affinity: Option<&str>, anti: &[&str]| {
+            state.attach(tenant_shard_id, None);
+            state.tenants.get_mut(&tenant_shard_id).unwrap().placement = TenantPlacement {
+                az_affinity: affinity.map(str::to_owned),
+                az_anti_affinity: anti.iter().map(|az| az.to_string()).collect(),
+            };
+        };
+
+        // no placement, the lowest node id
+        place(&mut state, None, &[]);
+        assert_eq!(state.schedule(tenant_shard_id), Some(NodeId(1)));
+        assert_eq!(state.placement_violation(tenant_shard_id, NodeId(3)), None);
+
+        place(&mut state, Some("az-b"), &[]);
+        assert_eq!(state.schedule(tenant_shard_id), Some(NodeId(3)));
+        assert!(state
+            .placement_violation(tenant_shard_id, NodeId(1))
+            .is_some());
+        // the affinity is a preference: without a schedulable node in its AZ, anywhere goes
+        state.nodes.get_mut(&NodeId(3)).unwrap().cordoned = true;
+        assert_eq!(state.schedule(tenant_shard_id), Some(NodeId(1)));
+        assert_eq!(state.placement_violation(tenant_shard_id, NodeId(1)), None);
+        state.nodes.get_mut(&NodeId(3)).unwrap().cordoned = false;
+
+        // the anti-affinity is not
+        place(&mut state, None, &["az-a"]);
+        assert_eq!(state.schedule(tenant_shard_id), Some(NodeId(3)));
+        assert!(state
+            .placement_violation(tenant_shard_id, NodeId(2))
+            .is_some());
+        place(&mut state, None, &["az-a", "az-b"]);
+        assert_eq!(state.schedule(tenant_shard_id), None);
+    }
+
+    #[test]
+    fn compute_hook_request() {
+        let unsharded: TenantShardId = TENANT_ID.parse().unwrap();
+        let shard = |n| TenantShardId {
+            shard_number: ShardNumber(n),
+            shard_count: ShardCount(2),
+            ..unsharded
+        };
+        let mut state = PersistentState::new();
+        test_node(&mut state, 1);
+        state.attach(shard(1), Some(NodeId(2)));
+        state.attach(shard(1), None);
+        state.attach(shard(0), Some(NodeId(1)));
+
+        let request = super::compute_hook_request(&state, unsharded.tenant_id);
+        assert_eq!(request.tenant_id, unsharded.tenant_id);
+        assert_eq!(
+            request.shards,
+            [
+                ComputeHookShard {
+                    shard_number: ShardNumber(0),
+                    shard_count: ShardCount(2),
+                    node_id: Some(NodeId(1)),
+                    gen: 1,
+                    listen_pg_addr: Some("localhost:1".to_owned()),
+                },
+                ComputeHookShard {
+                    shard_number: ShardNumber(1),
+                    shard_count: ShardCount(2),
+                    node_id: None,
+                    gen: 1,
+                    listen_pg_addr: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn history_entry() {
+        let tenant_shard_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+        let mut attach = |node_id| {
+            let (previous, generation) = state.attach(tenant_shard_id, node_id);
+            let entry =
+                super::history_entry("test", tenant_shard_id, previous, node_id, generation);
+            (
+                entry.old_node_id,
+                entry.new_node_id,
+                entry.old_gen,
+                entry.new_gen,
+            )
+        };
+
+        assert_eq!(attach(Some(NodeId(1))), (None, Some(NodeId(1)), 0, 1));
+        assert_eq!(
+            attach(Some(NodeId(2))),
+            (Some(NodeId(1)), Some(NodeId(2)), 1, 2)
+        );
+        // a detach keeps the generation
+        assert_eq!(attach(None), (Some(NodeId(2)), None, 2, 2));
+        assert_eq!(attach(Some(NodeId(2))), (None, Some(NodeId(2)), 2, 3));
+    }
+
+    #[test]
+    fn import_attachment() {
+        let tenant_shard_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let mut state = PersistentState::new();
+
+        assert!(!state.import_attachment(NodeId(1), &listed(tenant_shard_id, None)));
+        assert!(state.tenants.is_empty());
+        assert!(state.import_attachment(NodeId(1), &listed(tenant_shard_id, Some(3))));
+        // the later generation wins, whichever pageserver lists it first
+        assert!(!state.import_attachment(NodeId(2), &listed(tenant_shard_id, Some(2))));
+        assert!(state.import_attachment(NodeId(3), &listed(tenant_shard_id, Some(4))));
+        let tenant_state = &state.tenants[&tenant_shard_id];
+        assert_eq!(tenant_state.pageserver, Some(NodeId(3)));
+        assert_eq!(tenant_state.generation, 4);
+
+        // the next attachment gets a generation that none of them holds
+        assert_eq!(
+            state.attach(tenant_shard_id, Some(NodeId(1))),
+            (Some(NodeId(3)), 5)
+        );
+    }
+
+    #[test]
+    fn reconcile_attachment() {
+        let tenant_shard_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let other = TenantShardId {
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(2),
+            ..tenant_shard_id
+        };
+        let mut state = PersistentState::new();
+
+        assert_eq!(
+            state.reconcile_attachment(NodeId(1), &listed(tenant_shard_id, None)),
+            Reconciled::Flagged
+        );
+        // adopted in the generation after the reported one, which may have been issued already
+        assert_eq!(
+            state.reconcile_attachment(NodeId(1), &listed(tenant_shard_id, Some(3))),
+            Reconciled::Adopted
+        );
+        assert_eq!(state.tenants[&tenant_shard_id].generation, 4);
+        assert_eq!(state.tenants[&tenant_shard_id].pageserver, Some(NodeId(1)));
+
+        // reconciling again, before the pageserver re-attached, changes nothing
+        assert_eq!(
+            state.reconcile_attachment(NodeId(1), &listed(tenant_shard_id, Some(3))),
+            Reconciled::Unchanged
+        );
+        assert_eq!(state.tenants[&tenant_shard_id].generation, 4);
+        // a later generation is adopted too
+        assert_eq!(
+            state.reconcile_attachment(NodeId(1), &listed(tenant_shard_id, Some(7))),
+            Reconciled::Adopted
+        );
+        assert_eq!(state.tenants[&tenant_shard_id].generation, 8);
+        assert_eq!(
+            state.reconcile_attachment(NodeId(2), &listed(tenant_shard_id, Some(9))),
+            Reconciled::Flagged
+        );
+
+        // a stale attachment of a detached shard
+        state.attach(tenant_shard_id, None);
+        assert_eq!(
+            state.reconcile_attachment(NodeId(2), &listed(tenant_shard_id, Some(5))),
+            Reconciled::Flagged
+        );
+
+        // adopted at least at the floor of the epoch
+        state.bump_epoch(0).unwrap();
+        assert_eq!(
+            state.reconcile_attachment(NodeId(2), &listed(other, Some(3))),
+            Reconciled::Adopted
+        );
+        assert_eq!(state.tenants[&other].generation, state.generation_floor());
+    }
+
+    #[test]
+    fn chaos_pick() {
+        use rand::SeedableRng;
+
+        let tenant_shard_id: TenantShardId = TENANT_ID.parse().unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut state = PersistentState::new();
+        assert_eq!(state.chaos_pick(&mut rng), None);
+
+        // a detached shard with nowhere to go stays detached
+        state.attach(tenant_shard_id, Some(NodeId(1)));
+        state.attach(tenant_shard_id, None);
+        assert_eq!(state.chaos_pick(&mut rng), None);
+
+        for node_id in [1, 2] {
+            test_node(&mut state, node_id);
+        }
+        state.attach(tenant_shard_id, Some(NodeId(1)));
+        let mut actions = HashSet::new();
+        for _ in 0..100 {
+            let (picked, action) = state.chaos_pick(&mut rng).unwrap();
+            assert_eq!(picked, tenant_shard_id);
+            actions.insert(action);
+        }
+        // never moved to the node it is attached to
+        assert_eq!(
+            actions,
+            HashSet::from([
+                ChaosAction::NewGeneration,
+                ChaosAction::Detach,
+                ChaosAction::Move(NodeId(2))
+            ])
+        );
+    }
+
+    #[test]
+    fn diff_attachments() {
+        let shard = |n| TenantShardId {
+            tenant_id: TENANT_ID.parse().unwrap(),
+            shard_number: ShardNumber(n),
+            shard_count: ShardCount(4),
+        };
+        let mut state = PersistentState::new();
+        state.attach(shard(0), Some(NodeId(1)));
+        state.attach(shard(1), Some(NodeId(1)));
+        state.attach(shard(1), Some(NodeId(1)));
+        state.attach(shard(2), Some(NodeId(2)));
+        state.attach(shard(3), Some(NodeId(1)));
+
+        let mismatches = super::diff_attachments(
+            &state,
+            NodeId(1),
+            &[
+                listed(shard(0), Some(1)),
+                listed(shard(1), Some(1)),
+                listed(shard(2), Some(1)),
+            ],
+        );
+        let kinds: Vec<_> = mismatches
+            .iter()
+            .map(|m| (m.tenant_shard_id, m.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (shard(1), MismatchKind::StaleGeneration),
+                (shard(2), MismatchKind::UnexpectedAttachment),
+                (shard(3), MismatchKind::MissingTenant),
+            ]
+        );
+        assert_eq!(mismatches[0].expected, Some((2, NodeId(1))));
+        assert_eq!(mismatches[1].expected, Some((1, NodeId(2))));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let newer = decode(serde_json::json!({
+            "version": SCHEMA_VERSION + 1,
+            "tenants": {},
+            "nodes": {},
+            "timelines": {},
+        }))
+        .err()
+        .expect("newer versions are rejected");
+        assert!(format!("{newer:#}").contains("newer build"), "{newer:#}");
+
+        let current = |extra: (&str, serde_json::Value)| {
+            let mut state = serde_json::json!({
+                "version": SCHEMA_VERSION,
+                "tenants": {},
+                "nodes": {},
+                "timelines": {},
+            });
+            state[extra.0] = extra.1;
+            decode(state)
+        };
+        assert!(current(("unknown", serde_json::json!(1))).is_err());
+        assert!(current(("version", serde_json::json!(0))).is_err());
+        assert!(current(("nodes", serde_json::json!({ "1": { "weight": 0 } }))).is_err());
+        current(("nodes", serde_json::json!({ "1": { "weight": 1 } }))).unwrap();
+    }
+}
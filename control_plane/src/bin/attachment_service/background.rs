@@ -0,0 +1,327 @@
+//! The tasks that run alongside the HTTP API.
+
+use pageserver_api::shard::TenantShardId;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use utils::id::NodeId;
+
+use control_plane::attachment_service::{NodeDrainFailure, NodeDrainProgress, NodeDrainStatus};
+
+use crate::persistence::Changes;
+use crate::{migrate_tenant, update_node_metrics, PersistentState, State};
+
+impl PersistentState {
+    /// A random change of a random tenant shard's attachment, for `--chaos-interval-ms`. Only
+    /// moves shards to nodes that [`Self::schedule`] could pick.
+    pub(crate) fn chaos_pick(&self, rng: &mut impl Rng) -> Option<(TenantShardId, ChaosAction)> {
+        let (tenant_shard_id, tenant_state) = self.tenants.iter().choose(rng)?;
+        let dest = self
+            .schedulable_nodes()
+            .map(|(node_id, _)| *node_id)
+            .filter(|node_id| Some(*node_id) != tenant_state.pageserver)
+            .choose(rng);
+        let action = match (rng.gen_range(0..3), tenant_state.pageserver, dest) {
+            (0, Some(_), _) => ChaosAction::NewGeneration,
+            (1, Some(_), _) => ChaosAction::Detach,
+            (_, _, Some(dest)) => ChaosAction::Move(dest),
+            (_, Some(_), None) => ChaosAction::NewGeneration,
+            (_, None, None) => return None,
+        };
+        Some((*tenant_shard_id, action))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ChaosAction {
+    /// Attach again to the same pageserver, in a new generation
+    NewGeneration,
+    Detach,
+    /// Attach to another pageserver, or attach a detached shard
+    Move(NodeId),
+}
+
+/// Our side of the leader election, see [`lease_loop`].
+pub(crate) struct Lease {
+    pub(crate) holder: String,
+    pub(crate) duration: Duration,
+    // The holder of the lease when we last acquired or renewed it, None if that failed
+    pub(crate) leader: std::sync::Mutex<Option<String>>,
+}
+
+impl Lease {
+    pub(crate) fn is_held(&self) -> bool {
+        self.leader.lock().unwrap().as_deref() == Some(self.holder.as_str())
+    }
+}
+
+impl State {
+    /// Records the outcome of a heartbeat of `node_id`, and updates whether the node is
+    /// reachable: not after `max_failures` consecutive failures, again on the next success.
+    /// Its registered availability is left alone, see [`crate::NodeRegistration::is_active`].
+    pub(crate) async fn record_heartbeat(
+        &self,
+        node_id: NodeId,
+        result: Result<(), String>,
+        max_failures: u32,
+    ) {
+        let unreachable = {
+            let mut liveness = self.liveness.lock().unwrap();
+            let node = liveness.entry(node_id).or_default();
+            match &result {
+                Ok(()) => {
+                    node.last_seen = Some(SystemTime::now());
+                    node.consecutive_failures = 0;
+                    node.offline_since = None;
+                    false
+                }
+                Err(_) => {
+                    node.consecutive_failures += 1;
+                    if node.consecutive_failures < max_failures {
+                        return;
+                    }
+                    node.offline_since.get_or_insert_with(Instant::now);
+                    true
+                }
+            }
+        };
+
+        let changes = |locked: &PersistentState| {
+            locked
+                .nodes
+                .get(&node_id)
+                .and_then(|n| n.registration.as_ref())
+                .is_some_and(|r| r.unreachable != unreachable)
+        };
+        // Most heartbeats don't change anything, don't block the handlers for them
+        if !changes(&*self.inner.read().await) {
+            return;
+        }
+        let mut locked = self.inner.write().await;
+        if !changes(&*locked) {
+            return;
+        }
+        let registration = locked
+            .nodes
+            .get_mut(&node_id)
+            .and_then(|n| n.registration.as_mut())
+            .expect("checked above");
+        registration.unreachable = unreachable;
+        match result {
+            Ok(()) => tracing::info!(%node_id, "heartbeat succeeded, marking node reachable"),
+            Err(e) => tracing::warn!(
+                %node_id,
+                "{max_failures} heartbeats failed, marking node unreachable: {e}"
+            ),
+        }
+        if let Err(e) = self.persist(&locked, &Changes::node(node_id)).await {
+            tracing::warn!(%node_id, "failed to persist node reachability: {e:#}");
+        }
+    }
+}
+
+/// Polls the `/v1/status` of the pageservers with a known management API address, see
+/// [`State::node_mgmt_api_url`], every `interval`, for their availability, see
+/// [`State::record_heartbeat`]. A poll that takes longer than `interval` fails.
+pub(crate) async fn heartbeat_loop(state: Arc<State>, interval: Duration, max_failures: u32) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if !state.is_leader() {
+            // the leader records the availability
+            continue;
+        }
+
+        let targets: Vec<(NodeId, String)> = {
+            let locked = state.inner.read().await;
+            locked
+                .nodes
+                .keys()
+                .filter_map(|node_id| Some((*node_id, state.node_mgmt_api_url(&locked, *node_id)?)))
+                .collect()
+        };
+        let heartbeats = targets.into_iter().map(|(node_id, url)| {
+            let client = state.mgmt_api_client(url);
+            async move {
+                let result = match tokio::time::timeout(interval, client.status()).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(format!("no response within {interval:?}")),
+                };
+                (node_id, result)
+            }
+        });
+        for (node_id, result) in futures::future::join_all(heartbeats).await {
+            state.record_heartbeat(node_id, result, max_failures).await;
+        }
+        state.fail_over().await;
+    }
+}
+
+/// Renews the leader lease, or tries to take it over, every third of its duration, see
+/// `--leader-lease-ms`. A follower reloads the state each time, so that it serves recent
+/// reads, and so does a new leader, to continue from the old leader's last change.
+pub(crate) async fn lease_loop(state: Arc<State>) {
+    let lease = state
+        .lease
+        .as_ref()
+        .expect("only spawned with leader election");
+    let mut ticker = tokio::time::interval(lease.duration / 3);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes right away, and main just acquired the lease
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+
+        let leader = match state
+            .persistence
+            .acquire_lease(&lease.holder, lease.duration)
+            .await
+        {
+            Ok(leader) => Some(leader),
+            Err(e) => {
+                // we may have lost the lease meanwhile, stop changing anything
+                tracing::warn!("Failed to acquire the leader lease: {e:#}");
+                None
+            }
+        };
+        let was_leader = lease.is_held();
+        let is_leader = leader.as_deref() == Some(lease.holder.as_str());
+        if !is_leader {
+            *lease.leader.lock().unwrap() = leader.clone();
+            if was_leader {
+                tracing::warn!("Lost the leader lease, the leader is now {leader:?}");
+            }
+        }
+        if !is_leader || !was_leader {
+            let mut locked = state.inner.write().await;
+            match state.persistence.load().await {
+                Ok(loaded) => {
+                    *locked = loaded.unwrap_or_else(PersistentState::new);
+                    update_node_metrics(&locked);
+                }
+                Err(e) => {
+                    // a new leader retries on the next tick, before it makes any change
+                    tracing::warn!(
+                        "Failed to reload state from {}: {e:#}",
+                        state.persistence.describe()
+                    );
+                    continue;
+                }
+            }
+        }
+        if is_leader && !was_leader {
+            *lease.leader.lock().unwrap() = leader;
+            tracing::info!("Became the leader");
+        }
+    }
+}
+
+/// Changes a random attachment every `interval`, see `--chaos-interval-ms`.
+pub(crate) async fn chaos_loop(state: Arc<State>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes right away, don't change anything before the first interval
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+
+        if !state.is_leader() {
+            continue;
+        }
+        let mut locked = state.inner.write().await;
+        let pick = locked.chaos_pick(&mut rand::thread_rng());
+        let Some((tenant_shard_id, action)) = pick else {
+            continue;
+        };
+        let dest = match action {
+            ChaosAction::NewGeneration => locked.tenants[&tenant_shard_id].pageserver,
+            ChaosAction::Detach => None,
+            ChaosAction::Move(dest) => Some(dest),
+        };
+        // changed on a copy, which replaces the state once it's saved
+        let mut updated = locked.clone();
+        let (previous, generation) = updated.attach(tenant_shard_id, dest);
+        tracing::info!(%tenant_shard_id, ?action, ?previous, generation, "chaos");
+        if let Err(e) = state
+            .save(
+                &updated,
+                Changes::attachment("chaos", tenant_shard_id, previous, dest, generation),
+            )
+            .await
+        {
+            tracing::warn!(%tenant_shard_id, "chaos: failed to save the state: {e}");
+            continue;
+        }
+        *locked = updated;
+        state.notify_compute(&locked, tenant_shard_id.tenant_id);
+        drop(locked);
+
+        state.notify_attachment(tenant_shard_id, dest, previous, generation);
+    }
+}
+
+/// Migrates the `shards` off `node_id`, see [`handle_node_drain`].
+pub(crate) async fn drain_node(state: Arc<State>, node_id: NodeId, shards: Vec<TenantShardId>) {
+    let update = |f: &dyn Fn(&mut NodeDrainProgress)| {
+        if let Some(progress) = state.drains.lock().unwrap().get_mut(&node_id) {
+            f(progress);
+        }
+    };
+
+    let mut shards = shards.into_iter().peekable();
+    while let Some(tenant_shard_id) = shards.next() {
+        let dest = {
+            let locked = state.inner.read().await;
+            if !locked.nodes.get(&node_id).is_some_and(|n| n.cordoned) {
+                tracing::info!(%node_id, "node was uncordoned, cancelling its drain");
+                update(&|p| p.status = NodeDrainStatus::Cancelled);
+                return;
+            }
+            if locked
+                .tenants
+                .get(&tenant_shard_id)
+                .and_then(|t| t.pageserver)
+                != Some(node_id)
+            {
+                // moved or detached meanwhile
+                update(&|p| p.migrated += 1);
+                continue;
+            }
+            locked.schedule(tenant_shard_id)
+        };
+
+        let result = match dest {
+            Some(dest) => migrate_tenant(&state, tenant_shard_id, dest, true, None, "drain")
+                .await
+                .map_err(|e| e.to_string()),
+            None => Err(
+                "No registered pageserver is active, uncordoned and allowed by the tenant's \
+                 placement"
+                    .to_owned(),
+            ),
+        };
+        match result {
+            Ok(_) => update(&|p| p.migrated += 1),
+            Err(error) => {
+                tracing::warn!(%node_id, %tenant_shard_id, "drain: failed to migrate: {error}");
+                update(&|p| {
+                    p.failed.push(NodeDrainFailure {
+                        tenant_shard_id,
+                        error: error.clone(),
+                    })
+                });
+            }
+        }
+
+        if shards.peek().is_some() {
+            tokio::time::sleep(state.drain_interval).await;
+        }
+    }
+
+    tracing::info!(%node_id, "drained node");
+    update(&|p| p.status = NodeDrainStatus::Complete);
+}
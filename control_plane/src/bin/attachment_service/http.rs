@@ -0,0 +1,1856 @@
+//! The HTTP API: its handlers and the router.
+
+use anyhow::anyhow;
+use hyper::StatusCode;
+use hyper::{Body, Request, Response};
+use pageserver_api::models::{
+    LocationConfigMode, LocationConfigSecondary, TenantConfig, TenantConfigRequest,
+    TimelineCreateRequest,
+};
+use pageserver_api::shard::TenantShardId;
+use routerify::Middleware;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use utils::http::endpoint::request_span;
+
+use utils::{
+    auth::{AuthError, Scope},
+    http::{
+        endpoint::{self, auth_middleware, check_permission_with},
+        error::ApiError,
+        json::{json_request, json_response},
+        request::{parse_query_param, parse_request_param},
+        RequestExt, RouterBuilder,
+    },
+    id::{NodeId, TenantId, TimelineId},
+};
+
+use pageserver_api::control_api::{
+    ReAttachRequest, ReAttachResponse, ReAttachResponseTenant, ValidateRequest, ValidateResponse,
+    ValidateResponseTenant,
+};
+
+use control_plane::attachment_service::{
+    AttachHookBatchRequest, AttachHookBatchResponse, AttachHookRequest, AttachHookResponse,
+    ConsistencyMismatch, ConsistencyReport, FailoverRequest, FailoverStatus, InspectRequest,
+    InspectResponse, MismatchKind, NodeDescribeResponse, NodeDrainProgress, NodeDrainStatus,
+    NodeRegisterRequest, NodeWeightRequest, NotificationRecord, ObservedAttachment,
+    TenantDescribeResponse, TenantHistoryEntry, TenantLocateResponse, TenantMigrateRequest,
+    TenantPlacement, TenantShardDescribeResponse, TenantShardInspectResponse,
+    TimelineGenerationRequest, TimelineGenerationResponse,
+};
+
+use crate::background::drain_node;
+use crate::persistence::{history_entry, Changes};
+use crate::{
+    attached_generations, location_config, migrate_tenant, NodeRegistration, NodeState,
+    PersistentState, RecentReAttach, RequestRecord, RequestStart, State, TenantState,
+    TimelineState, GENERATIONS_ISSUED, REQUESTS, RE_ATTACH_REPLAYS,
+};
+
+#[inline(always)]
+pub(crate) fn get_state(request: &Request<Body>) -> &State {
+    get_state_arc(request).as_ref()
+}
+
+/// Checks the scope of the request's JWT, unless auth is disabled: the pageservers' calls
+/// require [`Scope::GenerationsApi`], all others [`Scope::Admin`], which passes for any call.
+pub(crate) fn check_permissions(
+    request: &Request<Body>,
+    required_scope: Scope,
+) -> Result<(), ApiError> {
+    check_permission_with(request, |claims| {
+        if claims.scope == required_scope || claims.scope == Scope::Admin {
+            Ok(())
+        } else {
+            Err(AuthError(
+                format!("{:?} scope is not allowed here", claims.scope).into(),
+            ))
+        }
+    })
+}
+
+/// The sequence in the `If-Match` header of a request, if any, to pass to
+/// [`PersistentState::check_sequence`]. Requests without the header change the tenant shard
+/// unconditionally.
+pub(crate) fn parse_if_match(request: &Request<Body>) -> Result<Option<u64>, ApiError> {
+    let Some(value) = request.headers().get(hyper::header::IF_MATCH) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .map(|v| v.trim().trim_matches('"'))
+        .and_then(|v| v.parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            ApiError::BadRequest(anyhow!(
+                "Invalid If-Match header {value:?}, expected the sequence of the tenant shard"
+            ))
+        })
+}
+
+#[inline(always)]
+pub(crate) fn get_state_arc(request: &Request<Body>) -> &Arc<State> {
+    request.data::<Arc<State>>().expect("unknown state type")
+}
+
+/// Pageserver calls into this on startup, to learn which tenants it should attach
+pub(crate) async fn handle_re_attach(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::GenerationsApi)?;
+    REQUESTS.with_label_values(&["re_attach"]).inc();
+
+    let reattach_req = json_request::<ReAttachRequest>(&mut req).await?;
+
+    let node_id = reattach_req.node_id;
+    let state = get_state(&req);
+    let mut locked = state.inner.write().await;
+
+    let now = Instant::now();
+    let generations = attached_generations(&locked, node_id);
+    let replay = state
+        .re_attaches
+        .lock()
+        .unwrap()
+        .get(&node_id)
+        .map_or(false, |recent| {
+            recent.replays(now, state.re_attach_window, &generations)
+        });
+    if replay {
+        // The node re-attaches again soon after its previous re-attach, e.g. because it's
+        // crash-looping: nothing attached to it since, so it keeps its generations
+        tracing::info!(
+            "handle_re_attach: {node_id} re-attached again within {:?}, keeping the generations of {} shards",
+            state.re_attach_window,
+            generations.len()
+        );
+        RE_ATTACH_REPLAYS.inc();
+        let response = ReAttachResponse {
+            tenants: generations
+                .into_iter()
+                .map(|(id, gen)| ReAttachResponseTenant { id, gen })
+                .collect(),
+        };
+        return json_response(StatusCode::OK, response);
+    }
+
+    let mut generations = Vec::with_capacity(generations.len());
+    for (tenant_shard_id, tenant_state) in &mut locked.tenants {
+        if tenant_state.pageserver == Some(node_id) {
+            tenant_state.generation += 1;
+            GENERATIONS_ISSUED.inc();
+            generations.push((*tenant_shard_id, tenant_state.generation));
+        }
+    }
+    generations.sort();
+    if !generations.is_empty() {
+        locked.sequence += 1;
+    }
+
+    state
+        .save(
+            &locked,
+            Changes::tenants(generations.iter().map(|(id, _)| *id)),
+        )
+        .await?;
+
+    let response = ReAttachResponse {
+        tenants: generations
+            .iter()
+            .map(|(id, gen)| ReAttachResponseTenant { id: *id, gen: *gen })
+            .collect(),
+    };
+    state.re_attaches.lock().unwrap().insert(
+        node_id,
+        RecentReAttach {
+            at: now,
+            generations,
+        },
+    );
+
+    json_response(StatusCode::OK, response)
+}
+
+/// Pageserver calls into this before doing deletions, to confirm that it still
+/// holds the latest generation for the tenants with deletions enqueued
+pub(crate) async fn handle_validate(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::GenerationsApi)?;
+    REQUESTS.with_label_values(&["validate"]).inc();
+
+    let validate_req = json_request::<ValidateRequest>(&mut req).await?;
+
+    // The deletion queues of the pageservers validate thousands of shards at once: answer them
+    // all from one read of the state, and only log the invalid ones
+    let locked = get_state(&req).inner.read().await;
+
+    let mut response = ValidateResponse {
+        tenants: Vec::with_capacity(validate_req.tenants.len()),
+        sequence: locked.sequence,
+    };
+    let mut invalid = 0;
+    for req_tenant in validate_req.tenants {
+        // an unknown shard has no valid generation
+        let latest = locked.tenants.get(&req_tenant.id).map(|t| t.generation);
+        let valid = latest == Some(req_tenant.gen);
+        if latest.is_some_and(|latest| req_tenant.gen > latest) {
+            // We never issued it, unless our state went back to an older copy
+            tracing::error!(
+                "handle_validate: {}(gen {}): newer than our latest {latest:?} in epoch {}, \
+                 was the state restored without bumping the epoch?",
+                req_tenant.id,
+                req_tenant.gen,
+                locked.epoch,
+            );
+            invalid += 1;
+        } else if !valid {
+            tracing::info!(
+                "handle_validate: {}(gen {}): invalid (latest {latest:?})",
+                req_tenant.id,
+                req_tenant.gen,
+            );
+            invalid += 1;
+        }
+        response.tenants.push(ValidateResponseTenant {
+            id: req_tenant.id,
+            valid,
+        });
+    }
+    drop(locked);
+    tracing::info!(
+        "handle_validate: {} shards, {invalid} invalid, at sequence {}",
+        response.tenants.len(),
+        response.sequence
+    );
+
+    json_response(StatusCode::OK, response)
+}
+/// Call into this before attaching a tenant to a pageserver, to acquire a generation number
+/// (in the real control plane this is unnecessary, because the same program is managing
+///  generation numbers and doing attachments).
+pub(crate) async fn handle_attach_hook(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+    REQUESTS.with_label_values(&["attach_hook"]).inc();
+
+    let expected_sequence = parse_if_match(&req)?;
+    let attach_req = json_request::<AttachHookRequest>(&mut req).await?;
+
+    let state = get_state(&req).inner.clone();
+    let mut locked = state.write().await;
+    locked.check_sequence(attach_req.tenant_shard_id, expected_sequence)?;
+
+    if let Some(node_id) = attach_req.node_id {
+        if locked.nodes.get(&node_id).is_some_and(|n| n.cordoned) {
+            return Err(ApiError::Conflict(format!(
+                "Node {node_id} is cordoned, cannot attach tenant {}",
+                attach_req.tenant_shard_id
+            )));
+        }
+    }
+
+    let (previous_pageserver, generation) =
+        locked.attach(attach_req.tenant_shard_id, attach_req.node_id);
+    let sequence = locked.tenants[&attach_req.tenant_shard_id].sequence;
+
+    tracing::info!(
+        "handle_attach_hook: tenant {} set generation {}, pageserver {}",
+        attach_req.tenant_shard_id,
+        generation,
+        attach_req.node_id.unwrap_or(utils::id::NodeId(0xfffffff))
+    );
+
+    get_state(&req)
+        .save(
+            &locked,
+            Changes::attachment(
+                "attach-hook",
+                attach_req.tenant_shard_id,
+                previous_pageserver,
+                attach_req.node_id,
+                generation,
+            ),
+        )
+        .await?;
+    get_state_arc(&req).notify_compute(&locked, attach_req.tenant_shard_id.tenant_id);
+    drop(locked);
+
+    get_state_arc(&req).notify_attachment(
+        attach_req.tenant_shard_id,
+        attach_req.node_id,
+        previous_pageserver,
+        generation,
+    );
+
+    json_response(
+        StatusCode::OK,
+        AttachHookResponse {
+            gen: attach_req.node_id.map(|_| generation),
+            sequence,
+        },
+    )
+}
+
+/// Like [`handle_attach_hook`] for many tenant shards, with a single write of the state, for
+/// tests that set up many tenants. If any of the attachments fails, none is made.
+pub(crate) async fn handle_attach_hook_batch(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+    REQUESTS.with_label_values(&["attach_hook_batch"]).inc();
+
+    let batch_req = json_request::<AttachHookBatchRequest>(&mut req).await?;
+
+    let state = get_state_arc(&req);
+    let mut locked = state.inner.write().await;
+
+    for attach_req in &batch_req.attachments {
+        if let Some(node_id) = attach_req.node_id {
+            if locked.nodes.get(&node_id).is_some_and(|n| n.cordoned) {
+                return Err(ApiError::Conflict(format!(
+                    "Node {node_id} is cordoned, cannot attach tenant {}",
+                    attach_req.tenant_shard_id
+                )));
+            }
+        }
+    }
+
+    let mut responses = Vec::with_capacity(batch_req.attachments.len());
+    let mut notifications = Vec::with_capacity(batch_req.attachments.len());
+    let mut history = Vec::with_capacity(batch_req.attachments.len());
+    for attach_req in &batch_req.attachments {
+        let (previous_pageserver, generation) =
+            locked.attach(attach_req.tenant_shard_id, attach_req.node_id);
+        responses.push(AttachHookResponse {
+            gen: attach_req.node_id.map(|_| generation),
+            sequence: locked.tenants[&attach_req.tenant_shard_id].sequence,
+        });
+        history.push(history_entry(
+            "attach-hook/batch",
+            attach_req.tenant_shard_id,
+            previous_pageserver,
+            attach_req.node_id,
+            generation,
+        ));
+        notifications.push((
+            attach_req.tenant_shard_id,
+            attach_req.node_id,
+            previous_pageserver,
+            generation,
+        ));
+    }
+    tracing::info!(
+        "handle_attach_hook_batch: {} attachments",
+        batch_req.attachments.len()
+    );
+
+    let mut changed: Vec<TenantShardId> = batch_req
+        .attachments
+        .iter()
+        .map(|r| r.tenant_shard_id)
+        .collect();
+    changed.sort();
+    changed.dedup();
+    state
+        .save(
+            &locked,
+            Changes::tenants(changed.iter().copied()).with_history(history),
+        )
+        .await?;
+    let mut changed_tenants: Vec<TenantId> = changed.iter().map(|id| id.tenant_id).collect();
+    changed_tenants.dedup();
+    for tenant_id in changed_tenants {
+        state.notify_compute(&locked, tenant_id);
+    }
+    drop(locked);
+
+    for (tenant_shard_id, node_id, previous_pageserver, generation) in notifications {
+        state.notify_attachment(tenant_shard_id, node_id, previous_pageserver, generation);
+    }
+
+    json_response(
+        StatusCode::OK,
+        AttachHookBatchResponse {
+            attachments: responses,
+        },
+    )
+}
+
+pub(crate) async fn handle_inspect(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let inspect_req = json_request::<InspectRequest>(&mut req).await?;
+
+    let state = get_state(&req).inner.clone();
+    let locked = state.write().await;
+    let tenant_state = locked.tenants.get(&inspect_req.tenant_shard_id);
+
+    json_response(
+        StatusCode::OK,
+        InspectResponse {
+            attachment: tenant_state.and_then(|s| s.pageserver.map(|ps| (s.generation, ps))),
+            sequence: tenant_state.map_or(0, |s| s.sequence),
+        },
+    )
+}
+
+/// Attach a tenant shard to a pageserver of our choosing, see [`PersistentState::schedule`], in
+/// a new generation. If the shard is already attached, its attachment is returned unchanged, so
+/// that the call may be retried.
+pub(crate) async fn handle_tenant_locate(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let expected_sequence = parse_if_match(&req)?;
+
+    let state = get_state_arc(&req);
+    let mut locked = state.inner.write().await;
+    locked.check_sequence(tenant_shard_id, expected_sequence)?;
+
+    if let Some(TenantState {
+        pageserver: Some(node_id),
+        generation,
+        sequence,
+        ..
+    }) = locked.tenants.get(&tenant_shard_id)
+    {
+        return json_response(
+            StatusCode::OK,
+            TenantLocateResponse {
+                node_id: *node_id,
+                gen: *generation,
+                sequence: *sequence,
+            },
+        );
+    }
+
+    let node_id = locked.schedule(tenant_shard_id).ok_or_else(|| {
+        ApiError::ResourceUnavailable(
+            "No registered pageserver is active, uncordoned and allowed by the tenant's placement"
+                .into(),
+        )
+    })?;
+    let (previous, generation) = locked.attach(tenant_shard_id, Some(node_id));
+    let sequence = locked.tenants[&tenant_shard_id].sequence;
+    tracing::info!(%tenant_shard_id, %node_id, generation, "scheduled tenant");
+    state
+        .save(
+            &locked,
+            Changes::attachment(
+                "locate",
+                tenant_shard_id,
+                previous,
+                Some(node_id),
+                generation,
+            ),
+        )
+        .await?;
+    state.notify_compute(&locked, tenant_shard_id.tenant_id);
+    drop(locked);
+
+    state.notify(tenant_shard_id, node_id, Some(generation));
+
+    json_response(
+        StatusCode::OK,
+        TenantLocateResponse {
+            node_id,
+            gen: generation,
+            sequence,
+        },
+    )
+}
+
+/// All tenants that we issued generations for, sorted by tenant id. Detached shards are
+/// included, with the generation they were last attached in.
+pub(crate) async fn handle_tenants_list(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let locked = get_state(&req).inner.read().await;
+
+    let mut tenants: BTreeMap<TenantId, Vec<TenantShardDescribeResponse>> = BTreeMap::new();
+    for (tenant_shard_id, tenant_state) in &locked.tenants {
+        tenants
+            .entry(tenant_shard_id.tenant_id)
+            .or_default()
+            .push(describe_shard(*tenant_shard_id, tenant_state));
+    }
+    let tenants: Vec<TenantDescribeResponse> = tenants
+        .into_iter()
+        .map(|(tenant_id, mut shards)| {
+            shards.sort_by_key(|s| s.tenant_shard_id);
+            TenantDescribeResponse { tenant_id, shards }
+        })
+        .collect();
+
+    json_response(StatusCode::OK, tenants)
+}
+
+pub(crate) fn describe_shard(
+    tenant_shard_id: TenantShardId,
+    tenant_state: &TenantState,
+) -> TenantShardDescribeResponse {
+    TenantShardDescribeResponse {
+        tenant_shard_id,
+        shard_number: tenant_shard_id.shard_number,
+        shard_count: tenant_shard_id.shard_count,
+        node_id: tenant_state.pageserver,
+        gen: tenant_state.generation,
+        secondaries: tenant_state.secondaries.clone(),
+        sequence: tenant_state.sequence,
+        placement: tenant_state.placement.clone(),
+    }
+}
+
+/// How many of the most recent changes of a tenant shard GET /tenant/:tenant_id returns.
+pub(crate) const INSPECT_HISTORY: usize = 20;
+
+/// Everything we know about a tenant shard, for debugging: its intended attachment, what its
+/// pageserver has, asked right away, the last notification and the recent history.
+pub(crate) async fn handle_tenant_shard_inspect(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    use pageserver_client::mgmt_api::Error;
+
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let state = get_state(&req);
+    let (intent, target) = {
+        let locked = state.inner.read().await;
+        let tenant_state = locked.tenants.get(&tenant_shard_id).ok_or_else(|| {
+            ApiError::NotFound(anyhow!("Tenant shard {tenant_shard_id} not found").into())
+        })?;
+        let target = tenant_state
+            .pageserver
+            .map(|node_id| (node_id, state.node_mgmt_api_url(&locked, node_id)));
+        (describe_shard(tenant_shard_id, tenant_state), target)
+    };
+
+    let observed = match target {
+        None => None,
+        Some((node_id, None)) => Some(ObservedAttachment {
+            node_id,
+            generation: None,
+            mismatch: None,
+            error: Some("the node has no known management API address".to_owned()),
+        }),
+        Some((node_id, Some(url))) => {
+            let client = state.mgmt_api_client(url);
+            let details = tokio::time::timeout(
+                CONSISTENCY_CHECK_TIMEOUT,
+                client.tenant_details(tenant_shard_id),
+            )
+            .await;
+            let tenants = match details {
+                Ok(Ok(details)) => Ok(vec![details.tenant_info]),
+                Ok(Err(Error::ApiError(StatusCode::NOT_FOUND, _))) => Ok(Vec::new()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err(format!("no response within {CONSISTENCY_CHECK_TIMEOUT:?}")),
+            };
+            Some(match tenants {
+                Ok(tenants) => {
+                    let locked = state.inner.read().await;
+                    ObservedAttachment {
+                        node_id,
+                        generation: tenants.first().and_then(|t| t.generation),
+                        mismatch: diff_attachments(&locked, node_id, &tenants)
+                            .into_iter()
+                            .find(|m| m.tenant_shard_id == tenant_shard_id),
+                        error: None,
+                    }
+                }
+                Err(error) => ObservedAttachment {
+                    node_id,
+                    generation: None,
+                    mismatch: None,
+                    error: Some(error),
+                },
+            })
+        }
+    };
+
+    let last_notification = state
+        .notifications
+        .lock()
+        .unwrap()
+        .recent
+        .iter()
+        .rev()
+        .find(|r| r.tenant_id == tenant_shard_id.tenant_id)
+        .cloned();
+
+    let mut history: Vec<TenantHistoryEntry> = state
+        .persistence
+        .history(tenant_shard_id.tenant_id)
+        .await
+        .map_err(ApiError::InternalServerError)?
+        .into_iter()
+        .filter(|e| e.tenant_shard_id == tenant_shard_id)
+        .collect();
+    history.drain(..history.len().saturating_sub(INSPECT_HISTORY));
+
+    json_response(
+        StatusCode::OK,
+        TenantShardInspectResponse {
+            intent,
+            observed,
+            last_notification,
+            history,
+        },
+    )
+}
+
+/// How long DELETE /tenant waits for a pageserver to finish deleting a tenant shard.
+pub(crate) const TENANT_DELETE_TIMEOUT: Duration = Duration::from_secs(60);
+pub(crate) const TENANT_DELETE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delete a tenant: each of its attached shards is deleted on its pageserver, and once all
+/// deletions completed, the tenant's shards and timelines are removed from the state. If a
+/// deletion fails, or a shard changed meanwhile, e.g. was migrated, the state is unchanged and
+/// the call may be retried.
+pub(crate) async fn handle_tenant_delete(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_id: TenantId = parse_request_param(&req, "tenant_id")?;
+    let state = get_state_arc(&req);
+
+    let (deletions, secondaries, sequences) = {
+        let locked = state.inner.read().await;
+        let mut deletions = Vec::new();
+        let mut secondaries = BTreeSet::new();
+        let mut sequences = HashMap::new();
+        for (tenant_shard_id, tenant_state) in &locked.tenants {
+            if tenant_shard_id.tenant_id != tenant_id {
+                continue;
+            }
+            sequences.insert(*tenant_shard_id, tenant_state.sequence);
+            secondaries.extend(tenant_state.secondaries.iter().copied());
+            let Some(node_id) = tenant_state.pageserver else {
+                continue;
+            };
+            let url = state.node_mgmt_api_url(&locked, node_id).ok_or_else(|| {
+                ApiError::PreconditionFailed(
+                    format!(
+                        "Node {node_id} has no known management API address, register it first"
+                    )
+                    .into(),
+                )
+            })?;
+            deletions.push((*tenant_shard_id, node_id, url));
+        }
+        if sequences.is_empty() {
+            return Err(ApiError::NotFound(
+                anyhow!("Tenant {tenant_id} not found").into(),
+            ));
+        }
+        let secondaries: Vec<(NodeId, String)> = secondaries
+            .into_iter()
+            .filter_map(|node_id| Some((node_id, state.node_mgmt_api_url(&locked, node_id)?)))
+            .collect();
+        (deletions, secondaries, sequences)
+    };
+
+    // The shards stay in the state meanwhile, so that a retry finds them
+    for (tenant_shard_id, node_id, url) in deletions {
+        delete_on_pageserver(&state.mgmt_api_client(url), tenant_shard_id)
+            .await
+            .map_err(|e| {
+                ApiError::InternalServerError(anyhow!(
+                    "Failed to delete tenant shard {tenant_shard_id} on node {node_id}: {e:#}"
+                ))
+            })?;
+        tracing::info!(%tenant_shard_id, %node_id, "deleted tenant shard on pageserver");
+    }
+
+    // Secondary locations hold no data that isn't in remote storage: a failure to detach one
+    // leaves some garbage on its pageserver, but doesn't fail the deletion. A pageserver's
+    // secondary locations of all the tenant's shards go at once.
+    for (node_id, url) in secondaries {
+        let detach = location_config(
+            LocationConfigMode::Detached,
+            None,
+            None,
+            TenantConfig::default(),
+        );
+        if let Err(e) = state
+            .mgmt_api_client(url)
+            .location_config(tenant_id, detach, None)
+            .await
+        {
+            tracing::warn!(%tenant_id, %node_id, "failed to detach secondary location: {e}");
+        }
+    }
+
+    let mut locked = state.inner.write().await;
+    let current: HashMap<TenantShardId, u64> = locked
+        .tenants
+        .iter()
+        .filter(|(t, _)| t.tenant_id == tenant_id)
+        .map(|(t, tenant_state)| (*t, tenant_state.sequence))
+        .collect();
+    if current != sequences {
+        // e.g. attached elsewhere, where we didn't delete it
+        return Err(ApiError::Conflict(format!(
+            "Tenant {tenant_id} changed while it was being deleted, retry the deletion"
+        )));
+    }
+    let mut changes = Changes::tenants(current.into_keys());
+    locked.tenants.retain(|t, _| t.tenant_id != tenant_id);
+    locked.sequence += 1;
+    if let Some(timelines) = locked.timelines.remove(&tenant_id) {
+        changes.timelines = timelines
+            .into_keys()
+            .map(|timeline_id| (tenant_id, timeline_id))
+            .collect();
+    }
+    tracing::info!(%tenant_id, "deleted tenant");
+    state.save(&locked, changes).await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Deletes a tenant shard on the pageserver of `client`, and waits until it is gone.
+pub(crate) async fn delete_on_pageserver(
+    client: &pageserver_client::mgmt_api::Client,
+    tenant_shard_id: TenantShardId,
+) -> anyhow::Result<()> {
+    use pageserver_client::mgmt_api::Error;
+
+    match client.tenant_delete(tenant_shard_id).await {
+        Ok(_) => {}
+        // e.g. the pageserver finished an earlier attempt
+        Err(Error::ApiError(StatusCode::NOT_FOUND, _)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+
+    let deadline = Instant::now() + TENANT_DELETE_TIMEOUT;
+    loop {
+        match client.tenant_details(tenant_shard_id).await {
+            Err(Error::ApiError(StatusCode::NOT_FOUND, _)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+            Ok(_) if Instant::now() >= deadline => {
+                anyhow::bail!("still deleting after {TENANT_DELETE_TIMEOUT:?}")
+            }
+            Ok(_) => tokio::time::sleep(TENANT_DELETE_POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// The management API of the pageserver that the tenant shard is attached to, for the
+/// endpoints that pass requests through to it.
+pub(crate) async fn attached_mgmt_api(
+    state: &State,
+    tenant_shard_id: TenantShardId,
+) -> Result<(NodeId, pageserver_client::mgmt_api::Client), ApiError> {
+    let locked = state.inner.read().await;
+    let tenant_state = locked.tenants.get(&tenant_shard_id).ok_or_else(|| {
+        ApiError::NotFound(anyhow!("Tenant shard {tenant_shard_id} not found").into())
+    })?;
+    let node_id = tenant_state.pageserver.ok_or_else(|| {
+        ApiError::PreconditionFailed(
+            format!("Tenant shard {tenant_shard_id} is not attached to any pageserver").into(),
+        )
+    })?;
+    let url = state.node_mgmt_api_url(&locked, node_id).ok_or_else(|| {
+        ApiError::PreconditionFailed(
+            format!("Node {node_id} has no known management API address, register it first").into(),
+        )
+    })?;
+    Ok((node_id, state.mgmt_api_client(url)))
+}
+
+/// The error of a request that we passed through to the pageserver `node_id`: the pageserver's
+/// errors keep their status, a failure to reach it is ours.
+pub(crate) fn pass_through_error(
+    node_id: NodeId,
+    e: pageserver_client::mgmt_api::Error,
+) -> ApiError {
+    use pageserver_client::mgmt_api::Error;
+
+    match e {
+        Error::ApiError(StatusCode::BAD_REQUEST, msg) => ApiError::BadRequest(anyhow!(msg)),
+        Error::ApiError(StatusCode::NOT_FOUND, msg) => ApiError::NotFound(anyhow!(msg).into()),
+        Error::ApiError(StatusCode::CONFLICT, msg) => ApiError::Conflict(msg),
+        Error::ApiError(StatusCode::PRECONDITION_FAILED, msg) => {
+            ApiError::PreconditionFailed(msg.into())
+        }
+        Error::ApiError(StatusCode::SERVICE_UNAVAILABLE, msg) => {
+            ApiError::ResourceUnavailable(msg.into())
+        }
+        e => ApiError::InternalServerError(anyhow!("Request to node {node_id} failed: {e}")),
+    }
+}
+
+/// Create a timeline on the pageserver that the tenant is attached to, so that clients don't
+/// need to track where tenants are.
+pub(crate) async fn handle_timeline_create(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let create_req = json_request::<TimelineCreateRequest>(&mut req).await?;
+    // TODO(sharding): create the timeline on all shards
+    if !tenant_shard_id.is_unsharded() {
+        return Err(ApiError::BadRequest(anyhow!(
+            "Creating timelines of sharded tenants is not supported yet"
+        )));
+    }
+
+    let (node_id, client) = attached_mgmt_api(get_state(&req), tenant_shard_id).await?;
+    let info = client
+        .timeline_create(tenant_shard_id.tenant_id, &create_req)
+        .await
+        .map_err(|e| pass_through_error(node_id, e))?;
+    tracing::info!(%tenant_shard_id, %node_id, timeline_id = %info.timeline_id, "created timeline");
+
+    json_response(StatusCode::CREATED, info)
+}
+
+/// Get a timeline's details from the pageserver that the tenant shard is attached to.
+pub(crate) async fn handle_timeline_detail(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let timeline_id: TimelineId = parse_request_param(&req, "timeline_id")?;
+    let (node_id, client) = attached_mgmt_api(get_state(&req), tenant_shard_id).await?;
+    // TODO(sharding): address the shard, the pageserver's client only takes a TenantId
+    let info = client
+        .timeline_info(tenant_shard_id.tenant_id, timeline_id)
+        .await
+        .map_err(|e| pass_through_error(node_id, e))?;
+
+    json_response(StatusCode::OK, info)
+}
+
+/// Delete a timeline on the pageserver that the tenant shard is attached to. Like the
+/// pageserver's API, this returns once the deletion started: the timeline is gone once its
+/// details are not found.
+pub(crate) async fn handle_timeline_delete(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let timeline_id: TimelineId = parse_request_param(&req, "timeline_id")?;
+    let (node_id, client) = attached_mgmt_api(get_state(&req), tenant_shard_id).await?;
+    let status = client
+        .timeline_delete(tenant_shard_id, timeline_id)
+        .await
+        .map_err(|e| pass_through_error(node_id, e))?;
+    tracing::info!(%tenant_shard_id, %node_id, %timeline_id, "deleting timeline");
+
+    json_response(status, ())
+}
+
+/// Move a tenant to another pageserver in a new generation: the origin is made stale, the
+/// destination attaches the tenant, and the origin then keeps a secondary location, or detaches
+/// the tenant with `detach_origin`. Unlike `neon_local tenant migrate`, this doesn't wait for the
+/// destination to catch up, nor reconfigure computes.
+///
+/// The new generation is persisted before the destination is told about it. If a step fails,
+/// the migration may be retried: the tenant is then attached to the destination in yet another
+/// generation.
+pub(crate) async fn handle_tenant_migrate(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let expected_sequence = parse_if_match(&req)?;
+    let migrate_req = json_request::<TenantMigrateRequest>(&mut req).await?;
+
+    // Not checked by migrate_tenant: a drain migrates wherever the scheduler picks
+    let violation = get_state(&req)
+        .inner
+        .read()
+        .await
+        .placement_violation(tenant_shard_id, migrate_req.node_id);
+    if let Some(violation) = violation {
+        return Err(ApiError::Conflict(format!(
+            "Cannot migrate tenant {tenant_shard_id}: {violation}, change its placement first"
+        )));
+    }
+
+    let response = migrate_tenant(
+        get_state_arc(&req),
+        tenant_shard_id,
+        migrate_req.node_id,
+        migrate_req.detach_origin,
+        expected_sequence,
+        "migrate",
+    )
+    .await?;
+
+    json_response(StatusCode::OK, response)
+}
+
+/// The changes of the attachments of the tenant's shards, oldest first.
+pub(crate) async fn handle_tenant_history(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_id: TenantId = parse_request_param(&req, "tenant_id")?;
+    let history = get_state(&req)
+        .persistence
+        .history(tenant_id)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+
+    json_response(StatusCode::OK, history)
+}
+
+/// Sets the placement of a tenant shard, for its attachments from now on. A tenant shard that
+/// we don't know yet is created detached, so that its first attachment is placed accordingly.
+pub(crate) async fn handle_tenant_placement(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let expected_sequence = parse_if_match(&req)?;
+    let placement = json_request::<TenantPlacement>(&mut req).await?;
+    if let Some(az) = placement
+        .az_affinity
+        .as_ref()
+        .filter(|az| placement.az_anti_affinity.contains(az))
+    {
+        return Err(ApiError::BadRequest(anyhow!(
+            "AZ {az} is in both the affinity and the anti-affinity"
+        )));
+    }
+
+    let state = get_state(&req);
+    let mut locked = state.inner.write().await;
+    locked.check_sequence(tenant_shard_id, expected_sequence)?;
+
+    let generation_floor = locked.generation_floor();
+    let tenant_state = locked
+        .tenants
+        .entry(tenant_shard_id)
+        .or_insert_with(|| TenantState {
+            pageserver: None,
+            generation: generation_floor,
+            secondaries: Vec::new(),
+            sequence: 0,
+            placement: TenantPlacement::default(),
+            config: None,
+        });
+    tenant_state.placement = placement;
+    tenant_state.sequence += 1;
+    tracing::info!(%tenant_shard_id, placement = ?tenant_state.placement, "updated placement");
+
+    state
+        .save(&locked, Changes::tenants([tenant_shard_id]))
+        .await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Get the tenant config overrides of a tenant shard, empty if it has none.
+pub(crate) async fn handle_tenant_config_get(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let locked = get_state(&req).inner.read().await;
+    if !locked.tenants.contains_key(&tenant_shard_id) {
+        return Err(ApiError::NotFound(
+            anyhow!("Tenant shard {tenant_shard_id} not found").into(),
+        ));
+    }
+
+    json_response(StatusCode::OK, locked.tenant_config(tenant_shard_id))
+}
+
+/// Replace the tenant config overrides of a tenant shard, which all of its locations get from
+/// now on, and apply them to its attached pageserver right away. Unlike the pageserver's own
+/// config, the overrides survive re-attaches and migrations.
+pub(crate) async fn handle_tenant_config_put(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let expected_sequence = parse_if_match(&req)?;
+    let config = json_request::<TenantConfig>(&mut req).await?;
+    set_tenant_config(
+        get_state_arc(&req),
+        tenant_shard_id,
+        expected_sequence,
+        Some(config),
+    )
+    .await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Remove the tenant config overrides of a tenant shard: its locations get the pageserver's
+/// defaults again.
+pub(crate) async fn handle_tenant_config_delete(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_shard_id: TenantShardId = parse_request_param(&req, "tenant_id")?;
+    let expected_sequence = parse_if_match(&req)?;
+    set_tenant_config(
+        get_state_arc(&req),
+        tenant_shard_id,
+        expected_sequence,
+        None,
+    )
+    .await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+pub(crate) async fn set_tenant_config(
+    state: Arc<State>,
+    tenant_shard_id: TenantShardId,
+    expected_sequence: Option<u64>,
+    config: Option<TenantConfig>,
+) -> Result<(), ApiError> {
+    let attached = {
+        let mut locked = state.inner.write().await;
+        locked.check_sequence(tenant_shard_id, expected_sequence)?;
+
+        let generation_floor = locked.generation_floor();
+        let tenant_state = locked
+            .tenants
+            .entry(tenant_shard_id)
+            .or_insert_with(|| TenantState {
+                pageserver: None,
+                generation: generation_floor,
+                secondaries: Vec::new(),
+                sequence: 0,
+                placement: TenantPlacement::default(),
+                config: None,
+            });
+        tenant_state.config = config;
+        tenant_state.sequence += 1;
+        let attached = tenant_state.pageserver;
+        tracing::info!(%tenant_shard_id, config = ?tenant_state.config, "updated tenant config");
+
+        state
+            .save(&locked, Changes::tenants([tenant_shard_id]))
+            .await?;
+        attached.and_then(|node_id| {
+            Some((
+                node_id,
+                state.node_mgmt_api_url(&locked, node_id)?,
+                locked.tenant_config(tenant_shard_id),
+            ))
+        })
+    };
+
+    // The attachment gets the config anyway on its next attach, so a failure here doesn't
+    // fail the request
+    if let Some((node_id, url, config)) = attached {
+        let request = TenantConfigRequest {
+            tenant_id: tenant_shard_id.tenant_id,
+            config,
+        };
+        if let Err(e) = state.mgmt_api_client(url).tenant_config(&request).await {
+            tracing::warn!(%tenant_shard_id, %node_id, "failed to apply the tenant config: {e}");
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_secondary_request(req: &Request<Body>) -> Result<(TenantId, NodeId), ApiError> {
+    let tenant_id: TenantId = parse_request_param(req, "tenant_id")?;
+    let node_id = parse_node_id(req)?;
+    Ok((tenant_id, node_id))
+}
+
+/// Create a warm secondary location of an unsharded tenant on a pageserver, from which it
+/// can later be attached quickly, e.g. by a migration to that pageserver.
+pub(crate) async fn handle_tenant_secondary_create(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    // TODO(sharding): the location config of a shard needs its stripe size, which we don't know
+    let (tenant_id, node_id) = parse_secondary_request(&req)?;
+    let tenant_shard_id = TenantShardId::unsharded(tenant_id);
+    let expected_sequence = parse_if_match(&req)?;
+    let state = get_state_arc(&req);
+
+    let (url, tenant_conf) = {
+        let locked = state.inner.read().await;
+        locked.check_sequence(tenant_shard_id, expected_sequence)?;
+        let Some(tenant_state) = locked.tenants.get(&tenant_shard_id) else {
+            return Err(ApiError::NotFound(
+                anyhow!("Tenant {tenant_id} not found").into(),
+            ));
+        };
+        if tenant_state.pageserver == Some(node_id) {
+            return Err(ApiError::Conflict(format!(
+                "Tenant {tenant_id} is attached to node {node_id}, it can't be a secondary there"
+            )));
+        }
+        if locked.nodes.get(&node_id).is_some_and(|n| n.cordoned) {
+            return Err(ApiError::Conflict(format!(
+                "Node {node_id} is cordoned, cannot create a secondary of tenant {tenant_id} on it"
+            )));
+        }
+        let url = state.node_mgmt_api_url(&locked, node_id).ok_or_else(|| {
+            ApiError::BadRequest(anyhow!(
+                "Node {node_id} has no known management API address, register it first"
+            ))
+        })?;
+        (url, locked.tenant_config(tenant_shard_id))
+    };
+
+    let config = location_config(
+        LocationConfigMode::Secondary,
+        None,
+        Some(LocationConfigSecondary { warm: true }),
+        tenant_conf,
+    );
+    state
+        .mgmt_api_client(url)
+        .location_config(tenant_id, config, None)
+        .await
+        .map_err(|e| {
+            ApiError::InternalServerError(anyhow!(
+                "Failed to create a secondary of tenant {tenant_id} on node {node_id}: {e}"
+            ))
+        })?;
+
+    let mut locked = state.inner.write().await;
+    // The tenant may have been attached there meanwhile, which supersedes the secondary
+    if locked
+        .tenants
+        .get(&tenant_shard_id)
+        .is_some_and(|t| t.pageserver != Some(node_id))
+    {
+        locked.add_secondary(tenant_shard_id, node_id);
+        state
+            .save(&locked, Changes::tenants([tenant_shard_id]))
+            .await?;
+    }
+    tracing::info!(%tenant_id, %node_id, "created secondary location");
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Detach a secondary location of a tenant from its pageserver, and forget it.
+pub(crate) async fn handle_tenant_secondary_remove(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let (tenant_id, node_id) = parse_secondary_request(&req)?;
+    let tenant_shard_id = TenantShardId::unsharded(tenant_id);
+    let expected_sequence = parse_if_match(&req)?;
+    let state = get_state_arc(&req);
+
+    let url = {
+        let locked = state.inner.read().await;
+        locked.check_sequence(tenant_shard_id, expected_sequence)?;
+        if !locked
+            .tenants
+            .get(&tenant_shard_id)
+            .is_some_and(|t| t.secondaries.contains(&node_id))
+        {
+            return Err(ApiError::NotFound(
+                anyhow!("Tenant {tenant_id} has no secondary on node {node_id}").into(),
+            ));
+        }
+        state.node_mgmt_api_url(&locked, node_id)
+    };
+
+    // A node we can't reach any more can't hold on to the location either
+    match url {
+        Some(url) => {
+            let detach = location_config(
+                LocationConfigMode::Detached,
+                None,
+                None,
+                TenantConfig::default(),
+            );
+            state
+                .mgmt_api_client(url)
+                .location_config(tenant_id, detach, None)
+                .await
+                .map_err(|e| {
+                    ApiError::InternalServerError(anyhow!(
+                        "Failed to detach the secondary of tenant {tenant_id} on node {node_id}: {e}"
+                    ))
+                })?;
+        }
+        None => tracing::warn!(%tenant_id, %node_id, "no address to detach secondary at"),
+    }
+
+    let mut locked = state.inner.write().await;
+    if let Some(tenant_state) = locked.tenants.get_mut(&tenant_shard_id) {
+        tenant_state.secondaries.retain(|s| *s != node_id);
+        tenant_state.sequence += 1;
+    }
+    state
+        .save(&locked, Changes::tenants([tenant_shard_id]))
+        .await?;
+    tracing::info!(%tenant_id, %node_id, "removed secondary location");
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Warm up the secondary locations of a tenant: its attached pageserver uploads a heatmap of
+/// the layers in use now, which the secondaries download.
+pub(crate) async fn handle_tenant_secondary_warmup(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_id: TenantId = parse_request_param(&req, "tenant_id")?;
+    let tenant_shard_id = TenantShardId::unsharded(tenant_id);
+    let state = get_state_arc(&req);
+
+    let (node_id, url) = {
+        let locked = state.inner.read().await;
+        let Some(tenant_state) = locked.tenants.get(&tenant_shard_id) else {
+            return Err(ApiError::NotFound(
+                anyhow!("Tenant {tenant_id} not found").into(),
+            ));
+        };
+        let Some(node_id) = tenant_state.pageserver else {
+            return Err(ApiError::Conflict(format!(
+                "Tenant {tenant_id} is not attached, there is no heatmap to warm up from"
+            )));
+        };
+        let url = state.node_mgmt_api_url(&locked, node_id).ok_or_else(|| {
+            ApiError::PreconditionFailed(
+                format!("Node {node_id} has no known management API address, register it first")
+                    .into(),
+            )
+        })?;
+        (node_id, url)
+    };
+
+    state
+        .mgmt_api_client(url)
+        .tenant_heatmap_upload(tenant_shard_id)
+        .await
+        .map_err(|e| {
+            ApiError::InternalServerError(anyhow!(
+                "Failed to upload the heatmap of tenant {tenant_id} on node {node_id}: {e}"
+            ))
+        })?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Cordon a node, and migrate all its attached tenant shards to other pageservers, one at a
+/// time, in the background. The progress is served by GET on the same path. Uncordoning the
+/// node cancels the drain. If the node is already draining, this only returns the progress.
+pub(crate) async fn handle_node_drain(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let node_id = parse_node_id(&req)?;
+    let state = get_state_arc(&req);
+
+    let mut locked = state.inner.write().await;
+    if let Some(progress) = state.drains.lock().unwrap().get(&node_id) {
+        if progress.status == NodeDrainStatus::Running {
+            return json_response(StatusCode::OK, progress.clone());
+        }
+    }
+    let shards: Vec<TenantShardId> = locked
+        .tenants
+        .iter()
+        .filter(|(_, t)| t.pageserver == Some(node_id))
+        .map(|(id, _)| *id)
+        .collect();
+    locked.nodes.entry(node_id).or_default().cordoned = true;
+    state.save(&locked, Changes::node(node_id)).await?;
+    drop(locked);
+
+    let progress = NodeDrainProgress {
+        status: NodeDrainStatus::Running,
+        total: shards.len(),
+        migrated: 0,
+        failed: Vec::new(),
+    };
+    state
+        .drains
+        .lock()
+        .unwrap()
+        .insert(node_id, progress.clone());
+    tracing::info!(%node_id, shards = shards.len(), "draining node");
+    tokio::spawn(drain_node(Arc::clone(state), node_id, shards));
+
+    json_response(StatusCode::OK, progress)
+}
+
+pub(crate) async fn handle_node_drain_progress(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let node_id = parse_node_id(&req)?;
+    let progress = get_state(&req)
+        .drains
+        .lock()
+        .unwrap()
+        .get(&node_id)
+        .cloned();
+    match progress {
+        Some(progress) => json_response(StatusCode::OK, progress),
+        None => Err(ApiError::NotFound(
+            anyhow!("Node {node_id} was not drained").into(),
+        )),
+    }
+}
+
+pub(crate) fn failover_status(state: &State) -> FailoverStatus {
+    FailoverStatus {
+        enabled: state
+            .failover
+            .as_ref()
+            .is_some_and(|f| f.enabled.load(std::sync::atomic::Ordering::Relaxed)),
+        grace_ms: state.failover.as_ref().map(|f| f.grace.as_millis() as u64),
+    }
+}
+
+pub(crate) async fn handle_failover_get(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    json_response(StatusCode::OK, failover_status(get_state(&req)))
+}
+
+/// Turn the automatic failover off or on again, e.g. during maintenance. Not persisted: on
+/// restart, the failover is on again if `--failover-grace-ms` is given.
+pub(crate) async fn handle_failover_put(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let failover_req = json_request::<FailoverRequest>(&mut req).await?;
+    let state = get_state(&req);
+    let Some(failover) = &state.failover else {
+        return Err(ApiError::PreconditionFailed(
+            "Failover is not configured, start with --failover-grace-ms".into(),
+        ));
+    };
+    failover
+        .enabled
+        .store(failover_req.enabled, std::sync::atomic::Ordering::Relaxed);
+    tracing::info!(enabled = failover_req.enabled, "updated failover");
+
+    json_response(StatusCode::OK, failover_status(state))
+}
+
+pub(crate) fn parse_node_id(req: &Request<Body>) -> Result<NodeId, ApiError> {
+    parse_request_param::<u64>(req, "node_id").map(NodeId)
+}
+
+pub(crate) async fn set_node_cordoned(
+    req: Request<Body>,
+    cordoned: bool,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let node_id = parse_node_id(&req)?;
+
+    let state = get_state(&req).inner.clone();
+    let mut locked = state.write().await;
+
+    locked.nodes.entry(node_id).or_default().cordoned = cordoned;
+    tracing::info!(%node_id, cordoned, "updated node");
+
+    get_state(&req)
+        .save(&locked, Changes::node(node_id))
+        .await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// Stop scheduling new attachments to a node, e.g. ahead of maintenance. Existing
+/// attachments are left in place.
+pub(crate) async fn handle_node_cordon(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    set_node_cordoned(req, true).await
+}
+
+pub(crate) async fn handle_node_uncordon(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    set_node_cordoned(req, false).await
+}
+
+/// Set a node's scheduling weight, to emulate pageservers of different capacities.
+pub(crate) async fn handle_node_weight(mut req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let node_id = parse_node_id(&req)?;
+    let weight_req = json_request::<NodeWeightRequest>(&mut req).await?;
+    if weight_req.weight == 0 {
+        return Err(ApiError::BadRequest(anyhow!(
+            "Node weight must be greater than zero, cordon the node instead"
+        )));
+    }
+
+    let state = get_state(&req).inner.clone();
+    let mut locked = state.write().await;
+
+    locked.nodes.entry(node_id).or_default().weight = weight_req.weight;
+    tracing::info!(%node_id, weight = weight_req.weight, "updated node");
+
+    get_state(&req)
+        .save(&locked, Changes::node(node_id))
+        .await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// A pageserver registers itself with its addresses, or updates its registration, e.g. to
+/// report that it's going offline. Its weight and cordon are kept, and whether its heartbeats
+/// reach it.
+pub(crate) async fn handle_node_register(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::GenerationsApi)?;
+
+    let register_req = json_request::<NodeRegisterRequest>(&mut req).await?;
+
+    let state = get_state(&req).inner.clone();
+    let mut locked = state.write().await;
+
+    let node_id = register_req.node_id;
+    let node = locked.nodes.entry(node_id).or_default();
+    let unreachable = node.registration.as_ref().is_some_and(|r| r.unreachable);
+    node.registration = Some(NodeRegistration {
+        listen_http_addr: register_req.listen_http_addr,
+        listen_pg_addr: register_req.listen_pg_addr,
+        availability: register_req.availability,
+        availability_zone: register_req.availability_zone.clone(),
+        unreachable,
+    });
+    tracing::info!(
+        %node_id,
+        availability = ?register_req.availability,
+        availability_zone = ?register_req.availability_zone,
+        "registered node"
+    );
+
+    get_state(&req)
+        .save(&locked, Changes::node(node_id))
+        .await?;
+
+    json_response(StatusCode::OK, ())
+}
+
+/// All known nodes, ordered by id: those that registered or were configured, and those that
+/// only have attachments through the attach hook.
+pub(crate) async fn handle_nodes_list(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let locked = get_state(&req).inner.read().await;
+
+    let mut node_ids: Vec<NodeId> = locked
+        .nodes
+        .keys()
+        .copied()
+        .chain(locked.tenants.values().filter_map(|t| t.pageserver))
+        .collect();
+    node_ids.sort();
+    node_ids.dedup();
+
+    let default_node = NodeState::default();
+    let liveness = get_state(&req).liveness.lock().unwrap();
+    let nodes: Vec<NodeDescribeResponse> = node_ids
+        .into_iter()
+        .map(|node_id| {
+            let node = locked.nodes.get(&node_id).unwrap_or(&default_node);
+            let registration = node.registration.as_ref();
+            NodeDescribeResponse {
+                node_id,
+                listen_http_addr: registration.map(|r| r.listen_http_addr.clone()),
+                listen_pg_addr: registration.map(|r| r.listen_pg_addr.clone()),
+                availability: registration.map(|r| r.availability),
+                unreachable: registration.is_some_and(|r| r.unreachable),
+                last_seen_millis_since_epoch: liveness.get(&node_id).and_then(|l| l.last_seen).map(
+                    |t| {
+                        t.duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_millis() as u64
+                    },
+                ),
+                weight: node.weight,
+                cordoned: node.cordoned,
+                availability_zone: registration.and_then(|r| r.availability_zone.clone()),
+            }
+        })
+        .collect();
+    drop(liveness);
+
+    json_response(StatusCode::OK, nodes)
+}
+
+pub(crate) fn timeline_generation_response(
+    locked: &PersistentState,
+    tenant_id: TenantId,
+    timeline_state: &TimelineState,
+) -> TimelineGenerationResponse {
+    TimelineGenerationResponse {
+        generation: timeline_state.generation,
+        safekeepers: timeline_state.safekeepers.clone(),
+        // TODO(sharding): report the attachments of all shards
+        pageserver_attachment: locked
+            .tenants
+            .get(&TenantShardId::unsharded(tenant_id))
+            .and_then(|s| s.pageserver.map(|ps| (s.generation, ps))),
+    }
+}
+
+/// The safekeeper membership generation of a timeline, along with its tenant's pageserver
+/// attachment, so that tests can coordinate the two through one API.
+pub(crate) async fn handle_timeline_generation_get(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_id: TenantId = parse_request_param(&req, "tenant_id")?;
+    let timeline_id: TimelineId = parse_request_param(&req, "timeline_id")?;
+
+    let locked = get_state(&req).inner.read().await;
+    let timeline_state = locked
+        .timelines
+        .get(&tenant_id)
+        .and_then(|timelines| timelines.get(&timeline_id))
+        .ok_or_else(|| {
+            ApiError::NotFound(
+                anyhow!("No generation for timeline {tenant_id}/{timeline_id}").into(),
+            )
+        })?;
+
+    json_response(
+        StatusCode::OK,
+        timeline_generation_response(&locked, tenant_id, timeline_state),
+    )
+}
+
+/// Change the safekeeper membership of a timeline, issuing a new generation.
+pub(crate) async fn handle_timeline_generation_issue(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_id: TenantId = parse_request_param(&req, "tenant_id")?;
+    let timeline_id: TimelineId = parse_request_param(&req, "timeline_id")?;
+    let generation_req = json_request::<TimelineGenerationRequest>(&mut req).await?;
+
+    let state = get_state(&req).inner.clone();
+    let mut locked = state.write().await;
+
+    let timeline_state = locked
+        .timelines
+        .entry(tenant_id)
+        .or_default()
+        .entry(timeline_id)
+        .or_default();
+    timeline_state.generation += 1;
+    timeline_state.safekeepers = generation_req.safekeepers;
+    tracing::info!(
+        %tenant_id,
+        %timeline_id,
+        generation = timeline_state.generation,
+        safekeepers = ?timeline_state.safekeepers,
+        "issuing timeline generation",
+    );
+    let timeline_state = timeline_state.clone();
+
+    get_state(&req)
+        .save(&locked, Changes::timeline(tenant_id, timeline_id))
+        .await?;
+
+    json_response(
+        StatusCode::OK,
+        timeline_generation_response(&locked, tenant_id, &timeline_state),
+    )
+}
+
+/// The recently handled requests, to correlate e.g. a pageserver's re-attach retries with how
+/// they were handled here.
+pub(crate) async fn handle_debug_requests(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let recent: Vec<RequestRecord> = get_state(&req)
+        .recent_requests
+        .lock()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect();
+
+    json_response(StatusCode::OK, recent)
+}
+
+/// How long POST /debug/consistency_check waits for each pageserver to list its tenants.
+pub(crate) const CONSISTENCY_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Compares the attachments that the pageserver `node_id` lists with `state`.
+pub(crate) fn diff_attachments(
+    state: &PersistentState,
+    node_id: NodeId,
+    tenants: &[pageserver_api::models::TenantInfo],
+) -> Vec<ConsistencyMismatch> {
+    let mut mismatches = Vec::new();
+    for tenant in tenants {
+        let expected = state
+            .tenants
+            .get(&tenant.id)
+            .and_then(|s| s.pageserver.map(|ps| (s.generation, ps)));
+        let kind = match expected {
+            Some((generation, ps)) if ps == node_id => {
+                if tenant.generation == Some(generation) {
+                    continue;
+                }
+                MismatchKind::StaleGeneration
+            }
+            _ => MismatchKind::UnexpectedAttachment,
+        };
+        mismatches.push(ConsistencyMismatch {
+            kind,
+            tenant_shard_id: tenant.id,
+            node_id,
+            pageserver_generation: tenant.generation,
+            expected,
+        });
+    }
+
+    let listed: HashSet<TenantShardId> = tenants.iter().map(|t| t.id).collect();
+    for (tenant_shard_id, tenant_state) in &state.tenants {
+        if tenant_state.pageserver == Some(node_id) && !listed.contains(tenant_shard_id) {
+            mismatches.push(ConsistencyMismatch {
+                kind: MismatchKind::MissingTenant,
+                tenant_shard_id: *tenant_shard_id,
+                node_id,
+                pageserver_generation: None,
+                expected: Some((tenant_state.generation, node_id)),
+            });
+        }
+    }
+    mismatches.sort_by_key(|m| m.tenant_shard_id);
+    mismatches
+}
+
+/// Whether a request only reads the state, so that a follower may serve it: all GETs, and the
+/// POSTs that carry a query rather than a change.
+fn is_read_only(req: &Request<Body>) -> bool {
+    match *req.method() {
+        hyper::Method::GET => true,
+        hyper::Method::POST => {
+            matches!(req.uri().path(), "/inspect" | "/debug/consistency_check")
+        }
+        _ => false,
+    }
+}
+
+/// Lists the attached tenants of every pageserver with a known management API address, see
+/// [`State::node_mgmt_api_url`], and reports where they differ from the state. Nothing is
+/// changed: the pageservers pick up the state's view on their next re-attach, or through a
+/// migration.
+pub(crate) async fn handle_consistency_check(
+    req: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let state = get_state(&req);
+    let mut targets: Vec<(NodeId, String)> = {
+        let locked = state.inner.read().await;
+        locked
+            .nodes
+            .keys()
+            .filter_map(|node_id| Some((*node_id, state.node_mgmt_api_url(&locked, *node_id)?)))
+            .collect()
+    };
+    targets.sort();
+
+    let listings = targets.into_iter().map(|(node_id, url)| {
+        let client = state.mgmt_api_client(url);
+        async move {
+            let listing = tokio::time::timeout(CONSISTENCY_CHECK_TIMEOUT, client.list_tenants());
+            let result = match listing.await {
+                Ok(Ok(tenants)) => Ok(tenants),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err(format!("no response within {CONSISTENCY_CHECK_TIMEOUT:?}")),
+            };
+            (node_id, result)
+        }
+    });
+    let listings = futures::future::join_all(listings).await;
+
+    let locked = state.inner.read().await;
+    let mut report = ConsistencyReport {
+        checked_nodes: Vec::new(),
+        unreachable_nodes: Vec::new(),
+        mismatches: Vec::new(),
+    };
+    for (node_id, result) in listings {
+        match result {
+            Ok(tenants) => {
+                report.checked_nodes.push(node_id);
+                report
+                    .mismatches
+                    .extend(diff_attachments(&locked, node_id, &tenants));
+            }
+            Err(e) => report.unreachable_nodes.push((node_id, e)),
+        }
+    }
+    tracing::info!(
+        "consistency check: {} mismatches on {} pageservers, {} unreachable",
+        report.mismatches.len(),
+        report.checked_nodes.len(),
+        report.unreachable_nodes.len()
+    );
+
+    json_response(StatusCode::OK, report)
+}
+
+/// The most recent notifications of pageservers about generation changes, oldest first,
+/// optionally only those of `?tenant_id`.
+pub(crate) async fn handle_notifications(req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permissions(&req, Scope::Admin)?;
+
+    let tenant_id: Option<TenantId> = parse_query_param(&req, "tenant_id")?;
+
+    let notifications: Vec<NotificationRecord> = get_state(&req)
+        .notifications
+        .lock()
+        .unwrap()
+        .recent
+        .iter()
+        .filter(|r| tenant_id.map_or(true, |t| r.tenant_id == t))
+        .cloned()
+        .collect();
+
+    json_response(StatusCode::OK, notifications)
+}
+
+pub(crate) fn make_router(state: Arc<State>) -> RouterBuilder<hyper::Body, ApiError> {
+    endpoint::make_router()
+        .data(Arc::clone(&state))
+        .middleware(Middleware::pre(|req| async move {
+            req.set_context(RequestStart {
+                at: Instant::now(),
+                wall: SystemTime::now(),
+            });
+            Ok(req)
+        }))
+        // like the pageserver, serve metrics without auth, for the scrapers
+        .middleware(auth_middleware(|req| {
+            if req.uri().path() == "/metrics" {
+                None
+            } else {
+                get_state(req).auth.as_ref()
+            }
+        }))
+        // a follower only serves reads, from the state it reloads, see lease_loop
+        .middleware(Middleware::pre(|req| async move {
+            let state = get_state(&req);
+            if !is_read_only(&req) && !state.is_leader() {
+                let leader = state
+                    .lease
+                    .as_ref()
+                    .and_then(|l| l.leader.lock().unwrap().clone());
+                return Err(ApiError::ResourceUnavailable(
+                    match leader {
+                        Some(leader) => format!("Not the leader, send changes to {leader}"),
+                        None => "No leader, retry later".to_owned(),
+                    }
+                    .into(),
+                ));
+            }
+            Ok(req)
+        }))
+        // runs after the post middleware of endpoint::make_router that sets x-request-id
+        .middleware(Middleware::post_with_info(move |res, info| {
+            let state = Arc::clone(&state);
+            async move {
+                state.record_request(&res, &info);
+                Ok(res)
+            }
+        }))
+        .get("/debug/requests", |r| {
+            request_span(r, handle_debug_requests)
+        })
+        .post("/debug/consistency_check", |r| {
+            request_span(r, handle_consistency_check)
+        })
+        .get("/notifications", |r| request_span(r, handle_notifications))
+        .post("/re-attach", |r| request_span(r, handle_re_attach))
+        .post("/validate", |r| request_span(r, handle_validate))
+        .post("/attach-hook", |r| request_span(r, handle_attach_hook))
+        .post("/attach-hook/batch", |r| {
+            request_span(r, handle_attach_hook_batch)
+        })
+        .post("/inspect", |r| request_span(r, handle_inspect))
+        .post("/tenant/:tenant_id/locate", |r| {
+            request_span(r, handle_tenant_locate)
+        })
+        .get("/tenants", |r| request_span(r, handle_tenants_list))
+        .get("/tenant/:tenant_id", |r| {
+            request_span(r, handle_tenant_shard_inspect)
+        })
+        .delete("/tenant/:tenant_id", |r| {
+            request_span(r, handle_tenant_delete)
+        })
+        .post("/tenant/:tenant_id/timeline", |r| {
+            request_span(r, handle_timeline_create)
+        })
+        .get("/tenant/:tenant_id/timeline/:timeline_id", |r| {
+            request_span(r, handle_timeline_detail)
+        })
+        .delete("/tenant/:tenant_id/timeline/:timeline_id", |r| {
+            request_span(r, handle_timeline_delete)
+        })
+        .get("/tenant/:tenant_id/history", |r| {
+            request_span(r, handle_tenant_history)
+        })
+        .get("/tenant/:tenant_id/config", |r| {
+            request_span(r, handle_tenant_config_get)
+        })
+        .put("/tenant/:tenant_id/config", |r| {
+            request_span(r, handle_tenant_config_put)
+        })
+        .delete("/tenant/:tenant_id/config", |r| {
+            request_span(r, handle_tenant_config_delete)
+        })
+        .put("/tenant/:tenant_id/placement", |r| {
+            request_span(r, handle_tenant_placement)
+        })
+        .put("/tenant/:tenant_id/migrate", |r| {
+            request_span(r, handle_tenant_migrate)
+        })
+        .put("/tenant/:tenant_id/secondary/:node_id", |r| {
+            request_span(r, handle_tenant_secondary_create)
+        })
+        .delete("/tenant/:tenant_id/secondary/:node_id", |r| {
+            request_span(r, handle_tenant_secondary_remove)
+        })
+        .post("/tenant/:tenant_id/secondary/warmup", |r| {
+            request_span(r, handle_tenant_secondary_warmup)
+        })
+        .get("/failover", |r| request_span(r, handle_failover_get))
+        .put("/failover", |r| request_span(r, handle_failover_put))
+        .post("/node", |r| request_span(r, handle_node_register))
+        .get("/nodes", |r| request_span(r, handle_nodes_list))
+        .post("/node/:node_id/cordon", |r| {
+            request_span(r, handle_node_cordon)
+        })
+        .post("/node/:node_id/uncordon", |r| {
+            request_span(r, handle_node_uncordon)
+        })
+        .put("/node/:node_id/drain", |r| {
+            request_span(r, handle_node_drain)
+        })
+        .get("/node/:node_id/drain", |r| {
+            request_span(r, handle_node_drain_progress)
+        })
+        .put("/node/:node_id/weight", |r| {
+            request_span(r, handle_node_weight)
+        })
+        .get("/timeline/:tenant_id/:timeline_id/generation", |r| {
+            request_span(r, handle_timeline_generation_get)
+        })
+        .post("/timeline/:tenant_id/:timeline_id/generation", |r| {
+            request_span(r, handle_timeline_generation_issue)
+        })
+}
@@ -0,0 +1,1184 @@
+//! Where the state is kept: a JSON file, an SQLite or a Postgres database, and the
+//! migrations of their formats.
+
+use anyhow::{anyhow, Context};
+use camino::{Utf8Path, Utf8PathBuf};
+use pageserver_api::shard::TenantShardId;
+use rusqlite::OptionalExtension;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use utils::crashsafe::{fsync_async, path_with_suffix_extension};
+use utils::fs_ext::ignore_not_found;
+use utils::lock_file::{self, LockFileGuard};
+
+use utils::id::{NodeId, TenantId, TimelineId};
+
+use control_plane::attachment_service::TenantHistoryEntry;
+
+use crate::{import_attachments, PersistentState};
+
+/// The version of the state file format that this build writes. Bump it whenever
+/// [`PersistentState`] gains a field: older builds reject the fields they don't know, and this
+/// way they fail with a clear message. Add a migration to [`MIGRATIONS`] only if the files
+/// written by older builds would fail to load: most versions only added optional fields, which
+/// load as their defaults.
+///
+/// - 2: the nodes and timelines sections are required.
+/// - 3: the registrations of nodes.
+/// - 4: tenants are keyed by TenantShardId, which is encoded like the TenantId if unsharded.
+/// - 5: the secondary locations of tenant shards.
+/// - 6: the sequences of tenant shards.
+/// - 7: the availability zones of nodes and the placements of tenant shards.
+/// - 8: the sequence of the whole state.
+/// - 9: the config overrides of tenant shards.
+/// - 10: the epoch.
+/// - 11: the reachability of nodes.
+pub(crate) const SCHEMA_VERSION: u32 = 11;
+
+pub(crate) type Migration =
+    fn(&mut serde_json::Map<String, serde_json::Value>) -> anyhow::Result<()>;
+
+/// The migrations of the state files written by older builds, by the version that they
+/// migrate to, in ascending order.
+pub(crate) const MIGRATIONS: &[(u32, Migration)] = &[(2, migrate_v1_to_v2)];
+
+/// Version 1 is the unversioned format, in which the sections added after `tenants` were
+/// optional.
+pub(crate) fn migrate_v1_to_v2(
+    state: &mut serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<()> {
+    if !state.get("tenants").is_some_and(|t| t.is_object()) {
+        anyhow::bail!("missing tenants");
+    }
+    for section in ["nodes", "timelines"] {
+        state
+            .entry(section)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+    }
+    Ok(())
+}
+
+impl PersistentState {
+    /// Decodes a state file of any version up to [`SCHEMA_VERSION`], migrating it to the
+    /// current version.
+    pub(crate) fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let state: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(bytes).context("not a JSON object")?;
+        Self::decode_object(state)
+    }
+
+    /// Like [`Self::decode`], for a state that is already parsed, e.g. reassembled from the
+    /// rows of a [`Database`].
+    pub(crate) fn decode_object(
+        mut state: serde_json::Map<String, serde_json::Value>,
+    ) -> anyhow::Result<Self> {
+        let version = match state.get("version") {
+            None => 1,
+            Some(v) => v
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .filter(|v| *v >= 1)
+                .ok_or_else(|| anyhow!("invalid schema version {v}"))?,
+        };
+        if version > SCHEMA_VERSION {
+            anyhow::bail!(
+                "schema version {version} is newer than this build supports ({SCHEMA_VERSION}), \
+                 it was written by a newer build"
+            );
+        }
+        let mut from = version;
+        for (to, migration) in MIGRATIONS.iter().filter(|(to, _)| *to > version) {
+            migration(&mut state)
+                .with_context(|| format!("migrate from schema version {from} to {to}"))?;
+            from = *to;
+        }
+        state.insert("version".to_owned(), SCHEMA_VERSION.into());
+
+        let decoded = serde_json::from_value::<Self>(serde_json::Value::Object(state))
+            .with_context(|| format!("invalid state for schema version {SCHEMA_VERSION}"))?;
+        decoded.validate()?;
+        Ok(decoded)
+    }
+
+    /// Takes the lock that keeps two processes from using the same state file at once: the
+    /// server holds it while it runs, the `state` subcommands while they edit the file.
+    pub(crate) fn lock(path: &Utf8Path) -> anyhow::Result<LockFileGuard> {
+        let lock_path = path_with_suffix_extension(path, "lock");
+        lock_file::create_exclusive(&lock_path)
+            .with_context(|| {
+                format!("Failed to lock {lock_path}, is an attachment service using the state?")
+            })?
+            .write_content(std::process::id().to_string())
+    }
+}
+
+/// The parts of the [`PersistentState`] that a change touched, so that a [`Persistence`] can
+/// write only those. A part that is no longer in the state was removed.
+#[derive(Default)]
+pub(crate) struct Changes {
+    pub(crate) tenants: Vec<TenantShardId>,
+    pub(crate) nodes: Vec<NodeId>,
+    pub(crate) timelines: Vec<(TenantId, TimelineId)>,
+    // Appended to the tenants' histories, never part of `state`
+    pub(crate) history: Vec<TenantHistoryEntry>,
+}
+
+impl Changes {
+    pub(crate) fn tenants(tenants: impl IntoIterator<Item = TenantShardId>) -> Self {
+        Self {
+            tenants: tenants.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The change of a tenant shard's attachment by [`PersistentState::attach`], with the
+    /// `previous` pageserver and the `generation` that it returned, and its history entry.
+    pub(crate) fn attachment(
+        actor: &str,
+        tenant_shard_id: TenantShardId,
+        previous: Option<NodeId>,
+        node_id: Option<NodeId>,
+        generation: u32,
+    ) -> Self {
+        Self::tenants([tenant_shard_id]).with_history([history_entry(
+            actor,
+            tenant_shard_id,
+            previous,
+            node_id,
+            generation,
+        )])
+    }
+
+    pub(crate) fn with_history(
+        mut self,
+        history: impl IntoIterator<Item = TenantHistoryEntry>,
+    ) -> Self {
+        self.history.extend(history);
+        self
+    }
+
+    pub(crate) fn node(node_id: NodeId) -> Self {
+        Self {
+            nodes: vec![node_id],
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn timeline(tenant_id: TenantId, timeline_id: TimelineId) -> Self {
+        Self {
+            timelines: vec![(tenant_id, timeline_id)],
+            ..Default::default()
+        }
+    }
+
+    /// All parts of `state`, for writing it from scratch.
+    pub(crate) fn all(state: &PersistentState) -> Self {
+        Self {
+            tenants: state.tenants.keys().copied().collect(),
+            nodes: state.nodes.keys().copied().collect(),
+            timelines: state
+                .timelines
+                .iter()
+                .flat_map(|(tenant_id, timelines)| {
+                    timelines
+                        .keys()
+                        .map(move |timeline_id| (*tenant_id, *timeline_id))
+                })
+                .collect(),
+            history: Vec::new(),
+        }
+    }
+}
+
+/// The history entry of an attachment by [`PersistentState::attach`], see
+/// [`Changes::attachment`]: attaching increments the generation, detaching keeps it.
+pub(crate) fn history_entry(
+    actor: &str,
+    tenant_shard_id: TenantShardId,
+    previous: Option<NodeId>,
+    node_id: Option<NodeId>,
+    generation: u32,
+) -> TenantHistoryEntry {
+    TenantHistoryEntry {
+        at_millis_since_epoch: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        tenant_shard_id,
+        actor: actor.to_owned(),
+        old_node_id: previous,
+        new_node_id: node_id,
+        old_gen: match node_id {
+            Some(_) => generation.saturating_sub(1),
+            None => generation,
+        },
+        new_gen: generation,
+    }
+}
+
+/// Where the [`PersistentState`] is stored.
+#[async_trait::async_trait]
+pub(crate) trait Persistence: Send + Sync {
+    /// For log messages.
+    fn describe(&self) -> String;
+
+    /// For metrics.
+    fn backend(&self) -> &'static str;
+
+    /// The stored state, migrated to [`SCHEMA_VERSION`], or None if nothing was stored yet.
+    async fn load(&self) -> anyhow::Result<Option<PersistentState>>;
+
+    /// Replaces whatever is stored with `state`.
+    async fn save_all(&self, state: &PersistentState) -> anyhow::Result<()>;
+
+    /// The highest epoch that was stored, even if the state was replaced by other means since,
+    /// e.g. by copying an old state file into place. Zero if the backend doesn't keep it apart
+    /// from the state.
+    async fn last_epoch(&self) -> anyhow::Result<u32> {
+        Ok(0)
+    }
+
+    /// Stores the `changes` of `state`, and appends their history entries.
+    async fn save(&self, state: &PersistentState, changes: &Changes) -> anyhow::Result<()>;
+
+    /// The history entries of the tenant's shards, oldest first, including those of shards
+    /// that were deleted since.
+    async fn history(&self, tenant_id: TenantId) -> anyhow::Result<Vec<TenantHistoryEntry>>;
+
+    /// Takes or renews the leader lease for `holder` for `duration`, unless another holder's
+    /// lease is still valid, and returns the holder of the lease.
+    async fn acquire_lease(&self, _holder: &str, _duration: Duration) -> anyhow::Result<String> {
+        anyhow::bail!("{} doesn't support leader election", self.describe())
+    }
+}
+
+/// The state as a JSON file, rewritten on every change.
+///
+/// The file is replaced atomically, so that a crash leaves either the old or the new state, never
+/// a torn file that would lose all generations. The state that the last write replaced is kept in
+/// `<path>.prev`, e.g. to recover from a bad `state set`. The tenants' histories are appended to
+/// `<path>.history`, which is never rewritten. The highest epoch of the states written is kept
+/// in `<path>.epoch`, which is updated before the state, see [`Persistence::last_epoch`].
+pub(crate) struct JsonFile {
+    pub(crate) path: Utf8PathBuf,
+}
+
+#[async_trait::async_trait]
+impl Persistence for JsonFile {
+    fn describe(&self) -> String {
+        format!("file '{}'", self.path)
+    }
+
+    fn backend(&self) -> &'static str {
+        "json_file"
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<PersistentState>> {
+        let bytes = match tokio::fs::read(&self.path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        PersistentState::decode(&bytes).map(Some)
+    }
+
+    async fn save_all(&self, state: &PersistentState) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(state)?;
+
+        if state.epoch > self.last_epoch().await? {
+            let epoch_path = path_with_suffix_extension(&self.path, "epoch");
+            let tmp_path = path_with_suffix_extension(&epoch_path, "tmp");
+            write_synced(&tmp_path, state.epoch.to_string().as_bytes()).await?;
+            tokio::fs::rename(&tmp_path, &epoch_path)
+                .await
+                .with_context(|| format!("Failed to replace {epoch_path}"))?;
+        }
+
+        // A crash may leave the temporary file behind, it is overwritten by the next write
+        let tmp_path = path_with_suffix_extension(&self.path, "tmp");
+        write_synced(&tmp_path, &bytes).await?;
+
+        // Link the previous copy rather than moving it, so that the state file exists throughout
+        let prev_path = path_with_suffix_extension(&self.path, "prev");
+        tokio::fs::remove_file(&prev_path)
+            .await
+            .or_else(ignore_not_found)?;
+        tokio::fs::hard_link(&self.path, &prev_path)
+            .await
+            .or_else(ignore_not_found)
+            .with_context(|| format!("Failed to keep the previous state as {prev_path}"))?;
+
+        tokio::fs::rename(&tmp_path, &self.path)
+            .await
+            .with_context(|| format!("Failed to replace {}", self.path))?;
+        // The rename is only durable once the directory is synced
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_str().is_empty() => dir,
+            _ => Utf8Path::new("."),
+        };
+        fsync_async(dir).await?;
+
+        Ok(())
+    }
+
+    async fn last_epoch(&self) -> anyhow::Result<u32> {
+        let epoch_path = path_with_suffix_extension(&self.path, "epoch");
+        match tokio::fs::read_to_string(&epoch_path).await {
+            Ok(epoch) => epoch
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid epoch in {epoch_path}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("Failed to read {epoch_path}"))),
+        }
+    }
+
+    /// The file can't be written in parts, so this rewrites all of it. The history entries go
+    /// to `<path>.history` first, a line of JSON each: if the state write fails, the change is
+    /// still in memory, and the next write stores it.
+    async fn save(&self, state: &PersistentState, changes: &Changes) -> anyhow::Result<()> {
+        if !changes.history.is_empty() {
+            let mut lines = Vec::new();
+            for entry in &changes.history {
+                serde_json::to_writer(&mut lines, entry)?;
+                lines.push(b'\n');
+            }
+            let history_path = path_with_suffix_extension(&self.path, "history");
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&history_path)
+                .await
+                .with_context(|| format!("Failed to open {history_path}"))?;
+            file.write_all(&lines).await?;
+            file.sync_all().await?;
+        }
+        self.save_all(state).await
+    }
+
+    async fn history(&self, tenant_id: TenantId) -> anyhow::Result<Vec<TenantHistoryEntry>> {
+        let history_path = path_with_suffix_extension(&self.path, "history");
+        let bytes = match tokio::fs::read(&history_path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut history = Vec::new();
+        for line in bytes.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            match serde_json::from_slice::<TenantHistoryEntry>(line) {
+                Ok(entry) if entry.tenant_shard_id.tenant_id == tenant_id => history.push(entry),
+                Ok(_) => {}
+                // a crash during an append leaves a torn last line
+                Err(e) => tracing::warn!("Skipping a bad line of {history_path}: {e}"),
+            }
+        }
+        Ok(history)
+    }
+}
+
+async fn write_synced(path: &Utf8Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {path}"))?;
+    file.write_all(bytes).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// `DATABASE_MIGRATIONS[i]` migrates the tables of a [`Database`] from version `i` to `i + 1`.
+/// The rows hold the same JSON as the sections of the state file, so a change of their content
+/// is a migration of the state's schema, see [`MIGRATIONS`], not of the tables.
+pub(crate) const DATABASE_MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE state_version (version integer NOT NULL);
+    CREATE TABLE tenant_shards (tenant_shard_id text PRIMARY KEY, state text NOT NULL);
+    CREATE TABLE nodes (node_id bigint PRIMARY KEY, state text NOT NULL);
+    CREATE TABLE timelines (
+        tenant_id text NOT NULL,
+        timeline_id text NOT NULL,
+        state text NOT NULL,
+        PRIMARY KEY (tenant_id, timeline_id)
+    );
+",
+    "
+    CREATE TABLE leader_lease (
+        id integer PRIMARY KEY CHECK (id = 1),
+        holder text NOT NULL,
+        expires_at timestamptz NOT NULL
+    );
+",
+    "
+    CREATE TABLE tenant_history (
+        id bigserial PRIMARY KEY,
+        tenant_id text NOT NULL,
+        entry text NOT NULL
+    );
+    CREATE INDEX tenant_history_tenant_id ON tenant_history (tenant_id);
+",
+    "
+    ALTER TABLE state_version ADD COLUMN sequence bigint NOT NULL DEFAULT 0;
+",
+    "
+    ALTER TABLE state_version ADD COLUMN epoch integer NOT NULL DEFAULT 0;
+",
+    "
+    CREATE TABLE last_epoch (
+        id integer PRIMARY KEY CHECK (id = 1),
+        epoch integer NOT NULL
+    );
+    INSERT INTO last_epoch (id, epoch) SELECT 1, epoch FROM state_version;
+",
+];
+
+/// Reassembles the state file's JSON from the rows of a [`Database`] or [`Sqlite`], so that the
+/// state's migrations apply to it. `versions` are the version, sequence and epoch of the state.
+fn state_object(
+    versions: (i64, i64, i64),
+    tenants: Vec<(String, String)>,
+    nodes: Vec<(i64, String)>,
+    timelines: Vec<(String, String, String)>,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let parse = |json: &str| serde_json::from_str::<serde_json::Value>(json);
+    let (version, sequence, epoch) = versions;
+    let mut state = serde_json::Map::new();
+    state.insert("version".to_owned(), version.into());
+
+    let mut section = serde_json::Map::new();
+    for (tenant_shard_id, tenant) in tenants {
+        section.insert(tenant_shard_id, parse(&tenant)?);
+    }
+    state.insert("tenants".to_owned(), section.into());
+
+    let mut section = serde_json::Map::new();
+    for (node_id, node) in nodes {
+        section.insert(node_id.to_string(), parse(&node)?);
+    }
+    state.insert("nodes".to_owned(), section.into());
+
+    let mut section = serde_json::Map::new();
+    for (tenant_id, timeline_id, timeline) in timelines {
+        section
+            .entry(tenant_id)
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
+            .as_object_mut()
+            .expect("inserted as an object")
+            .insert(timeline_id, parse(&timeline)?);
+    }
+    state.insert("timelines".to_owned(), section.into());
+
+    state.insert("sequence".to_owned(), sequence.into());
+    state.insert("epoch".to_owned(), epoch.into());
+    Ok(state)
+}
+
+/// The state in a Postgres database, a row per tenant shard, node and timeline, so that a
+/// change only writes its rows. The highest epoch written is kept in `last_epoch`, which
+/// [`Persistence::save_all`] doesn't clear, see [`Persistence::last_epoch`].
+pub(crate) struct Database {
+    pub(crate) client: tokio::sync::Mutex<tokio_postgres::Client>,
+    // With leader election, the writes of this holder only go through while it holds the
+    // lease, see Cli::leader_lease_ms
+    pub(crate) lease_holder: Option<String>,
+}
+
+impl Database {
+    /// Connects to the database at `url`, and creates or migrates its tables. With a
+    /// `lease_holder`, the writes require it to hold the leader lease.
+    pub(crate) async fn connect(url: &str, lease_holder: Option<&str>) -> anyhow::Result<Self> {
+        let (mut client, connection) = tokio_postgres::connect(url, tokio_postgres::NoTls)
+            .await
+            .context("Failed to connect to the database")?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::error!("database connection error: {e}");
+            }
+        });
+
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS database_migrations (version integer PRIMARY KEY)",
+            )
+            .await?;
+        let applied: i32 = client
+            .query_one(
+                "SELECT coalesce(max(version), 0) FROM database_migrations",
+                &[],
+            )
+            .await?
+            .get(0);
+        if applied as usize > DATABASE_MIGRATIONS.len() {
+            anyhow::bail!(
+                "database is at version {applied}, newer than this build supports ({}), \
+                 it was migrated by a newer build",
+                DATABASE_MIGRATIONS.len()
+            );
+        }
+        for (i, migration) in DATABASE_MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(applied as usize)
+        {
+            let version = i as i32 + 1;
+            let tx = client.transaction().await?;
+            tx.batch_execute(migration)
+                .await
+                .with_context(|| format!("migrate the database to version {version}"))?;
+            tx.execute(
+                "INSERT INTO database_migrations (version) VALUES ($1)",
+                &[&version],
+            )
+            .await?;
+            tx.commit().await?;
+            tracing::info!("Migrated the database to version {version}");
+        }
+
+        Ok(Self {
+            client: tokio::sync::Mutex::new(client),
+            lease_holder: lease_holder.map(str::to_owned),
+        })
+    }
+
+    /// Fails unless we hold the lease, if we need to. Locks the lease until the end of `tx`, so
+    /// that another holder can't take it over before our writes are committed.
+    pub(crate) async fn check_lease(
+        &self,
+        tx: &tokio_postgres::Transaction<'_>,
+    ) -> anyhow::Result<()> {
+        let Some(holder) = &self.lease_holder else {
+            return Ok(());
+        };
+        let held = tx
+            .query_opt(
+                "SELECT 1 FROM leader_lease WHERE holder = $1 AND expires_at > now() FOR UPDATE",
+                &[holder],
+            )
+            .await?;
+        if held.is_none() {
+            anyhow::bail!("not writing the state, {holder} doesn't hold the leader lease");
+        }
+        Ok(())
+    }
+
+    /// Upserts the rows of the `changes` that are in `state`, deletes the others.
+    pub(crate) async fn write_rows(
+        tx: &tokio_postgres::Transaction<'_>,
+        state: &PersistentState,
+        changes: &Changes,
+    ) -> anyhow::Result<()> {
+        // cheap, and any change may come with a new sequence or epoch
+        tx.execute(
+            "UPDATE state_version SET sequence = $1, epoch = $2",
+            &[&(state.sequence as i64), &(state.epoch as i32)],
+        )
+        .await?;
+        tx.execute(
+            "INSERT INTO last_epoch (id, epoch) VALUES (1, $1) \
+             ON CONFLICT (id) DO UPDATE SET epoch = greatest(last_epoch.epoch, excluded.epoch)",
+            &[&(state.epoch as i32)],
+        )
+        .await?;
+        for tenant_shard_id in &changes.tenants {
+            let key = tenant_shard_id.to_string();
+            match state.tenants.get(tenant_shard_id) {
+                Some(tenant) => {
+                    tx.execute(
+                        "INSERT INTO tenant_shards (tenant_shard_id, state) VALUES ($1, $2) \
+                         ON CONFLICT (tenant_shard_id) DO UPDATE SET state = excluded.state",
+                        &[&key, &serde_json::to_string(tenant)?],
+                    )
+                    .await?
+                }
+                None => {
+                    tx.execute(
+                        "DELETE FROM tenant_shards WHERE tenant_shard_id = $1",
+                        &[&key],
+                    )
+                    .await?
+                }
+            };
+        }
+        for node_id in &changes.nodes {
+            let key = node_id.0 as i64;
+            match state.nodes.get(node_id) {
+                Some(node) => {
+                    tx.execute(
+                        "INSERT INTO nodes (node_id, state) VALUES ($1, $2) \
+                         ON CONFLICT (node_id) DO UPDATE SET state = excluded.state",
+                        &[&key, &serde_json::to_string(node)?],
+                    )
+                    .await?
+                }
+                None => {
+                    tx.execute("DELETE FROM nodes WHERE node_id = $1", &[&key])
+                        .await?
+                }
+            };
+        }
+        for (tenant_id, timeline_id) in &changes.timelines {
+            let key = (tenant_id.to_string(), timeline_id.to_string());
+            match state
+                .timelines
+                .get(tenant_id)
+                .and_then(|timelines| timelines.get(timeline_id))
+            {
+                Some(timeline) => {
+                    tx.execute(
+                        "INSERT INTO timelines (tenant_id, timeline_id, state) \
+                         VALUES ($1, $2, $3) \
+                         ON CONFLICT (tenant_id, timeline_id) DO UPDATE SET state = excluded.state",
+                        &[&key.0, &key.1, &serde_json::to_string(timeline)?],
+                    )
+                    .await?
+                }
+                None => {
+                    tx.execute(
+                        "DELETE FROM timelines WHERE tenant_id = $1 AND timeline_id = $2",
+                        &[&key.0, &key.1],
+                    )
+                    .await?
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistence for Database {
+    fn describe(&self) -> String {
+        "database".to_owned()
+    }
+
+    fn backend(&self) -> &'static str {
+        "database"
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<PersistentState>> {
+        let mut client = self.client.lock().await;
+        // a snapshot of all tables, without the writes of a leader that commits meanwhile
+        let tx = client
+            .build_transaction()
+            .isolation_level(tokio_postgres::IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .await?;
+        let Some(row) = tx
+            .query_opt("SELECT version, sequence, epoch FROM state_version", &[])
+            .await?
+        else {
+            return Ok(None);
+        };
+        let version: i32 = row.get(0);
+        let versions: (i64, i64, i64) = (version.into(), row.get(1), row.get::<_, i32>(2).into());
+        let tenants = tx
+            .query("SELECT tenant_shard_id, state FROM tenant_shards", &[])
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect::<Vec<_>>();
+        let nodes = tx
+            .query("SELECT node_id, state FROM nodes", &[])
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect::<Vec<_>>();
+        let timelines = tx
+            .query("SELECT tenant_id, timeline_id, state FROM timelines", &[])
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect::<Vec<_>>();
+        tx.commit().await?;
+        drop(client);
+
+        let state =
+            PersistentState::decode_object(state_object(versions, tenants, nodes, timelines)?)?;
+        if version < SCHEMA_VERSION as i32 && self.lease_holder.is_none() {
+            // Rewrite the rows that the migrations changed. With leader election, a follower
+            // can't, and the leader rewrites them on its next save_all.
+            self.save_all(&state).await?;
+        }
+        Ok(Some(state))
+    }
+
+    async fn save_all(&self, state: &PersistentState) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        self.check_lease(&tx).await?;
+        tx.batch_execute(
+            "DELETE FROM state_version; DELETE FROM tenant_shards; \
+             DELETE FROM nodes; DELETE FROM timelines",
+        )
+        .await?;
+        tx.execute(
+            "INSERT INTO state_version (version, sequence, epoch) VALUES ($1, $2, $3)",
+            &[
+                &(state.version as i32),
+                &(state.sequence as i64),
+                &(state.epoch as i32),
+            ],
+        )
+        .await?;
+        Self::write_rows(&tx, state, &Changes::all(state)).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn last_epoch(&self) -> anyhow::Result<u32> {
+        let client = self.client.lock().await;
+        let epoch: Option<i32> = client
+            .query_opt("SELECT epoch FROM last_epoch", &[])
+            .await?
+            .map(|row| row.get(0));
+        Ok(epoch.unwrap_or(0) as u32)
+    }
+
+    async fn save(&self, state: &PersistentState, changes: &Changes) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        self.check_lease(&tx).await?;
+        Self::write_rows(&tx, state, changes).await?;
+        for entry in &changes.history {
+            tx.execute(
+                "INSERT INTO tenant_history (tenant_id, entry) VALUES ($1, $2)",
+                &[
+                    &entry.tenant_shard_id.tenant_id.to_string(),
+                    &serde_json::to_string(entry)?,
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn history(&self, tenant_id: TenantId) -> anyhow::Result<Vec<TenantHistoryEntry>> {
+        let client = self.client.lock().await;
+        client
+            .query(
+                "SELECT entry FROM tenant_history WHERE tenant_id = $1 ORDER BY id",
+                &[&tenant_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(|row| Ok(serde_json::from_str(row.get(0))?))
+            .collect()
+    }
+
+    async fn acquire_lease(&self, holder: &str, duration: Duration) -> anyhow::Result<String> {
+        let client = self.client.lock().await;
+        client
+            .execute(
+                "INSERT INTO leader_lease (id, holder, expires_at) \
+                 VALUES (1, $1, now() + $2::bigint * interval '1 millisecond') \
+                 ON CONFLICT (id) DO UPDATE \
+                 SET holder = excluded.holder, expires_at = excluded.expires_at \
+                 WHERE leader_lease.holder = excluded.holder OR leader_lease.expires_at < now()",
+                &[&holder, &(duration.as_millis() as i64)],
+            )
+            .await?;
+        Ok(client
+            .query_one("SELECT holder FROM leader_lease", &[])
+            .await?
+            .get(0))
+    }
+}
+
+/// `SQLITE_MIGRATIONS[i]` migrates the tables of an [`Sqlite`] from version `i` to `i + 1`, like
+/// [`DATABASE_MIGRATIONS`] those of a [`Database`].
+pub(crate) const SQLITE_MIGRATIONS: &[&str] = &["
+    CREATE TABLE state_version (
+        version integer NOT NULL,
+        sequence integer NOT NULL,
+        epoch integer NOT NULL
+    );
+    CREATE TABLE tenant_shards (tenant_shard_id text PRIMARY KEY, state text NOT NULL);
+    CREATE TABLE nodes (node_id integer PRIMARY KEY, state text NOT NULL);
+    CREATE TABLE timelines (
+        tenant_id text NOT NULL,
+        timeline_id text NOT NULL,
+        state text NOT NULL,
+        PRIMARY KEY (tenant_id, timeline_id)
+    );
+    CREATE TABLE tenant_history (
+        id integer PRIMARY KEY AUTOINCREMENT,
+        tenant_id text NOT NULL,
+        entry text NOT NULL
+    );
+    CREATE INDEX tenant_history_tenant_id ON tenant_history (tenant_id);
+    CREATE TABLE last_epoch (
+        id integer PRIMARY KEY CHECK (id = 1),
+        epoch integer NOT NULL
+    );
+"];
+
+/// The state in an SQLite database file, with the tables of a [`Database`]: a change only writes
+/// its rows, without a Postgres to run. It is locked like a [`JsonFile`], there is no leader
+/// election.
+pub(crate) struct Sqlite {
+    pub(crate) path: Utf8PathBuf,
+    connection: Arc<std::sync::Mutex<rusqlite::Connection>>,
+}
+
+/// The rows of the [`Changes`] of a state, encoded before they are written in a blocking task:
+/// the state of a row that was removed is None.
+struct SqliteRows {
+    sequence: i64,
+    epoch: u32,
+    tenants: Vec<(String, Option<String>)>,
+    nodes: Vec<(i64, Option<String>)>,
+    timelines: Vec<(String, String, Option<String>)>,
+    history: Vec<(String, String)>,
+}
+
+impl SqliteRows {
+    fn new(state: &PersistentState, changes: &Changes) -> anyhow::Result<Self> {
+        fn encode<T: serde::Serialize>(part: Option<&T>) -> serde_json::Result<Option<String>> {
+            part.map(serde_json::to_string).transpose()
+        }
+        Ok(Self {
+            sequence: state.sequence as i64,
+            epoch: state.epoch,
+            tenants: changes
+                .tenants
+                .iter()
+                .map(|id| Ok((id.to_string(), encode(state.tenants.get(id))?)))
+                .collect::<anyhow::Result<_>>()?,
+            nodes: changes
+                .nodes
+                .iter()
+                .map(|id| Ok((id.0 as i64, encode(state.nodes.get(id))?)))
+                .collect::<anyhow::Result<_>>()?,
+            timelines: changes
+                .timelines
+                .iter()
+                .map(|(tenant_id, timeline_id)| {
+                    let timeline = state
+                        .timelines
+                        .get(tenant_id)
+                        .and_then(|timelines| timelines.get(timeline_id));
+                    Ok((
+                        tenant_id.to_string(),
+                        timeline_id.to_string(),
+                        encode(timeline)?,
+                    ))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            history: changes
+                .history
+                .iter()
+                .map(|entry| {
+                    Ok((
+                        entry.tenant_shard_id.tenant_id.to_string(),
+                        serde_json::to_string(entry)?,
+                    ))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// Upserts the rows, deletes those that were removed, like [`Database::write_rows`], and
+    /// appends the history entries.
+    fn write(&self, tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        tx.execute(
+            "UPDATE state_version SET sequence = ?1, epoch = ?2",
+            rusqlite::params![self.sequence, self.epoch],
+        )?;
+        tx.execute(
+            "INSERT INTO last_epoch (id, epoch) VALUES (1, ?1) \
+             ON CONFLICT (id) DO UPDATE SET epoch = max(last_epoch.epoch, excluded.epoch)",
+            [self.epoch],
+        )?;
+        for (key, tenant) in &self.tenants {
+            match tenant {
+                Some(tenant) => tx.execute(
+                    "INSERT INTO tenant_shards (tenant_shard_id, state) VALUES (?1, ?2) \
+                     ON CONFLICT (tenant_shard_id) DO UPDATE SET state = excluded.state",
+                    [key, tenant],
+                )?,
+                None => tx.execute(
+                    "DELETE FROM tenant_shards WHERE tenant_shard_id = ?1",
+                    [key],
+                )?,
+            };
+        }
+        for (key, node) in &self.nodes {
+            match node {
+                Some(node) => tx.execute(
+                    "INSERT INTO nodes (node_id, state) VALUES (?1, ?2) \
+                     ON CONFLICT (node_id) DO UPDATE SET state = excluded.state",
+                    rusqlite::params![key, node],
+                )?,
+                None => tx.execute("DELETE FROM nodes WHERE node_id = ?1", [key])?,
+            };
+        }
+        for (tenant_id, timeline_id, timeline) in &self.timelines {
+            match timeline {
+                Some(timeline) => tx.execute(
+                    "INSERT INTO timelines (tenant_id, timeline_id, state) VALUES (?1, ?2, ?3) \
+                     ON CONFLICT (tenant_id, timeline_id) DO UPDATE SET state = excluded.state",
+                    [tenant_id, timeline_id, timeline],
+                )?,
+                None => tx.execute(
+                    "DELETE FROM timelines WHERE tenant_id = ?1 AND timeline_id = ?2",
+                    [tenant_id, timeline_id],
+                )?,
+            };
+        }
+        for (tenant_id, entry) in &self.history {
+            tx.execute(
+                "INSERT INTO tenant_history (tenant_id, entry) VALUES (?1, ?2)",
+                [tenant_id, entry],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Sqlite {
+    /// Opens or creates the database at `path`, and creates or migrates its tables. The caller
+    /// holds the lock of the path, see [`PersistentState::lock`].
+    pub(crate) async fn open(path: &Utf8Path) -> anyhow::Result<Self> {
+        let path = path.to_owned();
+        let connection = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || Self::migrate(&path)
+        })
+        .await??;
+        Ok(Self {
+            path,
+            connection: Arc::new(std::sync::Mutex::new(connection)),
+        })
+    }
+
+    /// Opens the database, and applies the [`SQLITE_MIGRATIONS`] that it lacks.
+    fn migrate(path: &Utf8Path) -> anyhow::Result<rusqlite::Connection> {
+        // The default rollback journal and synchronous = FULL make the commits durable
+        let mut connection = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open the database {path}"))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS database_migrations (version integer PRIMARY KEY)",
+        )?;
+        let applied: usize = connection.query_row(
+            "SELECT coalesce(max(version), 0) FROM database_migrations",
+            [],
+            |row| row.get(0),
+        )?;
+        if applied > SQLITE_MIGRATIONS.len() {
+            anyhow::bail!(
+                "database {path} is at version {applied}, newer than this build supports ({}), \
+                 it was migrated by a newer build",
+                SQLITE_MIGRATIONS.len()
+            );
+        }
+        for (i, migration) in SQLITE_MIGRATIONS.iter().enumerate().skip(applied) {
+            let version = i + 1;
+            let tx = connection.transaction()?;
+            tx.execute_batch(migration)
+                .with_context(|| format!("migrate the database {path} to version {version}"))?;
+            tx.execute(
+                "INSERT INTO database_migrations (version) VALUES (?1)",
+                [version],
+            )?;
+            tx.commit()?;
+            tracing::info!("Migrated the database {path} to version {version}");
+        }
+        Ok(connection)
+    }
+
+    /// Runs `f` on the connection in a blocking task, SQLite does its file I/O in the calls.
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut rusqlite::Connection) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap())).await?
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistence for Sqlite {
+    fn describe(&self) -> String {
+        format!("database '{}'", self.path)
+    }
+
+    fn backend(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<PersistentState>> {
+        let loaded = self
+            .with_connection(|connection| {
+                // a snapshot of all tables
+                let tx = connection.transaction()?;
+                let Some(versions) = tx
+                    .query_row(
+                        "SELECT version, sequence, epoch FROM state_version",
+                        [],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+                let tenants = tx
+                    .prepare("SELECT tenant_shard_id, state FROM tenant_shards")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                let nodes = tx
+                    .prepare("SELECT node_id, state FROM nodes")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                let timelines = tx
+                    .prepare("SELECT tenant_id, timeline_id, state FROM timelines")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                let state = state_object(versions, tenants, nodes, timelines)?;
+                Ok(Some((versions.0, state)))
+            })
+            .await?;
+        let Some((version, state)) = loaded else {
+            return Ok(None);
+        };
+        let state = PersistentState::decode_object(state)?;
+        if version < SCHEMA_VERSION as i64 {
+            // Rewrite the rows that the migrations changed
+            self.save_all(&state).await?;
+        }
+        Ok(Some(state))
+    }
+
+    async fn save_all(&self, state: &PersistentState) -> anyhow::Result<()> {
+        let version = state.version;
+        let rows = SqliteRows::new(state, &Changes::all(state))?;
+        self.with_connection(move |connection| {
+            let tx = connection.transaction()?;
+            tx.execute_batch(
+                "DELETE FROM state_version; DELETE FROM tenant_shards; \
+                 DELETE FROM nodes; DELETE FROM timelines",
+            )?;
+            tx.execute(
+                "INSERT INTO state_version (version, sequence, epoch) VALUES (?1, ?2, ?3)",
+                rusqlite::params![version, rows.sequence, rows.epoch],
+            )?;
+            rows.write(&tx)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn last_epoch(&self) -> anyhow::Result<u32> {
+        self.with_connection(|connection| {
+            let epoch = connection
+                .query_row("SELECT epoch FROM last_epoch", [], |row| row.get(0))
+                .optional()?;
+            Ok(epoch.unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn save(&self, state: &PersistentState, changes: &Changes) -> anyhow::Result<()> {
+        let rows = SqliteRows::new(state, changes)?;
+        self.with_connection(move |connection| {
+            let tx = connection.transaction()?;
+            rows.write(&tx)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn history(&self, tenant_id: TenantId) -> anyhow::Result<Vec<TenantHistoryEntry>> {
+        self.with_connection(move |connection| {
+            connection
+                .prepare("SELECT entry FROM tenant_history WHERE tenant_id = ?1 ORDER BY id")?
+                .query_map([tenant_id.to_string()], |row| row.get::<_, String>(0))?
+                .map(|entry| Ok(serde_json::from_str(&entry?)?))
+                .collect()
+        })
+        .await
+    }
+}
+
+/// The `--path` file, an SQLite database with `sqlite`, or the `--database-url` database, and
+/// for a path, the lock that keeps other processes from using it until the guard is dropped.
+/// With a `lease_holder`, the database only takes its writes while it holds the leader lease.
+pub(crate) async fn open_persistence(
+    path: Option<&Utf8Path>,
+    sqlite: bool,
+    database_url: Option<&str>,
+    lease_holder: Option<&str>,
+) -> anyhow::Result<(Box<dyn Persistence>, Option<LockFileGuard>)> {
+    match (path, database_url) {
+        (_, Some(url)) => Ok((Box::new(Database::connect(url, lease_holder).await?), None)),
+        (Some(path), None) => {
+            let lock = PersistentState::lock(path)?;
+            let persistence: Box<dyn Persistence> = if sqlite {
+                Box::new(Sqlite::open(path).await?)
+            } else {
+                Box::new(JsonFile {
+                    path: path.to_owned(),
+                })
+            };
+            Ok((persistence, Some(lock)))
+        }
+        (None, None) => unreachable!("clap requires --path or --database-url"),
+    }
+}
+
+/// Loads the stored state, or stores a new one if there is none yet: with the attachments of
+/// the `import_from` pageservers, asked with `pageserver_jwt`, or empty. Refuses a state of an
+/// epoch older than the [`Persistence::last_epoch`], which may hand out generations again.
+pub(crate) async fn load_or_new(
+    persistence: &dyn Persistence,
+    import_from: &[(NodeId, String)],
+    pageserver_jwt: Option<&str>,
+) -> anyhow::Result<PersistentState> {
+    let last_epoch = persistence.last_epoch().await?;
+    match persistence.load().await {
+        Ok(Some(s)) => {
+            if s.epoch < last_epoch {
+                anyhow::bail!(
+                    "The state in {} is of epoch {}, but epoch {last_epoch} was stored before: \
+                     it may be an old copy. Start a new epoch with `state bump-epoch`.",
+                    persistence.describe(),
+                    s.epoch
+                );
+            }
+            tracing::info!("Loaded state from {}", persistence.describe());
+            if !import_from.is_empty() {
+                tracing::info!("Not importing attachments from pageservers, the state exists");
+            }
+            Ok(s)
+        }
+        Ok(None) => {
+            tracing::info!("Creating state in {}", persistence.describe());
+            let mut state = PersistentState::new();
+            if last_epoch > 0 {
+                // The state was removed, not its generations from the pageservers
+                state.bump_epoch(last_epoch)?;
+            }
+            import_attachments(&mut state, import_from, pageserver_jwt).await?;
+            persistence.save_all(&state).await?;
+            Ok(state)
+        }
+        Err(e) => Err(e.context(format!(
+            "Failed to load state from {} (remove it to start from an empty state, \
+             or run the build that wrote it)",
+            persistence.describe()
+        ))),
+    }
+}
+
+/// Loads the stored state, which must exist.
+pub(crate) async fn load_existing(
+    persistence: &dyn Persistence,
+) -> anyhow::Result<PersistentState> {
+    persistence
+        .load()
+        .await
+        .with_context(|| format!("Failed to load state from {}", persistence.describe()))?
+        .ok_or_else(|| anyhow!("No state in {}", persistence.describe()))
+}
@@ -0,0 +1,239 @@
+//! Which pageserver each shard goes to: when it is attached, under its placement
+//! constraints, and when its pageserver is offline.
+
+use pageserver_api::shard::TenantShardId;
+use std::time::Duration;
+use std::{collections::HashMap, sync::Arc};
+
+use utils::id::{NodeId, TenantId};
+
+use control_plane::attachment_service::TenantPlacement;
+
+use crate::persistence::{history_entry, Changes};
+use crate::{NodeState, PersistentState, State};
+
+impl PersistentState {
+    /// The pageserver for a new attachment of the tenant shard: of the registered nodes that
+    /// are active and not cordoned, and outside the AZs of its placement's anti-affinity, the
+    /// one with the fewest attached shards relative to its weight, and of those the lowest node
+    /// id. The nodes in the AZ of its placement's affinity go first. The pageservers don't
+    /// report their disk usage to us, so the shard count stands in for it.
+    pub(crate) fn schedule(&self, tenant_shard_id: TenantShardId) -> Option<NodeId> {
+        let mut attached: HashMap<NodeId, u64> = HashMap::new();
+        for tenant in self.tenants.values() {
+            if let Some(node_id) = tenant.pageserver {
+                *attached.entry(node_id).or_default() += 1;
+            }
+        }
+        let placement = self.placement(tenant_shard_id);
+        let outside_affinity = |node_id: &NodeId| {
+            placement.az_affinity.is_some()
+                && self.node_az(*node_id) != placement.az_affinity.as_deref()
+        };
+
+        self.schedulable_nodes()
+            .filter(|(node_id, _)| !self.in_anti_affinity(placement, **node_id))
+            .min_by(|(a_id, a), (b_id, b)| {
+                // compare attached/weight without rounding
+                let a_attached = attached.get(*a_id).copied().unwrap_or_default();
+                let b_attached = attached.get(*b_id).copied().unwrap_or_default();
+                outside_affinity(a_id)
+                    .cmp(&outside_affinity(b_id))
+                    .then((a_attached * b.weight as u64).cmp(&(b_attached * a.weight as u64)))
+                    .then(a_id.cmp(b_id))
+            })
+            .map(|(node_id, _)| *node_id)
+    }
+
+    /// Attaches the tenant shards of `node_id` to the nodes that [`Self::schedule`] picks, in
+    /// new generations. Returns the moved shards with their new nodes and generations, and the
+    /// shards for which there is no node.
+    pub(crate) fn fail_over(
+        &mut self,
+        node_id: NodeId,
+    ) -> (Vec<(TenantShardId, NodeId, u32)>, Vec<TenantShardId>) {
+        let mut shards: Vec<TenantShardId> = self
+            .tenants
+            .iter()
+            .filter(|(_, t)| t.pageserver == Some(node_id))
+            .map(|(id, _)| *id)
+            .collect();
+        shards.sort();
+
+        let mut moved = Vec::new();
+        let mut stuck = Vec::new();
+        for tenant_shard_id in shards {
+            match self.schedule(tenant_shard_id) {
+                Some(dest) => {
+                    let (_, generation) = self.attach(tenant_shard_id, Some(dest));
+                    moved.push((tenant_shard_id, dest, generation));
+                }
+                None => stuck.push(tenant_shard_id),
+            }
+        }
+        (moved, stuck)
+    }
+
+    /// Why [`Self::schedule`] wouldn't pick `node_id` for the tenant shard by its placement, if
+    /// it wouldn't: the node is in an AZ of the anti-affinity, or outside the AZ of the
+    /// affinity while a node in there is schedulable.
+    pub(crate) fn placement_violation(
+        &self,
+        tenant_shard_id: TenantShardId,
+        node_id: NodeId,
+    ) -> Option<String> {
+        let placement = self.placement(tenant_shard_id);
+        let az = self.node_az(node_id);
+        if self.in_anti_affinity(placement, node_id) {
+            return Some(format!(
+                "node {node_id} is in AZ {}, which the tenant avoids",
+                az.unwrap_or_default()
+            ));
+        }
+        let affinity = placement.az_affinity.as_deref()?;
+        if az != Some(affinity)
+            && self
+                .schedulable_nodes()
+                .any(|(other, _)| self.node_az(*other) == Some(affinity))
+        {
+            return Some(format!(
+                "the tenant prefers AZ {affinity}, which has schedulable nodes, \
+                 and node {node_id} is in {}",
+                az.unwrap_or("no AZ")
+            ));
+        }
+        None
+    }
+
+    /// The placement of the tenant shard, the default one for an unknown tenant shard.
+    pub(crate) fn placement(&self, tenant_shard_id: TenantShardId) -> &TenantPlacement {
+        static DEFAULT: TenantPlacement = TenantPlacement {
+            az_affinity: None,
+            az_anti_affinity: Vec::new(),
+        };
+        self.tenants
+            .get(&tenant_shard_id)
+            .map_or(&DEFAULT, |t| &t.placement)
+    }
+
+    /// The AZ of the node, from its registration.
+    pub(crate) fn node_az(&self, node_id: NodeId) -> Option<&str> {
+        self.nodes
+            .get(&node_id)?
+            .registration
+            .as_ref()?
+            .availability_zone
+            .as_deref()
+    }
+
+    pub(crate) fn in_anti_affinity(&self, placement: &TenantPlacement, node_id: NodeId) -> bool {
+        self.node_az(node_id)
+            .is_some_and(|az| placement.az_anti_affinity.iter().any(|a| a == az))
+    }
+
+    /// The registered nodes that are active, see [`crate::NodeRegistration::is_active`], and
+    /// not cordoned.
+    pub(crate) fn schedulable_nodes(&self) -> impl Iterator<Item = (&NodeId, &NodeState)> {
+        self.nodes.iter().filter(|(_, node)| {
+            !node.cordoned && node.registration.as_ref().is_some_and(|r| r.is_active())
+        })
+    }
+}
+
+/// The automatic failover of the shards of offline nodes, see [`State::fail_over`].
+pub(crate) struct Failover {
+    pub(crate) grace: Duration,
+    // Turned off and on by PUT /failover
+    pub(crate) enabled: std::sync::atomic::AtomicBool,
+}
+
+impl State {
+    /// Attaches the tenant shards of the nodes that have been offline for longer than the
+    /// failover's grace period to other nodes, see `--failover-grace-ms`. The new nodes are
+    /// told right away, the offline ones find out when they re-attach.
+    pub(crate) async fn fail_over(self: &Arc<Self>) {
+        let Some(failover) = &self.failover else {
+            return;
+        };
+        if !failover.enabled.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        let failed: Vec<NodeId> = self
+            .liveness
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, l)| {
+                l.offline_since
+                    .is_some_and(|t| t.elapsed() >= failover.grace)
+            })
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        if failed.is_empty() {
+            return;
+        }
+
+        let mut locked = self.inner.write().await;
+        // moved on a copy, which replaces the state once it's saved
+        let mut updated = locked.clone();
+        let mut moved = Vec::new();
+        for node_id in failed {
+            let unreachable = updated
+                .nodes
+                .get(&node_id)
+                .and_then(|n| n.registration.as_ref())
+                .is_some_and(|r| r.unreachable);
+            if !unreachable {
+                continue;
+            }
+            let (node_moved, stuck) = updated.fail_over(node_id);
+            if !node_moved.is_empty() || !stuck.is_empty() {
+                tracing::warn!(
+                    %node_id,
+                    "failover: node offline for over {:?}, moved {} shards, {} have nowhere to go",
+                    failover.grace,
+                    node_moved.len(),
+                    stuck.len()
+                );
+            }
+            moved.extend(
+                node_moved
+                    .into_iter()
+                    .map(|(id, dest, gen)| (id, node_id, dest, gen)),
+            );
+        }
+        if moved.is_empty() {
+            return;
+        }
+
+        let changes = Changes::tenants(moved.iter().map(|(id, ..)| *id)).with_history(
+            moved.iter().map(|(id, previous, dest, generation)| {
+                history_entry("failover", *id, Some(*previous), Some(*dest), *generation)
+            }),
+        );
+        if let Err(e) = self.save(&updated, changes).await {
+            // the shards stay where they are, the next heartbeat round tries again
+            tracing::warn!("failover: failed to save the state: {e}");
+            return;
+        }
+        *locked = updated;
+        let mut tenants: Vec<TenantId> = moved.iter().map(|(id, ..)| id.tenant_id).collect();
+        tenants.sort();
+        tenants.dedup();
+        for tenant_id in tenants {
+            self.notify_compute(&locked, tenant_id);
+        }
+        let urls: Vec<_> = moved
+            .into_iter()
+            .map(|(id, _, dest, gen)| (id, dest, self.node_mgmt_api_url(&locked, dest), gen))
+            .collect();
+        drop(locked);
+
+        for (tenant_shard_id, dest, url, generation) in urls {
+            match url {
+                Some(url) => self.notify_at(tenant_shard_id, dest, url, Some(generation)),
+                None => tracing::warn!(%tenant_shard_id, %dest, "failover: no mgmt API address"),
+            }
+        }
+    }
+}
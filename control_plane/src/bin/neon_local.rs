@@ -8,14 +8,15 @@
 use anyhow::{anyhow, bail, Context, Result};
 use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use compute_api::spec::ComputeMode;
-use control_plane::attachment_service::AttachmentService;
+use control_plane::attachment_service::{AttachmentService, TenantMigrateRequest};
 use control_plane::endpoint::ComputeControlPlane;
 use control_plane::local_env::LocalEnv;
 use control_plane::pageserver::{PageServerNode, PAGESERVER_REMOTE_STORAGE_DIR};
 use control_plane::safekeeper::SafekeeperNode;
 use control_plane::tenant_migration::migrate_tenant;
 use control_plane::{broker, local_env};
-use pageserver_api::models::TimelineInfo;
+use pageserver_api::models::{TimelineCreateRequest, TimelineInfo};
+use pageserver_api::shard::TenantShardId;
 use pageserver_api::{
     DEFAULT_HTTP_LISTEN_PORT as DEFAULT_PAGESERVER_HTTP_PORT,
     DEFAULT_PG_LISTEN_PORT as DEFAULT_PAGESERVER_PG_PORT,
@@ -272,18 +273,59 @@ fn print_timeline(
     Ok(())
 }
 
-/// Returns a map of timeline IDs to timeline_id@lsn strings.
-/// Connects to the pageserver to query this information.
+/// Returns a map of timeline IDs to timeline infos, of at least the `timeline_ids`.
+/// Connects to the pageserver that the tenant is attached to, through the attachment
+/// service if there is one, to query this information.
 async fn get_timeline_infos(
     env: &local_env::LocalEnv,
     tenant_id: &TenantId,
+    timeline_ids: impl IntoIterator<Item = TimelineId>,
 ) -> Result<HashMap<TimelineId, TimelineInfo>> {
-    Ok(get_default_pageserver(env)
-        .timeline_list(tenant_id)
-        .await?
-        .into_iter()
-        .map(|timeline_info| (timeline_info.timeline_id, timeline_info))
-        .collect())
+    if env.control_plane_api.is_none() {
+        return Ok(get_default_pageserver(env)
+            .timeline_list(tenant_id)
+            .await?
+            .into_iter()
+            .map(|timeline_info| (timeline_info.timeline_id, timeline_info))
+            .collect());
+    }
+
+    let attachment_service = AttachmentService::from_env(env);
+    let mut timeline_infos = HashMap::new();
+    for timeline_id in timeline_ids {
+        if timeline_infos.contains_key(&timeline_id) {
+            continue;
+        }
+        let timeline_info = attachment_service
+            .timeline_info(TenantShardId::unsharded(*tenant_id), timeline_id)
+            .await?;
+        timeline_infos.insert(timeline_id, timeline_info);
+    }
+    Ok(timeline_infos)
+}
+
+/// Creates a timeline on the pageserver that the tenant is attached to: through the attachment
+/// service if there is one, which knows where that is, or else on the default pageserver.
+async fn timeline_create(
+    env: &local_env::LocalEnv,
+    tenant_id: TenantId,
+    request: &TimelineCreateRequest,
+) -> Result<TimelineInfo> {
+    if env.control_plane_api.is_some() {
+        return AttachmentService::from_env(env)
+            .timeline_create(tenant_id, request)
+            .await;
+    }
+    get_default_pageserver(env)
+        .timeline_create(
+            tenant_id,
+            Some(request.new_timeline_id),
+            request.ancestor_start_lsn,
+            request.ancestor_timeline_id,
+            request.pg_version,
+            request.existing_initdb_timeline_id,
+        )
+        .await
 }
 
 // Helper function to parse --tenant_id option, or get the default from config file
@@ -422,16 +464,14 @@ async fn handle_tenant(
                 .copied()
                 .context("Failed to parse postgres version from the argument string")?;
 
-            let timeline_info = pageserver
-                .timeline_create(
-                    tenant_id,
-                    new_timeline_id,
-                    None,
-                    None,
-                    Some(pg_version),
-                    None,
-                )
-                .await?;
+            let request = TimelineCreateRequest {
+                new_timeline_id: new_timeline_id.unwrap_or_else(TimelineId::generate),
+                ancestor_timeline_id: None,
+                existing_initdb_timeline_id: None,
+                ancestor_start_lsn: None,
+                pg_version: Some(pg_version),
+            };
+            let timeline_info = timeline_create(env, tenant_id, &request).await?;
             let new_timeline_id = timeline_info.timeline_id;
             let last_record_lsn = timeline_info.last_record_lsn;
 
@@ -506,16 +546,14 @@ async fn handle_timeline(timeline_match: &ArgMatches, env: &mut local_env::Local
 
             let new_timeline_id_opt = parse_timeline_id(create_match)?;
 
-            let timeline_info = pageserver
-                .timeline_create(
-                    tenant_id,
-                    new_timeline_id_opt,
-                    None,
-                    None,
-                    Some(pg_version),
-                    None,
-                )
-                .await?;
+            let request = TimelineCreateRequest {
+                new_timeline_id: new_timeline_id_opt.unwrap_or_else(TimelineId::generate),
+                ancestor_timeline_id: None,
+                existing_initdb_timeline_id: None,
+                ancestor_start_lsn: None,
+                pg_version: Some(pg_version),
+            };
+            let timeline_info = timeline_create(env, tenant_id, &request).await?;
             let new_timeline_id = timeline_info.timeline_id;
 
             let last_record_lsn = timeline_info.last_record_lsn;
@@ -598,16 +636,14 @@ async fn handle_timeline(timeline_match: &ArgMatches, env: &mut local_env::Local
                 .map(|lsn_str| Lsn::from_str(lsn_str))
                 .transpose()
                 .context("Failed to parse ancestor start Lsn from the request")?;
-            let timeline_info = pageserver
-                .timeline_create(
-                    tenant_id,
-                    None,
-                    start_lsn,
-                    Some(ancestor_timeline_id),
-                    None,
-                    None,
-                )
-                .await?;
+            let request = TimelineCreateRequest {
+                new_timeline_id: TimelineId::generate(),
+                ancestor_timeline_id: Some(ancestor_timeline_id),
+                existing_initdb_timeline_id: None,
+                ancestor_start_lsn: start_lsn,
+                pg_version: None,
+            };
+            let timeline_info = timeline_create(env, tenant_id, &request).await?;
             let new_timeline_id = timeline_info.timeline_id;
 
             let last_record_lsn = timeline_info.last_record_lsn;
@@ -636,7 +672,12 @@ async fn handle_endpoint(ep_match: &ArgMatches, env: &local_env::LocalEnv) -> Re
     match sub_name {
         "list" => {
             let tenant_id = get_tenant_id(sub_args, env)?;
-            let timeline_infos = get_timeline_infos(env, &tenant_id)
+            let timeline_ids = cplane
+                .endpoints
+                .values()
+                .filter(|endpoint| endpoint.tenant_id == tenant_id)
+                .map(|endpoint| endpoint.timeline_id);
+            let timeline_infos = get_timeline_infos(env, &tenant_id, timeline_ids)
                 .await
                 .unwrap_or_else(|e| {
                     eprintln!("Failed to load timeline info: {}", e);
@@ -999,12 +1040,168 @@ async fn handle_attachment_service(
                 exit(1);
             }
         }
+
+        Some(("inspect", inspect_match)) => {
+            let tenant_shard_id = TenantShardId::unsharded(get_tenant_id(inspect_match, env)?);
+            let response = svc.tenant_inspect(tenant_shard_id).await?;
+            print_output(inspect_match, &response, |response| {
+                let mut table = output_table(["TENANT SHARD", "NODE", "GENERATION", "SEQUENCE"]);
+                let (gen, node) = match response.attachment {
+                    Some((gen, node_id)) => (gen.to_string(), node_id.to_string()),
+                    None => ("-".to_owned(), "-".to_owned()),
+                };
+                table.add_row([
+                    tenant_shard_id.to_string(),
+                    node,
+                    gen,
+                    response.sequence.to_string(),
+                ]);
+                table.to_string()
+            })?;
+        }
+
+        Some(("list", list_match)) => {
+            let tenants = svc.tenant_list().await?;
+            print_output(list_match, &tenants, |tenants| {
+                let mut table = output_table([
+                    "TENANT SHARD",
+                    "NODE",
+                    "GENERATION",
+                    "SECONDARIES",
+                    "SEQUENCE",
+                ]);
+                for shard in tenants.iter().flat_map(|t| &t.shards) {
+                    table.add_row([
+                        shard.tenant_shard_id.to_string(),
+                        shard
+                            .node_id
+                            .map_or_else(|| "-".to_owned(), |n| n.to_string()),
+                        shard.gen.to_string(),
+                        shard
+                            .secondaries
+                            .iter()
+                            .map(|n| n.to_string())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                        shard.sequence.to_string(),
+                    ]);
+                }
+                table.to_string()
+            })?;
+        }
+
+        Some(("migrate", migrate_match)) => {
+            let tenant_shard_id = TenantShardId::unsharded(get_tenant_id(migrate_match, env)?);
+            let node_id = NodeId(*migrate_match.get_one::<u64>("node-id").unwrap());
+            let request = TenantMigrateRequest {
+                node_id,
+                detach_origin: migrate_match.get_flag("detach-origin"),
+            };
+            let response = svc.tenant_migrate(tenant_shard_id, &request).await?;
+            print_output(migrate_match, &response, |response| {
+                let origin = response
+                    .origin
+                    .map_or_else(|| "nowhere".to_owned(), |n| format!("node {n}"));
+                format!(
+                    "tenant shard {tenant_shard_id} migrated from {origin} to node {node_id} in generation {}",
+                    response.gen
+                )
+            })?;
+        }
+
+        Some(("drain", drain_match)) => {
+            let node_id = NodeId(*drain_match.get_one::<u64>("node-id").unwrap());
+            let progress = if drain_match.get_flag("progress") {
+                svc.node_drain_progress(node_id).await?
+            } else {
+                svc.node_drain(node_id).await?
+            };
+            print_output(drain_match, &progress, |progress| {
+                let mut table = output_table(["NODE", "STATUS", "TOTAL", "MIGRATED", "FAILED"]);
+                table.add_row([
+                    node_id.to_string(),
+                    format!("{:?}", progress.status),
+                    progress.total.to_string(),
+                    progress.migrated.to_string(),
+                    progress.failed.len().to_string(),
+                ]);
+                let mut output = table.to_string();
+                for failure in &progress.failed {
+                    output.push_str(&format!(
+                        "\nfailed to migrate {}: {}",
+                        failure.tenant_shard_id, failure.error
+                    ));
+                }
+                output
+            })?;
+        }
+
+        Some(("consistency-check", check_match)) => {
+            let report = svc.consistency_check().await?;
+            print_output(check_match, &report, |report| {
+                let mut table = output_table([
+                    "KIND",
+                    "TENANT SHARD",
+                    "NODE",
+                    "NODE GENERATION",
+                    "EXPECTED",
+                ]);
+                for mismatch in &report.mismatches {
+                    table.add_row([
+                        format!("{:?}", mismatch.kind),
+                        mismatch.tenant_shard_id.to_string(),
+                        mismatch.node_id.to_string(),
+                        mismatch
+                            .pageserver_generation
+                            .map_or_else(|| "-".to_owned(), |g| g.to_string()),
+                        mismatch.expected.map_or_else(
+                            || "detached".to_owned(),
+                            |(gen, node_id)| format!("node {node_id}, generation {gen}"),
+                        ),
+                    ]);
+                }
+                let mut output = format!(
+                    "checked {} pageservers, {} mismatches",
+                    report.checked_nodes.len(),
+                    report.mismatches.len()
+                );
+                if !report.mismatches.is_empty() {
+                    output.push_str(&format!("\n{table}"));
+                }
+                for (node_id, error) in &report.unreachable_nodes {
+                    output.push_str(&format!("\nnode {node_id} is unreachable: {error}"));
+                }
+                output
+            })?;
+        }
+
         Some((sub_name, _)) => bail!("Unexpected attachment_service subcommand '{}'", sub_name),
         None => bail!("no attachment_service subcommand provided"),
     }
     Ok(())
 }
 
+/// Prints `value` as JSON with `--json`, as `text` renders it otherwise.
+fn print_output<T: serde::Serialize>(
+    sub_match: &ArgMatches,
+    value: &T,
+    text: impl FnOnce(&T) -> String,
+) -> Result<()> {
+    if sub_match.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        println!("{}", text(value));
+    }
+    Ok(())
+}
+
+fn output_table<const N: usize>(header: [&str; N]) -> comfy_table::Table {
+    let mut table = comfy_table::Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    table.set_header(header);
+    table
+}
+
 fn get_safekeeper(env: &local_env::LocalEnv, id: NodeId) -> Result<SafekeeperNode> {
     if let Some(node) = env.safekeepers.iter().find(|node| node.id == id) {
         Ok(SafekeeperNode::from_env(env, node))
@@ -1272,6 +1469,18 @@ fn cli() -> Command {
         .help("Force initialization even if the repository is not empty")
         .required(false);
 
+    let json_arg = Arg::new("json")
+        .long("json")
+        .action(ArgAction::SetTrue)
+        .help("Print the response as JSON")
+        .required(false);
+
+    let node_id_arg = Arg::new("node-id")
+        .long("node-id")
+        .value_parser(value_parser!(u64))
+        .help("Pageserver node id")
+        .required(true);
+
     let num_pageservers_arg = Arg::new("num-pageservers")
         .value_parser(value_parser!(u16))
         .long("num-pageservers")
@@ -1389,6 +1598,33 @@ fn cli() -> Command {
                 .subcommand(Command::new("start").about("Start local pageserver").arg(pageserver_config_args.clone()))
                 .subcommand(Command::new("stop").about("Stop local pageserver")
                             .arg(stop_mode_arg.clone()))
+                .subcommand(Command::new("inspect")
+                    .about("Show the attachment of a tenant")
+                    .arg(tenant_id_arg.clone())
+                    .arg(json_arg.clone()))
+                .subcommand(Command::new("list")
+                    .about("List the attachments of all tenants")
+                    .arg(json_arg.clone()))
+                .subcommand(Command::new("migrate")
+                    .about("Move the attachment of a tenant to another pageserver")
+                    .arg(tenant_id_arg.clone())
+                    .arg(node_id_arg.clone())
+                    .arg(Arg::new("detach-origin")
+                        .long("detach-origin")
+                        .action(ArgAction::SetTrue)
+                        .help("Detach the tenant from the origin pageserver, instead of leaving a secondary location"))
+                    .arg(json_arg.clone()))
+                .subcommand(Command::new("drain")
+                    .about("Migrate all tenants away from a pageserver, in the background")
+                    .arg(node_id_arg.clone())
+                    .arg(Arg::new("progress")
+                        .long("progress")
+                        .action(ArgAction::SetTrue)
+                        .help("Only show the progress of the drain, don't start one"))
+                    .arg(json_arg.clone()))
+                .subcommand(Command::new("consistency-check")
+                    .about("Compare the pageservers' attachments with the attachment service's")
+                    .arg(json_arg.clone()))
         )
         .subcommand(
             Command::new("safekeeper")
@@ -28,6 +28,7 @@ use utils::{
     lsn::Lsn,
 };
 
+use crate::attachment_service::{AttachmentService, NodeAvailability, NodeRegisterRequest};
 use crate::local_env::PageServerConf;
 use crate::{background_process, local_env::LocalEnv};
 
@@ -216,7 +217,7 @@ impl PageServerNode {
         if update_config {
             args.push(Cow::Borrowed("--update-config"));
         }
-        background_process::start_process(
+        let child = background_process::start_process(
             "pageserver",
             &datadir,
             &self.env.pageserver_bin(),
@@ -232,7 +233,31 @@ impl PageServerNode {
                 }
             },
         )
-        .await
+        .await?;
+
+        if self.env.control_plane_api.is_some() {
+            // for the attachment service to schedule tenants on it, and to migrate them
+            let request = NodeRegisterRequest {
+                node_id: self.conf.id,
+                listen_http_addr: self.conf.listen_http_addr.clone(),
+                listen_pg_addr: self.conf.listen_pg_addr.clone(),
+                availability: NodeAvailability::Active,
+                availability_zone: None,
+            };
+            if let Err(e) = AttachmentService::from_env(&self.env)
+                .node_register(&request)
+                .await
+            {
+                // e.g. a test stopped it: the pageserver works without, the operations that
+                // need the registration fail until it registers on its next start
+                eprintln!(
+                    "Failed to register pageserver node {} with the attachment service: {e:#}",
+                    self.conf.id
+                );
+            }
+        }
+
+        Ok(child)
     }
 
     fn pageserver_basic_args<'a>(
@@ -295,7 +320,7 @@ impl PageServerNode {
     }
 
     pub async fn check_status(&self) -> mgmt_api::Result<()> {
-        self.http_client.status().await
+        self.http_client.status().await.map(|_| ())
     }
 
     pub async fn tenant_list(&self) -> mgmt_api::Result<Vec<TenantInfo>> {
@@ -370,6 +395,11 @@ impl PageServerNode {
                 .transpose()
                 .context("Failed to parse 'gc_feedback' as bool")?,
             heatmap_period: settings.remove("heatmap_period").map(|x| x.to_string()),
+            never_evict_below_min_resident_size: settings
+                .remove("never_evict_below_min_resident_size")
+                .map(|x| x.parse::<bool>())
+                .transpose()
+                .context("Failed to parse 'never_evict_below_min_resident_size' as bool")?,
         };
 
         let request = models::TenantCreateRequest {
@@ -455,6 +485,11 @@ impl PageServerNode {
                     .transpose()
                     .context("Failed to parse 'gc_feedback' as bool")?,
                 heatmap_period: settings.remove("heatmap_period").map(|x| x.to_string()),
+                never_evict_below_min_resident_size: settings
+                    .remove("never_evict_below_min_resident_size")
+                    .map(|x| x.parse::<bool>())
+                    .transpose()
+                    .context("Failed to parse 'never_evict_below_min_resident_size' as bool")?,
             }
         };
 
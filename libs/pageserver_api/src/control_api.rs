@@ -38,6 +38,11 @@ pub struct ValidateRequest {
 #[derive(Serialize, Deserialize)]
 pub struct ValidateResponse {
     pub tenants: Vec<ValidateResponseTenant>,
+    /// Changes with every change of the generations that the control plane hands out, so
+    /// the results of validations at the same sequence are the same. Zero if the control plane
+    /// doesn't track it.
+    #[serde(default)]
+    pub sequence: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -217,7 +217,7 @@ impl std::ops::Deref for TenantCreateRequest {
 
 /// An alternative representation of `pageserver::tenant::TenantConf` with
 /// simpler types.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct TenantConfig {
     pub checkpoint_distance: Option<u64>,
     pub checkpoint_timeout: Option<String>,
@@ -241,6 +241,7 @@ pub struct TenantConfig {
     pub evictions_low_residence_duration_metric_threshold: Option<String>,
     pub gc_feedback: Option<bool>,
     pub heatmap_period: Option<String>,
+    pub never_evict_below_min_resident_size: Option<bool>,
 }
 
 /// A flattened analog of a `pagesever::tenant::LocationMode`, which
@@ -289,9 +290,12 @@ pub struct LocationConfig {
 #[serde(transparent)]
 pub struct TenantCreateResponse(pub TenantId);
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct StatusResponse {
     pub id: NodeId,
+    /// Set while disk usage based eviction is paused through the management API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_usage_eviction_paused_until_millis_since_epoch: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -368,6 +372,9 @@ pub struct TenantInfo {
     /// If a layer is present in both local FS and S3, it counts only once.
     pub current_physical_size: Option<u64>, // physical size is only included in `tenant_status` endpoint
     pub attachment_status: TenantAttachmentStatus,
+    /// The generation this shard is attached with, if the pageserver uses generations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -525,6 +532,9 @@ pub enum HistoricLayerInfo {
         lsn_end: Lsn,
         remote: bool,
         access_stats: LayerAccessStats,
+        /// Only if requested, see [`LayerReconstructCost`].
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reconstruct_cost: Option<LayerReconstructCost>,
     },
     Image {
         layer_file_name: String,
@@ -533,9 +543,22 @@ pub enum HistoricLayerInfo {
         lsn_start: Lsn,
         remote: bool,
         access_stats: LayerAccessStats,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reconstruct_cost: Option<LayerReconstructCost>,
     },
 }
 
+/// An estimate of how costly the reads of a layer's pages are, which is what the disk usage
+/// based eviction's `ReconstructCost` order avoids making them pay on top of a download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LayerReconstructCost {
+    /// The delta layers that a read visits, from this layer down to the newest image layer
+    /// beneath it. Zero for image layers.
+    pub delta_depth: u32,
+    /// The total size of those delta layers, as a proxy for the WAL to redo.
+    pub wal_redo_bytes: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DownloadRemoteLayersTaskSpawnRequest {
     pub max_concurrent_downloads: NonZeroUsize,
@@ -618,7 +641,7 @@ pub struct PagestreamNblocksRequest {
     pub rel: RelTag,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PagestreamGetPageRequest {
     pub latest: bool,
     pub lsn: Lsn,
@@ -909,6 +932,7 @@ mod tests {
             state: TenantState::Active,
             current_physical_size: Some(42),
             attachment_status: TenantAttachmentStatus::Attached,
+            generation: None,
         };
         let expected_active = json!({
             "id": original_active.id.to_string(),
@@ -929,6 +953,7 @@ mod tests {
             },
             current_physical_size: Some(42),
             attachment_status: TenantAttachmentStatus::Attached,
+            generation: None,
         };
         let expected_broken = json!({
             "id": original_broken.id.to_string(),
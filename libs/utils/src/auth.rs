@@ -29,6 +29,11 @@ pub enum Scope {
     // Should only be used e.g. for status check.
     // Currently also used for connection from any pageserver to any safekeeper.
     SafekeeperData,
+    // The pageservers' calls to the control plane's generations API, e.g. re-attach.
+    #[serde(rename = "generations_api")]
+    GenerationsApi,
+    // Provides access to all of the control plane's APIs, for operators.
+    Admin,
 }
 
 /// JWT payload. See docs/authentication.md for the format
@@ -1,5 +1,9 @@
+use std::num::NonZeroU32;
+use std::time::Duration;
+
 use pageserver_api::models::*;
-use reqwest::{IntoUrl, Method};
+use pageserver_api::shard::TenantShardId;
+use reqwest::{IntoUrl, Method, StatusCode};
 use utils::{
     http::error::HttpErrorBody,
     id::{TenantId, TimelineId},
@@ -10,24 +14,118 @@ pub mod util;
 #[derive(Debug)]
 pub struct Client {
     mgmt_api_endpoint: String,
-    authorization_header: Option<String>,
+    auth: Option<Auth>,
+    retry: RetryPolicy,
     client: reqwest::Client,
 }
 
+/// How [`Client`] authenticates to the management API.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// A bearer token, as the pageserver itself checks.
+    Jwt(String),
+    /// HTTP basic auth, e.g., for a pageserver behind an authenticating proxy.
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+/// Which failed requests [`Client`] retries, and how long it waits before each retry: the
+/// backoff doubles from `base_backoff` up to `max_backoff`.
+///
+/// The failures to retry on are those that a restarting pageserver causes. A request that
+/// the pageserver got before the connection was reset may have been processed, so requests
+/// that aren't idempotent may be applied twice.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Including the first attempt, so 1 doesn't retry.
+    pub max_attempts: NonZeroU32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    /// The response statuses to retry on.
+    pub retry_statuses: Vec<StatusCode>,
+    /// Retry when the request wasn't sent or got no response, e.g., when the connection was
+    /// refused, reset, or timed out.
+    pub retry_connection_errors: bool,
+}
+
+impl RetryPolicy {
+    /// The default: fail on the first error.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: NonZeroU32::new(1).unwrap(),
+            base_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            retry_statuses: Vec::new(),
+            retry_connection_errors: false,
+        }
+    }
+
+    /// Up to `max_attempts` on connection errors, 429 and 503, with a backoff from 100ms to 3s.
+    pub fn transient(max_attempts: NonZeroU32) -> Self {
+        RetryPolicy {
+            max_attempts,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(3),
+            retry_statuses: vec![
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ],
+            retry_connection_errors: true,
+        }
+    }
+
+    fn retries(&self, res: &reqwest::Result<reqwest::Response>) -> bool {
+        match res {
+            Ok(response) => self.retry_statuses.contains(&response.status()),
+            Err(e) => {
+                self.retry_connection_errors && (e.is_connect() || e.is_timeout() || e.is_request())
+            }
+        }
+    }
+
+    /// The wait before the retry that follows the `attempt`-th attempt, counting from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        self.base_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::never()
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("invalid URL: {0}")]
+    InvalidUrl(reqwest::Error),
+
     #[error("receive body: {0}")]
     ReceiveBody(reqwest::Error),
 
     #[error("receive error body: {0}")]
     ReceiveErrorBody(String),
 
-    #[error("pageserver API: {0}")]
-    ApiError(String),
+    #[error("pageserver API: {1}")]
+    ApiError(StatusCode, String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The parts of a [`HistoricLayerInfo`] that clients use: that type only serializes.
+#[derive(Debug, serde::Deserialize)]
+pub struct HistoricLayerSummary {
+    pub layer_file_name: String,
+    pub layer_file_size: u64,
+    /// Whether the layer is evicted, i.e., needs an on-demand download to be read.
+    pub remote: bool,
+}
+
 #[async_trait::async_trait]
 pub trait ResponseErrorMessageExt: Sized {
     async fn error_from_body(self) -> Result<Self>;
@@ -43,7 +141,7 @@ impl ResponseErrorMessageExt for reqwest::Response {
 
         let url = self.url().to_owned();
         Err(match self.json::<HttpErrorBody>().await {
-            Ok(HttpErrorBody { msg }) => Error::ApiError(msg),
+            Ok(HttpErrorBody { msg }) => Error::ApiError(status, msg),
             Err(_) => {
                 Error::ReceiveErrorBody(format!("Http error ({}) at {}.", status.as_u16(), url))
             }
@@ -53,13 +151,23 @@ impl ResponseErrorMessageExt for reqwest::Response {
 
 impl Client {
     pub fn new(mgmt_api_endpoint: String, jwt: Option<&str>) -> Self {
+        Self::with_auth(mgmt_api_endpoint, jwt.map(|jwt| Auth::Jwt(jwt.to_owned())))
+    }
+
+    pub fn with_auth(mgmt_api_endpoint: String, auth: Option<Auth>) -> Self {
         Self {
             mgmt_api_endpoint,
-            authorization_header: jwt.map(|jwt| format!("Bearer {jwt}")),
+            auth,
+            retry: RetryPolicy::default(),
             client: reqwest::Client::new(),
         }
     }
 
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
     pub async fn list_tenants(&self) -> Result<Vec<pageserver_api::models::TenantInfo>> {
         let uri = format!("{}/v1/tenant", self.mgmt_api_endpoint);
         let resp = self.get(&uri).await?;
@@ -68,9 +176,9 @@ impl Client {
 
     pub async fn tenant_details(
         &self,
-        tenant_id: TenantId,
+        tenant_shard_id: TenantShardId,
     ) -> Result<pageserver_api::models::TenantDetails> {
-        let uri = format!("{}/v1/tenant/{tenant_id}", self.mgmt_api_endpoint);
+        let uri = format!("{}/v1/tenant/{tenant_shard_id}", self.mgmt_api_endpoint);
         self.get(uri)
             .await?
             .json()
@@ -132,21 +240,38 @@ impl Client {
         uri: U,
         body: B,
     ) -> Result<reqwest::Response> {
-        let req = self.client.request(method, uri);
-        let req = if let Some(value) = &self.authorization_header {
-            req.header(reqwest::header::AUTHORIZATION, value)
-        } else {
-            req
-        };
-        let res = req.json(&body).send().await.map_err(Error::ReceiveBody)?;
-        let response = res.error_from_body().await?;
-        Ok(response)
+        let uri = uri.into_url().map_err(Error::InvalidUrl)?;
+        let mut attempt = 1;
+        loop {
+            let req = self.client.request(method.clone(), uri.clone());
+            let req = match &self.auth {
+                Some(Auth::Jwt(jwt)) => {
+                    req.header(reqwest::header::AUTHORIZATION, format!("Bearer {jwt}"))
+                }
+                Some(Auth::Basic { username, password }) => {
+                    req.basic_auth(username, password.as_ref())
+                }
+                None => req,
+            };
+            let res = req.json(&body).send().await;
+            if attempt < self.retry.max_attempts.get() && self.retry.retries(&res) {
+                tokio::time::sleep(self.retry.backoff(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+            let res = res.map_err(Error::ReceiveBody)?;
+            let response = res.error_from_body().await?;
+            return Ok(response);
+        }
     }
 
-    pub async fn status(&self) -> Result<()> {
+    pub async fn status(&self) -> Result<StatusResponse> {
         let uri = format!("{}/v1/status", self.mgmt_api_endpoint);
-        self.get(&uri).await?;
-        Ok(())
+        self.get(&uri)
+            .await?
+            .json()
+            .await
+            .map_err(Error::ReceiveBody)
     }
 
     pub async fn tenant_create(&self, req: &TenantCreateRequest) -> Result<TenantId> {
@@ -158,6 +283,15 @@ impl Client {
             .map_err(Error::ReceiveBody)
     }
 
+    /// The deletion continues in the background after this returns: the tenant is gone once
+    /// [`Self::tenant_details`] fails with [`StatusCode::NOT_FOUND`].
+    pub async fn tenant_delete(&self, tenant_shard_id: TenantShardId) -> Result<StatusCode> {
+        let uri = format!("{}/v1/tenant/{tenant_shard_id}", self.mgmt_api_endpoint);
+        self.request(Method::DELETE, &uri, ())
+            .await
+            .map(|resp| resp.status())
+    }
+
     pub async fn tenant_config(&self, req: &TenantConfigRequest) -> Result<()> {
         let uri = format!("{}/v1/tenant/config", self.mgmt_api_endpoint);
         self.request(Method::PUT, &uri, req).await?;
@@ -184,6 +318,17 @@ impl Client {
         Ok(())
     }
 
+    /// Uploads the heatmap of an attached tenant shard now, rather than on its next period, for
+    /// its secondary locations to download.
+    pub async fn tenant_heatmap_upload(&self, tenant_shard_id: TenantShardId) -> Result<()> {
+        let uri = format!(
+            "{}/v1/tenant/{tenant_shard_id}/heatmap_upload",
+            self.mgmt_api_endpoint
+        );
+        self.request(Method::POST, &uri, ()).await?;
+        Ok(())
+    }
+
     pub async fn timeline_create(
         &self,
         tenant_id: TenantId,
@@ -199,4 +344,105 @@ impl Client {
             .await
             .map_err(Error::ReceiveBody)
     }
+
+    /// The deletion continues in the background after this returns: the timeline is gone once
+    /// [`Self::timeline_info`] fails with [`StatusCode::NOT_FOUND`].
+    pub async fn timeline_delete(
+        &self,
+        tenant_shard_id: TenantShardId,
+        timeline_id: TimelineId,
+    ) -> Result<StatusCode> {
+        let uri = format!(
+            "{}/v1/tenant/{tenant_shard_id}/timeline/{timeline_id}",
+            self.mgmt_api_endpoint
+        );
+        self.request(Method::DELETE, &uri, ())
+            .await
+            .map(|resp| resp.status())
+    }
+
+    pub async fn historic_layers(
+        &self,
+        tenant_shard_id: TenantShardId,
+        timeline_id: TimelineId,
+    ) -> Result<Vec<HistoricLayerSummary>> {
+        #[derive(serde::Deserialize)]
+        struct LayerMapSummary {
+            historic_layers: Vec<HistoricLayerSummary>,
+        }
+
+        let uri = format!(
+            "{}/v1/tenant/{tenant_shard_id}/timeline/{timeline_id}/layer",
+            self.mgmt_api_endpoint
+        );
+        let layer_map: LayerMapSummary = self
+            .get(&uri)
+            .await?
+            .json()
+            .await
+            .map_err(Error::ReceiveBody)?;
+        Ok(layer_map.historic_layers)
+    }
+
+    /// Returns false if the layer was already evicted.
+    pub async fn layer_evict(
+        &self,
+        tenant_shard_id: TenantShardId,
+        timeline_id: TimelineId,
+        layer_file_name: &str,
+    ) -> Result<bool> {
+        let uri = format!(
+            "{}/v1/tenant/{tenant_shard_id}/timeline/{timeline_id}/layer/{layer_file_name}",
+            self.mgmt_api_endpoint
+        );
+        let resp = self.request(Method::DELETE, &uri, ()).await?;
+        Ok(resp.status() != StatusCode::NOT_MODIFIED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode) -> reqwest::Result<reqwest::Response> {
+        Ok(hyper::Response::builder()
+            .status(status)
+            .body("")
+            .unwrap()
+            .into())
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let policy = RetryPolicy::transient(NonZeroU32::new(3).unwrap());
+        assert!(policy.retries(&response(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(policy.retries(&response(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!policy.retries(&response(StatusCode::OK)));
+        assert!(!policy.retries(&response(StatusCode::INTERNAL_SERVER_ERROR)));
+
+        // nothing listens on port 1
+        let refused = reqwest::Client::new()
+            .get("http://127.0.0.1:1")
+            .send()
+            .await;
+        assert!(refused.as_ref().unwrap_err().is_connect());
+        assert!(policy.retries(&refused));
+        assert!(!RetryPolicy::never().retries(&refused));
+
+        let invalid = reqwest::Client::new().get("http://[::1").send().await;
+        assert!(!policy.retries(&invalid));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy::transient(NonZeroU32::new(10).unwrap());
+        let backoffs = (1..=7)
+            .map(|a| policy.backoff(a).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1600, 3000, 3000]);
+        // the shift and the multiplication saturate
+        assert_eq!(policy.backoff(33), policy.max_backoff);
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+        assert_eq!(RetryPolicy::never().backoff(1), Duration::ZERO);
+    }
 }
@@ -1,6 +1,7 @@
 use std::pin::Pin;
 
-use futures::SinkExt;
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
 use pageserver_api::{
     models::{
         PagestreamBeMessage, PagestreamFeMessage, PagestreamGetPageRequest,
@@ -10,13 +11,15 @@ use pageserver_api::{
 };
 use tokio::task::JoinHandle;
 use tokio_postgres::CopyOutStream;
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 use tokio_util::sync::CancellationToken;
 use utils::{
     id::{TenantId, TimelineId},
     lsn::Lsn,
 };
 
+pub mod pool;
+
 pub struct Client {
     client: tokio_postgres::Client,
     cancel_on_client_drop: Option<tokio_util::sync::DropGuard>,
@@ -28,6 +31,8 @@ pub struct BasebackupRequest {
     pub timeline_id: TimelineId,
     pub lsn: Option<Lsn>,
     pub gzip: bool,
+    /// A `fullbackup`, which includes the relation data. It can't be gzipped.
+    pub full: bool,
 }
 
 impl Client {
@@ -80,9 +85,13 @@ impl Client {
             timeline_id,
             lsn,
             gzip,
+            full,
         } = req;
+        if *full && *gzip {
+            anyhow::bail!("a fullbackup can't be gzipped");
+        }
         let mut args = Vec::with_capacity(5);
-        args.push("basebackup".to_string());
+        args.push(if *full { "fullbackup" } else { "basebackup" }.to_string());
         args.push(format!("{tenant_id}"));
         args.push(format!("{timeline_id}"));
         if let Some(lsn) = lsn {
@@ -93,15 +102,53 @@ impl Client {
         }
         Ok(self.client.copy_out(&args.join(" ")).await?)
     }
+
+    /// The tar output of [`Self::basebackup`], calling `progress` with the number of bytes
+    /// received so far after each chunk.
+    pub async fn basebackup_stream(
+        &self,
+        req: &BasebackupRequest,
+        mut progress: impl FnMut(u64) + Send + 'static,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Bytes>>> {
+        Ok(with_progress(self.basebackup(req).await?, progress))
+    }
+}
+
+fn with_progress<E: Into<anyhow::Error>>(
+    stream: impl Stream<Item = Result<Bytes, E>>,
+    mut progress: impl FnMut(u64) + Send + 'static,
+) -> impl Stream<Item = anyhow::Result<Bytes>> {
+    let mut received = 0;
+    stream.map(move |chunk| {
+        let chunk = chunk.map_err(Into::into)?;
+        received += chunk.len() as u64;
+        progress(received);
+        anyhow::Ok(chunk)
+    })
 }
 
 /// Create using [`Client::pagestream`].
 pub struct PagestreamClient {
-    copy_both: Pin<Box<tokio_postgres::CopyBothDuplex<bytes::Bytes>>>,
+    copy_both: Pin<Box<dyn Duplex>>,
     cancel_on_client_drop: Option<tokio_util::sync::DropGuard>,
     conn_task: JoinHandle<()>,
 }
 
+/// The `CopyBoth` stream of a pagestream, or a fake pageserver in tests.
+trait Duplex:
+    Sink<Bytes, Error = tokio_postgres::Error>
+    + Stream<Item = Result<Bytes, tokio_postgres::Error>>
+    + Send
+{
+}
+
+impl<T> Duplex for T where
+    T: Sink<Bytes, Error = tokio_postgres::Error>
+        + Stream<Item = Result<Bytes, tokio_postgres::Error>>
+        + Send
+{
+}
+
 pub struct RelTagBlockNo {
     pub rel_tag: RelTag,
     pub block_no: u32,
@@ -117,28 +164,233 @@ impl PagestreamClient {
         &mut self,
         req: PagestreamGetPageRequest,
     ) -> anyhow::Result<PagestreamGetPageResponse> {
-        let req = PagestreamFeMessage::GetPage(req);
+        self.send_getpage(req).await?;
+        self.recv_getpage().await
+    }
+
+    /// Sends all of `reqs` before receiving any of the responses, which are in request order.
+    /// The requests are written out with a single flush of the connection.
+    pub async fn getpage_batch(
+        &mut self,
+        reqs: impl IntoIterator<Item = PagestreamGetPageRequest>,
+    ) -> anyhow::Result<Vec<PagestreamGetPageResponse>> {
+        let mut sent = 0;
+        for req in reqs {
+            let req: bytes::Bytes = PagestreamFeMessage::GetPage(req).serialize();
+            self.copy_both.feed(req).await?;
+            sent += 1;
+        }
+        self.copy_both.flush().await?;
+
+        let mut responses = Vec::with_capacity(sent);
+        for _ in 0..sent {
+            responses.push(self.recv_getpage().await?);
+        }
+        Ok(responses)
+    }
+
+    /// Sends a getpage request without waiting for its response, to pipeline requests.
+    /// The pageserver responds in request order, see [`Self::recv_getpage`].
+    pub async fn send_getpage(&mut self, req: PagestreamGetPageRequest) -> anyhow::Result<()> {
+        self.send(PagestreamFeMessage::GetPage(req)).await
+    }
+
+    /// The response to the oldest request sent with [`Self::send_getpage`] that has not been
+    /// received yet.
+    pub async fn recv_getpage(&mut self) -> anyhow::Result<PagestreamGetPageResponse> {
+        match self.recv().await? {
+            PagestreamBeMessage::GetPage(p) => Ok(p),
+            msg => Err(unexpected_response(&msg, "getpage")),
+        }
+    }
+
+    async fn send(&mut self, req: PagestreamFeMessage) -> anyhow::Result<()> {
         let req: bytes::Bytes = req.serialize();
         // let mut req = tokio_util::io::ReaderStream::new(&req);
         let mut req = tokio_stream::once(Ok(req));
 
         self.copy_both.send_all(&mut req).await?;
+        Ok(())
+    }
 
-        let next: Option<Result<bytes::Bytes, _>> = self.copy_both.next().await;
-        let next: bytes::Bytes = next.unwrap()?;
+    /// The next response, or the error that the pageserver responded with.
+    async fn recv(&mut self) -> anyhow::Result<PagestreamBeMessage> {
+        let Some(next) = self.copy_both.next().await else {
+            anyhow::bail!("pagestream connection closed");
+        };
+        let next: bytes::Bytes = next?;
 
-        let msg = PagestreamBeMessage::deserialize(next)?;
-        match msg {
-            PagestreamBeMessage::GetPage(p) => Ok(p),
+        match PagestreamBeMessage::deserialize(next)? {
             PagestreamBeMessage::Error(e) => anyhow::bail!("Error: {:?}", e),
-            PagestreamBeMessage::Exists(_)
-            | PagestreamBeMessage::Nblocks(_)
-            | PagestreamBeMessage::DbSize(_) => {
-                anyhow::bail!(
-                    "unexpected be message kind in response to getpage request: {}",
-                    msg.kind()
-                )
+            msg => Ok(msg),
+        }
+    }
+}
+
+fn unexpected_response(msg: &PagestreamBeMessage, request: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "unexpected be message kind in response to {request} request: {}",
+        msg.kind()
+    )
+}
+
+/// A pageserver that responds to getpage requests with pages that hold their block number,
+/// and fails those for block [`u32::MAX`].
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::collections::VecDeque;
+    use std::task::{Context, Poll};
+
+    use pageserver_api::models::PagestreamErrorResponse;
+    use pageserver_api::reltag::RelTag;
+
+    use super::*;
+
+    #[derive(Default)]
+    pub(crate) struct Pageserver {
+        /// Fed, but not flushed yet.
+        unflushed: Vec<Bytes>,
+        responses: VecDeque<Bytes>,
+    }
+
+    impl PagestreamClient {
+        pub(crate) fn fake() -> Self {
+            PagestreamClient {
+                copy_both: Box::pin(Pageserver::default()),
+                cancel_on_client_drop: None,
+                conn_task: tokio::spawn(async {}),
             }
         }
     }
+
+    pub(crate) fn getpage(blkno: u32) -> PagestreamGetPageRequest {
+        PagestreamGetPageRequest {
+            latest: true,
+            lsn: Lsn(0x10),
+            rel: RelTag {
+                spcnode: 1663,
+                dbnode: 5,
+                relnode: 1259,
+                forknum: 0,
+            },
+            blkno,
+        }
+    }
+
+    pub(crate) fn page(blkno: u32) -> Bytes {
+        Bytes::copy_from_slice(&blkno.to_be_bytes())
+    }
+
+    impl Sink<Bytes> for Pageserver {
+        type Error = tokio_postgres::Error;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, req: Bytes) -> Result<(), Self::Error> {
+            self.unflushed.push(req);
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            for req in std::mem::take(&mut self.unflushed) {
+                let response = match PagestreamFeMessage::parse(&mut &req[..]).unwrap() {
+                    PagestreamFeMessage::GetPage(req) if req.blkno == u32::MAX => {
+                        PagestreamBeMessage::Error(PagestreamErrorResponse {
+                            message: "no such block".to_owned(),
+                        })
+                    }
+                    PagestreamFeMessage::GetPage(req) => {
+                        PagestreamBeMessage::GetPage(PagestreamGetPageResponse {
+                            page: page(req.blkno),
+                        })
+                    }
+                    req => panic!("unexpected request {req:?}"),
+                };
+                self.responses.push_back(response.serialize());
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    impl Stream for Pageserver {
+        type Item = Result<Bytes, tokio_postgres::Error>;
+
+        /// Closes the connection if there is no response to a flushed request.
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.responses.pop_front().map(Ok))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::fake::{getpage, page};
+    use super::*;
+
+    #[tokio::test]
+    async fn pipelined_responses_are_in_request_order() {
+        let mut client = PagestreamClient::fake();
+        for blkno in [3, 1, 2] {
+            client.send_getpage(getpage(blkno)).await.unwrap();
+        }
+        for blkno in [3, 1, 2] {
+            assert_eq!(client.recv_getpage().await.unwrap().page, page(blkno));
+        }
+        client.recv_getpage().await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn getpage_batch_responses_are_in_request_order() {
+        let mut client = PagestreamClient::fake();
+        let responses = client
+            .getpage_batch((0..10).map(getpage))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.page)
+            .collect::<Vec<_>>();
+        assert_eq!(responses, (0..10).map(page).collect::<Vec<_>>());
+
+        // the error response fails the batch
+        client
+            .getpage_batch([getpage(1), getpage(u32::MAX), getpage(2)])
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn basebackup_progress_counts_the_received_bytes() {
+        let chunks = vec![
+            Ok(Bytes::from_static(b"abc")),
+            Ok(Bytes::from_static(b"de")),
+            Err(std::io::Error::other("connection reset")),
+        ];
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let stream = with_progress(tokio_stream::iter(chunks), {
+            let seen = Arc::clone(&seen);
+            move |received| seen.lock().unwrap().push(received)
+        });
+        let results = stream.collect::<Vec<_>>().await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &Bytes::from_static(b"abc"));
+        assert_eq!(results[1].as_ref().unwrap(), &Bytes::from_static(b"de"));
+        assert!(results[2].is_err());
+        // not called for the error
+        assert_eq!(*seen.lock().unwrap(), [3, 5]);
+    }
 }
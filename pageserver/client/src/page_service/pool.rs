@@ -0,0 +1,299 @@
+//! A pool of pagestream connections, shared by the tasks that send getpage requests.
+//!
+//! Each timeline gets up to a configurable number of connections, and the requests of all tasks
+//! are multiplexed over them: a request goes to an idle connection, or to a new one while the
+//! timeline has fewer than the maximum, or else to the connection with the fewest requests
+//! queued. A task per connection sends the requests queued for it in batches, see
+//! [`PagestreamClient::getpage_batch`], and hands each response to the request's task. A
+//! connection on which a request failed is closed, failing the requests queued for it, and a
+//! later request opens a new one.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use metrics::{
+    register_histogram, register_int_counter, register_int_counter_vec, Histogram, IntCounter,
+    IntCounterVec,
+};
+use once_cell::sync::Lazy;
+use pageserver_api::models::{PagestreamGetPageRequest, PagestreamGetPageResponse};
+use tokio::sync::{mpsc, oneshot};
+use utils::id::{TenantId, TenantTimelineId, TimelineId};
+
+use super::{Client, PagestreamClient};
+
+static CHECKOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_client_pagestream_pool_checkouts_total",
+        "Requests assigned to a connection of a pool, by whether the connection was opened for \
+         them or shared",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+static CONNECT_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_client_pagestream_pool_connect_errors_total",
+        "Connections of a pool that failed to open"
+    )
+    .unwrap()
+});
+
+static CHECKOUT_QUEUE_DEPTH: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "pageserver_client_pagestream_pool_checkout_queue_depth",
+        "Requests queued on the connection that a request was assigned to, ahead of it",
+        vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]
+    )
+    .unwrap()
+});
+
+/// The most requests that a connection sends before it receives their responses.
+const MAX_BATCH: usize = 32;
+
+type Connect = Box<
+    dyn Fn(TenantTimelineId) -> BoxFuture<'static, anyhow::Result<PagestreamClient>> + Send + Sync,
+>;
+
+/// Pagestream connections to a pageserver, up to `connections_per_timeline` for each timeline.
+pub struct PagestreamPool {
+    connect: Connect,
+    connections_per_timeline: NonZeroUsize,
+    timelines: Mutex<HashMap<TenantTimelineId, Vec<Connection>>>,
+}
+
+/// The handle of a connection's task.
+struct Connection {
+    requests: mpsc::UnboundedSender<Queued>,
+    /// The requests sent to the task that it hasn't responded to yet.
+    queued: Arc<AtomicUsize>,
+}
+
+struct Queued {
+    req: PagestreamGetPageRequest,
+    response: oneshot::Sender<anyhow::Result<PagestreamGetPageResponse>>,
+}
+
+impl PagestreamPool {
+    pub fn new(connstring: String, connections_per_timeline: NonZeroUsize) -> Self {
+        Self::with_connect(
+            Box::new(move |timeline| {
+                let connstring = connstring.clone();
+                Box::pin(async move {
+                    Client::new(connstring)
+                        .await?
+                        .pagestream(timeline.tenant_id, timeline.timeline_id)
+                        .await
+                })
+            }),
+            connections_per_timeline,
+        )
+    }
+
+    fn with_connect(connect: Connect, connections_per_timeline: NonZeroUsize) -> Self {
+        PagestreamPool {
+            connect,
+            connections_per_timeline,
+            timelines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends a getpage request on one of the timeline's connections, opening one if needed.
+    pub async fn getpage(
+        &self,
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        req: PagestreamGetPageRequest,
+    ) -> anyhow::Result<PagestreamGetPageResponse> {
+        let (response, response_rx) = oneshot::channel();
+        {
+            let mut timelines = self.timelines.lock().unwrap();
+            let timeline = TenantTimelineId::new(tenant_id, timeline_id);
+            let connections = timelines.entry(timeline).or_default();
+            connections.retain(|c| !c.requests.is_closed());
+
+            let least_busy = connections
+                .iter()
+                .min_by_key(|c| c.queued.load(Ordering::Relaxed));
+            let connection = match least_busy {
+                Some(c)
+                    if c.queued.load(Ordering::Relaxed) == 0
+                        || connections.len() >= self.connections_per_timeline.get() =>
+                {
+                    CHECKOUTS.with_label_values(&["shared"]).inc();
+                    c
+                }
+                _ => {
+                    CHECKOUTS.with_label_values(&["new"]).inc();
+                    connections.push(Connection::open((self.connect)(timeline)));
+                    connections.last().expect("just pushed")
+                }
+            };
+
+            let ahead = connection.queued.fetch_add(1, Ordering::Relaxed);
+            CHECKOUT_QUEUE_DEPTH.observe(ahead as f64);
+            if connection.requests.send(Queued { req, response }).is_err() {
+                connection.queued.fetch_sub(1, Ordering::Relaxed);
+                anyhow::bail!("pagestream connection failed");
+            }
+        }
+        response_rx
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("pagestream connection failed")))
+    }
+}
+
+impl Connection {
+    fn open(connect: BoxFuture<'static, anyhow::Result<PagestreamClient>>) -> Self {
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run(connect, requests_rx, Arc::clone(&queued)));
+        Connection { requests, queued }
+    }
+}
+
+/// The task of a [`Connection`], until the pool is dropped or a request fails.
+async fn run(
+    connect: BoxFuture<'static, anyhow::Result<PagestreamClient>>,
+    mut requests: mpsc::UnboundedReceiver<Queued>,
+    queued: Arc<AtomicUsize>,
+) {
+    let mut client = match connect.await {
+        Ok(client) => client,
+        Err(e) => {
+            CONNECT_ERRORS.inc();
+            fail_queued(&mut requests, &e);
+            return;
+        }
+    };
+
+    while let Some(first) = requests.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match requests.try_recv() {
+                Ok(next) => batch.push(next),
+                Err(_) => break,
+            }
+        }
+        let (reqs, senders): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|Queued { req, response }| (req, response))
+            .unzip();
+        // before the responses, so that the next request of their tasks sees this one as idle
+        queued.fetch_sub(senders.len(), Ordering::Relaxed);
+
+        match client.getpage_batch(reqs).await {
+            Ok(responses) => {
+                for (sender, response) in senders.into_iter().zip(responses) {
+                    // the request may have been cancelled meanwhile
+                    let _ = sender.send(Ok(response));
+                }
+            }
+            Err(e) => {
+                // the pageserver closes the connection on errors
+                for sender in senders {
+                    let _ = sender.send(Err(anyhow::anyhow!("{e:#}")));
+                }
+                fail_queued(&mut requests, &e);
+                return;
+            }
+        }
+    }
+
+    client.shutdown().await;
+}
+
+/// Closes the channel of a failed connection, and fails the requests queued in it.
+fn fail_queued(requests: &mut mpsc::UnboundedReceiver<Queued>, e: &anyhow::Error) {
+    requests.close();
+    while let Ok(Queued { response, .. }) = requests.try_recv() {
+        let _ = response.send(Err(anyhow::anyhow!("{e:#}")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake::{getpage, page};
+    use super::*;
+
+    /// A pool of fake connections, failing the first `failing_connects` connects. Returns the
+    /// number of connects so far.
+    fn pool(
+        connections_per_timeline: usize,
+        failing_connects: usize,
+    ) -> (PagestreamPool, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let pool = PagestreamPool::with_connect(
+            Box::new({
+                let connects = Arc::clone(&connects);
+                move |_| {
+                    let n = connects.fetch_add(1, Ordering::Relaxed);
+                    Box::pin(async move {
+                        if n < failing_connects {
+                            anyhow::bail!("connection refused");
+                        }
+                        Ok(PagestreamClient::fake())
+                    })
+                }
+            }),
+            NonZeroUsize::new(connections_per_timeline).unwrap(),
+        );
+        (pool, connects)
+    }
+
+    #[tokio::test]
+    async fn requests_share_the_connections() {
+        let (pool, connects) = pool(2, 0);
+        let (tenant_id, timeline_id) = (TenantId::generate(), TimelineId::generate());
+
+        // one after the other, the connection is idle for each
+        for blkno in 0..5 {
+            let response = pool.getpage(tenant_id, timeline_id, getpage(blkno)).await;
+            assert_eq!(response.unwrap().page, page(blkno));
+        }
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+
+        let responses = futures::future::join_all(
+            (0..50).map(|blkno| pool.getpage(tenant_id, timeline_id, getpage(blkno))),
+        )
+        .await;
+        for (blkno, response) in responses.into_iter().enumerate() {
+            assert_eq!(response.unwrap().page, page(blkno as u32));
+        }
+        assert_eq!(connects.load(Ordering::Relaxed), 2, "up to the limit");
+
+        // another timeline gets connections of its own
+        let other_timeline_id = TimelineId::generate();
+        let response = pool.getpage(tenant_id, other_timeline_id, getpage(7)).await;
+        assert_eq!(response.unwrap().page, page(7));
+        assert_eq!(connects.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_connections_are_replaced() {
+        let (pool, connects) = pool(1, 1);
+        let (tenant_id, timeline_id) = (TenantId::generate(), TimelineId::generate());
+
+        let err = pool
+            .getpage(tenant_id, timeline_id, getpage(1))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("connection refused"), "{err:#}");
+
+        let response = pool.getpage(tenant_id, timeline_id, getpage(1)).await;
+        assert_eq!(response.unwrap().page, page(1));
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+
+        // an error response closes the connection, the next request opens a new one
+        pool.getpage(tenant_id, timeline_id, getpage(u32::MAX))
+            .await
+            .unwrap_err();
+        let response = pool.getpage(tenant_id, timeline_id, getpage(2)).await;
+        assert_eq!(response.unwrap().page, page(2));
+        assert_eq!(connects.load(Ordering::Relaxed), 3);
+    }
+}
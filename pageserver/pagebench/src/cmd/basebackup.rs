@@ -12,12 +12,16 @@ use tracing::{debug, info, instrument};
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
 use std::ops::Range;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 use crate::util::tokio_thread_local_stats::AllThreadLocalStats;
-use crate::util::{request_stats, tokio_thread_local_stats};
+use crate::util::{
+    affinity, auth, client_usage, metrics, request_stats, rng, rtt, run_metadata, server_metrics,
+    tokio_thread_local_stats,
+};
 
 /// basebackup@LatestLSN
 #[derive(clap::Parser)]
@@ -26,8 +30,8 @@ pub(crate) struct Args {
     mgmt_api_endpoint: String,
     #[clap(long, default_value = "localhost:64000")]
     page_service_host_port: String,
-    #[clap(long)]
-    pageserver_jwt: Option<String>,
+    #[clap(flatten)]
+    auth: auth::Args,
     #[clap(long, default_value = "1")]
     num_clients: NonZeroUsize,
     #[clap(long, default_value = "1.0")]
@@ -36,6 +40,22 @@ pub(crate) struct Args {
     runtime: Option<humantime::Duration>,
     #[clap(long)]
     limit_to_first_n_targets: Option<usize>,
+    /// Write the latency histogram of `total` to this file, as an HdrHistogram interval log.
+    #[clap(long)]
+    histogram_out: Option<PathBuf>,
+    /// Serve live request counters and latency histograms at `/metrics` on this address.
+    #[clap(long)]
+    prometheus_listen: Option<std::net::SocketAddr>,
+    #[clap(flatten)]
+    server_metrics: server_metrics::Args,
+    #[clap(flatten)]
+    rtt: rtt::Args,
+    #[clap(flatten)]
+    client_usage: client_usage::Args,
+    #[clap(flatten)]
+    affinity: affinity::Args,
+    #[clap(flatten)]
+    rng: rng::Args,
     targets: Option<Vec<TenantTimelineId>>,
 }
 
@@ -57,27 +77,44 @@ struct Target {
 
 #[derive(serde::Serialize)]
 struct Output {
+    #[serde(flatten)]
+    metadata: run_metadata::Metadata,
+    /// Pass as `--seed` to issue the same requests again.
+    seed: u64,
     total: request_stats::Output,
+    /// Only with `--rtt-calibration-samples`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_calibration: Option<rtt::Calibration>,
+    /// `total` minus the round-trip time of `rtt_calibration`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_rtt_adjusted: Option<request_stats::Output>,
+    /// Only with `--cpus` or `--numa-node`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    affinity: Option<affinity::Affinity>,
+    /// Only with `--server-metrics`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_metrics: Option<server_metrics::Output>,
+    client_usage: client_usage::Output,
 }
 
 tokio_thread_local_stats::declare!(STATS: request_stats::Stats);
 
 pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let affinity = args.affinity.apply()?;
     tokio_thread_local_stats::main!(STATS, move |thread_local_stats| {
-        main_impl(args, thread_local_stats)
+        main_impl(args, affinity, thread_local_stats)
     })
 }
 
 async fn main_impl(
     args: Args,
+    affinity: Option<affinity::Affinity>,
     all_thread_local_stats: AllThreadLocalStats<request_stats::Stats>,
 ) -> anyhow::Result<()> {
     let args: &'static Args = Box::leak(Box::new(args));
+    let run = run_metadata::Recorder::start();
 
-    let mgmt_api_client = Arc::new(pageserver_client::mgmt_api::Client::new(
-        args.mgmt_api_endpoint.clone(),
-        args.pageserver_jwt.as_deref(),
-    ));
+    let mgmt_api_client = Arc::new(args.auth.mgmt_api_client(args.mgmt_api_endpoint.clone()));
 
     // discover targets
     let timelines: Vec<TenantTimelineId> = crate::util::cli::targets::discover(
@@ -111,6 +148,13 @@ async fn main_impl(
     while let Some(res) = js.join_next().await {
         all_targets.push(res.unwrap().unwrap());
     }
+    // the order in which the targets came in is random, it affects the choice of target
+    all_targets.sort_by_key(|target| target.timeline);
+    let seed = args.rng.seed();
+
+    if let Some(listen) = args.prometheus_listen {
+        metrics::spawn_server(listen)?;
+    }
 
     let live_stats = Arc::new(LiveStats::default());
 
@@ -157,10 +201,10 @@ async fn main_impl(
     }
 
     let work_sender = async move {
+        let mut rng = seed.rng("all targets");
         start_work_barrier.wait().await;
         loop {
             let (timeline, work) = {
-                let mut rng = rand::thread_rng();
                 let target = all_targets.choose(&mut rng).unwrap();
                 let lsn = target.lsn_range.clone().map(|r| rng.gen_range(r));
                 (
@@ -177,6 +221,11 @@ async fn main_impl(
         }
     };
 
+    let rtt_calibration = rtt::calibrate(&args.rtt, &args.page_service_host_port).await?;
+    let server_metrics =
+        server_metrics::Snapshots::start(&args.server_metrics, &args.mgmt_api_endpoint).await?;
+    let measurement_start = (Instant::now(), SystemTime::now());
+    let client_usage = client_usage::Sampler::start(&args.client_usage);
     if let Some(runtime) = args.runtime {
         match tokio::time::timeout(runtime.into(), work_sender).await {
             Ok(()) => unreachable!("work sender never terminates"),
@@ -189,19 +238,36 @@ async fn main_impl(
         unreachable!("work sender never terminates");
     }
 
+    let measurement_elapsed = measurement_start.0.elapsed();
+    let client_usage = client_usage.finish().await?;
+
     for t in tasks {
         t.await.unwrap();
     }
+    let server_metrics = match server_metrics {
+        Some(snapshots) => Some(snapshots.finish().await?),
+        None => None,
+    };
+
+    let mut total = request_stats::Stats::new();
+    for stats in all_thread_local_stats.lock().unwrap().iter() {
+        let stats = stats.lock().unwrap();
+        total.add(&stats);
+    }
+    if let Some(path) = &args.histogram_out {
+        total.write_histogram_log(path, measurement_start.1, measurement_elapsed)?;
+    }
 
+    let total = total.output();
     let output = Output {
-        total: {
-            let mut agg_stats = request_stats::Stats::new();
-            for stats in all_thread_local_stats.lock().unwrap().iter() {
-                let stats = stats.lock().unwrap();
-                agg_stats.add(&stats);
-            }
-            agg_stats.output()
-        },
+        metadata: run.finish(&[args.mgmt_api_endpoint.as_str()]).await,
+        seed: seed.get(),
+        total_rtt_adjusted: rtt_calibration.as_ref().map(|c| total.minus(c.rtt())),
+        total,
+        rtt_calibration,
+        affinity,
+        server_metrics,
+        client_usage,
     };
 
     let output = serde_json::to_string_pretty(&output).unwrap();
@@ -227,42 +293,45 @@ async fn client(
 ) {
     start_work_barrier.wait().await;
 
-    let client = pageserver_client::page_service::Client::new(crate::util::connstring::connstring(
-        &args.page_service_host_port,
-        args.pageserver_jwt.as_deref(),
-    ))
-    .await
-    .unwrap();
+    let connstring = args
+        .auth
+        .page_service_connstring(&crate::util::connstring::connstring(
+            &args.page_service_host_port,
+        ))
+        .unwrap();
+    let client = pageserver_client::page_service::Client::new(connstring)
+        .await
+        .unwrap();
 
     while let Some(Work { lsn, gzip }) = work.recv().await {
         let start = Instant::now();
-        let copy_out_stream = client
-            .basebackup(&BasebackupRequest {
-                tenant_id: timeline.tenant_id,
-                timeline_id: timeline.timeline_id,
-                lsn,
-                gzip,
+        let req = BasebackupRequest {
+            tenant_id: timeline.tenant_id,
+            timeline_id: timeline.timeline_id,
+            lsn,
+            gzip,
+            full: false,
+        };
+        let size = Arc::new(AtomicU64::new(0));
+        let stream = client
+            .basebackup_stream(&req, {
+                let size = Arc::clone(&size);
+                move |received| size.store(received, Ordering::Relaxed)
             })
             .await
             .with_context(|| format!("start basebackup for {timeline}"))
             .unwrap();
 
         use futures::StreamExt;
-        let size = Arc::new(AtomicUsize::new(0));
-        copy_out_stream
-            .for_each({
-                |r| {
-                    let size = Arc::clone(&size);
-                    async move {
-                        let size = Arc::clone(&size);
-                        size.fetch_add(r.unwrap().len(), Ordering::Relaxed);
-                    }
-                }
+        stream
+            .for_each(|chunk| async move {
+                chunk.unwrap();
             })
             .await;
         debug!("basebackup size is {} bytes", size.load(Ordering::Relaxed));
         let elapsed = start.elapsed();
         live_stats.inc();
+        metrics::observe_request(elapsed);
         STATS.with(|stats| {
             stats.borrow().lock().unwrap().observe(elapsed).unwrap();
         });
@@ -0,0 +1,237 @@
+//! Runs the same benchmark against two pageservers and reports the difference, e.g., to validate
+//! a refactoring of the pageserver:
+//!
+//! ```text
+//! pagebench compare \
+//!     --page-service-connstring postgres://postgres@localhost:64000 \
+//!     --mgmt-api-endpoint http://localhost:9898 \
+//!     --page-service-connstring postgres://postgres@localhost:64001 \
+//!     --mgmt-api-endpoint http://localhost:9899 \
+//!     -- get-page-latest-lsn --num-clients 8
+//! ```
+//!
+//! Both pageservers need the same timelines, e.g., attached from the same remote storage. The
+//! runs use the same `--seed`, so both sides get the same requests. They alternate between the
+//! sides in the order A B B A A B ..., so that drift over the session, e.g., of the host's page
+//! cache, affects both sides alike. Like [`super::sweep`], each run is a separate pagebench
+//! process, with a cooldown in between.
+
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+/// Run a benchmark against two pageservers, alternating between them, and compare the results.
+#[derive(clap::Parser)]
+pub(crate) struct Args {
+    /// Passed as `--page-service-connstring` to the benchmark: first for pageserver A, then for B.
+    #[clap(long = "page-service-connstring", required = true)]
+    page_service_connstrings: Vec<String>,
+    /// Passed as `--mgmt-api-endpoint` to the benchmark: first for pageserver A, then for B.
+    /// If not given, the benchmark's default or its own argument applies to both.
+    #[clap(long = "mgmt-api-endpoint")]
+    mgmt_api_endpoints: Vec<String>,
+    /// Number of runs against each pageserver.
+    #[clap(long, default_value = "2")]
+    rounds: NonZeroUsize,
+    /// Passed as `--runtime` to each run.
+    #[clap(long, default_value = "30s")]
+    runtime: humantime::Duration,
+    /// Time to wait between two runs.
+    #[clap(long, default_value = "10s")]
+    cooldown: humantime::Duration,
+    /// Passed as `--seed` to each run. If not set, a random seed is used.
+    #[clap(long)]
+    seed: Option<u64>,
+    /// The benchmark sub-command and its arguments, e.g., `get-page-latest-lsn`. It must report
+    /// its request stats as `total`.
+    #[clap(last = true, required = true)]
+    benchmark: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+enum Side {
+    A,
+    B,
+}
+
+#[derive(serde::Serialize)]
+struct Output {
+    benchmark: Vec<String>,
+    #[serde(with = "humantime_serde")]
+    runtime: Duration,
+    seed: u64,
+    runs: Vec<Run>,
+    /// The mean of each metric over the runs of each side. Only if all runs succeeded.
+    diff: Option<Vec<MetricDiff>>,
+}
+
+#[derive(serde::Serialize)]
+struct Run {
+    side: Side,
+    /// The benchmark's own output, or the reason why the run failed.
+    result: Result<serde_json::Value, String>,
+}
+
+#[derive(serde::Serialize)]
+struct MetricDiff {
+    metric: String,
+    a: f64,
+    b: f64,
+    /// `b - a`.
+    delta: f64,
+    /// How much worse B is than A, relative to A: positive if B has higher latency or lower
+    /// throughput.
+    regression_pct: f64,
+}
+
+pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    if args.page_service_connstrings.len() != 2 {
+        anyhow::bail!("expected --page-service-connstring exactly twice, for A and for B");
+    }
+    if !matches!(args.mgmt_api_endpoints.len(), 0 | 2) {
+        anyhow::bail!("expected --mgmt-api-endpoint either not at all or twice, for A and for B");
+    }
+    let exe = std::env::current_exe().context("find pagebench executable")?;
+    let seed = args.seed.unwrap_or_else(rand::random);
+    info!("using seed {seed}");
+
+    let order = (0..args.rounds.get()).flat_map(|round| {
+        if round % 2 == 0 {
+            [Side::A, Side::B]
+        } else {
+            [Side::B, Side::A]
+        }
+    });
+
+    let mut output = Output {
+        benchmark: args.benchmark.clone(),
+        runtime: args.runtime.into(),
+        seed,
+        runs: Vec::with_capacity(2 * args.rounds.get()),
+        diff: None,
+    };
+    for (i, side) in order.enumerate() {
+        if i > 0 {
+            info!("cooling down for {}", args.cooldown);
+            std::thread::sleep(args.cooldown.into());
+        }
+        info!(run = i, ?side, "running benchmark");
+
+        let idx = match side {
+            Side::A => 0,
+            Side::B => 1,
+        };
+        let mut cmd = Command::new(&exe);
+        cmd.args(&args.benchmark)
+            .arg("--runtime")
+            .arg(args.runtime.to_string())
+            .arg("--seed")
+            .arg(seed.to_string())
+            .arg("--page-service-connstring")
+            .arg(&args.page_service_connstrings[idx]);
+        if let Some(endpoint) = args.mgmt_api_endpoints.get(idx) {
+            cmd.arg("--mgmt-api-endpoint").arg(endpoint);
+        }
+        let result = super::sweep::run_cell(cmd);
+        if let Err(e) = &result {
+            warn!(run = i, ?side, "run failed: {e}");
+        }
+        output.runs.push(Run { side, result });
+    }
+
+    match diff(&output.runs, args.runtime.into()) {
+        Ok(diff) => {
+            for m in &diff {
+                info!(
+                    "{}: A={:.1} B={:.1} delta={:+.1} regression={:+.2}%",
+                    m.metric, m.a, m.b, m.delta, m.regression_pct
+                );
+            }
+            output.diff = Some(diff);
+        }
+        Err(e) => warn!("cannot compare the runs: {e:#}"),
+    }
+
+    let output = serde_json::to_string_pretty(&output).unwrap();
+    println!("{output}");
+
+    Ok(())
+}
+
+/// Throughput in requests per second, latencies in microseconds.
+fn metrics(output: &serde_json::Value, runtime: Duration) -> anyhow::Result<Vec<(String, f64)>> {
+    let total = &output["total"];
+    let duration_micros = |value: &serde_json::Value| -> anyhow::Result<f64> {
+        let value = value.as_str().context("expected a duration")?;
+        let duration = humantime::parse_duration(value)
+            .with_context(|| format!("parse duration {value:?}"))?;
+        Ok(duration.as_secs_f64() * 1e6)
+    };
+
+    let request_count = total["request_count"]
+        .as_u64()
+        .context("no total.request_count in benchmark output")?;
+    let mut metrics = vec![
+        (
+            "throughput_rps".to_owned(),
+            request_count as f64 / runtime.as_secs_f64(),
+        ),
+        (
+            "latency_mean_us".to_owned(),
+            duration_micros(&total["latency_mean"])?,
+        ),
+    ];
+    let percentiles = total["latency_percentiles"]
+        .as_object()
+        .context("no total.latency_percentiles in benchmark output")?;
+    for (percentile, value) in percentiles {
+        metrics.push((format!("latency_{percentile}_us"), duration_micros(value)?));
+    }
+    Ok(metrics)
+}
+
+fn diff(runs: &[Run], runtime: Duration) -> anyhow::Result<Vec<MetricDiff>> {
+    // metric => (sum A, sum B), in the order of the metrics of the first run
+    let mut order = Vec::new();
+    let mut sums = BTreeMap::<String, (f64, f64)>::new();
+    for run in runs {
+        let output = run.result.as_ref().map_err(|e| anyhow::anyhow!("{e}"))?;
+        for (metric, value) in metrics(output, runtime)? {
+            let sum = sums.entry(metric.clone()).or_insert_with(|| {
+                order.push(metric);
+                (0.0, 0.0)
+            });
+            match run.side {
+                Side::A => sum.0 += value,
+                Side::B => sum.1 += value,
+            }
+        }
+    }
+
+    let runs_per_side = (runs.len() / 2) as f64;
+    Ok(order
+        .into_iter()
+        .map(|metric| {
+            let (a, b) = sums[&metric];
+            let (a, b) = (a / runs_per_side, b / runs_per_side);
+            let delta = b - a;
+            let relative_pct = if a == 0.0 { 0.0 } else { delta / a * 100.0 };
+            let regression_pct = if metric.starts_with("throughput") {
+                -relative_pct
+            } else {
+                relative_pct
+            };
+            MetricDiff {
+                metric,
+                a,
+                b,
+                delta,
+                regression_pct,
+            }
+        })
+        .collect())
+}
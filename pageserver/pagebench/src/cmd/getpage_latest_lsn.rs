@@ -2,51 +2,167 @@ use anyhow::Context;
 use futures::future::join_all;
 use pageserver::pgdatadir_mapping::key_to_rel_block;
 use pageserver::repository;
-use pageserver_api::key::is_rel_block_key;
-use pageserver_api::models::PagestreamGetPageRequest;
+use pageserver_api::models::{PagestreamGetPageRequest, PagestreamGetPageResponse};
+use pageserver_client::mgmt_api;
+use pageserver_client::page_service::PagestreamClient;
 
 use utils::id::TenantTimelineId;
 use utils::lsn::Lsn;
 
 use rand::prelude::*;
-use tokio::sync::Barrier;
+use tokio::sync::{Barrier, OwnedSemaphorePermit};
 use tokio::task::JoinSet;
-use tracing::{info, instrument};
+use tracing::{error, info, instrument, warn};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::future::Future;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::util::cli::pageservers::{self, Pageserver, Pageservers};
+use crate::util::request_generator::{self, RequestGenerator};
 use crate::util::tokio_thread_local_stats::AllThreadLocalStats;
-use crate::util::{request_stats, tokio_thread_local_stats};
+use crate::util::{
+    affinity, auth, client_usage, connstring, interval_stats, keyspace_filter, metrics, ramp,
+    request_stats, rng, rtt, run_metadata, server_metrics, soak, spool, stationarity,
+    tokio_thread_local_stats, verify,
+};
 
-/// GetPage@LatestLSN, uniformly distributed across the compute-accessible keyspace.
+/// GetPage@LatestLSN, distributed across the compute-accessible keyspace.
 #[derive(clap::Parser)]
 pub(crate) struct Args {
-    #[clap(long, default_value = "http://localhost:9898")]
-    mgmt_api_endpoint: String,
-    #[clap(long, default_value = "postgres://postgres@localhost:64000")]
-    page_service_connstring: String,
-    #[clap(long)]
-    pageserver_jwt: Option<String>,
+    #[clap(flatten)]
+    pageservers: pageservers::Args,
+    #[clap(flatten)]
+    auth: auth::Args,
     #[clap(long, default_value = "1")]
     num_clients: NonZeroUsize,
     #[clap(long)]
     runtime: Option<humantime::Duration>,
     #[clap(long)]
     per_target_rate_limit: Option<usize>,
+    /// Issue requests on a fixed schedule at this aggregate rate across all targets (open loop),
+    /// instead of as fast as the pageserver responds. The output then reports the queueing delay,
+    /// i.e., how late the requests were started relative to their schedule, separately from
+    /// the service latency.
+    #[clap(long, conflicts_with = "per_target_rate_limit", value_parser = parse_rate)]
+    target_rps: Option<f64>,
     /// Probability for sending `latest=true` in the request (uniform distribution).
+    /// Only applies to `--lsn-distribution=latest`.
     #[clap(long, default_value = "1")]
     req_latest_probability: f64,
+    /// The LSNs to request the pages at:
+    /// - `latest`: the timeline's last record LSN at the start of the benchmark.
+    /// - `uniform-over-history`: uniformly distributed between the timeline's GC cutoff and
+    ///   its last record LSN, so that reconstruction has to traverse delta layers. GC must
+    ///   not advance the cutoff during the run.
+    /// - `fixed=<lsn>`: the same LSN for all requests, useful with a single target.
+    #[clap(long, default_value = "latest")]
+    lsn_distribution: LsnDistribution,
+    #[clap(flatten)]
+    generator: request_generator::Args,
+    #[clap(flatten)]
+    keyspace_filter: keyspace_filter::Args,
     #[clap(long)]
     limit_to_first_n_targets: Option<usize>,
+    /// Also report the request count and latencies of each target, e.g., to see which tenants
+    /// suffered in a multi-tenant benchmark.
+    #[clap(long)]
+    per_target_breakdown: bool,
+    /// If a target timeline is deleted or its tenant detached during the run, stop sending
+    /// requests to it and record the event in the output, instead of aborting the benchmark.
+    #[clap(long)]
+    tolerate_target_churn: bool,
+    /// On the first getpage error, write a diagnostics dump to `--fail-fast-diagnostics-path`
+    /// and exit with a non-zero status. Implies no retries and no error budget.
+    #[clap(long)]
+    fail_fast: bool,
+    #[clap(long, default_value = "pagebench-diagnostics.json")]
+    fail_fast_diagnostics_path: PathBuf,
+    /// Retry a failed getpage request this many times, each on a new connection. The request's
+    /// latency includes its retries.
+    #[clap(long, default_value = "3")]
+    max_retries: u32,
+    /// Wait this long before the first retry of a request, doubling for each further retry up
+    /// to 5s, or this, if longer.
+    #[clap(long, default_value = "10ms")]
+    retry_backoff: humantime::Duration,
+    /// The number of requests that may still fail after their retries. The benchmark skips them
+    /// and exits with a non-zero status once more requests failed.
+    #[clap(long, default_value = "0")]
+    error_budget: u64,
+    /// Open a new page_service connection after this many requests, to benchmark the
+    /// pageserver's connection accept path. The output then reports the latencies of connection
+    /// establishment and of the pagestream handshake, which are not part of the requests'
+    /// latencies.
+    #[clap(long)]
+    requests_per_connection: Option<NonZeroUsize>,
+    /// Keep up to this many getpage requests in flight on each connection, sending the next ones
+    /// before the responses to the previous ones arrived. A request's latency is then measured
+    /// from sending it to receiving its response, including the wait for the requests ahead of
+    /// it. Above 1, failed requests are not retried: an error fails all requests in flight on the
+    /// connection.
+    #[clap(long, default_value = "1")]
+    pipeline_depth: NonZeroUsize,
+    /// Limit the requests in flight, including those queued for their target's connection, and
+    /// raise the limit step by step: `start=<n>,step=<n>,interval=<duration>,max=<n>`. The
+    /// output then reports each step as `ramp`. The requests in flight are also limited by the
+    /// number of targets times `--pipeline-depth`.
+    #[clap(long, conflicts_with_all = ["per_target_rate_limit", "target_rps"])]
+    ramp: Option<ramp::Spec>,
+    /// Move the latency histograms to this file every `--spool-interval`, for long runs.
+    /// The final output is then computed from the file, which also survives a crash.
+    #[clap(long)]
+    spool_path: Option<PathBuf>,
+    #[clap(long, default_value = "60s")]
+    spool_interval: humantime::Duration,
+    /// Write the throughput and latency percentiles of every `--interval-stats-interval` to
+    /// this file, to see how they change over the run, e.g., during compactions.
+    #[clap(long)]
+    interval_stats_out: Option<PathBuf>,
+    #[clap(long, value_enum, default_value = "json-lines")]
+    interval_stats_format: interval_stats::Format,
+    #[clap(long, default_value = "1s")]
+    interval_stats_interval: humantime::Duration,
+    #[clap(flatten)]
+    stationarity: stationarity::Args,
+    #[clap(flatten)]
+    soak: soak::Args,
+    #[clap(flatten)]
+    verify: verify::Args,
+    /// Write the latency histogram of `total` to this file, as an HdrHistogram interval log.
+    #[clap(long)]
+    histogram_out: Option<PathBuf>,
+    /// Serve live request counters and latency histograms at `/metrics` on this address.
+    #[clap(long)]
+    prometheus_listen: Option<std::net::SocketAddr>,
+    #[clap(flatten)]
+    server_metrics: server_metrics::Args,
+    #[clap(flatten)]
+    rtt: rtt::Args,
+    #[clap(flatten)]
+    client_usage: client_usage::Args,
+    #[clap(flatten)]
+    affinity: affinity::Args,
+    #[clap(flatten)]
+    rng: rng::Args,
     targets: Option<Vec<TenantTimelineId>>,
 }
 
+fn parse_rate(s: &str) -> anyhow::Result<f64> {
+    let rate = s.parse::<f64>()?;
+    if !(rate.is_finite() && rate > 0.0) {
+        anyhow::bail!("{rate} is not a positive number");
+    }
+    // the interval between the requests
+    Duration::try_from_secs_f64(1.0 / rate).with_context(|| format!("{rate} is too low"))?;
+    Ok(rate)
+}
+
 #[derive(Debug, Default)]
 struct LiveStats {
     completed_requests: AtomicU64,
@@ -58,10 +174,51 @@ impl LiveStats {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    Connect,
+    StartPagestream,
+    Getpage,
+}
+
+/// The errors of all clients, see `--max-retries` and `--error-budget`.
+#[derive(Default)]
+struct Errors {
+    /// All failed attempts, including those that were retried.
+    by_kind: Mutex<BTreeMap<ErrorKind, u64>>,
+    retries: AtomicU64,
+    failed_requests: AtomicU64,
+}
+
+impl Errors {
+    fn record(&self, kind: ErrorKind) {
+        *self.by_kind.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Counts a request that failed after its retries. Returns whether the run is still within
+    /// the error budget.
+    fn request_failed(&self, error_budget: u64) -> bool {
+        let failed = self.failed_requests.fetch_add(1, Ordering::Relaxed) + 1;
+        failed <= error_budget
+    }
+
+    fn output(&self, error_budget: u64) -> ErrorsOutput {
+        ErrorsOutput {
+            by_kind: self.by_kind.lock().unwrap().clone(),
+            retries: self.retries.load(Ordering::Relaxed),
+            failed_requests: self.failed_requests.load(Ordering::Relaxed),
+            error_budget,
+        }
+    }
+}
+
 #[derive(Clone)]
 struct KeyRange {
     timeline: TenantTimelineId,
     timeline_lsn: Lsn,
+    /// The oldest LSN we can request pages at, for `--lsn-distribution=uniform-over-history`.
+    history_start: Lsn,
     start: i128,
     end: i128,
 }
@@ -70,86 +227,390 @@ impl KeyRange {
     fn len(&self) -> i128 {
         self.end - self.start
     }
+
+    /// The `latest` and `lsn` fields of a request in this range.
+    fn request_lsn(&self, args: &Args, rng: &mut impl Rng) -> (bool, Lsn) {
+        match args.lsn_distribution {
+            LsnDistribution::Latest => {
+                (rng.gen_bool(args.req_latest_probability), self.timeline_lsn)
+            }
+            LsnDistribution::UniformOverHistory => {
+                (false, rng.gen_range(self.history_start..=self.timeline_lsn))
+            }
+            LsnDistribution::Fixed(lsn) => (false, lsn),
+        }
+    }
+}
+
+/// The rel block key ranges of one target, as a single sequence of keys.
+struct TimelineKeyspace {
+    ranges: Vec<KeyRange>,
+    /// The cumulative key count up to and including each range.
+    cumulative_len: Vec<i128>,
+    /// Only used by the one task that issues the requests to this target.
+    generator: Mutex<Box<dyn RequestGenerator>>,
+}
+
+impl TimelineKeyspace {
+    fn new(ranges: Vec<KeyRange>, generator: Box<dyn RequestGenerator>) -> Self {
+        let cumulative_len = ranges
+            .iter()
+            .scan(0, |sum, r| {
+                *sum += r.len();
+                Some(*sum)
+            })
+            .collect();
+        TimelineKeyspace {
+            ranges,
+            cumulative_len,
+            generator: Mutex::new(generator),
+        }
+    }
+
+    fn len(&self) -> i128 {
+        self.cumulative_len.last().copied().unwrap_or(0)
+    }
+
+    fn timeline(&self) -> TenantTimelineId {
+        self.ranges[0].timeline
+    }
+
+    fn request(&self, args: &Args, rng: &mut impl Rng) -> PagestreamGetPageRequest {
+        let (r, key) = self.sample(rng);
+        let (rel_tag, block_no) =
+            key_to_rel_block(key).expect("we filter non-rel-block keys out above");
+        let (latest, lsn) = r.request_lsn(args, rng);
+        PagestreamGetPageRequest {
+            latest,
+            lsn,
+            rel: rel_tag,
+            blkno: block_no,
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> (&KeyRange, repository::Key) {
+        let len = self.len();
+        let idx = self.generator.lock().unwrap().next_key(rng, len);
+        assert!(
+            (0..len).contains(&idx),
+            "generator returned key {idx} outside of the keyspace of length {len}"
+        );
+        let range_idx = self.cumulative_len.partition_point(|&end| end <= idx);
+        let range = &self.ranges[range_idx];
+        let range_start = self.cumulative_len[range_idx] - range.len();
+        let key = repository::Key::from_i128(range.start + (idx - range_start));
+        (range, key)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum LsnDistribution {
+    Latest,
+    UniformOverHistory,
+    Fixed(Lsn),
+}
+
+impl std::str::FromStr for LsnDistribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(LsnDistribution::Latest),
+            "uniform-over-history" => Ok(LsnDistribution::UniformOverHistory),
+            s => match s.strip_prefix("fixed=") {
+                Some(lsn) => lsn
+                    .parse()
+                    .map(LsnDistribution::Fixed)
+                    .map_err(|_| anyhow::anyhow!("invalid lsn: {lsn}")),
+                None => {
+                    anyhow::bail!("expected latest, uniform-over-history or fixed=<lsn>, got {s:?}")
+                }
+            },
+        }
+    }
 }
 
 #[derive(serde::Serialize)]
 struct Output {
+    #[serde(flatten)]
+    metadata: run_metadata::Metadata,
+    /// Pass as `--seed` to issue the same requests again.
+    seed: u64,
     total: request_stats::Output,
+    /// Only with `--rtt-calibration-samples`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_calibration: Option<rtt::Calibration>,
+    /// `total` minus the round-trip time of `rtt_calibration`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_rtt_adjusted: Option<request_stats::Output>,
+    /// Only with `--target-rps`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queueing_delay: Option<request_stats::Output>,
+    /// Targets that went away during the run, see `--tolerate-target-churn`.
+    target_churn: Vec<TargetChurnEvent>,
+    /// Only with `--per-target-breakdown`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    per_target: Option<Vec<TargetOutput>>,
+    /// Only with `--cpus` or `--numa-node`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    affinity: Option<affinity::Affinity>,
+    errors: ErrorsOutput,
+    /// Only with `--requests-per-connection`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connections: Option<ConnectionsOutput>,
+    /// Only with `--stationarity-check`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stationarity: Option<stationarity::Output>,
+    /// Only with `--ramp`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ramp: Option<Vec<ramp::Step>>,
+    /// Only with `--verify`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification: Option<verify::Output>,
+    /// Only with `--server-metrics`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_metrics: Option<server_metrics::Output>,
+    client_usage: client_usage::Output,
+}
+
+#[derive(serde::Serialize)]
+struct ConnectionsOutput {
+    connect: request_stats::Output,
+    pagestream_handshake: request_stats::Output,
+}
+
+/// See `--requests-per-connection`.
+struct ConnectionStats {
+    connect: request_stats::Stats,
+    pagestream_handshake: request_stats::Stats,
+}
+
+impl ConnectionStats {
+    fn new() -> Self {
+        ConnectionStats {
+            connect: request_stats::Stats::new(),
+            pagestream_handshake: request_stats::Stats::new(),
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.connect.add(&other.connect);
+        self.pagestream_handshake.add(&other.pagestream_handshake);
+    }
+
+    fn output(&self) -> ConnectionsOutput {
+        ConnectionsOutput {
+            connect: self.connect.output(),
+            pagestream_handshake: self.pagestream_handshake.output(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ErrorsOutput {
+    /// Failed attempts, including those that were retried.
+    by_kind: BTreeMap<ErrorKind, u64>,
+    retries: u64,
+    /// Requests that failed after their retries and are not part of the `total`.
+    failed_requests: u64,
+    error_budget: u64,
+}
+
+#[derive(serde::Serialize)]
+struct TargetOutput {
+    timeline: TenantTimelineId,
+    #[serde(flatten)]
+    stats: request_stats::Output,
+}
+
+#[derive(serde::Serialize)]
+struct TargetChurnEvent {
+    timeline: TenantTimelineId,
+    /// Time since the start of the measurement at which the target's client stopped.
+    #[serde(with = "humantime_serde")]
+    elapsed: Duration,
+    error: String,
+}
+
+struct Work {
+    req: PagestreamGetPageRequest,
+    /// When the request should have been issued, with `--target-rps`.
+    scheduled_at: Option<Instant>,
+    /// Held until the request completed, with `--ramp`.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// The parameters of a getpage request, for the `--fail-fast` diagnostics.
+#[derive(Debug, Clone, serde::Serialize)]
+struct RequestParams {
+    latest: bool,
+    lsn: Lsn,
+    rel: String,
+    blkno: u32,
+}
+
+impl From<&PagestreamGetPageRequest> for RequestParams {
+    fn from(req: &PagestreamGetPageRequest) -> Self {
+        RequestParams {
+            latest: req.latest,
+            lsn: req.lsn,
+            rel: req.rel.to_string(),
+            blkno: req.blkno,
+        }
+    }
+}
+
+/// How many of a client's most recent request latencies to keep for the `--fail-fast` diagnostics.
+const RECENT_LATENCIES: usize = 100;
+
+/// See `--retry-backoff`.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct RecentRequests {
+    /// The request that was in flight when the client failed, if any.
+    in_flight: Option<RequestParams>,
+    latencies: VecDeque<Duration>,
+}
+
+#[derive(serde::Serialize)]
+struct FailFastDiagnostics {
+    timeline: TenantTimelineId,
+    error: String,
+    failed_request: Option<RequestParams>,
+    /// Oldest first.
+    recent_latencies_micros: Vec<u128>,
+    /// The pageserver's `/v1/status`, or the error we got when fetching it.
+    pageserver_status: Result<pageserver_api::models::StatusResponse, String>,
+    /// The timeline detail, or the error we got when fetching it.
+    timeline_info: Result<pageserver_api::models::TimelineInfo, String>,
 }
 
 tokio_thread_local_stats::declare!(STATS: request_stats::Stats);
 
 pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let affinity = args.affinity.apply()?;
     tokio_thread_local_stats::main!(STATS, move |thread_local_stats| {
-        main_impl(args, thread_local_stats)
+        main_impl(args, affinity, thread_local_stats)
     })
 }
 
 async fn main_impl(
     args: Args,
+    affinity: Option<affinity::Affinity>,
     all_thread_local_stats: AllThreadLocalStats<request_stats::Stats>,
 ) -> anyhow::Result<()> {
     let args: &'static Args = Box::leak(Box::new(args));
+    let run = run_metadata::Recorder::start();
 
-    let mgmt_api_client = Arc::new(pageserver_client::mgmt_api::Client::new(
-        args.mgmt_api_endpoint.clone(),
-        args.pageserver_jwt.as_deref(),
-    ));
-
-    // discover targets
-    let timelines: Vec<TenantTimelineId> = crate::util::cli::targets::discover(
-        &mgmt_api_client,
-        crate::util::cli::targets::Spec {
-            limit_to_first_n_targets: args.limit_to_first_n_targets,
-            targets: args.targets.clone(),
-        },
-    )
-    .await?;
+    // discover targets, and their pageservers
+    let (pageservers, timelines) = args
+        .pageservers
+        .resolve(
+            &args.auth,
+            crate::util::cli::targets::Spec {
+                limit_to_first_n_targets: args.limit_to_first_n_targets,
+                targets: args.targets.clone(),
+            },
+        )
+        .await?;
+    let pageservers: &'static Pageservers = Box::leak(Box::new(pageservers));
 
     let mut js = JoinSet::new();
     for timeline in &timelines {
         js.spawn({
-            let mgmt_api_client = Arc::clone(&mgmt_api_client);
+            let mgmt_api_client = Arc::clone(&pageservers.get(*timeline).mgmt_api_client);
             let timeline = *timeline;
             async move {
                 let partitioning = mgmt_api_client
                     .keyspace(timeline.tenant_id, timeline.timeline_id)
                     .await?;
                 let lsn = partitioning.at_lsn;
+                let history_start = match args.lsn_distribution {
+                    LsnDistribution::UniformOverHistory => {
+                        let info = mgmt_api_client
+                            .timeline_info(timeline.tenant_id, timeline.timeline_id)
+                            .await?;
+                        std::cmp::min(
+                            std::cmp::max(info.latest_gc_cutoff_lsn, info.initdb_lsn),
+                            lsn,
+                        )
+                    }
+                    LsnDistribution::Latest | LsnDistribution::Fixed(_) => lsn,
+                };
 
-                let ranges = partitioning
-                    .keys
-                    .ranges
-                    .iter()
-                    .filter_map(|r| {
-                        let start = r.start;
-                        let end = r.end;
-                        // filter out non-relblock keys
-                        match (is_rel_block_key(&start), is_rel_block_key(&end)) {
-                            (true, true) => Some(KeyRange {
-                                timeline,
-                                timeline_lsn: lsn,
-                                start: start.to_i128(),
-                                end: end.to_i128(),
-                            }),
-                            (true, false) | (false, true) => {
-                                unimplemented!("split up range")
-                            }
-                            (false, false) => None,
-                        }
+                let ranges = args
+                    .keyspace_filter
+                    .rel_block_ranges(&partitioning.keys.ranges)
+                    .into_iter()
+                    .map(|r| KeyRange {
+                        timeline,
+                        timeline_lsn: lsn,
+                        history_start,
+                        start: r.start,
+                        end: r.end,
                     })
                     .collect::<Vec<_>>();
 
-                anyhow::Ok(ranges)
+                anyhow::Ok((timeline, ranges))
             }
         });
     }
-    let mut all_ranges: Vec<KeyRange> = Vec::new();
+    let mut all_keyspaces: Vec<TimelineKeyspace> = Vec::new();
     while let Some(res) = js.join_next().await {
-        all_ranges.extend(res.unwrap().unwrap());
+        let (timeline, ranges) = res.unwrap().unwrap();
+        let keyspace = TimelineKeyspace::new(ranges, args.generator.new_generator()?);
+        if keyspace.len() > 0 {
+            all_keyspaces.push(keyspace);
+        } else {
+            warn!("no keys to request in {timeline}, not benchmarking it");
+        }
+    }
+    if all_keyspaces.is_empty() {
+        anyhow::bail!("no keys to request in any of the targets, check the keyspace filter");
+    }
+    // the order in which the keyspaces came in is random, it affects the choice of timeline
+    all_keyspaces.sort_by_key(|keyspace| keyspace.timeline());
+    // only the targets with keys to request get a client task and a work sender
+    let timelines = all_keyspaces
+        .iter()
+        .map(|keyspace| keyspace.timeline())
+        .collect::<Vec<_>>();
+    let seed = args.rng.seed();
+
+    let spool = args
+        .spool_path
+        .as_deref()
+        .map(spool::Spool::create)
+        .transpose()?;
+    let interval_stats = args
+        .interval_stats_out
+        .as_deref()
+        .map(|path| interval_stats::Writer::create(path, args.interval_stats_format))
+        .transpose()?;
+    let stationarity = stationarity::Tracker::new(&args.stationarity);
+    let ramp = args.ramp.map(ramp::Ramp::new);
+    let ramp_semaphore = ramp.as_ref().map(|ramp| ramp.semaphore());
+    if let Some(spec) = &args.ramp {
+        let max_in_flight = timelines.len() * args.pipeline_depth.get();
+        if spec.max() > max_in_flight {
+            warn!(
+                "the ramp goes up to {} requests in flight, but {} targets with a pipeline depth \
+                 of {} only have {max_in_flight}",
+                spec.max(),
+                timelines.len(),
+                args.pipeline_depth
+            );
+        }
+    }
+
+    if let Some(listen) = args.prometheus_listen {
+        metrics::spawn_server(listen)?;
     }
 
     let live_stats = Arc::new(LiveStats::default());
+    let errors = Arc::new(Errors::default());
+    let verifier = verify::Verifier::new(&args.verify)?.map(Arc::new);
 
     let num_client_tasks = timelines.len();
     let num_live_stats_dump = 1;
@@ -186,43 +647,68 @@ async fn main_impl(
         tasks.push(tokio::spawn(client(
             args,
             *tl,
+            pageservers.get(*tl),
             Arc::clone(&start_work_barrier),
             receiver,
             Arc::clone(&all_work_done_barrier),
             Arc::clone(&live_stats),
+            Arc::clone(&errors),
+            verifier.clone(),
         )));
     }
 
     let work_sender: Pin<Box<dyn Send + Future<Output = ()>>> = match args.per_target_rate_limit {
         None => Box::pin(async move {
             let weights = rand::distributions::weighted::WeightedIndex::new(
-                all_ranges.iter().map(|v| v.len()),
+                all_keyspaces.iter().map(|v| v.len()),
             )
             .unwrap();
+            let mut rng = seed.rng("all targets");
+
+            // With `--target-rps`, the schedule is independent of how fast the clients are.
+            // If a client's channel is full we fall behind, but since we record the queueing
+            // delay relative to the schedule, that shows up in the results.
+            let mut ticker = args.target_rps.map(|target_rps| {
+                let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / target_rps));
+                ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Burst);
+                ticker
+            });
 
             start_work_barrier.wait().await;
 
-            loop {
-                let (timeline, req) = {
-                    let mut rng = rand::thread_rng();
-                    let r = &all_ranges[weights.sample(&mut rng)];
-                    let key: i128 = rng.gen_range(r.start..r.end);
-                    let key = repository::Key::from_i128(key);
-                    let (rel_tag, block_no) =
-                        key_to_rel_block(key).expect("we filter non-rel-block keys out above");
-                    (
-                        r.timeline,
-                        PagestreamGetPageRequest {
-                            latest: rng.gen_bool(args.req_latest_probability),
-                            lsn: r.timeline_lsn,
-                            rel: rel_tag,
-                            blkno: block_no,
-                        },
-                    )
+            while !work_senders.is_empty() {
+                let scheduled_at = match &mut ticker {
+                    Some(ticker) => Some(ticker.tick().await.into_std()),
+                    None => None,
+                };
+                let permit = match &ramp_semaphore {
+                    Some(semaphore) => Some(
+                        Arc::clone(semaphore)
+                            .acquire_owned()
+                            .await
+                            .expect("the semaphore is never closed"),
+                    ),
+                    None => None,
+                };
+                let keyspace = &all_keyspaces[weights.sample(&mut rng)];
+                let (timeline, req) = (keyspace.timeline(), keyspace.request(args, &mut rng));
+                let Some(sender) = work_senders.get(&timeline) else {
+                    // the target went away, see `--tolerate-target-churn`
+                    continue;
                 };
-                let sender = work_senders.get(&timeline).unwrap();
                 // TODO: what if this blocks?
-                sender.send(req).await.ok().unwrap();
+                let work = Work {
+                    req,
+                    scheduled_at,
+                    permit,
+                };
+                if sender.send(work).await.is_err() {
+                    assert!(
+                        args.tolerate_target_churn,
+                        "client for {timeline} exited prematurely"
+                    );
+                    work_senders.remove(&timeline);
+                }
             }
         }),
         Some(rps_limit) => Box::pin(async move {
@@ -233,17 +719,13 @@ async fn main_impl(
             )
                 -> Pin<Box<dyn Send + Future<Output = ()>>> = &|timeline| {
                 let sender = work_senders.get(&timeline).unwrap();
-                let ranges: Vec<KeyRange> = all_ranges
+                let keyspace = all_keyspaces
                     .iter()
-                    .filter(|r| r.timeline == timeline)
-                    .cloned()
-                    .collect();
-                let weights = rand::distributions::weighted::WeightedIndex::new(
-                    ranges.iter().map(|v| v.len()),
-                )
-                .unwrap();
+                    .find(|k| k.timeline() == timeline)
+                    .expect("no keyspace for timeline");
 
                 Box::pin(async move {
+                    let mut rng = seed.rng(timeline);
                     let mut ticker = tokio::time::interval(period);
                     ticker.set_missed_tick_behavior(
                         /* TODO review this choice */
@@ -251,21 +733,20 @@ async fn main_impl(
                     );
                     loop {
                         ticker.tick().await;
-                        let req = {
-                            let mut rng = rand::thread_rng();
-                            let r = &ranges[weights.sample(&mut rng)];
-                            let key: i128 = rng.gen_range(r.start..r.end);
-                            let key = repository::Key::from_i128(key);
-                            let (rel_tag, block_no) = key_to_rel_block(key)
-                                .expect("we filter non-rel-block keys out above");
-                            PagestreamGetPageRequest {
-                                latest: rng.gen_bool(args.req_latest_probability),
-                                lsn: r.timeline_lsn,
-                                rel: rel_tag,
-                                blkno: block_no,
-                            }
+                        let req = keyspace.request(args, &mut rng);
+                        let work = Work {
+                            req,
+                            scheduled_at: None,
+                            permit: None,
                         };
-                        sender.send(req).await.ok().unwrap();
+                        if sender.send(work).await.is_err() {
+                            assert!(
+                                args.tolerate_target_churn,
+                                "client for {timeline} exited prematurely"
+                            );
+                            // the target went away, see `--tolerate-target-churn`
+                            return;
+                        }
                     }
                 })
             };
@@ -281,71 +762,799 @@ async fn main_impl(
         }),
     };
 
-    if let Some(runtime) = args.runtime {
+    // with several pageservers, the calibration and the server metrics are of the first
+    let rtt_calibration = rtt::calibrate(
+        &args.rtt,
+        &connstring::host_port(&pageservers.first().page_service_connstring)?,
+    )
+    .await?;
+    let server_metrics = server_metrics::Snapshots::start(
+        &args.server_metrics,
+        &pageservers.first().mgmt_api_endpoint,
+    )
+    .await?;
+    let harvest = if spool.is_some()
+        || interval_stats.is_some()
+        || stationarity.is_some()
+        || ramp.is_some()
+        || args.soak.enabled()
+    {
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+        let task = tokio::spawn(harvest_loop(
+            args,
+            spool,
+            interval_stats,
+            stationarity,
+            ramp,
+            Arc::clone(&all_thread_local_stats),
+            stop_rx,
+        ));
+        Some((stop_tx, task))
+    } else {
+        None
+    };
+    let measurement_start = (Instant::now(), SystemTime::now());
+    let client_usage = client_usage::Sampler::start(&args.client_usage);
+    let work_sender_terminated = if let Some(runtime) = args.runtime {
         match tokio::time::timeout(runtime.into(), work_sender).await {
-            Ok(()) => unreachable!("work sender never terminates"),
+            Ok(()) => true,
             Err(_timeout) => {
                 // this implicitly drops the work_senders, making all the clients exit
+                false
             }
         }
     } else {
         work_sender.await;
-        unreachable!("work sender never terminates");
+        true
+    };
+    let measurement_elapsed = measurement_start.0.elapsed();
+    let client_usage = client_usage.finish().await?;
+    if work_sender_terminated {
+        // the work sender only terminates on its own once all targets went away
+        assert!(args.tolerate_target_churn);
+        warn!("all targets went away, stopping the benchmark early");
     }
 
-    for t in tasks {
-        t.await.unwrap();
+    let mut target_churn = Vec::new();
+    let mut queueing_delay = request_stats::Stats::new();
+    let mut per_target = args.per_target_breakdown.then(Vec::new);
+    let mut connections = args.requests_per_connection.map(|_| ConnectionStats::new());
+    for (timeline, t) in timelines.iter().zip(tasks) {
+        let ClientOutcome {
+            churn,
+            queueing_delay: client_queueing_delay,
+            latency,
+            connections: client_connections,
+        } = t.await.unwrap();
+        queueing_delay.add(&client_queueing_delay);
+        if let (Some(connections), Some(client_connections)) =
+            (&mut connections, client_connections)
+        {
+            connections.add(&client_connections);
+        }
+        if let (Some(per_target), Some(latency)) = (&mut per_target, latency) {
+            per_target.push(TargetOutput {
+                timeline: *timeline,
+                stats: latency.output(),
+            });
+        }
+        if let Some((at, err)) = churn {
+            target_churn.push(TargetChurnEvent {
+                timeline: *timeline,
+                elapsed: at.saturating_duration_since(measurement_start.0),
+                error: format!("{err:#}"),
+            });
+        }
     }
 
-    let output = Output {
-        total: {
+    let server_metrics = match server_metrics {
+        Some(snapshots) => Some(snapshots.finish().await?),
+        None => None,
+    };
+
+    let mut stationarity = None;
+    let mut ramp = None;
+    let total = match harvest {
+        Some((stop_tx, task)) => {
+            let _ = stop_tx.send(());
+            let (total, tracker, harvested_ramp) = task.await.unwrap()?;
+            stationarity = tracker.map(|tracker| tracker.output());
+            ramp = harvested_ramp.map(|ramp| ramp.output());
+            match &args.spool_path {
+                Some(path) => spool::read_aggregate(path)?,
+                None => total,
+            }
+        }
+        None => {
             let mut agg_stats = request_stats::Stats::new();
             for stats in all_thread_local_stats.lock().unwrap().iter() {
                 let stats = stats.lock().unwrap();
                 agg_stats.add(&stats);
             }
-            agg_stats.output()
-        },
+            agg_stats
+        }
+    };
+
+    if let Some(path) = &args.histogram_out {
+        total.write_histogram_log(path, measurement_start.1, measurement_elapsed)?;
+    }
+
+    if let Some(stationarity) = &stationarity {
+        match stationarity.steady_state() {
+            Some(true) => info!("the run was in steady state"),
+            Some(false) => warn!("the run was not in steady state, see stationarity in the output"),
+            None => warn!("the run was too short to check for steady state"),
+        }
+    }
+
+    let verification = verifier.map(|verifier| verifier.finish()).transpose()?;
+    let divergent = verification.as_ref().map_or(0, |v| v.divergent());
+
+    let mgmt_api_endpoints: Vec<&str> = pageservers
+        .iter()
+        .map(|pageserver| pageserver.mgmt_api_endpoint.as_str())
+        .collect();
+    let total = total.output();
+    let output = Output {
+        metadata: run.finish(&mgmt_api_endpoints).await,
+        seed: seed.get(),
+        total_rtt_adjusted: rtt_calibration.as_ref().map(|c| total.minus(c.rtt())),
+        total,
+        rtt_calibration,
+        queueing_delay: args.target_rps.map(|_| queueing_delay.output()),
+        target_churn,
+        per_target,
+        affinity,
+        errors: errors.output(args.error_budget),
+        connections: connections.map(|connections| connections.output()),
+        stationarity,
+        ramp,
+        verification,
+        server_metrics,
+        client_usage,
     };
 
     let output = serde_json::to_string_pretty(&output).unwrap();
     println!("{output}");
 
+    if divergent > 0 {
+        anyhow::bail!("{divergent} reads returned divergent pages, see verification in the output");
+    }
+
     anyhow::Ok(())
 }
 
+/// Periodically moves the thread-local stats into the spool, the interval stats file, the
+/// stationarity tracker, the ramp's current step and the soak period, and advances the ramp.
+/// Flushes the remainder and returns the sum of all intervals, along with the tracker and the
+/// ramp, once `stop` fires.
+async fn harvest_loop(
+    args: &'static Args,
+    mut spool: Option<spool::Spool>,
+    mut interval_stats: Option<interval_stats::Writer>,
+    mut stationarity: Option<stationarity::Tracker>,
+    mut ramp: Option<ramp::Ramp>,
+    all_thread_local_stats: AllThreadLocalStats<request_stats::Stats>,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) -> anyhow::Result<(
+    request_stats::Stats,
+    Option<stationarity::Tracker>,
+    Option<ramp::Ramp>,
+)> {
+    let spool_interval: Duration = args.spool_interval.into();
+    // The spool's intervals are much longer, accumulate them at the interval stats' pace.
+    let tick_interval = if interval_stats.is_some() || stationarity.is_some() || args.soak.enabled()
+    {
+        args.interval_stats_interval.into()
+    } else {
+        spool_interval
+    };
+
+    let mut total = request_stats::Stats::new();
+    let mut harvested = request_stats::Stats::new();
+    let mut spool_pending = request_stats::Stats::new();
+    let mut ticker = tokio::time::interval(tick_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut interval_start = (Instant::now(), SystemTime::now());
+    let mut spool_interval_start = interval_start;
+    if let Some(ramp) = &mut ramp {
+        ramp.start(interval_start.0);
+    }
+    let mut soak = soak::Soak::start(&args.soak, args.interval_stats_format, interval_start)?;
+    loop {
+        let next_ramp_step = ramp.as_ref().and_then(|ramp| ramp.next_step_at());
+        let (stopped, ramp_step) = tokio::select! {
+            _ = ticker.tick() => (false, false),
+            _ = async {
+                match next_ramp_step {
+                    Some(at) => tokio::time::sleep_until(at.into()).await,
+                    None => std::future::pending().await,
+                }
+            } => (false, true),
+            _ = &mut stop => (true, false),
+        };
+
+        harvested.reset();
+        for stats in all_thread_local_stats.lock().unwrap().iter() {
+            let mut stats = stats.lock().unwrap();
+            harvested.add(&stats);
+            stats.reset();
+        }
+        total.add(&harvested);
+        let now = (Instant::now(), SystemTime::now());
+
+        if let Some(interval_stats) = &mut interval_stats {
+            interval_stats.write(interval_start.1, now.0 - interval_start.0, &harvested)?;
+        }
+        // the last interval is cut short by `stop`, it would skew the medians
+        if let (Some(stationarity), false) = (&mut stationarity, stopped) {
+            stationarity.observe(now.0 - interval_start.0, &harvested);
+        }
+        if let Some(ramp) = &mut ramp {
+            ramp.observe(&harvested);
+            if stopped || ramp_step {
+                ramp.finish_step(now.0);
+            }
+            if ramp_step {
+                ramp.advance(now.0);
+            }
+        }
+        if let Some(soak) = &mut soak {
+            soak.observe(interval_start, now, &harvested)?;
+        }
+        if let Some(spool) = &mut spool {
+            spool_pending.add(&harvested);
+            if stopped || now.0 - spool_interval_start.0 >= spool_interval {
+                spool.append(spool_interval_start.1, &spool_pending)?;
+                spool_pending.reset();
+                spool_interval_start = now;
+            }
+        }
+        interval_start = now;
+
+        if stopped {
+            if let Some(soak) = soak {
+                soak.finish(now.0)?;
+            }
+            return Ok((total, stationarity, ramp));
+        }
+    }
+}
+
+struct ClientOutcome {
+    /// The time and error at which the client stopped if the target went away mid-run,
+    /// see `--tolerate-target-churn`.
+    churn: Option<(Instant, anyhow::Error)>,
+    /// See `--target-rps`.
+    queueing_delay: request_stats::Stats,
+    /// The latencies of this client's requests, with `--per-target-breakdown`.
+    latency: Option<request_stats::Stats>,
+    /// With `--requests-per-connection`.
+    connections: Option<ConnectionStats>,
+}
+
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all)]
 async fn client(
     args: &'static Args,
     timeline: TenantTimelineId,
+    pageserver: &'static Pageserver,
     start_work_barrier: Arc<Barrier>,
-    mut work: tokio::sync::mpsc::Receiver<PagestreamGetPageRequest>,
+    mut work: tokio::sync::mpsc::Receiver<Work>,
     all_work_done_barrier: Arc<Barrier>,
     live_stats: Arc<LiveStats>,
-) {
+    errors: Arc<Errors>,
+    verifier: Option<Arc<verify::Verifier>>,
+) -> ClientOutcome {
+    let mut queueing_delay = request_stats::Stats::new();
+    let mut latency = args.per_target_breakdown.then(request_stats::Stats::new);
+    let mut connections = args.requests_per_connection.map(|_| ConnectionStats::new());
     start_work_barrier.wait().await;
 
-    let client = pageserver_client::page_service::Client::new(args.page_service_connstring.clone())
-        .await
-        .unwrap();
-    let mut client = client
-        .pagestream(timeline.tenant_id, timeline.timeline_id)
-        .await
-        .unwrap();
+    let mut recent = RecentRequests::default();
+    let mgmt_api_client = &pageserver.mgmt_api_client;
+    let res = client_loop(
+        args,
+        timeline,
+        pageserver,
+        &mut work,
+        &live_stats,
+        &errors,
+        verifier.as_deref(),
+        &mut recent,
+        &mut queueing_delay,
+        latency.as_mut(),
+        connections.as_mut(),
+    )
+    .await;
+    // dropping the receiver lets the work sender know that we're done
+    drop(work);
 
-    while let Some(req) = work.recv().await {
+    let churn = match res {
+        Ok(()) => None,
+        Err(err) => {
+            if !(args.tolerate_target_churn && target_went_away(mgmt_api_client, timeline).await) {
+                if args.fail_fast {
+                    fail_fast(args, timeline, mgmt_api_client, recent, err).await;
+                }
+                error!("client for {timeline} failed, exiting: {err:?}");
+                std::process::exit(1);
+            }
+            warn!("target went away, stopping its client: {err:#}");
+            Some((Instant::now(), err))
+        }
+    };
+
+    all_work_done_barrier.wait().await;
+
+    ClientOutcome {
+        churn,
+        queueing_delay,
+        latency,
+        connections,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn client_loop(
+    args: &'static Args,
+    timeline: TenantTimelineId,
+    pageserver: &Pageserver,
+    work: &mut tokio::sync::mpsc::Receiver<Work>,
+    live_stats: &LiveStats,
+    errors: &Errors,
+    verifier: Option<&verify::Verifier>,
+    recent: &mut RecentRequests,
+    queueing_delay: &mut request_stats::Stats,
+    mut latency: Option<&mut request_stats::Stats>,
+    mut connections: Option<&mut ConnectionStats>,
+) -> anyhow::Result<()> {
+    if args.pipeline_depth.get() > 1 {
+        return client_loop_pipelined(
+            args,
+            timeline,
+            pageserver,
+            work,
+            live_stats,
+            errors,
+            verifier,
+            recent,
+            queueing_delay,
+            latency,
+            connections,
+        )
+        .await;
+    }
+
+    // connected on the first request, and again after each error
+    let mut client: Option<PagestreamClient> = None;
+    let mut requests_on_connection = 0;
+
+    while let Some(Work {
+        req,
+        scheduled_at,
+        permit: _permit,
+    }) = work.recv().await
+    {
+        if args.fail_fast {
+            recent.in_flight = Some(RequestParams::from(&req));
+        }
+        if let Some(requests_per_connection) = args.requests_per_connection {
+            if requests_on_connection >= requests_per_connection.get() {
+                if let Some(client) = client.take() {
+                    client.shutdown().await;
+                }
+            }
+            if client.is_none() {
+                requests_on_connection = 0;
+                // connect before the request, so that the request's latency doesn't include it;
+                // if this fails, the request's retries reconnect
+                match connect(pageserver, timeline, connections.as_deref_mut()).await {
+                    Ok(connected) => client = Some(connected),
+                    Err((kind, err)) => {
+                        errors.record(kind);
+                        warn!("failed to open a new connection: {err:#}");
+                    }
+                }
+            }
+            requests_on_connection += 1;
+        }
         let start = Instant::now();
-        client
-            .getpage(req)
+        if let Some(scheduled_at) = scheduled_at {
+            let delay = start.saturating_duration_since(scheduled_at);
+            queueing_delay.observe(delay)?;
+            metrics::observe_queueing_delay(delay);
+        }
+        let response = match getpage_with_retries(
+            args,
+            pageserver,
+            timeline,
+            &mut client,
+            &mut requests_on_connection,
+            connections.as_deref_mut(),
+            &req,
+            errors,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                skip_failed_requests(args, timeline, &pageserver.mgmt_api_client, errors, err, 1)
+                    .await?;
+                continue;
+            }
+        };
+        request_completed(
+            args,
+            live_stats,
+            recent,
+            latency.as_deref_mut(),
+            start.elapsed(),
+        )?;
+        if let Some(verifier) = verifier {
+            verifier.check(timeline, &req, &response.page);
+        }
+    }
+
+    Ok(())
+}
+
+/// [`client_loop`] with a `--pipeline-depth` above 1.
+#[allow(clippy::too_many_arguments)]
+async fn client_loop_pipelined(
+    args: &'static Args,
+    timeline: TenantTimelineId,
+    pageserver: &Pageserver,
+    work: &mut tokio::sync::mpsc::Receiver<Work>,
+    live_stats: &LiveStats,
+    errors: &Errors,
+    verifier: Option<&verify::Verifier>,
+    recent: &mut RecentRequests,
+    queueing_delay: &mut request_stats::Stats,
+    mut latency: Option<&mut request_stats::Stats>,
+    mut connections: Option<&mut ConnectionStats>,
+) -> anyhow::Result<()> {
+    let depth = args.pipeline_depth.get();
+    let mut client: Option<PagestreamClient> = None;
+    let mut requests_on_connection = 0;
+    // the requests awaiting their responses, oldest first
+    let mut in_flight: VecDeque<InFlight> = VecDeque::with_capacity(depth);
+    let mut work_done = false;
+
+    loop {
+        // Fill the pipeline with the work that is ready. We only wait for work if there are no
+        // responses to wait for.
+        while !work_done && in_flight.len() < depth {
+            let next = if in_flight.is_empty() {
+                work.recv().await
+            } else {
+                match work.try_recv() {
+                    Ok(next) => Some(next),
+                    Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break,
+                    Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => None,
+                }
+            };
+            let Some(Work {
+                req,
+                scheduled_at,
+                permit,
+            }) = next
+            else {
+                work_done = true;
+                break;
+            };
+
+            if in_flight.is_empty()
+                && args
+                    .requests_per_connection
+                    .is_some_and(|n| requests_on_connection >= n.get())
+            {
+                if let Some(client) = client.take() {
+                    client.shutdown().await;
+                }
+            }
+            if client.is_none() {
+                match connect(pageserver, timeline, connections.as_deref_mut()).await {
+                    Ok(connected) => {
+                        client = Some(connected);
+                        requests_on_connection = 0;
+                    }
+                    Err((kind, err)) => {
+                        errors.record(kind);
+                        if args.fail_fast {
+                            recent.in_flight = Some(RequestParams::from(&req));
+                        }
+                        skip_failed_requests(
+                            args,
+                            timeline,
+                            &pageserver.mgmt_api_client,
+                            errors,
+                            err,
+                            1,
+                        )
+                        .await?;
+                        continue;
+                    }
+                }
+            }
+            let conn = client.as_mut().expect("connected above");
+
+            let start = Instant::now();
+            if let Some(scheduled_at) = scheduled_at {
+                let delay = start.saturating_duration_since(scheduled_at);
+                queueing_delay.observe(delay)?;
+                metrics::observe_queueing_delay(delay);
+            }
+            let params = args.fail_fast.then(|| RequestParams::from(&req));
+            let verify_req = verifier.is_some().then(|| req.clone());
+            if let Err(err) = conn.send_getpage(req).await {
+                // the requests in flight are lost with the connection
+                client = None;
+                errors.record(ErrorKind::Getpage);
+                if args.fail_fast {
+                    recent.in_flight = params;
+                }
+                let failed = in_flight.len() + 1;
+                in_flight.clear();
+                let err = err.context(format!("getpage for {timeline}"));
+                skip_failed_requests(
+                    args,
+                    timeline,
+                    &pageserver.mgmt_api_client,
+                    errors,
+                    err,
+                    failed,
+                )
+                .await?;
+                continue;
+            }
+            requests_on_connection += 1;
+            in_flight.push_back(InFlight {
+                start,
+                params,
+                verify_req,
+                _permit: permit,
+            });
+        }
+
+        if in_flight.is_empty() {
+            // we only stop filling the pipeline on an empty one once the work is done
+            assert!(work_done);
+            return Ok(());
+        }
+
+        let conn = client.as_mut().expect("requests are in flight");
+        match conn.recv_getpage().await {
+            Ok(response) => {
+                let request = in_flight.pop_front().expect("requests are in flight");
+                request_completed(
+                    args,
+                    live_stats,
+                    recent,
+                    latency.as_deref_mut(),
+                    request.start.elapsed(),
+                )?;
+                if let (Some(verifier), Some(req)) = (verifier, &request.verify_req) {
+                    verifier.check(timeline, req, &response.page);
+                }
+            }
+            Err(err) => {
+                client = None;
+                errors.record(ErrorKind::Getpage);
+                if args.fail_fast {
+                    recent.in_flight = in_flight.front().and_then(|r| r.params.clone());
+                }
+                let failed = in_flight.len();
+                in_flight.clear();
+                let err = err.context(format!("getpage for {timeline}"));
+                skip_failed_requests(
+                    args,
+                    timeline,
+                    &pageserver.mgmt_api_client,
+                    errors,
+                    err,
+                    failed,
+                )
+                .await?;
+            }
+        }
+    }
+}
+
+/// A request of [`client_loop_pipelined`] that awaits its response.
+struct InFlight {
+    start: Instant,
+    /// With `--fail-fast`.
+    params: Option<RequestParams>,
+    /// With `--verify`.
+    verify_req: Option<PagestreamGetPageRequest>,
+    /// With `--ramp`, returned with the response.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Records a successful request that took `elapsed`.
+fn request_completed(
+    args: &'static Args,
+    live_stats: &LiveStats,
+    recent: &mut RecentRequests,
+    latency: Option<&mut request_stats::Stats>,
+    elapsed: Duration,
+) -> anyhow::Result<()> {
+    if args.fail_fast {
+        recent.in_flight = None;
+        if recent.latencies.len() == RECENT_LATENCIES {
+            recent.latencies.pop_front();
+        }
+        recent.latencies.push_back(elapsed);
+    }
+    live_stats.inc();
+    metrics::observe_request(elapsed);
+    if let Some(latency) = latency {
+        latency.observe(elapsed)?;
+    }
+    STATS.with(|stats| {
+        stats.borrow().lock().unwrap().observe(elapsed).unwrap();
+    });
+    Ok(())
+}
+
+/// Counts `count` requests that failed with `err` against the `--error-budget`. Returns an error
+/// if the client must stop instead.
+async fn skip_failed_requests(
+    args: &'static Args,
+    timeline: TenantTimelineId,
+    mgmt_api_client: &mgmt_api::Client,
+    errors: &Errors,
+    err: anyhow::Error,
+    count: usize,
+) -> anyhow::Result<()> {
+    if args.tolerate_target_churn && target_went_away(mgmt_api_client, timeline).await {
+        return Err(err);
+    }
+    if args.fail_fast {
+        return Err(err);
+    }
+    for _ in 0..count {
+        if !errors.request_failed(args.error_budget) {
+            return Err(err.context(format!(
+                "error budget of {} failed requests exceeded",
+                args.error_budget
+            )));
+        }
+    }
+    warn!("skipping {count} failed requests: {err:#}");
+    Ok(())
+}
+
+/// Sends `req`, (re)connecting first if there is no connection. Retries on a new connection with
+/// exponential backoff, and returns the last error once `--max-retries` are exhausted. The new
+/// connections are accounted like those of [`client_loop`], with `req` as their first request.
+#[allow(clippy::too_many_arguments)]
+async fn getpage_with_retries(
+    args: &'static Args,
+    pageserver: &Pageserver,
+    timeline: TenantTimelineId,
+    client: &mut Option<PagestreamClient>,
+    requests_on_connection: &mut usize,
+    mut connections: Option<&mut ConnectionStats>,
+    req: &PagestreamGetPageRequest,
+    errors: &Errors,
+) -> anyhow::Result<PagestreamGetPageResponse> {
+    let max_retries = if args.fail_fast { 0 } else { args.max_retries };
+    let mut backoff: Duration = args.retry_backoff.into();
+    let max_backoff = backoff.max(MAX_RETRY_BACKOFF);
+    let mut retry = 0;
+    loop {
+        let res = async {
+            if client.is_none() {
+                *client = Some(connect(pageserver, timeline, connections.as_deref_mut()).await?);
+                *requests_on_connection = 1;
+            }
+            let client = client.as_mut().expect("connected above");
+            client.getpage(req.clone()).await.map_err(|e| {
+                (
+                    ErrorKind::Getpage,
+                    e.context(format!("getpage for {timeline}")),
+                )
+            })
+        }
+        .await;
+        let (kind, err) = match res {
+            Ok(response) => return Ok(response),
+            Err(e) => e,
+        };
+        // the pageserver closes the connection on errors
+        *client = None;
+        errors.record(kind);
+        if retry == max_retries {
+            return Err(err);
+        }
+
+        retry += 1;
+        errors.retries.fetch_add(1, Ordering::Relaxed);
+        warn!("retrying request in {backoff:?}, retry {retry}/{max_retries}: {err:#}");
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2).min(max_backoff);
+    }
+}
+
+/// Records the latencies of the two steps in `stats`, if any.
+async fn connect(
+    pageserver: &Pageserver,
+    timeline: TenantTimelineId,
+    stats: Option<&mut ConnectionStats>,
+) -> Result<PagestreamClient, (ErrorKind, anyhow::Error)> {
+    let start = Instant::now();
+    let client =
+        pageserver_client::page_service::Client::new(pageserver.page_service_connstring.clone())
             .await
-            .with_context(|| format!("getpage for {timeline}"))
+            .map_err(|e| (ErrorKind::Connect, e.context("connect to page service")))?;
+    let connected = Instant::now();
+    let client = client
+        .pagestream(timeline.tenant_id, timeline.timeline_id)
+        .await
+        .map_err(|e| {
+            (
+                ErrorKind::StartPagestream,
+                e.context(format!("start pagestream for {timeline}")),
+            )
+        })?;
+    if let Some(stats) = stats {
+        stats.connect.observe(connected - start).unwrap();
+        stats
+            .pagestream_handshake
+            .observe(connected.elapsed())
             .unwrap();
-        let elapsed = start.elapsed();
-        live_stats.inc();
-        STATS.with(|stats| {
-            stats.borrow().lock().unwrap().observe(elapsed).unwrap();
-        });
     }
+    Ok(client)
+}
 
-    all_work_done_barrier.wait().await;
+/// Distinguishes a timeline deletion or tenant detach from other getpage failures.
+async fn target_went_away(mgmt_api_client: &mgmt_api::Client, timeline: TenantTimelineId) -> bool {
+    match mgmt_api_client
+        .timeline_info(timeline.tenant_id, timeline.timeline_id)
+        .await
+    {
+        Ok(_) => false,
+        Err(mgmt_api::Error::ApiError(status, _)) => status == reqwest::StatusCode::NOT_FOUND,
+        Err(_) => false,
+    }
+}
+
+/// Writes the `--fail-fast` diagnostics for the first getpage error and exits the process.
+async fn fail_fast(
+    args: &'static Args,
+    timeline: TenantTimelineId,
+    mgmt_api_client: &mgmt_api::Client,
+    recent: RecentRequests,
+    err: anyhow::Error,
+) -> ! {
+    error!("getpage failed, writing diagnostics and exiting: {err:#}");
+
+    let pageserver_status = mgmt_api_client.status().await.map_err(|e| format!("{e:#}"));
+    let timeline_info = mgmt_api_client
+        .timeline_info(timeline.tenant_id, timeline.timeline_id)
+        .await
+        .map_err(|e| format!("{e:#}"));
+
+    let diagnostics = FailFastDiagnostics {
+        timeline,
+        error: format!("{err:?}"),
+        failed_request: recent.in_flight,
+        recent_latencies_micros: recent.latencies.iter().map(|l| l.as_micros()).collect(),
+        pageserver_status,
+        timeline_info,
+    };
+
+    let path = &args.fail_fast_diagnostics_path;
+    let res = serde_json::to_vec_pretty(&diagnostics)
+        .context("serialize diagnostics")
+        .and_then(|buf| std::fs::write(path, buf).context("write diagnostics"));
+    match res {
+        Ok(()) => error!("wrote diagnostics to {}", path.display()),
+        Err(e) => error!("failed to write diagnostics to {}: {e:#}", path.display()),
+    }
+
+    std::process::exit(1)
 }
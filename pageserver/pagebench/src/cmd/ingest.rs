@@ -0,0 +1,224 @@
+//! WAL ingest benchmark.
+//!
+//! Generates WAL by running batches of sequential heap inserts against a compute, so the WAL
+//! takes the regular ingest path: compute -> safekeepers -> pageserver. For each batch, we
+//! measure the commit latency, i.e., until the safekeepers have acknowledged the WAL, and the
+//! flush latency, i.e., until the pageserver's `last_record_lsn` has caught up with the commit.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use utils::id::{TenantId, TenantTimelineId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::util::{auth, request_stats, run_metadata};
+
+/// Sequential heap inserts through a compute, measuring pageserver ingest.
+#[derive(clap::Parser)]
+pub(crate) struct Args {
+    #[clap(long, default_value = "http://localhost:9898")]
+    mgmt_api_endpoint: String,
+    #[clap(flatten)]
+    auth: auth::Args,
+    /// Connection string of the compute to generate the WAL on.
+    /// Its timeline is the benchmark target.
+    #[clap(
+        long,
+        default_value = "postgres://cloud_admin@localhost:55432/postgres"
+    )]
+    compute_connstr: String,
+    #[clap(long, default_value = "pagebench_ingest")]
+    table: String,
+    #[clap(long, default_value = "1000")]
+    rows_per_batch: u32,
+    /// Size of each row's payload. Keep it below the TOAST threshold (~2kB) for WAL to grow
+    /// linearly with it.
+    #[clap(long, default_value = "100")]
+    row_size: u32,
+    #[clap(long, default_value = "10ms")]
+    poll_interval: humantime::Duration,
+    #[clap(long, default_value = "10s")]
+    runtime: humantime::Duration,
+}
+
+#[derive(serde::Serialize)]
+struct Output {
+    #[serde(flatten)]
+    metadata: run_metadata::Metadata,
+    target: TenantTimelineId,
+    wal_bytes: u64,
+    #[serde(with = "humantime_serde")]
+    elapsed: Duration,
+    ingest_mb_per_sec: f64,
+    commit: request_stats::Output,
+    flush: request_stats::Output,
+}
+
+pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let main_task = rt.spawn(main_impl(args));
+    rt.block_on(main_task).unwrap()
+}
+
+async fn main_impl(args: Args) -> anyhow::Result<()> {
+    let run = run_metadata::Recorder::start();
+    let mgmt_api_client = Arc::new(args.auth.mgmt_api_client(args.mgmt_api_endpoint.clone()));
+
+    let (compute, connection) =
+        tokio_postgres::connect(&args.compute_connstr, tokio_postgres::NoTls)
+            .await
+            .context("connect to compute")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("compute connection error: {e}");
+        }
+    });
+
+    let target = TenantTimelineId {
+        tenant_id: show(&compute, "neon.tenant_id")
+            .await?
+            .parse::<TenantId>()?,
+        timeline_id: show(&compute, "neon.timeline_id")
+            .await?
+            .parse::<TimelineId>()?,
+    };
+    info!("target timeline is {target}");
+
+    compute
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (id bigint, payload text)",
+            args.table
+        ))
+        .await
+        .context("create table")?;
+    let insert = compute
+        .prepare(&format!(
+            "INSERT INTO {} SELECT g, repeat('x', $2::int) FROM generate_series(1, $1::bigint) g",
+            args.table
+        ))
+        .await
+        .context("prepare insert")?;
+
+    let start_lsn = flush_lsn(&compute).await?;
+    let start = Instant::now();
+
+    // The watcher runs concurrently with the inserts, so that waiting for the pageserver
+    // doesn't throttle WAL generation.
+    let (committed_tx, committed_rx) = mpsc::unbounded_channel();
+    let watcher = tokio::spawn(watch_ingest(
+        Arc::clone(&mgmt_api_client),
+        target,
+        args.poll_interval.into(),
+        committed_rx,
+    ));
+
+    let mut commit_stats = request_stats::Stats::new();
+    let runtime: Duration = args.runtime.into();
+    let mut end_lsn = start_lsn;
+    while start.elapsed() < runtime {
+        let batch_start = Instant::now();
+        compute
+            .execute(
+                &insert,
+                &[&(args.rows_per_batch as i64), &(args.row_size as i32)],
+            )
+            .await
+            .context("insert batch")?;
+        let committed_at = Instant::now();
+        commit_stats.observe(committed_at - batch_start)?;
+        end_lsn = flush_lsn(&compute).await?;
+        if committed_tx.send((end_lsn, committed_at)).is_err() {
+            // the watcher failed, its error is returned below
+            break;
+        }
+    }
+    drop(committed_tx);
+
+    let flush_stats = watcher.await.unwrap()?;
+    let elapsed = start.elapsed();
+    let wal_bytes = end_lsn.0 - start_lsn.0;
+
+    let output = Output {
+        metadata: run.finish(&[args.mgmt_api_endpoint.as_str()]).await,
+        target,
+        wal_bytes,
+        elapsed,
+        ingest_mb_per_sec: wal_bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64(),
+        commit: commit_stats.output(),
+        flush: flush_stats.output(),
+    };
+    let output = serde_json::to_string_pretty(&output).unwrap();
+    println!("{output}");
+
+    anyhow::Ok(())
+}
+
+pub(super) async fn show(compute: &tokio_postgres::Client, guc: &str) -> anyhow::Result<String> {
+    let row = compute
+        .query_one(&format!("SHOW {guc}"), &[])
+        .await
+        .with_context(|| format!("show {guc}"))?;
+    Ok(row.get(0))
+}
+
+pub(super) async fn flush_lsn(compute: &tokio_postgres::Client) -> anyhow::Result<Lsn> {
+    let row = compute
+        .query_one("SELECT pg_current_wal_flush_lsn()::text", &[])
+        .await
+        .context("get flush lsn")?;
+    let lsn: String = row.get(0);
+    lsn.parse::<Lsn>()
+        .map_err(|e| anyhow::anyhow!("parse lsn {lsn:?}: {e:?}"))
+}
+
+/// Polls the pageserver's `last_record_lsn` and records the flush latency of each committed
+/// batch once the pageserver has ingested it. Returns after the last batch was ingested.
+pub(super) async fn watch_ingest(
+    mgmt_api_client: Arc<pageserver_client::mgmt_api::Client>,
+    target: TenantTimelineId,
+    poll_interval: Duration,
+    mut committed: mpsc::UnboundedReceiver<(Lsn, Instant)>,
+) -> anyhow::Result<request_stats::Stats> {
+    let mut stats = request_stats::Stats::new();
+    let mut pending = VecDeque::new();
+    let mut senders_done = false;
+
+    let mut ticker = tokio::time::interval(poll_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        while !senders_done {
+            match committed.try_recv() {
+                Ok(batch) => pending.push_back(batch),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => senders_done = true,
+            }
+        }
+        if senders_done && pending.is_empty() {
+            return Ok(stats);
+        }
+
+        ticker.tick().await;
+        if pending.is_empty() {
+            continue;
+        }
+        let info = mgmt_api_client
+            .timeline_info(target.tenant_id, target.timeline_id)
+            .await
+            .context("get timeline info")?;
+        while let Some((lsn, committed_at)) = pending.front() {
+            if *lsn > info.last_record_lsn {
+                break;
+            }
+            stats.observe(committed_at.elapsed())?;
+            pending.pop_front();
+        }
+    }
+}
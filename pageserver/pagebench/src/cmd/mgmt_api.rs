@@ -0,0 +1,358 @@
+//! Management API benchmark.
+//!
+//! Each client repeatedly runs a tenant through its lifecycle: create it, create timelines,
+//! detach and re-attach it, delete the timelines, delete the tenant. We measure the latency of
+//! each operation, until its effect is visible: e.g., a deletion until the pageserver reports
+//! the tenant as not found, an attach until the tenant is active.
+
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Context;
+use pageserver_api::models::{
+    LocationConfig, LocationConfigMode, TenantConfig, TenantCreateRequest, TenantState,
+    TimelineCreateRequest,
+};
+use pageserver_api::shard::TenantShardId;
+use pageserver_client::mgmt_api;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use utils::id::{TenantId, TimelineId};
+
+use crate::util::{auth, request_stats, run_metadata};
+
+/// Tenant and timeline lifecycles through the management API, measuring each operation.
+#[derive(clap::Parser)]
+pub(crate) struct Args {
+    #[clap(long, default_value = "http://localhost:9898")]
+    mgmt_api_endpoint: String,
+    #[clap(flatten)]
+    auth: auth::Args,
+    /// The control plane API of the pageserver, e.g., the attachment service, to get the
+    /// generations for creating and attaching tenants from, for `--node-id`. Required if the
+    /// pageserver has a `control_plane_api`.
+    #[clap(long, requires = "node_id")]
+    control_plane_api: Option<String>,
+    /// The id of the pageserver, see `--control-plane-api`.
+    #[clap(long)]
+    node_id: Option<u64>,
+    #[clap(long, default_value = "1")]
+    num_clients: NonZeroUsize,
+    /// Stop starting new lifecycles after this long. Without it, each client runs one
+    /// lifecycle.
+    #[clap(long)]
+    runtime: Option<humantime::Duration>,
+    #[clap(long, default_value = "1")]
+    timelines_per_tenant: usize,
+    #[clap(long, default_value = "15")]
+    pg_version: u32,
+    /// How often to check whether an operation took effect.
+    #[clap(long, default_value = "10ms")]
+    poll_interval: humantime::Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Operation {
+    TenantCreate,
+    TimelineCreate,
+    TenantDetach,
+    TenantAttach,
+    TimelineDelete,
+    TenantDelete,
+}
+
+#[derive(serde::Serialize)]
+struct Output {
+    #[serde(flatten)]
+    metadata: run_metadata::Metadata,
+    lifecycles: u64,
+    /// Lifecycles that were abandoned after an error, see `errors`.
+    failed_lifecycles: u64,
+    operations: BTreeMap<Operation, request_stats::Output>,
+    /// The operations that failed, each ending its lifecycle.
+    errors: BTreeMap<Operation, u64>,
+}
+
+#[derive(Default)]
+struct ClientStats {
+    lifecycles: u64,
+    failed_lifecycles: u64,
+    operations: BTreeMap<Operation, request_stats::Stats>,
+    errors: BTreeMap<Operation, u64>,
+}
+
+impl ClientStats {
+    fn add(&mut self, other: &ClientStats) {
+        self.lifecycles += other.lifecycles;
+        self.failed_lifecycles += other.failed_lifecycles;
+        for (op, stats) in &other.operations {
+            self.operations.entry(*op).or_default().add(stats);
+        }
+        for (op, count) in &other.errors {
+            *self.errors.entry(*op).or_default() += count;
+        }
+    }
+}
+
+pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let main_task = rt.spawn(main_impl(args));
+    rt.block_on(main_task).unwrap()
+}
+
+async fn main_impl(args: Args) -> anyhow::Result<()> {
+    let args: &'static Args = Box::leak(Box::new(args));
+    let run = run_metadata::Recorder::start();
+
+    // a retry would count towards the operation's latency, and may apply it twice
+    let mgmt_api_client = Arc::new(
+        args.auth
+            .mgmt_api_client(args.mgmt_api_endpoint.clone())
+            .with_retry_policy(mgmt_api::RetryPolicy::never()),
+    );
+
+    let deadline = args.runtime.map(|runtime| Instant::now() + runtime.into());
+    let mut tasks = JoinSet::new();
+    for client_id in 0..args.num_clients.get() {
+        let mgmt_api_client = Arc::clone(&mgmt_api_client);
+        let control_plane_client = reqwest::Client::new();
+        tasks.spawn(async move {
+            let mut stats = ClientStats::default();
+            loop {
+                let lifecycle = Lifecycle {
+                    args,
+                    mgmt_api_client: &mgmt_api_client,
+                    control_plane_client: &control_plane_client,
+                };
+                if let Err((op, e)) = lifecycle.run(&mut stats).await {
+                    warn!(client_id, ?op, "abandoning lifecycle: {e:#}");
+                    *stats.errors.entry(op).or_default() += 1;
+                    stats.failed_lifecycles += 1;
+                }
+                stats.lifecycles += 1;
+                if !deadline.is_some_and(|deadline| Instant::now() < deadline) {
+                    return stats;
+                }
+            }
+        });
+    }
+
+    let mut total = ClientStats::default();
+    while let Some(stats) = tasks.join_next().await {
+        total.add(&stats.unwrap());
+    }
+    info!(
+        "ran {} lifecycles, {} failed",
+        total.lifecycles, total.failed_lifecycles
+    );
+
+    let output = Output {
+        metadata: run.finish(&[args.mgmt_api_endpoint.as_str()]).await,
+        lifecycles: total.lifecycles,
+        failed_lifecycles: total.failed_lifecycles,
+        operations: total
+            .operations
+            .iter()
+            .map(|(op, stats)| (*op, stats.output()))
+            .collect(),
+        errors: total.errors,
+    };
+
+    let output = serde_json::to_string_pretty(&output).unwrap();
+    println!("{output}");
+
+    anyhow::Ok(())
+}
+
+struct Lifecycle<'a> {
+    args: &'static Args,
+    mgmt_api_client: &'a mgmt_api::Client,
+    control_plane_client: &'a reqwest::Client,
+}
+
+type OpResult<T> = Result<T, (Operation, anyhow::Error)>;
+
+impl Lifecycle<'_> {
+    async fn run(&self, stats: &mut ClientStats) -> OpResult<()> {
+        let client = self.mgmt_api_client;
+        let tenant_id = TenantId::generate();
+        let tenant_shard_id = TenantShardId::unsharded(tenant_id);
+
+        let generation = self.generation(Operation::TenantCreate, tenant_id).await?;
+        measure(stats, Operation::TenantCreate, async {
+            client
+                .tenant_create(&TenantCreateRequest {
+                    new_tenant_id: tenant_shard_id,
+                    generation,
+                    config: TenantConfig::default(),
+                })
+                .await?;
+            self.wait_for_active(tenant_id).await
+        })
+        .await?;
+
+        let mut timelines = Vec::with_capacity(self.args.timelines_per_tenant);
+        for _ in 0..self.args.timelines_per_tenant {
+            let timeline_id = TimelineId::generate();
+            measure(stats, Operation::TimelineCreate, async {
+                client
+                    .timeline_create(
+                        tenant_id,
+                        &TimelineCreateRequest {
+                            new_timeline_id: timeline_id,
+                            ancestor_timeline_id: None,
+                            existing_initdb_timeline_id: None,
+                            ancestor_start_lsn: None,
+                            pg_version: Some(self.args.pg_version),
+                        },
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await?;
+            timelines.push(timeline_id);
+        }
+
+        measure(stats, Operation::TenantDetach, async {
+            client
+                .location_config(tenant_id, location_config(None), None)
+                .await?;
+            Ok(())
+        })
+        .await?;
+
+        let generation = self.generation(Operation::TenantAttach, tenant_id).await?;
+        measure(stats, Operation::TenantAttach, async {
+            client
+                .location_config(tenant_id, location_config(generation), None)
+                .await?;
+            self.wait_for_active(tenant_id).await
+        })
+        .await?;
+
+        for timeline_id in timelines {
+            measure(stats, Operation::TimelineDelete, async {
+                client.timeline_delete(tenant_shard_id, timeline_id).await?;
+                self.wait_for_not_found(|| client.timeline_info(tenant_id, timeline_id))
+                    .await
+            })
+            .await?;
+        }
+
+        measure(stats, Operation::TenantDelete, async {
+            client.tenant_delete(tenant_shard_id).await?;
+            self.wait_for_not_found(|| client.tenant_details(tenant_shard_id))
+                .await
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// The generation to create or attach the tenant in, for `op`. Without
+    /// `--control-plane-api`, the tenant is created without a generation, which the pageserver
+    /// then doesn't require, and attached in generation 1.
+    async fn generation(&self, op: Operation, tenant_id: TenantId) -> OpResult<Option<u32>> {
+        let (Some(control_plane_api), Some(node_id)) =
+            (&self.args.control_plane_api, self.args.node_id)
+        else {
+            return Ok((op != Operation::TenantCreate).then_some(1));
+        };
+
+        #[derive(serde::Deserialize)]
+        struct AttachHookResponse {
+            gen: Option<u32>,
+        }
+
+        let response: AttachHookResponse = async {
+            self.control_plane_client
+                .post(format!("{control_plane_api}/attach-hook"))
+                .json(&serde_json::json!({ "tenant_id": tenant_id, "node_id": node_id }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        }
+        .await
+        .context("get generation from control plane")
+        .map_err(|e| (op, e))?;
+        response
+            .gen
+            .context("control plane issued no generation")
+            .map(Some)
+            .map_err(|e| (op, e))
+    }
+
+    async fn wait_for_active(&self, tenant_id: TenantId) -> anyhow::Result<()> {
+        loop {
+            let details = self
+                .mgmt_api_client
+                .tenant_details(TenantShardId::unsharded(tenant_id))
+                .await?;
+            match details.tenant_info.state {
+                TenantState::Active => return Ok(()),
+                TenantState::Broken { reason, .. } => {
+                    anyhow::bail!("tenant {tenant_id} is broken: {reason}")
+                }
+                _ => tokio::time::sleep(self.args.poll_interval.into()).await,
+            }
+        }
+    }
+
+    async fn wait_for_not_found<T, F>(&self, get: impl Fn() -> F) -> anyhow::Result<()>
+    where
+        F: std::future::Future<Output = mgmt_api::Result<T>>,
+    {
+        loop {
+            match get().await {
+                Ok(_) => {}
+                Err(mgmt_api::Error::ApiError(status, _))
+                    if status == reqwest::StatusCode::NOT_FOUND =>
+                {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            }
+            tokio::time::sleep(self.args.poll_interval.into()).await;
+        }
+    }
+}
+
+async fn measure(
+    stats: &mut ClientStats,
+    op: Operation,
+    fut: impl std::future::Future<Output = anyhow::Result<()>>,
+) -> OpResult<()> {
+    let start = Instant::now();
+    fut.await.map_err(|e| (op, e))?;
+    stats
+        .operations
+        .entry(op)
+        .or_default()
+        .observe(start.elapsed())
+        .map_err(|e| (op, e))
+}
+
+/// Attached in `generation`, or detached if None.
+fn location_config(generation: Option<u32>) -> LocationConfig {
+    LocationConfig {
+        mode: match generation {
+            Some(_) => LocationConfigMode::AttachedSingle,
+            None => LocationConfigMode::Detached,
+        },
+        generation,
+        secondary_conf: None,
+        tenant_conf: TenantConfig::default(),
+        shard_number: 0,
+        shard_count: 0,
+        shard_stripe_size: 0,
+    }
+}
@@ -0,0 +1,282 @@
+//! Mixed read/write benchmark.
+//!
+//! Reads pages of a compute's timeline through the page service while the compute generates WAL
+//! for the same timeline, like the `ingest` benchmark does. This measures the read latency
+//! under ingest pressure, e.g., while the pageserver flushes and compacts the new layers.
+//!
+//! The reads are issued by `--num-clients` clients, as fast as the pageserver responds. A single
+//! writer inserts a batch of rows after every `--reads-per-write` completed reads, or with zero,
+//! as fast as the compute commits them.
+
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use pageserver::pgdatadir_mapping::key_to_rel_block;
+use pageserver::repository::Key;
+use pageserver_api::models::PagestreamGetPageRequest;
+use pageserver_client::page_service::pool::PagestreamPool;
+use rand::prelude::*;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use utils::id::{TenantId, TenantTimelineId, TimelineId};
+use utils::lsn::Lsn;
+
+use super::ingest;
+use crate::util::{auth, keyspace_filter, request_stats, rng, run_metadata};
+
+/// Getpage requests against a compute's timeline while the compute ingests WAL into it.
+#[derive(clap::Parser)]
+pub(crate) struct Args {
+    #[clap(long, default_value = "http://localhost:9898")]
+    mgmt_api_endpoint: String,
+    #[clap(long, default_value = "postgres://postgres@localhost:64000")]
+    page_service_connstring: String,
+    #[clap(flatten)]
+    auth: auth::Args,
+    /// Connection string of the compute to generate the WAL on.
+    /// Its timeline is the benchmark target.
+    #[clap(
+        long,
+        default_value = "postgres://cloud_admin@localhost:55432/postgres"
+    )]
+    compute_connstr: String,
+    #[clap(long, default_value = "pagebench_mixed")]
+    table: String,
+    #[clap(long, default_value = "1")]
+    num_clients: NonZeroUsize,
+    /// The ratio of reads to writes: insert a batch after this many reads. With zero, the
+    /// writes don't wait for the reads.
+    #[clap(long, default_value = "1000")]
+    reads_per_write: u32,
+    #[clap(long, default_value = "1000")]
+    rows_per_batch: u32,
+    /// Size of each row's payload, see the `ingest` benchmark.
+    #[clap(long, default_value = "100")]
+    row_size: u32,
+    #[clap(long, default_value = "10ms")]
+    poll_interval: humantime::Duration,
+    #[clap(long, default_value = "10s")]
+    runtime: humantime::Duration,
+    #[clap(flatten)]
+    keyspace_filter: keyspace_filter::Args,
+    #[clap(flatten)]
+    rng: rng::Args,
+}
+
+#[derive(serde::Serialize)]
+struct Output {
+    #[serde(flatten)]
+    metadata: run_metadata::Metadata,
+    target: TenantTimelineId,
+    /// Pass as `--seed` to issue the same requests again.
+    seed: u64,
+    #[serde(with = "humantime_serde")]
+    elapsed: Duration,
+    reads: request_stats::Output,
+    read_rps: f64,
+    /// The commit latency of the batches, see the `ingest` benchmark.
+    commit: request_stats::Output,
+    /// The flush latency of the batches, see the `ingest` benchmark.
+    flush: request_stats::Output,
+    wal_bytes: u64,
+    ingest_mb_per_sec: f64,
+}
+
+pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let main_task = rt.spawn(main_impl(args));
+    rt.block_on(main_task).unwrap()
+}
+
+async fn main_impl(args: Args) -> anyhow::Result<()> {
+    let args: &'static Args = Box::leak(Box::new(args));
+    let run = run_metadata::Recorder::start();
+
+    let mgmt_api_client = Arc::new(args.auth.mgmt_api_client(args.mgmt_api_endpoint.clone()));
+
+    let (compute, connection) =
+        tokio_postgres::connect(&args.compute_connstr, tokio_postgres::NoTls)
+            .await
+            .context("connect to compute")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("compute connection error: {e}");
+        }
+    });
+
+    let target = TenantTimelineId {
+        tenant_id: ingest::show(&compute, "neon.tenant_id")
+            .await?
+            .parse::<TenantId>()?,
+        timeline_id: ingest::show(&compute, "neon.timeline_id")
+            .await?
+            .parse::<TimelineId>()?,
+    };
+    info!("target timeline is {target}");
+
+    compute
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (id bigint, payload text)",
+            args.table
+        ))
+        .await
+        .context("create table")?;
+    let insert = compute
+        .prepare(&format!(
+            "INSERT INTO {} SELECT g, repeat('x', $2::int) FROM generate_series(1, $1::bigint) g",
+            args.table
+        ))
+        .await
+        .context("prepare insert")?;
+
+    // the keys that exist before the writes start, the new ones are not read
+    let partitioning = mgmt_api_client
+        .keyspace(target.tenant_id, target.timeline_id)
+        .await?;
+    let ranges: &'static [Range<i128>] = Vec::leak(
+        args.keyspace_filter
+            .rel_block_ranges(&partitioning.keys.ranges),
+    );
+    if ranges.is_empty() {
+        anyhow::bail!("no keys to request in {target}, check the keyspace filter");
+    }
+    let lsn = partitioning.at_lsn;
+    let seed = args.rng.seed();
+    let page_service_connstring = args
+        .auth
+        .page_service_connstring(&args.page_service_connstring)?;
+
+    // a permit per completed read, the writer takes `--reads-per-write` for each batch
+    let reads_done = Arc::new(Semaphore::new(0));
+    let deadline = Instant::now() + args.runtime.into();
+    let start_lsn = ingest::flush_lsn(&compute).await?;
+    let start = Instant::now();
+
+    // up to a connection per client, the requests of all clients are multiplexed over them
+    let pool = Arc::new(PagestreamPool::new(
+        page_service_connstring,
+        args.num_clients,
+    ));
+    let mut readers = JoinSet::new();
+    for client_id in 0..args.num_clients.get() {
+        let reads_done = Arc::clone(&reads_done);
+        let mut rng = seed.rng(client_id);
+        let pool = Arc::clone(&pool);
+        readers.spawn(async move {
+            let mut stats = request_stats::Stats::new();
+            while Instant::now() < deadline {
+                let req = request(ranges, lsn, &mut rng);
+                let start = Instant::now();
+                pool.getpage(target.tenant_id, target.timeline_id, req)
+                    .await
+                    .with_context(|| format!("getpage for {target}"))?;
+                stats.observe(start.elapsed())?;
+                reads_done.add_permits(1);
+            }
+            anyhow::Ok(stats)
+        });
+    }
+
+    // The watcher runs concurrently with the inserts, so that waiting for the pageserver
+    // doesn't throttle WAL generation.
+    let (committed_tx, committed_rx) = mpsc::unbounded_channel();
+    let watcher = tokio::spawn(ingest::watch_ingest(
+        Arc::clone(&mgmt_api_client),
+        target,
+        args.poll_interval.into(),
+        committed_rx,
+    ));
+
+    let mut commit_stats = request_stats::Stats::new();
+    let mut end_lsn = start_lsn;
+    while Instant::now() < deadline {
+        if args.reads_per_write > 0 {
+            let wait = reads_done.acquire_many(args.reads_per_write);
+            match tokio::time::timeout_at(deadline.into(), wait).await {
+                Ok(permits) => permits.expect("the semaphore is never closed").forget(),
+                Err(_timeout) => break,
+            }
+        }
+        let batch_start = Instant::now();
+        compute
+            .execute(
+                &insert,
+                &[&(args.rows_per_batch as i64), &(args.row_size as i32)],
+            )
+            .await
+            .context("insert batch")?;
+        let committed_at = Instant::now();
+        commit_stats.observe(committed_at - batch_start)?;
+        end_lsn = ingest::flush_lsn(&compute).await?;
+        if committed_tx.send((end_lsn, committed_at)).is_err() {
+            // the watcher failed, its error is returned below
+            break;
+        }
+    }
+    drop(committed_tx);
+
+    let mut read_stats = request_stats::Stats::new();
+    while let Some(res) = readers.join_next().await {
+        read_stats.add(&res.unwrap()?);
+    }
+    let flush_stats = watcher.await.unwrap()?;
+    let elapsed = start.elapsed();
+    let wal_bytes = end_lsn.0 - start_lsn.0;
+    info!(
+        "{} reads, {} batches in {elapsed:?}",
+        read_stats.request_count(),
+        commit_stats.request_count()
+    );
+
+    let output = Output {
+        metadata: run.finish(&[args.mgmt_api_endpoint.as_str()]).await,
+        target,
+        seed: seed.get(),
+        elapsed,
+        read_rps: read_stats.request_count() as f64 / elapsed.as_secs_f64(),
+        reads: read_stats.output(),
+        commit: commit_stats.output(),
+        flush: flush_stats.output(),
+        wal_bytes,
+        ingest_mb_per_sec: wal_bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64(),
+    };
+    let output = serde_json::to_string_pretty(&output).unwrap();
+    println!("{output}");
+
+    anyhow::Ok(())
+}
+
+/// A request for a page in `ranges`, uniformly distributed over their keys.
+fn request(ranges: &[Range<i128>], lsn: Lsn, rng: &mut impl Rng) -> PagestreamGetPageRequest {
+    let total: i128 = ranges.iter().map(|r| r.end - r.start).sum();
+    let mut idx = rng.gen_range(0..total);
+    let range = ranges
+        .iter()
+        .find(|r| {
+            let len = r.end - r.start;
+            if idx < len {
+                true
+            } else {
+                idx -= len;
+                false
+            }
+        })
+        .expect("idx is below the total length");
+    let key = Key::from_i128(range.start + idx);
+    let (rel, blkno) = key_to_rel_block(key).expect("we filter non-rel-block keys out above");
+    PagestreamGetPageRequest {
+        // the pages change with the writes, read their latest version
+        latest: true,
+        lsn,
+        rel,
+        blkno,
+    }
+}
@@ -0,0 +1,313 @@
+//! On-demand download benchmark.
+//!
+//! For each target, we pick resident layers, evict them through the management API, and then
+//! read a page through each of them. That first, cold read has to download the layer before it
+//! can reconstruct the page. Then we read the same page again, a few times, which measures the
+//! warm reads of the same request for comparison.
+//!
+//! The reads are at the layer's LSN, so that they visit the evicted layer, and not a newer one
+//! that covers the same key. Layers whose LSN is below the GC cutoff can't be read that way and
+//! are not picked.
+
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use pageserver::pgdatadir_mapping::key_to_rel_block;
+use pageserver::repository::Key;
+use pageserver::tenant::storage_layer::LayerFileName;
+use pageserver_api::models::PagestreamGetPageRequest;
+use pageserver_api::shard::TenantShardId;
+use pageserver_client::mgmt_api::{self, HistoricLayerSummary};
+use pageserver_client::page_service::PagestreamClient;
+use rand::prelude::*;
+use tracing::{info, warn};
+use utils::id::TenantTimelineId;
+use utils::lsn::Lsn;
+
+use crate::util::{auth, keyspace_filter, request_stats, rng, run_metadata};
+
+/// Evict layers, then measure the reads that download them on demand, and the warm reads after.
+#[derive(clap::Parser)]
+pub(crate) struct Args {
+    #[clap(long, default_value = "http://localhost:9898")]
+    mgmt_api_endpoint: String,
+    #[clap(long, default_value = "postgres://postgres@localhost:64000")]
+    page_service_connstring: String,
+    #[clap(flatten)]
+    auth: auth::Args,
+    #[clap(long)]
+    limit_to_first_n_targets: Option<usize>,
+    /// The number of layers to evict and read, per target. Fewer if the target has fewer
+    /// readable resident layers.
+    #[clap(long, default_value = "8")]
+    layers_per_timeline: usize,
+    /// The number of warm reads after each cold read.
+    #[clap(long, default_value = "10")]
+    warm_reads_per_layer: usize,
+    #[clap(flatten)]
+    keyspace_filter: keyspace_filter::Args,
+    #[clap(flatten)]
+    rng: rng::Args,
+    targets: Option<Vec<TenantTimelineId>>,
+}
+
+#[derive(serde::Serialize)]
+struct Output {
+    #[serde(flatten)]
+    metadata: run_metadata::Metadata,
+    seed: u64,
+    /// The reads that downloaded their layer.
+    cold: request_stats::Output,
+    warm: request_stats::Output,
+    /// Cold reads after which the layer was still evicted, e.g., because the page was
+    /// reconstructed from a layer above it. They are not in `cold`.
+    not_downloaded: u64,
+    /// Layers that we skipped after an error, see `layers`.
+    errors: u64,
+    layers: Vec<LayerOutput>,
+}
+
+#[derive(serde::Serialize)]
+struct LayerOutput {
+    timeline: TenantTimelineId,
+    layer_file_name: String,
+    layer_file_size: u64,
+    #[serde(with = "humantime_serde")]
+    cold_latency: Option<Duration>,
+    downloaded: bool,
+    error: Option<String>,
+}
+
+/// A layer that we read through, and the page that we read.
+struct Candidate {
+    layer: HistoricLayerSummary,
+    request: PagestreamGetPageRequest,
+}
+
+pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let main_task = rt.spawn(main_impl(args));
+    rt.block_on(main_task).unwrap()
+}
+
+async fn main_impl(args: Args) -> anyhow::Result<()> {
+    let args: &'static Args = Box::leak(Box::new(args));
+    let run = run_metadata::Recorder::start();
+
+    let mgmt_api_client = Arc::new(args.auth.mgmt_api_client(args.mgmt_api_endpoint.clone()));
+
+    // discover targets
+    let timelines: Vec<TenantTimelineId> = crate::util::cli::targets::discover(
+        &mgmt_api_client,
+        crate::util::cli::targets::Spec {
+            limit_to_first_n_targets: args.limit_to_first_n_targets,
+            targets: args.targets.clone(),
+        },
+    )
+    .await?;
+    let seed = args.rng.seed();
+    let page_service_connstring = args
+        .auth
+        .page_service_connstring(&args.page_service_connstring)?;
+
+    let mut cold = request_stats::Stats::new();
+    let mut warm = request_stats::Stats::new();
+    let mut not_downloaded = 0;
+    let mut errors = 0;
+    let mut layers = Vec::new();
+    for timeline in timelines {
+        let candidates = candidates(args, &mgmt_api_client, timeline, &mut seed.rng(timeline))
+            .await
+            .with_context(|| format!("pick layers of {timeline}"))?;
+        info!("reading through {} layers of {timeline}", candidates.len());
+        if candidates.is_empty() {
+            continue;
+        }
+
+        let mut client =
+            pageserver_client::page_service::Client::new(page_service_connstring.clone())
+                .await?
+                .pagestream(timeline.tenant_id, timeline.timeline_id)
+                .await?;
+        for candidate in candidates {
+            let mut output = LayerOutput {
+                timeline,
+                layer_file_name: candidate.layer.layer_file_name.clone(),
+                layer_file_size: candidate.layer.layer_file_size,
+                cold_latency: None,
+                downloaded: false,
+                error: None,
+            };
+            let res = read_layer(
+                args,
+                &mgmt_api_client,
+                &mut client,
+                timeline,
+                &candidate,
+                &mut output,
+                &mut warm,
+            )
+            .await;
+            match res {
+                Ok(()) if output.downloaded => {
+                    cold.observe(output.cold_latency.expect("set on success"))
+                        .unwrap();
+                }
+                Ok(()) => {
+                    warn!(
+                        "cold read of {timeline} did not download layer {}",
+                        output.layer_file_name
+                    );
+                    not_downloaded += 1;
+                }
+                Err(e) => {
+                    warn!("layer {} of {timeline}: {e:#}", output.layer_file_name);
+                    output.error = Some(format!("{e:#}"));
+                    errors += 1;
+                }
+            }
+            layers.push(output);
+        }
+    }
+    info!(
+        "read through {} layers: {} downloaded, {} not downloaded, {} errors",
+        layers.len(),
+        cold.request_count(),
+        not_downloaded,
+        errors
+    );
+
+    let output = Output {
+        metadata: run.finish(&[args.mgmt_api_endpoint.as_str()]).await,
+        seed: seed.get(),
+        cold: cold.output(),
+        warm: warm.output(),
+        not_downloaded,
+        errors,
+        layers,
+    };
+
+    let output = serde_json::to_string_pretty(&output).unwrap();
+    println!("{output}");
+
+    anyhow::Ok(())
+}
+
+/// Up to `--layers-per-timeline` resident layers of `timeline` that hold keys that pass the
+/// keyspace filter, and a request for a page in each.
+async fn candidates(
+    args: &Args,
+    mgmt_api_client: &mgmt_api::Client,
+    timeline: TenantTimelineId,
+    rng: &mut impl Rng,
+) -> anyhow::Result<Vec<Candidate>> {
+    let partitioning = mgmt_api_client
+        .keyspace(timeline.tenant_id, timeline.timeline_id)
+        .await?;
+    let rel_block_ranges = args
+        .keyspace_filter
+        .rel_block_ranges(&partitioning.keys.ranges);
+    let gc_cutoff = mgmt_api_client
+        .timeline_info(timeline.tenant_id, timeline.timeline_id)
+        .await?
+        .latest_gc_cutoff_lsn;
+
+    let mut layers = mgmt_api_client
+        .historic_layers(
+            TenantShardId::unsharded(timeline.tenant_id),
+            timeline.timeline_id,
+        )
+        .await?;
+    layers.retain(|layer| !layer.remote);
+    // the order of the layers in the layer map is not stable, sort for determinism
+    layers.sort_by(|a, b| a.layer_file_name.cmp(&b.layer_file_name));
+    layers.shuffle(rng);
+
+    let mut candidates = Vec::new();
+    for layer in layers {
+        if candidates.len() >= args.layers_per_timeline {
+            break;
+        }
+        let name: LayerFileName = layer
+            .layer_file_name
+            .parse()
+            .map_err(|e: String| anyhow::anyhow!(e))?;
+        let (key_range, lsn) = match name {
+            LayerFileName::Image(image) => (image.key_range, image.lsn),
+            // the newest LSN in the layer, lsn_range is exclusive
+            LayerFileName::Delta(delta) => (delta.key_range, Lsn(delta.lsn_range.end.0 - 1)),
+        };
+        if lsn < gc_cutoff {
+            continue;
+        }
+        let overlap: Vec<Range<i128>> = rel_block_ranges
+            .iter()
+            .map(|r| r.start.max(key_range.start.to_i128())..r.end.min(key_range.end.to_i128()))
+            .filter(|r| !r.is_empty())
+            .collect();
+        let Some(range) = overlap.choose(rng) else {
+            continue;
+        };
+        let key = Key::from_i128(rng.gen_range(range.clone()));
+        let (rel, blkno) = key_to_rel_block(key).expect("we filter non-rel-block keys out above");
+        candidates.push(Candidate {
+            layer,
+            request: PagestreamGetPageRequest {
+                latest: false,
+                lsn,
+                rel,
+                blkno,
+            },
+        });
+    }
+    Ok(candidates)
+}
+
+/// Evicts the layer of `candidate`, reads its page cold, checks that this downloaded the layer,
+/// then reads it warm.
+async fn read_layer(
+    args: &Args,
+    mgmt_api_client: &mgmt_api::Client,
+    client: &mut PagestreamClient,
+    timeline: TenantTimelineId,
+    candidate: &Candidate,
+    output: &mut LayerOutput,
+    warm: &mut request_stats::Stats,
+) -> anyhow::Result<()> {
+    let tenant_shard_id = TenantShardId::unsharded(timeline.tenant_id);
+    let layer_file_name = &candidate.layer.layer_file_name;
+    mgmt_api_client
+        .layer_evict(tenant_shard_id, timeline.timeline_id, layer_file_name)
+        .await
+        .context("evict layer")?;
+
+    let start = Instant::now();
+    client
+        .getpage(candidate.request.clone())
+        .await
+        .context("cold read")?;
+    output.cold_latency = Some(start.elapsed());
+
+    let layers = mgmt_api_client
+        .historic_layers(tenant_shard_id, timeline.timeline_id)
+        .await?;
+    output.downloaded = layers
+        .iter()
+        .any(|layer| &layer.layer_file_name == layer_file_name && !layer.remote);
+
+    for _ in 0..args.warm_reads_per_layer {
+        let start = Instant::now();
+        client
+            .getpage(candidate.request.clone())
+            .await
+            .context("warm read")?;
+        warm.observe(start.elapsed()).unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Runs another pagebench benchmark for every combination of a set of parameter values, e.g.
+//!
+//! ```text
+//! pagebench sweep \
+//!     --axis limit-to-first-n-targets=1,4,16 \
+//!     --axis req-latest-probability=0,1 \
+//!     --axis per-target-rate-limit=100,1000 \
+//!     -- get-page-latest-lsn --mgmt-api-endpoint http://localhost:9898
+//! ```
+//!
+//! The cells of the matrix run sequentially, each in a separate pagebench process, with a
+//! cooldown in between so that one cell's background work (compaction, eviction, ...) affects
+//! the next one as little as possible. The combined report has each cell's parameters next to
+//! the benchmark's output.
+
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::{info, warn};
+
+/// Run a benchmark for each combination of parameter values.
+#[derive(clap::Parser)]
+pub(crate) struct Args {
+    /// A parameter of the benchmark and the values to sweep it over: `<flag>=<v1>,<v2>,...`,
+    /// where `<flag>` is the benchmark's long option without the leading `--`. The matrix is
+    /// the cartesian product of all axes.
+    #[clap(long = "axis", required = true)]
+    axes: Vec<Axis>,
+    /// Passed as `--runtime` to each cell.
+    #[clap(long, default_value = "30s")]
+    runtime: humantime::Duration,
+    /// Time to wait between two cells.
+    #[clap(long, default_value = "10s")]
+    cooldown: humantime::Duration,
+    /// The benchmark sub-command and its arguments that are the same for all cells.
+    #[clap(last = true, required = true)]
+    benchmark: Vec<String>,
+}
+
+#[derive(Clone)]
+struct Axis {
+    flag: String,
+    values: Vec<String>,
+}
+
+impl FromStr for Axis {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (flag, values) = s
+            .split_once('=')
+            .with_context(|| format!("expected <flag>=<v1>,<v2>,..., got {s:?}"))?;
+        let values: Vec<String> = values.split(',').map(|v| v.to_owned()).collect();
+        if flag.is_empty() || values.iter().any(|v| v.is_empty()) {
+            anyhow::bail!("empty flag or value in axis {s:?}");
+        }
+        Ok(Axis {
+            flag: flag.to_owned(),
+            values,
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Output {
+    benchmark: Vec<String>,
+    #[serde(with = "humantime_serde")]
+    runtime: Duration,
+    cells: Vec<Cell>,
+}
+
+#[derive(serde::Serialize)]
+struct Cell {
+    /// Flag and value for each axis, in the order of the `--axis` arguments.
+    params: Vec<(String, String)>,
+    /// The benchmark's own output, or the reason why the cell failed.
+    result: Result<serde_json::Value, String>,
+}
+
+pub(crate) fn main(args: Args) -> anyhow::Result<()> {
+    let exe = std::env::current_exe().context("find pagebench executable")?;
+    let cells = cartesian_product(&args.axes);
+
+    let mut output = Output {
+        benchmark: args.benchmark.clone(),
+        runtime: args.runtime.into(),
+        cells: Vec::with_capacity(cells.len()),
+    };
+    for (i, params) in cells.into_iter().enumerate() {
+        if i > 0 {
+            info!("cooling down for {}", args.cooldown);
+            std::thread::sleep(args.cooldown.into());
+        }
+        info!(cell = i, ?params, "running cell");
+
+        let mut cmd = Command::new(&exe);
+        cmd.args(&args.benchmark)
+            .arg("--runtime")
+            .arg(args.runtime.to_string());
+        for (flag, value) in &params {
+            cmd.arg(format!("--{flag}")).arg(value);
+        }
+        let result = run_cell(cmd);
+        if let Err(e) = &result {
+            warn!(cell = i, "cell failed: {e}");
+        }
+        output.cells.push(Cell { params, result });
+    }
+
+    for (i, cell) in output.cells.iter().enumerate() {
+        info!(cell = i, params = ?cell.params, "{}", summary(&cell.result));
+    }
+
+    let output = serde_json::to_string_pretty(&output).unwrap();
+    println!("{output}");
+
+    Ok(())
+}
+
+fn cartesian_product(axes: &[Axis]) -> Vec<Vec<(String, String)>> {
+    let mut cells = vec![Vec::new()];
+    for axis in axes {
+        cells = cells
+            .into_iter()
+            .flat_map(|cell| {
+                axis.values.iter().map(move |value| {
+                    let mut cell = cell.clone();
+                    cell.push((axis.flag.clone(), value.clone()));
+                    cell
+                })
+            })
+            .collect();
+    }
+    cells
+}
+
+/// Runs a benchmark process and parses its output.
+pub(super) fn run_cell(mut cmd: Command) -> Result<serde_json::Value, String> {
+    // the benchmark logs to stderr, let it through so that the cells' progress is visible
+    let output = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("spawn benchmark: {e}"))?;
+    if !output.status.success() {
+        return Err(format!("benchmark exited with {}", output.status));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("parse benchmark output: {e}"))
+}
+
+/// One-line comparison of the cells, for the benchmarks that report `request_stats` as `total`.
+fn summary(result: &Result<serde_json::Value, String>) -> String {
+    match result {
+        Ok(output) => {
+            let total = &output["total"];
+            format!(
+                "requests={} latency_mean={} latency_percentiles={}",
+                total["request_count"], total["latency_mean"], total["latency_percentiles"]
+            )
+        }
+        Err(e) => format!("failed: {e}"),
+    }
+}
@@ -10,8 +10,8 @@ pub(crate) struct Args {
     mgmt_api_endpoint: String,
     #[clap(long, default_value = "localhost:64000")]
     page_service_host_port: String,
-    #[clap(long)]
-    pageserver_jwt: Option<String>,
+    #[clap(flatten)]
+    auth: auth::Args,
     #[clap(
         long,
         help = "if specified, poll mgmt api to check whether init logical size calculation has completed"
@@ -35,10 +35,7 @@ pub(crate) fn main(args: Args) -> anyhow::Result<()> {
 async fn main_impl(args: Args) -> anyhow::Result<()> {
     let args: &'static Args = Box::leak(Box::new(args));
 
-    let mgmt_api_client = Arc::new(pageserver_client::mgmt_api::Client::new(
-        args.mgmt_api_endpoint.clone(),
-        args.pageserver_jwt.as_deref(),
-    ));
+    let mgmt_api_client = Arc::new(args.auth.mgmt_api_client(args.mgmt_api_endpoint.clone()));
 
     // discover targets
     let timelines: Vec<TenantTimelineId> = crate::util::cli::targets::discover(
@@ -3,12 +3,29 @@ use utils::logging;
 
 /// Re-usable pieces of code that aren't CLI-specific.
 mod util {
+    pub(crate) mod affinity;
+    pub(crate) mod auth;
+    pub(crate) mod client_usage;
     pub(crate) mod connstring;
+    pub(crate) mod interval_stats;
+    pub(crate) mod keyspace_filter;
+    pub(crate) mod metrics;
+    pub(crate) mod ramp;
+    pub(crate) mod request_generator;
     pub(crate) mod request_stats;
+    pub(crate) mod rng;
+    pub(crate) mod rtt;
+    pub(crate) mod run_metadata;
+    pub(crate) mod server_metrics;
+    pub(crate) mod soak;
+    pub(crate) mod spool;
+    pub(crate) mod stationarity;
     #[macro_use]
     pub(crate) mod tokio_thread_local_stats;
+    pub(crate) mod verify;
     /// Re-usable pieces of CLI-specific code.
     pub(crate) mod cli {
+        pub(crate) mod pageservers;
         pub(crate) mod targets;
     }
 }
@@ -16,15 +33,37 @@ mod util {
 /// The pagebench CLI sub-commands, dispatched in [`main`] below.
 mod cmd {
     pub(super) mod basebackup;
+    pub(super) mod compare;
     pub(super) mod getpage_latest_lsn;
+    pub(super) mod ingest;
+    pub(super) mod mgmt_api;
+    pub(super) mod mixed;
+    pub(super) mod ondemand_download;
+    pub(super) mod sweep;
     pub(super) mod trigger_initial_size_calculation;
 }
 
 /// Component-level performance test for pageserver.
 #[derive(clap::Parser)]
+struct Cli {
+    /// The latency percentiles to report, e.g., `50,90,99,99.9,99.99`. By default,
+    /// `95,99,99.9,99.99`.
+    #[clap(long, global = true, value_delimiter = ',')]
+    percentiles: Vec<f64>,
+    #[clap(subcommand)]
+    command: Args,
+}
+
+#[derive(clap::Subcommand)]
 enum Args {
     Basebackup(cmd::basebackup::Args),
+    Compare(cmd::compare::Args),
     GetPageLatestLsn(cmd::getpage_latest_lsn::Args),
+    Ingest(cmd::ingest::Args),
+    MgmtApi(cmd::mgmt_api::Args),
+    Mixed(cmd::mixed::Args),
+    OndemandDownload(cmd::ondemand_download::Args),
+    Sweep(cmd::sweep::Args),
     TriggerInitialSizeCalculation(cmd::trigger_initial_size_calculation::Args),
 }
 
@@ -36,10 +75,19 @@ fn main() {
     )
     .unwrap();
 
-    let args = Args::parse();
-    match args {
+    let cli = Cli::parse();
+    if !cli.percentiles.is_empty() {
+        util::request_stats::set_latency_percentiles(cli.percentiles).unwrap();
+    }
+    match cli.command {
         Args::Basebackup(args) => cmd::basebackup::main(args),
+        Args::Compare(args) => cmd::compare::main(args),
         Args::GetPageLatestLsn(args) => cmd::getpage_latest_lsn::main(args),
+        Args::Ingest(args) => cmd::ingest::main(args),
+        Args::MgmtApi(args) => cmd::mgmt_api::main(args),
+        Args::Mixed(args) => cmd::mixed::main(args),
+        Args::OndemandDownload(args) => cmd::ondemand_download::main(args),
+        Args::Sweep(args) => cmd::sweep::main(args),
         Args::TriggerInitialSizeCalculation(args) => {
             cmd::trigger_initial_size_calculation::main(args)
         }
@@ -0,0 +1,91 @@
+//! Pinning the benchmark to a set of CPUs, e.g., to one NUMA node of a dual-socket host, so that
+//! the results aren't skewed by the scheduler moving the client threads across nodes and by
+//! cross-node memory traffic.
+//!
+//! We pin the main thread before the tokio runtime starts: its worker and blocking threads
+//! inherit the affinity, and the runtime sizes its worker pool to the CPUs in the set. With
+//! the threads confined to a node, the kernel's first-touch policy also keeps their memory there.
+
+use std::fs;
+
+use anyhow::Context;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Run the benchmark's threads only on these CPUs, in the Linux cpulist format,
+    /// e.g., `0-7,16-23`.
+    #[clap(long, value_parser = parse_cpu_list)]
+    cpus: Option<CpuList>,
+    /// Run the benchmark's threads only on the CPUs of this NUMA node.
+    #[clap(long, conflicts_with = "cpus")]
+    numa_node: Option<usize>,
+}
+
+#[derive(Clone)]
+struct CpuList(Vec<usize>);
+
+/// The affinity that the benchmark ran with, for its output.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct Affinity {
+    cpus: Vec<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    numa_node: Option<usize>,
+}
+
+impl Args {
+    /// Pins the current thread, to be called before starting the runtime.
+    pub(crate) fn apply(&self) -> anyhow::Result<Option<Affinity>> {
+        let affinity = match (&self.cpus, self.numa_node) {
+            (None, None) => return Ok(None),
+            (Some(CpuList(cpus)), _) => Affinity {
+                cpus: cpus.clone(),
+                numa_node: None,
+            },
+            (None, Some(node)) => {
+                let path = format!("/sys/devices/system/node/node{node}/cpulist");
+                let cpulist = fs::read_to_string(&path).with_context(|| format!("read {path}"))?;
+                let CpuList(cpus) =
+                    parse_cpu_list(cpulist.trim()).with_context(|| format!("parse {path}"))?;
+                Affinity {
+                    cpus,
+                    numa_node: Some(node),
+                }
+            }
+        };
+        if affinity.cpus.is_empty() {
+            anyhow::bail!("empty CPU set");
+        }
+
+        let mut set = CpuSet::new();
+        for cpu in &affinity.cpus {
+            set.set(*cpu)
+                .with_context(|| format!("CPU {cpu} out of range"))?;
+        }
+        sched_setaffinity(Pid::from_raw(0), &set).context("set CPU affinity")?;
+        tracing::info!(?affinity, "pinned benchmark threads");
+
+        Ok(Some(affinity))
+    }
+}
+
+fn parse_cpu_list(s: &str) -> anyhow::Result<CpuList> {
+    let mut cpus = Vec::new();
+    for part in s.split(',').filter(|part| !part.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.parse::<usize>()?, last.parse::<usize>()?),
+            None => {
+                let cpu = part.parse::<usize>()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            anyhow::bail!("invalid CPU range {part:?}");
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(CpuList(cpus))
+}
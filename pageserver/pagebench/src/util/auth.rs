@@ -0,0 +1,100 @@
+//! The credentials for an auth-enabled pageserver, for both the management API and the page
+//! service.
+//!
+//! The pageserver itself takes a JWT: as a bearer token on the management API, and as the
+//! password on the page service. A user name and password are for pageservers behind a proxy
+//! that authenticates the connections: they are sent with HTTP basic auth, and as the user and
+//! password of the page service connection. The secrets may come from the environment, so that
+//! they don't show up in the process list.
+
+use std::num::NonZeroU32;
+
+use anyhow::Context;
+use pageserver_client::mgmt_api;
+
+/// Read if `--auth-token` is not given.
+const AUTH_TOKEN_ENV: &str = "PAGEBENCH_AUTH_TOKEN";
+/// Read if `--auth-password` is not given.
+const AUTH_PASSWORD_ENV: &str = "PAGEBENCH_AUTH_PASSWORD";
+
+/// See [`Args::mgmt_api_client`].
+const MGMT_API_MAX_ATTEMPTS: u32 = 5;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// The JWT for the management API and the page service. Defaults to
+    /// `$PAGEBENCH_AUTH_TOKEN`.
+    #[clap(long, alias = "pageserver-jwt")]
+    auth_token: Option<String>,
+    /// Authenticate as this user with `--auth-password` instead of with a JWT.
+    #[clap(long, conflicts_with = "auth_token")]
+    auth_user: Option<String>,
+    /// Defaults to `$PAGEBENCH_AUTH_PASSWORD`.
+    #[clap(long, requires = "auth_user")]
+    auth_password: Option<String>,
+}
+
+impl Args {
+    fn token(&self) -> Option<String> {
+        if self.auth_user.is_some() {
+            return None;
+        }
+        self.auth_token
+            .clone()
+            .or_else(|| std::env::var(AUTH_TOKEN_ENV).ok())
+    }
+
+    fn password(&self) -> Option<String> {
+        self.auth_password
+            .clone()
+            .or_else(|| std::env::var(AUTH_PASSWORD_ENV).ok())
+    }
+
+    /// The client retries on the transient errors of a restarting pageserver, so that a setup
+    /// or a target lookup doesn't abort the benchmark.
+    pub(crate) fn mgmt_api_client(&self, mgmt_api_endpoint: String) -> mgmt_api::Client {
+        let auth = match &self.auth_user {
+            Some(username) => Some(mgmt_api::Auth::Basic {
+                username: username.clone(),
+                password: self.password(),
+            }),
+            None => self.token().map(mgmt_api::Auth::Jwt),
+        };
+        mgmt_api::Client::with_auth(mgmt_api_endpoint, auth).with_retry_policy(
+            mgmt_api::RetryPolicy::transient(NonZeroU32::new(MGMT_API_MAX_ATTEMPTS).unwrap()),
+        )
+    }
+
+    /// `connstring` with the user and password, in its URL or key-value format.
+    pub(crate) fn page_service_connstring(&self, connstring: &str) -> anyhow::Result<String> {
+        let password = match &self.auth_user {
+            Some(_) => self.password(),
+            None => self.token(),
+        };
+        if self.auth_user.is_none() && password.is_none() {
+            return Ok(connstring.to_owned());
+        }
+
+        if connstring.starts_with("postgres://") || connstring.starts_with("postgresql://") {
+            let mut url = reqwest::Url::parse(connstring)
+                .with_context(|| format!("parse page service connstring {connstring:?}"))?;
+            let url_err = |()| anyhow::anyhow!("can't set credentials in {connstring:?}");
+            if let Some(user) = &self.auth_user {
+                url.set_username(user).map_err(url_err)?;
+            }
+            url.set_password(password.as_deref()).map_err(url_err)?;
+            return Ok(url.into());
+        }
+
+        // key-value format, later keys override earlier ones
+        let quote = |value: &str| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"));
+        let mut connstring = connstring.to_owned();
+        if let Some(user) = &self.auth_user {
+            connstring.push_str(&format!(" user={}", quote(user)));
+        }
+        if let Some(password) = &password {
+            connstring.push_str(&format!(" password={}", quote(password)));
+        }
+        Ok(connstring)
+    }
+}
@@ -0,0 +1,246 @@
+//! The pageservers of a benchmark that spans several of them, and which of them serves each
+//! target's tenant.
+//!
+//! The n-th `--mgmt-api-endpoint` and the n-th `--page-service-connstring` belong to the n-th
+//! pageserver. A tenant's pageserver is, in this order of precedence, the one given with
+//! `--tenant-pageserver`, the one that `--attachment-service` has the tenant attached to, or
+//! the only pageserver that lists the tenant.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use pageserver_client::mgmt_api;
+use tracing::info;
+use utils::id::{TenantId, TenantTimelineId};
+
+use crate::util::auth;
+use crate::util::cli::targets;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// May be repeated, once per pageserver.
+    #[clap(long = "mgmt-api-endpoint", default_value = "http://localhost:9898")]
+    mgmt_api_endpoints: Vec<String>,
+    /// May be repeated, once per pageserver, in the order of `--mgmt-api-endpoint`.
+    #[clap(
+        long = "page-service-connstring",
+        default_value = "postgres://postgres@localhost:64000"
+    )]
+    page_service_connstrings: Vec<String>,
+    /// Send the requests for a tenant to a pageserver: `<tenant_id>=<n>`, where `<n>` counts
+    /// the `--mgmt-api-endpoint`s from zero. May be repeated.
+    #[clap(long = "tenant-pageserver")]
+    tenant_pageservers: Vec<TenantPageserver>,
+    /// Look up the pageserver of each tenant in the attachment service at this URL. Requires
+    /// `--pageserver-node-id`.
+    #[clap(long, requires = "pageserver_node_ids")]
+    attachment_service: Option<String>,
+    /// The node id of a pageserver, for `--attachment-service`. May be repeated, in the order
+    /// of `--mgmt-api-endpoint`.
+    #[clap(long = "pageserver-node-id")]
+    pageserver_node_ids: Vec<u64>,
+}
+
+#[derive(Clone)]
+struct TenantPageserver {
+    tenant_id: TenantId,
+    pageserver: usize,
+}
+
+impl FromStr for TenantPageserver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tenant_id, pageserver) = s
+            .split_once('=')
+            .with_context(|| format!("expected <tenant_id>=<n>, got {s:?}"))?;
+        Ok(TenantPageserver {
+            tenant_id: tenant_id.parse()?,
+            pageserver: pageserver.parse()?,
+        })
+    }
+}
+
+pub(crate) struct Pageserver {
+    pub(crate) mgmt_api_endpoint: String,
+    pub(crate) mgmt_api_client: Arc<mgmt_api::Client>,
+    pub(crate) page_service_connstring: String,
+}
+
+pub(crate) struct Pageservers {
+    pageservers: Vec<Pageserver>,
+    tenants: HashMap<TenantId, usize>,
+}
+
+impl Pageservers {
+    /// The pageserver of the tenant of `timeline`, which must be one of the targets from
+    /// [`Args::resolve`].
+    pub(crate) fn get(&self, timeline: TenantTimelineId) -> &Pageserver {
+        &self.pageservers[self.tenants[&timeline.tenant_id]]
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Pageserver> {
+        self.pageservers.iter()
+    }
+
+    /// The first pageserver, e.g., to calibrate the round-trip time against.
+    pub(crate) fn first(&self) -> &Pageserver {
+        &self.pageservers[0]
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct InspectResponse {
+    /// The generation and the node id.
+    attachment: Option<(u32, u64)>,
+}
+
+impl Args {
+    /// Discovers the targets of `spec` on all pageservers, and the pageserver of each.
+    pub(crate) async fn resolve(
+        &self,
+        auth: &auth::Args,
+        spec: targets::Spec,
+    ) -> anyhow::Result<(Pageservers, Vec<TenantTimelineId>)> {
+        if self.mgmt_api_endpoints.len() != self.page_service_connstrings.len() {
+            anyhow::bail!(
+                "got {} --mgmt-api-endpoint but {} --page-service-connstring",
+                self.mgmt_api_endpoints.len(),
+                self.page_service_connstrings.len()
+            );
+        }
+        if self.attachment_service.is_some()
+            && self.pageserver_node_ids.len() != self.mgmt_api_endpoints.len()
+        {
+            anyhow::bail!("--attachment-service requires a --pageserver-node-id per pageserver");
+        }
+        let pageservers: Vec<Pageserver> = self
+            .mgmt_api_endpoints
+            .iter()
+            .zip(&self.page_service_connstrings)
+            .map(|(mgmt_api_endpoint, page_service_connstring)| {
+                anyhow::Ok(Pageserver {
+                    mgmt_api_endpoint: mgmt_api_endpoint.clone(),
+                    mgmt_api_client: Arc::new(auth.mgmt_api_client(mgmt_api_endpoint.clone())),
+                    page_service_connstring: auth
+                        .page_service_connstring(page_service_connstring)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if let Some(mapping) = self
+            .tenant_pageservers
+            .iter()
+            .find(|mapping| mapping.pageserver >= pageservers.len())
+        {
+            anyhow::bail!(
+                "--tenant-pageserver for {} refers to pageserver {}, but there are only {}",
+                mapping.tenant_id,
+                mapping.pageserver,
+                pageservers.len()
+            );
+        }
+
+        // which pageservers list each tenant, only needed to tell several pageservers apart
+        let mut listed: HashMap<TenantId, Vec<usize>> = HashMap::new();
+        let mut listed_timelines = Vec::new();
+        if pageservers.len() > 1 || spec.targets.is_none() {
+            for (i, pageserver) in pageservers.iter().enumerate() {
+                let timelines = mgmt_api::util::get_pageserver_tenant_timelines_unsharded(
+                    &pageserver.mgmt_api_client,
+                )
+                .await
+                .with_context(|| format!("list timelines of {}", pageserver.mgmt_api_endpoint))?;
+                for timeline in timelines {
+                    let on = listed.entry(timeline.tenant_id).or_default();
+                    if !on.contains(&i) {
+                        on.push(i);
+                    }
+                    listed_timelines.push(timeline);
+                }
+            }
+        }
+        listed_timelines.sort();
+        listed_timelines.dedup();
+
+        let timelines = targets::discover(
+            &pageservers[0].mgmt_api_client,
+            targets::Spec {
+                limit_to_first_n_targets: spec.limit_to_first_n_targets,
+                targets: Some(spec.targets.unwrap_or(listed_timelines)),
+            },
+        )
+        .await?;
+
+        let attachment_service = reqwest::Client::new();
+        let mut tenants = HashMap::new();
+        for timeline in &timelines {
+            let tenant_id = timeline.tenant_id;
+            if tenants.contains_key(&tenant_id) {
+                continue;
+            }
+            let pageserver = match self
+                .tenant_pageservers
+                .iter()
+                .find(|mapping| mapping.tenant_id == tenant_id)
+            {
+                Some(mapping) => mapping.pageserver,
+                None => match &self.attachment_service {
+                    Some(url) => self
+                        .attached_pageserver(&attachment_service, url, tenant_id)
+                        .await
+                        .with_context(|| format!("look up the pageserver of {tenant_id}"))?,
+                    None if pageservers.len() == 1 => 0,
+                    None => match listed.get(&tenant_id).map(Vec::as_slice) {
+                        Some([pageserver]) => *pageserver,
+                        Some(_) => anyhow::bail!(
+                            "tenant {tenant_id} is on several pageservers, pick one with \
+                             --tenant-pageserver or --attachment-service"
+                        ),
+                        None => anyhow::bail!("tenant {tenant_id} is on none of the pageservers"),
+                    },
+                },
+            };
+            tenants.insert(tenant_id, pageserver);
+        }
+        if pageservers.len() > 1 {
+            for (i, pageserver) in pageservers.iter().enumerate() {
+                let count = tenants.values().filter(|p| **p == i).count();
+                info!("{count} tenants on {}", pageserver.mgmt_api_endpoint);
+            }
+        }
+
+        Ok((
+            Pageservers {
+                pageservers,
+                tenants,
+            },
+            timelines,
+        ))
+    }
+
+    /// The pageserver that the attachment service has the tenant attached to.
+    async fn attached_pageserver(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        tenant_id: TenantId,
+    ) -> anyhow::Result<usize> {
+        let response: InspectResponse = client
+            .post(format!("{url}/inspect"))
+            .json(&serde_json::json!({ "tenant_id": tenant_id }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let (_generation, node_id) = response
+            .attachment
+            .context("the tenant is not attached anywhere")?;
+        self.pageserver_node_ids
+            .iter()
+            .position(|id| *id == node_id)
+            .with_context(|| format!("no --pageserver-node-id {node_id}"))
+    }
+}
@@ -0,0 +1,148 @@
+//! The benchmark client's own CPU and memory usage over the run, reported as `client_usage`.
+//!
+//! A client that is busy on all the CPUs it may run on can't issue requests any faster, so the
+//! results then describe the load generator rather than the pageserver. We warn about such runs.
+//! The utilization is relative to the CPUs available to the process, i.e., it honors `--cpus`
+//! and cgroup limits.
+
+use std::time::{Duration, Instant};
+
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::time::TimeVal;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// How often to sample the client's own CPU utilization.
+    #[clap(long, default_value = "1s")]
+    client_usage_interval: humantime::Duration,
+    /// The client's CPU utilization, relative to the CPUs it may run on, above which the client
+    /// counts as saturated.
+    #[clap(long, default_value = "0.9")]
+    client_saturation_threshold: f64,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Output {
+    /// The CPUs available to the client.
+    cpus: usize,
+    /// CPU time over the whole run, relative to the wall-clock time of all `cpus`.
+    cpu_utilization_mean: f64,
+    /// Of the busiest interval of `--client-usage-interval`.
+    cpu_utilization_max: f64,
+    intervals: usize,
+    /// Intervals above `--client-saturation-threshold`.
+    saturated_intervals: usize,
+    /// Whether the mean exceeded `--client-saturation-threshold`.
+    saturated: bool,
+    /// The peak resident set size of the client process.
+    max_rss_bytes: u64,
+}
+
+/// Samples the usage from [`Sampler::start`] to [`Sampler::finish`].
+pub(crate) struct Sampler {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<anyhow::Result<Output>>,
+}
+
+impl Sampler {
+    pub(crate) fn start(args: &Args) -> Sampler {
+        let (stop, stop_rx) = oneshot::channel();
+        let task = tokio::spawn(sample_loop(
+            args.client_usage_interval.into(),
+            args.client_saturation_threshold,
+            stop_rx,
+        ));
+        Sampler { stop, task }
+    }
+
+    pub(crate) async fn finish(self) -> anyhow::Result<Output> {
+        let _ = self.stop.send(());
+        let output = self.task.await.unwrap()?;
+        if output.saturated {
+            warn!(
+                "the benchmark client was saturated, using {:.0}% of its {} CPUs: the results \
+                 may show the client's limits rather than the pageserver's",
+                output.cpu_utilization_mean * 100.0,
+                output.cpus
+            );
+        } else {
+            info!(
+                "the benchmark client used {:.0}% of its {} CPUs, {} of {} intervals saturated",
+                output.cpu_utilization_mean * 100.0,
+                output.cpus,
+                output.saturated_intervals,
+                output.intervals
+            );
+        }
+        Ok(output)
+    }
+}
+
+async fn sample_loop(
+    interval: Duration,
+    threshold: f64,
+    mut stop: oneshot::Receiver<()>,
+) -> anyhow::Result<Output> {
+    let cpus = std::thread::available_parallelism()?.get();
+    let utilization = |wall: Duration, cpu: Duration| {
+        cpu.as_secs_f64() / (wall.as_secs_f64() * cpus as f64).max(f64::MIN_POSITIVE)
+    };
+
+    let start = (Instant::now(), cpu_time()?);
+    let mut last = start;
+    let mut intervals = 0;
+    let mut saturated_intervals = 0;
+    let mut cpu_utilization_max: f64 = 0.0;
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await; // the first tick is immediate
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            // the interval that `stop` cuts short only counts towards the mean
+            _ = &mut stop => break,
+        }
+        let now = (Instant::now(), cpu_time()?);
+        let interval_utilization = utilization(now.0 - last.0, now.1.saturating_sub(last.1));
+        intervals += 1;
+        if interval_utilization > threshold {
+            saturated_intervals += 1;
+        }
+        cpu_utilization_max = cpu_utilization_max.max(interval_utilization);
+        last = now;
+    }
+
+    let end = (Instant::now(), cpu_time()?);
+    let cpu_utilization_mean = utilization(end.0 - start.0, end.1.saturating_sub(start.1));
+    Ok(Output {
+        cpus,
+        cpu_utilization_mean,
+        cpu_utilization_max: cpu_utilization_max.max(cpu_utilization_mean),
+        intervals,
+        saturated_intervals,
+        saturated: cpu_utilization_mean > threshold,
+        max_rss_bytes: max_rss_bytes()?,
+    })
+}
+
+/// The user and system CPU time of all threads of the process so far.
+fn cpu_time() -> anyhow::Result<Duration> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF)?;
+    let duration = |t: TimeVal| {
+        Duration::from_secs(t.tv_sec() as u64) + Duration::from_micros(t.tv_usec() as u64)
+    };
+    Ok(duration(usage.user_time()) + duration(usage.system_time()))
+}
+
+fn max_rss_bytes() -> anyhow::Result<u64> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF)?;
+    // kilobytes on Linux, bytes on macOS
+    let max_rss = usage.max_rss() as u64;
+    Ok(if cfg!(target_os = "macos") {
+        max_rss
+    } else {
+        max_rss * 1024
+    })
+}
@@ -1,8 +1,20 @@
-pub(crate) fn connstring(host_port: &str, jwt: Option<&str>) -> String {
-    let colon_and_jwt = if let Some(jwt) = jwt {
-        format!(":{jwt}") // TODO: urlescape
-    } else {
-        String::new()
-    };
-    format!("postgres://postgres{colon_and_jwt}@{host_port}")
+/// Without credentials, see [`crate::util::auth`].
+pub(crate) fn connstring(host_port: &str) -> String {
+    format!("postgres://postgres@{host_port}")
+}
+
+/// The `host:port` of the first TCP host in `connstring`.
+pub(crate) fn host_port(connstring: &str) -> anyhow::Result<String> {
+    let config: tokio_postgres::Config = connstring.parse()?;
+    let host = config
+        .get_hosts()
+        .iter()
+        .find_map(|host| match host {
+            tokio_postgres::config::Host::Tcp(host) => Some(host.clone()),
+            #[cfg(unix)]
+            tokio_postgres::config::Host::Unix(_) => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no TCP host in {connstring:?}"))?;
+    let port = config.get_ports().first().copied().unwrap_or(5432);
+    Ok(format!("{host}:{port}"))
 }
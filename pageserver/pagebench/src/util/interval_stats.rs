@@ -0,0 +1,123 @@
+//! A file with one line of [`request_stats::Stats`] summary per interval of a benchmark run,
+//! for plotting throughput and latency over time.
+//!
+//! Latencies are in microseconds, timestamps in milliseconds since the epoch. Lines are flushed
+//! as they are written, so the file can be followed while the benchmark is running.
+
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use crate::util::request_stats;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum Format {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma-separated values, with a header line.
+    Csv,
+}
+
+impl Format {
+    /// The file name extension of the format.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Format::JsonLines => "jsonl",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// The columns of the CSV format, in the order of the fields of [`Row`].
+const CSV_HEADER: &str = "interval_start_millis_since_epoch,elapsed_micros,request_count,rps,latency_mean_micros,latency_p95_micros,latency_p99_micros,latency_p99_9_micros,latency_p99_99_micros";
+
+#[derive(serde::Serialize)]
+struct Row {
+    interval_start_millis_since_epoch: u128,
+    elapsed_micros: u128,
+    request_count: u64,
+    rps: f64,
+    latency_mean_micros: u128,
+    latency_p95_micros: u128,
+    latency_p99_micros: u128,
+    latency_p99_9_micros: u128,
+    latency_p99_99_micros: u128,
+}
+
+pub(crate) struct Writer {
+    path: PathBuf,
+    format: Format,
+    file: BufWriter<std::fs::File>,
+}
+
+impl Writer {
+    pub(crate) fn create(path: &Path, format: Format) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("create interval stats file {}", path.display()))?;
+        let mut writer = Writer {
+            path: path.to_owned(),
+            format,
+            file: BufWriter::new(file),
+        };
+        if let Format::Csv = format {
+            writeln!(writer.file, "{CSV_HEADER}")?;
+            writer.flush()?;
+        }
+        Ok(writer)
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        interval_start: SystemTime,
+        elapsed: Duration,
+        stats: &request_stats::Stats,
+    ) -> anyhow::Result<()> {
+        let percentile = |p| stats.latency_percentile(p).as_micros();
+        let row = Row {
+            interval_start_millis_since_epoch: interval_start
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            elapsed_micros: elapsed.as_micros(),
+            request_count: stats.request_count(),
+            rps: stats.request_count() as f64 / elapsed.as_secs_f64(),
+            latency_mean_micros: stats.latency_mean().as_micros(),
+            latency_p95_micros: percentile(95.0),
+            latency_p99_micros: percentile(99.0),
+            latency_p99_9_micros: percentile(99.9),
+            latency_p99_99_micros: percentile(99.99),
+        };
+        match self.format {
+            Format::JsonLines => {
+                serde_json::to_writer(&mut self.file, &row)?;
+                writeln!(self.file)?;
+            }
+            Format::Csv => {
+                let Row {
+                    interval_start_millis_since_epoch,
+                    elapsed_micros,
+                    request_count,
+                    rps,
+                    latency_mean_micros,
+                    latency_p95_micros,
+                    latency_p99_micros,
+                    latency_p99_9_micros,
+                    latency_p99_99_micros,
+                } = row;
+                writeln!(
+                    self.file,
+                    "{interval_start_millis_since_epoch},{elapsed_micros},{request_count},{rps:.1},{latency_mean_micros},{latency_p95_micros},{latency_p99_micros},{latency_p99_9_micros},{latency_p99_99_micros}"
+                )?;
+            }
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.file
+            .flush()
+            .with_context(|| format!("write interval stats file {}", self.path.display()))
+    }
+}
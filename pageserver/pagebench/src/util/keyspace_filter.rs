@@ -0,0 +1,195 @@
+//! Restricts the benchmarked keys to some relations and forks, e.g., to the main forks of the
+//! user tables, so that the requests have the shape of the intended workload.
+//!
+//! The filter picks from the rel block keys of the keyspace that the management API reports:
+//! the other keys, e.g., of the SLRUs, are never requested.
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use anyhow::Context;
+use pageserver::repository::Key;
+use pageserver_api::key::is_rel_block_key;
+
+/// The first OID that is not used by the system catalogs, `FirstNormalObjectId` in Postgres.
+const FIRST_NORMAL_OBJECT_ID: u32 = 16384;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Only request pages of this fork. May be repeated. By default, all forks.
+    #[clap(long = "fork", value_enum)]
+    forks: Vec<Fork>,
+    /// Only request pages of user relations, not of the system catalogs.
+    #[clap(long)]
+    user_relations_only: bool,
+    /// Only request pages of this relation, given as `<spcnode>/<dbnode>/<relnode>`, like
+    /// in the relation's data file path. May be repeated.
+    #[clap(long = "relation")]
+    relations: Vec<Relation>,
+}
+
+/// The fork numbers, from Postgres' relpath.h.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Fork {
+    Main = 0,
+    Fsm = 1,
+    Vm = 2,
+    Init = 3,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Relation {
+    spcnode: u32,
+    dbnode: u32,
+    relnode: u32,
+}
+
+impl FromStr for Relation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || -> Option<Relation> {
+            let mut parts = s.split('/').map(|part| part.parse::<u32>().ok());
+            let relation = Relation {
+                spcnode: parts.next()??,
+                dbnode: parts.next()??,
+                relnode: parts.next()??,
+            };
+            parts.next().is_none().then_some(relation)
+        };
+        parse().with_context(|| format!("expected <spcnode>/<dbnode>/<relnode>, got {s:?}"))
+    }
+}
+
+impl Args {
+    /// The rel block keys in `ranges` that pass the filter, as ranges of [`Key::to_i128`].
+    pub(crate) fn rel_block_ranges(&self, ranges: &[Range<Key>]) -> Vec<Range<i128>> {
+        let mut filtered: Vec<Range<i128>> = Vec::new();
+        for range in ranges {
+            let end = range.end.to_i128();
+            let mut start = range.start.to_i128();
+            while start < end {
+                let key = Key::from_i128(start);
+                if key.field1 != 0x00 {
+                    // only the rel keys have field1 zero, and they sort first
+                    break;
+                }
+                // the keys of a relation fork differ only in the block number, field6
+                let fork_end = std::cmp::min((start | 0xFFFF_FFFF) + 1, end);
+                if self.includes(&key) {
+                    match filtered.last_mut() {
+                        Some(last) if last.end == start => last.end = fork_end,
+                        _ => filtered.push(start..fork_end),
+                    }
+                }
+                start = fork_end;
+            }
+        }
+        filtered
+    }
+
+    fn includes(&self, key: &Key) -> bool {
+        is_rel_block_key(key)
+            && (self.forks.is_empty() || self.forks.iter().any(|f| *f as u8 == key.field5))
+            && (!self.user_relations_only || key.field4 >= FIRST_NORMAL_OBJECT_ID)
+            && (self.relations.is_empty()
+                || self.relations.contains(&Relation {
+                    spcnode: key.field2,
+                    dbnode: key.field3,
+                    relnode: key.field4,
+                }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(relnode: u32, fork: Fork, blknum: u32) -> Key {
+        Key {
+            field1: 0x00,
+            field2: 1663,
+            field3: 5,
+            field4: relnode,
+            field5: fork as u8,
+            field6: blknum,
+        }
+    }
+
+    fn k(relnode: u32, fork: Fork, blknum: u32) -> i128 {
+        key(relnode, fork, blknum).to_i128()
+    }
+
+    fn filter(forks: Vec<Fork>, user_relations_only: bool, relations: &[&str]) -> Args {
+        Args {
+            forks,
+            user_relations_only,
+            relations: relations.iter().map(|r| r.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_relation() {
+        let relation: Relation = "1663/5/16384".parse().unwrap();
+        assert_eq!(
+            (relation.spcnode, relation.dbnode, relation.relnode),
+            (1663, 5, 16384)
+        );
+        for invalid in ["", "1663/5", "1663/5/16384/1", "1663/5/x", "1663//16384"] {
+            invalid.parse::<Relation>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn rel_block_ranges() {
+        let slru = Key {
+            field1: 0x01,
+            field2: 0,
+            field3: 0,
+            field4: 0,
+            field5: 0,
+            field6: 0,
+        };
+        let ranges = [
+            // not a rel block key
+            key(0, Fork::Main, 0)..key(0, Fork::Main, 3),
+            // a system catalog
+            key(1259, Fork::Main, 0)..key(1259, Fork::Main, 10),
+            // the main and the fsm fork of a user relation
+            key(16384, Fork::Main, 0)..key(16384, Fork::Fsm, 2),
+            key(16385, Fork::Main, 0)..key(16385, Fork::Main, 1),
+            slru..slru.next(),
+        ];
+
+        assert_eq!(
+            filter(vec![], false, &[]).rel_block_ranges(&ranges),
+            [
+                k(1259, Fork::Main, 0)..k(1259, Fork::Main, 10),
+                k(16384, Fork::Main, 0)..k(16384, Fork::Fsm, 2),
+                k(16385, Fork::Main, 0)..k(16385, Fork::Main, 1),
+            ]
+        );
+        assert_eq!(
+            filter(vec![Fork::Main], false, &[]).rel_block_ranges(&ranges),
+            [
+                k(1259, Fork::Main, 0)..k(1259, Fork::Main, 10),
+                k(16384, Fork::Main, 0)..k(16384, Fork::Fsm, 0),
+                k(16385, Fork::Main, 0)..k(16385, Fork::Main, 1),
+            ]
+        );
+        assert_eq!(
+            filter(vec![Fork::Fsm], true, &[]).rel_block_ranges(&ranges),
+            [k(16384, Fork::Fsm, 0)..k(16384, Fork::Fsm, 2)]
+        );
+        assert_eq!(
+            filter(vec![], false, &["1663/5/1259", "1663/5/16385"]).rel_block_ranges(&ranges),
+            [
+                k(1259, Fork::Main, 0)..k(1259, Fork::Main, 10),
+                k(16385, Fork::Main, 0)..k(16385, Fork::Main, 1),
+            ]
+        );
+        assert!(filter(vec![Fork::Vm], false, &[])
+            .rel_block_ranges(&ranges)
+            .is_empty());
+    }
+}
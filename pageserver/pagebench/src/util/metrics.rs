@@ -0,0 +1,77 @@
+//! Live benchmark metrics, served in the Prometheus format with `--prometheus-listen`.
+//!
+//! The final JSON output summarizes a whole run. These let dashboards correlate the client-side
+//! latencies with the pageserver's own metrics while the benchmark is running, e.g., during
+//! long soak tests.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+use metrics::{register_histogram, register_int_counter, Histogram, IntCounter};
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+use utils::http::endpoint;
+
+static REQUESTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pagebench_requests_total",
+        "Number of completed benchmark requests"
+    )
+    .expect("failed to define a metric")
+});
+
+static REQUEST_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "pagebench_request_latency_seconds",
+        "Latency of completed benchmark requests",
+        latency_buckets(),
+    )
+    .expect("failed to define a metric")
+});
+
+static QUEUEING_DELAY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "pagebench_request_queueing_delay_seconds",
+        "How late requests were issued relative to their schedule, in open loop benchmarks",
+        latency_buckets(),
+    )
+    .expect("failed to define a metric")
+});
+
+/// 10us to ~40s
+fn latency_buckets() -> Vec<f64> {
+    metrics::exponential_buckets(0.000_010, 2.0, 22).unwrap()
+}
+
+pub(crate) fn observe_request(latency: Duration) {
+    REQUESTS.inc();
+    REQUEST_LATENCY.observe(latency.as_secs_f64());
+}
+
+pub(crate) fn observe_queueing_delay(delay: Duration) {
+    QUEUEING_DELAY.observe(delay.as_secs_f64());
+}
+
+/// Serves `/metrics` at `listen` until the benchmark exits.
+pub(crate) fn spawn_server(listen: SocketAddr) -> anyhow::Result<()> {
+    // register the metrics before the first scrape
+    Lazy::force(&REQUESTS);
+    Lazy::force(&REQUEST_LATENCY);
+    Lazy::force(&QUEUEING_DELAY);
+
+    let listener = utils::tcp_listener::bind(listen)
+        .with_context(|| format!("bind prometheus listener to {listen}"))?;
+    let router = endpoint::make_router()
+        .build()
+        .map_err(|err| anyhow::anyhow!(err))?;
+    let service = utils::http::RouterService::new(router).unwrap();
+    let server = hyper::Server::from_tcp(listener)?.serve(service);
+    info!("serving prometheus metrics on {listen}");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("prometheus server failed: {e}");
+        }
+    });
+    Ok(())
+}
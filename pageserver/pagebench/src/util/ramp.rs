@@ -0,0 +1,218 @@
+//! A concurrency ramp, selected with `--ramp`. The run starts with few requests in flight and
+//! allows more at fixed intervals, up to a maximum, and each of these steps is reported on its
+//! own. One run then shows the concurrency at which the throughput stops growing and the
+//! latency grows instead, i.e., the knee of the curve.
+//!
+//! The concurrency is enforced with a semaphore: the benchmark takes a permit for each request
+//! before issuing it, and returns it once the request completed.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use crate::util::request_stats;
+
+/// `start=<n>,step=<n>,interval=<duration>,max=<n>`, e.g., `start=4,step=4,interval=30s,max=64`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Spec {
+    start: usize,
+    step: usize,
+    interval: Duration,
+    max: usize,
+}
+
+impl FromStr for Spec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut start, mut step, mut interval, mut max) = (None, None, None, None);
+        for part in s.split(',') {
+            let (key, value) = part
+                .split_once('=')
+                .with_context(|| format!("expected <key>=<value>, got {part:?}"))?;
+            match key {
+                "start" => start = Some(value.parse()?),
+                "step" => step = Some(value.parse()?),
+                "interval" => interval = Some(humantime::parse_duration(value)?),
+                "max" => max = Some(value.parse()?),
+                _ => anyhow::bail!("unknown ramp parameter {key:?}"),
+            }
+        }
+        let spec = Spec {
+            start: start.context("missing start=<n>")?,
+            step: step.context("missing step=<n>")?,
+            interval: interval.context("missing interval=<duration>")?,
+            max: max.context("missing max=<n>")?,
+        };
+        if spec.start == 0 || spec.step == 0 || spec.interval.is_zero() {
+            anyhow::bail!("start, step and interval must be positive");
+        }
+        if spec.max < spec.start {
+            anyhow::bail!("max must be at least start");
+        }
+        Ok(spec)
+    }
+}
+
+impl Spec {
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Step {
+    /// The requests that were allowed in flight during the step.
+    concurrency: usize,
+    /// Shorter than the ramp's interval for the last step if the run ended during it.
+    #[serde(with = "humantime_serde")]
+    elapsed: Duration,
+    rps: f64,
+    #[serde(flatten)]
+    stats: request_stats::Output,
+}
+
+pub(crate) struct Ramp {
+    spec: Spec,
+    semaphore: Arc<Semaphore>,
+    concurrency: usize,
+    step_start: Instant,
+    current: request_stats::Stats,
+    steps: Vec<Step>,
+}
+
+impl Ramp {
+    pub(crate) fn new(spec: Spec) -> Self {
+        Ramp {
+            spec,
+            semaphore: Arc::new(Semaphore::new(spec.start)),
+            concurrency: spec.start,
+            step_start: Instant::now(),
+            current: request_stats::Stats::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// The permits for the requests, one per request in flight.
+    pub(crate) fn semaphore(&self) -> Arc<Semaphore> {
+        Arc::clone(&self.semaphore)
+    }
+
+    /// Starts the first step at `now`.
+    pub(crate) fn start(&mut self, now: Instant) {
+        self.step_start = now;
+        info!("ramp: {} requests in flight", self.concurrency);
+    }
+
+    /// When to end the current step and allow more requests in flight. None at the maximum,
+    /// whose step lasts until the end of the run.
+    pub(crate) fn next_step_at(&self) -> Option<Instant> {
+        (self.concurrency < self.spec.max).then(|| self.step_start + self.spec.interval)
+    }
+
+    /// Adds the requests that completed since the last call to the current step.
+    pub(crate) fn observe(&mut self, stats: &request_stats::Stats) {
+        self.current.add(stats);
+    }
+
+    /// Ends the current step at `now`.
+    pub(crate) fn finish_step(&mut self, now: Instant) {
+        let elapsed = now - self.step_start;
+        self.steps.push(Step {
+            concurrency: self.concurrency,
+            elapsed,
+            rps: self.current.request_count() as f64 / elapsed.as_secs_f64(),
+            stats: self.current.output(),
+        });
+        self.current.reset();
+    }
+
+    /// Starts the next step at `now`, with `step` more requests in flight.
+    pub(crate) fn advance(&mut self, now: Instant) {
+        let added = std::cmp::min(self.spec.step, self.spec.max - self.concurrency);
+        self.semaphore.add_permits(added);
+        self.concurrency += added;
+        self.step_start = now;
+        info!("ramp: {} requests in flight", self.concurrency);
+    }
+
+    pub(crate) fn output(self) -> Vec<Step> {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        let spec: Spec = "start=4,step=2,interval=30s,max=9".parse().unwrap();
+        assert_eq!(
+            (spec.start, spec.step, spec.interval, spec.max),
+            (4, 2, Duration::from_secs(30), 9)
+        );
+
+        for invalid in [
+            "",
+            "start=4,step=2,interval=30s",
+            "start=4,step=2,interval=30s,max=9,min=1",
+            "start=4,step=2,interval=30,max=9",
+            "start=0,step=2,interval=30s,max=9",
+            "start=4,step=0,interval=30s,max=9",
+            "start=4,step=2,interval=0s,max=9",
+            "start=4,step=2,interval=30s,max=3",
+            "start=4,step=2,interval=30s,max",
+        ] {
+            invalid.parse::<Spec>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn steps_up_to_the_max() {
+        let spec: Spec = "start=2,step=3,interval=10s,max=6".parse().unwrap();
+        let mut ramp = Ramp::new(spec);
+        let semaphore = ramp.semaphore();
+        let start = Instant::now();
+        ramp.start(start);
+        assert_eq!(semaphore.available_permits(), 2);
+
+        let mut stats = request_stats::Stats::new();
+        for _ in 0..20 {
+            stats.observe(Duration::from_micros(100)).unwrap();
+        }
+
+        let mut now = start;
+        let mut concurrency = Vec::new();
+        while let Some(next_step_at) = ramp.next_step_at() {
+            assert_eq!(next_step_at, now + Duration::from_secs(10));
+            now = next_step_at;
+            ramp.observe(&stats);
+            ramp.finish_step(now);
+            ramp.advance(now);
+            concurrency.push(semaphore.available_permits());
+        }
+        // the last step only goes up to the max
+        assert_eq!(concurrency, [5, 6]);
+        ramp.finish_step(now + Duration::from_secs(5));
+
+        let steps = ramp.output();
+        let steps = steps
+            .iter()
+            .map(|s| (s.concurrency, s.elapsed, s.rps))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            [
+                (2, Duration::from_secs(10), 2.0),
+                (5, Duration::from_secs(10), 2.0),
+                // nothing observed in the last step
+                (6, Duration::from_secs(5), 0.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Access patterns of the getpage benchmark, selected with `--generator`.
+//!
+//! A generator picks the keys that are requested from one target, as offsets into the target's
+//! keyspace: its rel block keys, in ascending order. Each target has its own generator instance,
+//! so generators can produce correlated sequences of keys. To add an access pattern, implement
+//! [`RequestGenerator`] and add it to [`GENERATORS`]; its parameters go into [`Args`].
+
+use std::collections::VecDeque;
+
+use anyhow::Context;
+use rand::{Rng, RngCore};
+
+pub(crate) trait RequestGenerator: Send {
+    /// The offset of the next key to request, in `0..keyspace_len`. `keyspace_len` is the same
+    /// for all calls on an instance, and never zero.
+    fn next_key(&mut self, rng: &mut dyn RngCore, keyspace_len: i128) -> i128;
+}
+
+pub(crate) struct Registration {
+    pub(crate) name: &'static str,
+    pub(crate) new: fn(&Args) -> Box<dyn RequestGenerator>,
+}
+
+pub(crate) const GENERATORS: &[Registration] = &[
+    Registration {
+        name: "random",
+        new: |args| Box::new(Random::new(args)),
+    },
+    Registration {
+        name: "btree-descent",
+        new: |args| Box::new(BtreeDescent::new(args)),
+    },
+];
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// The access pattern:
+    /// - `random`: independent keys, following `--key-distribution`.
+    /// - `btree-descent`: the page reads of index lookups, from the root page down to a uniformly
+    ///   distributed leaf, in a tree with `--btree-fanout`. The upper levels are hot.
+    #[clap(long, default_value = "random")]
+    generator: String,
+    /// How `--generator=random` picks the keys. Hot keys are at the start of the keyspace.
+    #[clap(long, value_enum, default_value = "uniform")]
+    key_distribution: KeyDistribution,
+    /// Exponent of `--key-distribution=zipfian`, positive.
+    #[clap(long, default_value = "0.99", value_parser = parse_exponent)]
+    zipf_exponent: f64,
+    /// Fraction of the keyspace that `--key-distribution=hotspot` considers hot, in [0, 1].
+    #[clap(long, default_value = "0.2", value_parser = parse_fraction)]
+    hot_fraction: f64,
+    /// Probability of `--key-distribution=hotspot` picking a hot key, in [0, 1].
+    #[clap(long, default_value = "0.8", value_parser = parse_fraction)]
+    hot_probability: f64,
+    /// Children per page of `--generator=btree-descent`.
+    #[clap(long, default_value = "256")]
+    btree_fanout: u32,
+}
+
+impl Args {
+    /// A generator for one target.
+    pub(crate) fn new_generator(&self) -> anyhow::Result<Box<dyn RequestGenerator>> {
+        let registration = GENERATORS
+            .iter()
+            .find(|r| r.name == self.generator)
+            .with_context(|| {
+                let names: Vec<_> = GENERATORS.iter().map(|r| r.name).collect();
+                format!(
+                    "unknown generator {:?}, expected one of {names:?}",
+                    self.generator
+                )
+            })?;
+        Ok((registration.new)(self))
+    }
+}
+
+fn parse_fraction(s: &str) -> anyhow::Result<f64> {
+    let fraction = s.parse::<f64>()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        anyhow::bail!("{fraction} is not in [0, 1]");
+    }
+    Ok(fraction)
+}
+
+fn parse_exponent(s: &str) -> anyhow::Result<f64> {
+    let exponent = s.parse::<f64>()?;
+    if !(exponent.is_finite() && exponent > 0.0) {
+        anyhow::bail!("{exponent} is not a positive number");
+    }
+    Ok(exponent)
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub(crate) enum KeyDistribution {
+    Uniform,
+    /// Approximated by its continuous counterpart, so that it works for any keyspace size.
+    Zipfian,
+    /// `--hot-probability` of the requests go to `--hot-fraction` of the keyspace.
+    Hotspot,
+}
+
+struct Random {
+    key_distribution: KeyDistribution,
+    zipf_exponent: f64,
+    hot_fraction: f64,
+    hot_probability: f64,
+}
+
+impl Random {
+    fn new(args: &Args) -> Self {
+        Random {
+            key_distribution: args.key_distribution,
+            zipf_exponent: args.zipf_exponent,
+            hot_fraction: args.hot_fraction,
+            hot_probability: args.hot_probability,
+        }
+    }
+}
+
+impl RequestGenerator for Random {
+    fn next_key(&mut self, rng: &mut dyn RngCore, len: i128) -> i128 {
+        match self.key_distribution {
+            KeyDistribution::Uniform => rng.gen_range(0..len),
+            KeyDistribution::Zipfian => {
+                // inverse CDF of the bounded continuous zipf distribution, in [1, len]
+                let (n, s, u) = (len as f64, self.zipf_exponent, rng.gen::<f64>());
+                let rank = if (s - 1.0).abs() < 1e-9 {
+                    n.powf(u)
+                } else {
+                    ((n.powf(1.0 - s) - 1.0) * u + 1.0).powf(1.0 / (1.0 - s))
+                };
+                (rank as i128 - 1).clamp(0, len - 1)
+            }
+            KeyDistribution::Hotspot => {
+                let hot_len = ((len as f64 * self.hot_fraction) as i128).clamp(1, len);
+                if hot_len == len || rng.gen_bool(self.hot_probability) {
+                    rng.gen_range(0..hot_len)
+                } else {
+                    rng.gen_range(hot_len..len)
+                }
+            }
+        }
+    }
+}
+
+/// Lays a tree with `fanout` over the keyspace: a page at `level` covers `fanout^level`
+/// consecutive leaf keys and is stored at the first of them. A lookup reads the pages on the path
+/// from the root to its leaf.
+struct BtreeDescent {
+    fanout: i128,
+    /// The remaining pages of the current lookup.
+    path: VecDeque<i128>,
+}
+
+impl BtreeDescent {
+    fn new(args: &Args) -> Self {
+        BtreeDescent {
+            fanout: i128::from(args.btree_fanout.max(2)),
+            path: VecDeque::new(),
+        }
+    }
+}
+
+impl RequestGenerator for BtreeDescent {
+    fn next_key(&mut self, rng: &mut dyn RngCore, len: i128) -> i128 {
+        if self.path.is_empty() {
+            let leaf = rng.gen_range(0..len);
+            // the subtree of each level, from the leaf up to the one covering the whole keyspace
+            let mut subtree_len = 1;
+            loop {
+                self.path.push_front(leaf - leaf % subtree_len);
+                if subtree_len >= len {
+                    break;
+                }
+                subtree_len = subtree_len.saturating_mul(self.fanout);
+            }
+        }
+        self.path.pop_front().expect("filled above")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn zipfian_keys_favor_the_start_of_the_keyspace() {
+        let len = 1000;
+        for zipf_exponent in [0.99, 1.0, 1.5] {
+            let mut generator = Random {
+                key_distribution: KeyDistribution::Zipfian,
+                zipf_exponent,
+                hot_fraction: 0.0,
+                hot_probability: 0.0,
+            };
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut counts = vec![0usize; len as usize];
+            for _ in 0..10_000 {
+                let key = generator.next_key(&mut rng, len);
+                assert!((0..len).contains(&key), "{key} out of range");
+                counts[key as usize] += 1;
+            }
+            let max = counts.iter().max().unwrap();
+            assert_eq!(counts[0], *max, "exponent {zipf_exponent}");
+            let first_tenth: usize = counts[..len as usize / 10].iter().sum();
+            assert!(
+                first_tenth > 5_000,
+                "exponent {zipf_exponent}: {first_tenth}"
+            );
+        }
+    }
+
+    #[test]
+    fn btree_descent_reads_from_the_root_to_the_leaf() {
+        let mut generator = BtreeDescent {
+            fanout: 4,
+            path: VecDeque::new(),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for (len, depth) in [(1, 1), (10, 3), (16, 3), (40, 4)] {
+            for _ in 0..100 {
+                let path = (0..depth)
+                    .map(|_| generator.next_key(&mut rng, len))
+                    .collect::<Vec<_>>();
+                let leaf = *path.last().unwrap();
+                assert!((0..len).contains(&leaf));
+                // each page is at the first key of its subtree, the root at the first of all
+                let expected = (0..depth)
+                    .rev()
+                    .map(|level| leaf - leaf % 4i128.pow(level))
+                    .collect::<Vec<_>>();
+                assert_eq!(path, expected, "keyspace of {len}");
+                assert_eq!(path[0], 0);
+            }
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use std::time::Duration;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Context;
+use once_cell::sync::OnceCell;
 
 pub(crate) struct Stats {
     latency_histo: hdrhistogram::Histogram<u64>,
@@ -25,12 +28,13 @@ impl Stats {
         Ok(())
     }
     pub(crate) fn output(&self) -> Output {
-        let latency_percentiles = std::array::from_fn(|idx| {
-            let micros = self
-                .latency_histo
-                .value_at_percentile(LATENCY_PERCENTILES[idx]);
-            Duration::from_micros(micros)
-        });
+        let latency_percentiles = latency_percentiles()
+            .iter()
+            .map(|p| {
+                let micros = self.latency_histo.value_at_percentile(*p);
+                (*p, Duration::from_micros(micros))
+            })
+            .collect();
         Output {
             request_count: self.latency_histo.len(),
             latency_mean: Duration::from_micros(self.latency_histo.mean() as u64),
@@ -39,12 +43,81 @@ impl Stats {
             },
         }
     }
+    pub(crate) fn request_count(&self) -> u64 {
+        self.latency_histo.len()
+    }
+    pub(crate) fn latency_mean(&self) -> Duration {
+        Duration::from_micros(self.latency_histo.mean() as u64)
+    }
+    pub(crate) fn latency_percentile(&self, percentile: f64) -> Duration {
+        Duration::from_micros(self.latency_histo.value_at_percentile(percentile))
+    }
     pub(crate) fn add(&mut self, other: &Self) {
         let Self {
             ref mut latency_histo,
         } = self;
         latency_histo.add(&other.latency_histo).unwrap();
     }
+    pub(crate) fn reset(&mut self) {
+        self.latency_histo.reset();
+    }
+    pub(crate) fn serialize(&self, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        use hdrhistogram::serialization::Serializer;
+        hdrhistogram::serialization::V2Serializer::new()
+            .serialize(&self.latency_histo, buf)
+            .map_err(|e| anyhow::anyhow!("serialize histogram: {e:?}"))?;
+        Ok(())
+    }
+    /// Writes the histogram to `path` as an interval log with a single interval, `start` plus
+    /// `elapsed`, in the format of HdrHistogram's `HistogramLogWriter`: its tools, e.g.,
+    /// `HistogramLogProcessor`, can merge the logs of several runs or compute any percentile.
+    /// Values are in microseconds.
+    pub(crate) fn write_histogram_log(
+        &self,
+        path: &Path,
+        start: SystemTime,
+        elapsed: Duration,
+    ) -> anyhow::Result<()> {
+        use hdrhistogram::serialization::interval_log::{IntervalLogWriterBuilder, Tag};
+        use hdrhistogram::serialization::V2DeflateSerializer;
+
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("create histogram file {}", path.display()))?;
+        let mut file = std::io::BufWriter::new(file);
+        let mut serializer = V2DeflateSerializer::new();
+        let mut writer = IntervalLogWriterBuilder::new()
+            .with_start_time(start)
+            .begin_log_with(&mut file, &mut serializer)
+            .context("write histogram log header")?;
+        writer
+            .write_comment("pagebench latencies in microseconds")
+            .context("write histogram log header")?;
+        writer
+            .write_histogram(
+                &self.latency_histo,
+                start
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default(),
+                elapsed,
+                Tag::new("total"),
+            )
+            .map_err(|e| anyhow::anyhow!("write histogram: {e:?}"))?;
+        drop(writer);
+        file.flush()
+            .with_context(|| format!("write histogram file {}", path.display()))
+    }
+    pub(crate) fn deserialize(mut buf: &[u8]) -> anyhow::Result<Self> {
+        let latency_histo: hdrhistogram::Histogram<u64> =
+            hdrhistogram::serialization::Deserializer::new()
+                .deserialize(&mut buf)
+                .map_err(|e| anyhow::anyhow!("deserialize histogram: {e:?}"))?;
+        let mut stats = Self::new();
+        stats
+            .latency_histo
+            .add(&latency_histo)
+            .context("add to histogram")?;
+        Ok(stats)
+    }
 }
 
 impl Default for Stats {
@@ -53,10 +126,33 @@ impl Default for Stats {
     }
 }
 
-const LATENCY_PERCENTILES: [f64; 4] = [95.0, 99.00, 99.90, 99.99];
+const DEFAULT_LATENCY_PERCENTILES: [f64; 4] = [95.0, 99.00, 99.90, 99.99];
+
+/// The percentiles that [`Output`] reports, see [`set_latency_percentiles`].
+static LATENCY_PERCENTILES: OnceCell<Vec<f64>> = OnceCell::new();
+
+/// Sets the percentiles that [`Output`] reports, for the whole process. Without a call, it
+/// reports [`DEFAULT_LATENCY_PERCENTILES`].
+pub(crate) fn set_latency_percentiles(mut percentiles: Vec<f64>) -> anyhow::Result<()> {
+    if let Some(p) = percentiles.iter().find(|p| !(0.0..=100.0).contains(*p)) {
+        anyhow::bail!("percentile {p} is not between 0 and 100");
+    }
+    percentiles.sort_by(f64::total_cmp);
+    percentiles.dedup();
+    LATENCY_PERCENTILES
+        .set(percentiles)
+        .map_err(|_| anyhow::anyhow!("latency percentiles are already set"))
+}
+
+fn latency_percentiles() -> &'static [f64] {
+    LATENCY_PERCENTILES
+        .get()
+        .map(Vec::as_slice)
+        .unwrap_or(&DEFAULT_LATENCY_PERCENTILES)
+}
 
 struct LatencyPercentiles {
-    latency_percentiles: [Duration; 4],
+    latency_percentiles: Vec<(f64, Duration)>,
 }
 
 impl serde::Serialize for LatencyPercentiles {
@@ -65,14 +161,11 @@ impl serde::Serialize for LatencyPercentiles {
         S: serde::Serializer,
     {
         use serde::ser::SerializeMap;
-        let mut ser = serializer.serialize_map(Some(LATENCY_PERCENTILES.len()))?;
-        for p in LATENCY_PERCENTILES {
+        let mut ser = serializer.serialize_map(Some(self.latency_percentiles.len()))?;
+        for (p, latency) in &self.latency_percentiles {
             ser.serialize_entry(
                 &format!("p{p}"),
-                &format!(
-                    "{}",
-                    &humantime::format_duration(self.latency_percentiles[0])
-                ),
+                &format!("{}", &humantime::format_duration(*latency)),
             )?;
         }
         ser.end()
@@ -86,3 +179,21 @@ pub(crate) struct Output {
     latency_mean: Duration,
     latency_percentiles: LatencyPercentiles,
 }
+
+impl Output {
+    /// The latencies minus `offset`, e.g., the network round trip, but at least zero.
+    pub(crate) fn minus(&self, offset: Duration) -> Output {
+        Output {
+            request_count: self.request_count,
+            latency_mean: self.latency_mean.saturating_sub(offset),
+            latency_percentiles: LatencyPercentiles {
+                latency_percentiles: self
+                    .latency_percentiles
+                    .latency_percentiles
+                    .iter()
+                    .map(|(p, latency)| (*p, latency.saturating_sub(offset)))
+                    .collect(),
+            },
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Reproducible random choices, so that two runs of a benchmark issue the same requests, e.g.,
+//! to compare the performance before and after a change.
+//!
+//! Each stream of choices, e.g., the requests for one target, gets its own RNG, derived from the
+//! seed and the stream's identity only. The streams are thus independent of how the tasks are
+//! scheduled onto threads. The sequences are reproducible with the same pagebench build.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tracing::info;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Seed for the random choices of the benchmark. If not set, a random seed is used, and
+    /// reported in the output to reproduce the run.
+    #[clap(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Seed(u64);
+
+impl Args {
+    pub(crate) fn seed(&self) -> Seed {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        info!("using seed {seed}");
+        Seed(seed)
+    }
+}
+
+impl Seed {
+    pub(crate) fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The RNG for the stream of choices identified by `stream`.
+    pub(crate) fn rng(self, stream: impl Hash) -> StdRng {
+        // `DefaultHasher::new` always uses the same keys, unlike `RandomState`
+        let mut hasher = DefaultHasher::new();
+        self.0.hash(&mut hasher);
+        stream.hash(&mut hasher);
+        StdRng::seed_from_u64(hasher.finish())
+    }
+}
@@ -0,0 +1,76 @@
+//! Calibration of the network round-trip time to the page service, with
+//! `--rtt-calibration-samples`.
+//!
+//! Before the run, we time TCP handshakes with the page service: a connect completes after one
+//! round trip, and doesn't involve the pageserver's request processing. The pagestream protocol
+//! has no no-op request that would measure the same on an established connection. Subtracting
+//! the median round trip from the latencies makes the runs of co-located clients and of clients
+//! in another availability zone comparable. It is an approximation: the requests and responses
+//! are larger than the handshake's segments.
+
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tracing::info;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Before the run, time this many TCP handshakes with the page service, and also report the
+    /// latencies with the median round trip subtracted, as `total_rtt_adjusted`.
+    #[clap(long)]
+    rtt_calibration_samples: Option<NonZeroUsize>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Calibration {
+    samples: usize,
+    #[serde(with = "humantime_serde")]
+    rtt_min: Duration,
+    #[serde(with = "humantime_serde")]
+    rtt_median: Duration,
+    #[serde(with = "humantime_serde")]
+    rtt_max: Duration,
+}
+
+impl Calibration {
+    /// The round-trip time to subtract from the latencies.
+    pub(crate) fn rtt(&self) -> Duration {
+        self.rtt_median
+    }
+}
+
+/// Times the handshakes with `host_port`. None without `--rtt-calibration-samples`.
+pub(crate) async fn calibrate(args: &Args, host_port: &str) -> anyhow::Result<Option<Calibration>> {
+    let Some(samples) = args.rtt_calibration_samples else {
+        return Ok(None);
+    };
+    // resolve once, so that the samples don't include name resolution
+    let addr = tokio::net::lookup_host(host_port)
+        .await?
+        .next()
+        .with_context(|| format!("resolve {host_port}"))?;
+
+    let mut rtts = Vec::with_capacity(samples.get());
+    for _ in 0..samples.get() {
+        let start = Instant::now();
+        let stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("connect to {addr}"))?;
+        rtts.push(start.elapsed());
+        drop(stream);
+    }
+    rtts.sort();
+
+    let calibration = Calibration {
+        samples: rtts.len(),
+        rtt_min: rtts[0],
+        rtt_median: rtts[rtts.len() / 2],
+        rtt_max: rtts[rtts.len() - 1],
+    };
+    info!(
+        "round trip to {addr}: median {:?}, min {:?}, max {:?}",
+        calibration.rtt_median, calibration.rtt_min, calibration.rtt_max
+    );
+    Ok(Some(calibration))
+}
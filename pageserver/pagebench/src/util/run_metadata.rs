@@ -0,0 +1,105 @@
+//! The metadata of a benchmark run, at the top of its output, for the pipelines that ingest the
+//! results: the version of the output's schema, the command line, when the run started and
+//! ended, and the build of each pageserver that it ran against.
+
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+/// The version of the output's schema. Bump it when a change of the output breaks its readers,
+/// e.g., when a field is renamed, removed or changes its meaning, but not for new fields.
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(serde::Serialize)]
+pub(crate) struct Metadata {
+    schema_version: u32,
+    /// The command line, including the program name.
+    args: Vec<String>,
+    #[serde(with = "humantime_serde")]
+    start: SystemTime,
+    #[serde(with = "humantime_serde")]
+    end: SystemTime,
+    pageservers: Vec<PageserverBuild>,
+}
+
+#[derive(serde::Serialize)]
+struct PageserverBuild {
+    mgmt_api_endpoint: String,
+    /// The labels of the pageserver's `libmetrics_build_info`, or the error we got when
+    /// fetching them.
+    build_info: Result<BuildInfo, String>,
+}
+
+#[derive(serde::Serialize)]
+struct BuildInfo {
+    revision: String,
+    build_tag: String,
+}
+
+/// Records a run from [`Recorder::start`] to [`Recorder::finish`].
+pub(crate) struct Recorder {
+    start: SystemTime,
+}
+
+impl Recorder {
+    pub(crate) fn start() -> Self {
+        Recorder {
+            start: SystemTime::now(),
+        }
+    }
+
+    /// Ends the run, and fetches the builds of the pageservers at `mgmt_api_endpoints`.
+    pub(crate) async fn finish(self, mgmt_api_endpoints: &[&str]) -> Metadata {
+        let end = SystemTime::now();
+        let client = reqwest::Client::new();
+        let mut pageservers = Vec::new();
+        for mgmt_api_endpoint in mgmt_api_endpoints {
+            pageservers.push(PageserverBuild {
+                mgmt_api_endpoint: mgmt_api_endpoint.to_string(),
+                build_info: build_info(&client, mgmt_api_endpoint)
+                    .await
+                    .map_err(|e| format!("{e:#}")),
+            });
+        }
+        Metadata {
+            schema_version: SCHEMA_VERSION,
+            args: std::env::args().collect(),
+            start: self.start,
+            end,
+            pageservers,
+        }
+    }
+}
+
+/// The build of the pageserver, from the labels of the `libmetrics_build_info` in its
+/// `/metrics`: the management API has no endpoint for it.
+async fn build_info(
+    client: &reqwest::Client,
+    mgmt_api_endpoint: &str,
+) -> anyhow::Result<BuildInfo> {
+    let body = client
+        .get(format!("{mgmt_api_endpoint}/metrics"))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let labels = body
+        .lines()
+        .find_map(|line| line.strip_prefix("libmetrics_build_info{"))
+        .and_then(|line| line.split_once('}'))
+        .map(|(labels, _value)| labels)
+        .context("no libmetrics_build_info in the metrics")?;
+    let label = |name: &str| {
+        let value = labels
+            .split(',')
+            .find_map(|label| label.trim().strip_prefix(name)?.strip_prefix("=\""))
+            .and_then(|value| value.strip_suffix('"'))
+            .with_context(|| format!("no {name} in libmetrics_build_info"))?;
+        anyhow::Ok(value.to_owned())
+    };
+    Ok(BuildInfo {
+        revision: label("revision")?,
+        build_tag: label("build_tag")?,
+    })
+}
@@ -0,0 +1,185 @@
+//! Snapshots of the pageserver's own metrics, scraped from its `/metrics` endpoint with
+//! `--server-metrics`.
+//!
+//! They end up in the benchmark's output next to the latencies, so that one artifact tells
+//! whether e.g. a latency spike coincided with page cache misses, on-demand downloads or
+//! compactions. Unlike `--prometheus-listen`, this needs no Prometheus server.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Scrape the pageserver's metrics before and after the run, and report the selected ones
+    /// as `server_metrics` in the output.
+    #[clap(long)]
+    server_metrics: bool,
+    /// Also scrape the metrics this often during the run.
+    #[clap(long, requires = "server_metrics")]
+    server_metrics_interval: Option<humantime::Duration>,
+    /// Report the series whose name starts with this. May be repeated. By default, the page
+    /// cache hits, the on-demand layer downloads and the storage operations, e.g., compactions.
+    #[clap(long = "server-metric", requires = "server_metrics")]
+    server_metric_prefixes: Vec<String>,
+}
+
+const DEFAULT_PREFIXES: &[&str] = &[
+    "pageserver_page_cache_read_hits_total",
+    "pageserver_page_cache_read_accesses_total",
+    "pageserver_remote_ondemand_downloaded_layers_total",
+    "pageserver_remote_ondemand_downloaded_bytes_total",
+    "pageserver_storage_operations_seconds_global_count",
+];
+
+struct Scraper {
+    client: reqwest::Client,
+    url: String,
+    prefixes: Vec<String>,
+    start: Instant,
+    snapshots: Mutex<Vec<Snapshot>>,
+}
+
+/// The snapshots of one run, from [`Snapshots::start`] to [`Snapshots::finish`].
+pub(crate) struct Snapshots {
+    scraper: Arc<Scraper>,
+    interval_task: Option<JoinHandle<()>>,
+}
+
+impl Snapshots {
+    /// Takes the first snapshot, and starts taking them every `--server-metrics-interval`.
+    /// None without `--server-metrics`.
+    pub(crate) async fn start(
+        args: &Args,
+        mgmt_api_endpoint: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        if !args.server_metrics {
+            return Ok(None);
+        }
+        let prefixes = if args.server_metric_prefixes.is_empty() {
+            DEFAULT_PREFIXES.iter().map(|p| p.to_string()).collect()
+        } else {
+            args.server_metric_prefixes.clone()
+        };
+        let scraper = Arc::new(Scraper {
+            client: reqwest::Client::new(),
+            url: format!("{mgmt_api_endpoint}/metrics"),
+            prefixes,
+            start: Instant::now(),
+            snapshots: Mutex::new(Vec::new()),
+        });
+        scraper
+            .scrape()
+            .await
+            .context("scrape pageserver metrics before the run")?;
+
+        let interval_task = args.server_metrics_interval.map(|interval| {
+            let scraper = Arc::clone(&scraper);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval.into());
+                ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                ticker.tick().await; // the first tick is immediate, we just scraped
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = scraper.scrape().await {
+                        warn!("failed to scrape pageserver metrics: {e:#}");
+                    }
+                }
+            })
+        });
+
+        Ok(Some(Snapshots {
+            scraper,
+            interval_task,
+        }))
+    }
+
+    /// Stops the periodic snapshots and takes the last one.
+    pub(crate) async fn finish(self) -> anyhow::Result<Output> {
+        if let Some(task) = self.interval_task {
+            task.abort();
+            let _ = task.await;
+        }
+        self.scraper
+            .scrape()
+            .await
+            .context("scrape pageserver metrics after the run")?;
+
+        let snapshots = std::mem::take(&mut *self.scraper.snapshots.lock().unwrap());
+        let (first, last) = (
+            snapshots.first().expect("scraped on start"),
+            snapshots.last().expect("scraped above"),
+        );
+        let delta = last
+            .samples
+            .iter()
+            .map(|(series, value)| {
+                // a series that appeared during the run started from zero
+                let before = first.samples.get(series).copied().unwrap_or(0.0);
+                (series.clone(), value - before)
+            })
+            .collect();
+        Ok(Output { delta, snapshots })
+    }
+}
+
+impl Scraper {
+    async fn scrape(&self) -> anyhow::Result<()> {
+        let elapsed = self.start.elapsed();
+        let body = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let samples = parse(&body)
+            .filter(|(series, _)| self.prefixes.iter().any(|p| series.starts_with(p.as_str())))
+            .map(|(series, value)| (series.to_owned(), value))
+            .collect();
+        self.snapshots
+            .lock()
+            .unwrap()
+            .push(Snapshot { elapsed, samples });
+        Ok(())
+    }
+}
+
+/// The samples of the Prometheus text format, as the series, i.e., the name with its labels,
+/// and the value. Skips what it doesn't understand.
+fn parse(body: &str) -> impl Iterator<Item = (&str, f64)> {
+    body.lines().filter_map(|line| {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        // label values may contain spaces, the series ends with its last closing brace
+        let series_end = match line.find('{') {
+            Some(_) => line.rfind('}')? + 1,
+            None => line.find(char::is_whitespace)?,
+        };
+        let (series, rest) = line.split_at(series_end);
+        let value = rest.split_whitespace().next()?.parse().ok()?;
+        Some((series, value))
+    })
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Output {
+    /// The change of each series over the run, from the first to the last snapshot.
+    delta: BTreeMap<String, f64>,
+    snapshots: Vec<Snapshot>,
+}
+
+#[derive(serde::Serialize)]
+struct Snapshot {
+    /// Since the first snapshot, which is taken right before the run.
+    #[serde(with = "humantime_serde")]
+    elapsed: Duration,
+    samples: BTreeMap<String, f64>,
+}
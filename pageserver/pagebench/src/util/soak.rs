@@ -0,0 +1,139 @@
+//! Soak mode, selected with `--soak-dir`, for stability tests that run for days.
+//!
+//! A single histogram over such a run hides a regression that sets in late, e.g., as layers
+//! accumulate, under the many hours before it. So the run is cut into periods of
+//! `--soak-rotate-interval`, and each period gets its own files in the soak directory, named
+//! after the period's start time: the interval stats of the period, and once it ends, a summary
+//! of its histogram. The histogram is then reset for the next period.
+//!
+//! The benchmark runs until it is stopped. The files of all but the current period are complete
+//! at any time, so they can be inspected while it's running.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use tracing::info;
+
+use crate::util::{interval_stats, request_stats};
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Run until stopped, and write the interval stats and a summary of every
+    /// `--soak-rotate-interval` to timestamped files in this directory.
+    #[clap(long, conflicts_with = "runtime")]
+    soak_dir: Option<PathBuf>,
+    #[clap(long, default_value = "1h")]
+    soak_rotate_interval: humantime::Duration,
+}
+
+/// The `<start>.summary.json` of a period.
+#[derive(serde::Serialize)]
+struct Summary {
+    #[serde(with = "humantime_serde")]
+    start: SystemTime,
+    /// Shorter than `--soak-rotate-interval` for the last period.
+    #[serde(with = "humantime_serde")]
+    elapsed: Duration,
+    rps: f64,
+    #[serde(flatten)]
+    stats: request_stats::Output,
+}
+
+impl Args {
+    pub(crate) fn enabled(&self) -> bool {
+        self.soak_dir.is_some()
+    }
+}
+
+pub(crate) struct Soak {
+    dir: PathBuf,
+    rotate_interval: Duration,
+    format: interval_stats::Format,
+    period_start: (Instant, SystemTime),
+    period: request_stats::Stats,
+    interval_stats: interval_stats::Writer,
+}
+
+impl Soak {
+    /// Creates the soak directory and starts the first period at `now`. None without
+    /// `--soak-dir`.
+    pub(crate) fn start(
+        args: &Args,
+        format: interval_stats::Format,
+        now: (Instant, SystemTime),
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(dir) = &args.soak_dir else {
+            return Ok(None);
+        };
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create soak dir {}", dir.display()))?;
+        let interval_stats =
+            interval_stats::Writer::create(&period_path(dir, now.1, format.extension()), format)?;
+        Ok(Some(Soak {
+            dir: dir.clone(),
+            rotate_interval: args.soak_rotate_interval.into(),
+            format,
+            period_start: now,
+            period: request_stats::Stats::new(),
+            interval_stats,
+        }))
+    }
+
+    /// Adds the interval that started at `interval_start` to the current period, and rotates to
+    /// the next period if the current one is over at `now`.
+    pub(crate) fn observe(
+        &mut self,
+        interval_start: (Instant, SystemTime),
+        now: (Instant, SystemTime),
+        stats: &request_stats::Stats,
+    ) -> anyhow::Result<()> {
+        self.interval_stats
+            .write(interval_start.1, now.0 - interval_start.0, stats)?;
+        self.period.add(stats);
+        if now.0 - self.period_start.0 >= self.rotate_interval {
+            self.write_summary(now.0)?;
+            self.period.reset();
+            self.period_start = now;
+            self.interval_stats = interval_stats::Writer::create(
+                &period_path(&self.dir, now.1, self.format.extension()),
+                self.format,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the summary of the last period, which ends at `now`.
+    pub(crate) fn finish(self, now: Instant) -> anyhow::Result<()> {
+        self.write_summary(now)
+    }
+
+    fn write_summary(&self, now: Instant) -> anyhow::Result<()> {
+        let elapsed = now - self.period_start.0;
+        let rps = self.period.request_count() as f64 / elapsed.as_secs_f64();
+        let summary = Summary {
+            start: self.period_start.1,
+            elapsed,
+            rps,
+            stats: self.period.output(),
+        };
+        let path = period_path(&self.dir, self.period_start.1, "summary.json");
+        let summary = serde_json::to_string_pretty(&summary).unwrap();
+        std::fs::write(&path, summary)
+            .with_context(|| format!("write soak summary {}", path.display()))?;
+        info!(
+            "soak: {} requests at {rps:.0} RPS since {}",
+            self.period.request_count(),
+            humantime::format_rfc3339_seconds(self.period_start.1)
+        );
+        Ok(())
+    }
+}
+
+/// `<dir>/<start>.<suffix>`, with the start in RFC 3339 so that the files sort by time.
+fn period_path(dir: &Path, start: SystemTime, suffix: &str) -> PathBuf {
+    dir.join(format!(
+        "{}.{suffix}",
+        humantime::format_rfc3339_seconds(start)
+    ))
+}
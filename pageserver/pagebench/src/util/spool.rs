@@ -0,0 +1,135 @@
+//! An append-only file of [`request_stats::Stats`], one per interval of a benchmark run.
+//!
+//! Each record is the interval's start time in milliseconds since the epoch (u64), the length
+//! of the serialized histogram (u32), and the histogram in the hdrhistogram V2 format, all
+//! big-endian. Records are flushed as they are written, so if the benchmark crashes, all but the
+//! last interval survive, and [`read_aggregate`] ignores a truncated last record.
+
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::util::request_stats;
+
+pub(crate) struct Spool {
+    path: PathBuf,
+    file: BufWriter<std::fs::File>,
+    buf: Vec<u8>,
+}
+
+impl Spool {
+    pub(crate) fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("create spool {}", path.display()))?;
+        Ok(Spool {
+            path: path.to_owned(),
+            file: BufWriter::new(file),
+            buf: Vec::new(),
+        })
+    }
+
+    pub(crate) fn append(
+        &mut self,
+        interval_start: SystemTime,
+        stats: &request_stats::Stats,
+    ) -> anyhow::Result<()> {
+        self.buf.clear();
+        stats.serialize(&mut self.buf)?;
+        let millis = interval_start
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let len = u32::try_from(self.buf.len()).context("histogram too large")?;
+        self.file.write_all(&millis.to_be_bytes())?;
+        self.file.write_all(&len.to_be_bytes())?;
+        self.file.write_all(&self.buf)?;
+        self.file
+            .flush()
+            .with_context(|| format!("write spool {}", self.path.display()))
+    }
+}
+
+/// Sums up all intervals in the spool at `path`.
+pub(crate) fn read_aggregate(path: &Path) -> anyhow::Result<request_stats::Stats> {
+    let mut file = std::io::BufReader::new(
+        std::fs::File::open(path).with_context(|| format!("open spool {}", path.display()))?,
+    );
+    let mut total = request_stats::Stats::new();
+    let mut header = [0u8; 12];
+    let mut buf = Vec::new();
+    loop {
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context("read spool"),
+        }
+        let len = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+        buf.resize(len, 0);
+        match file.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                tracing::warn!("ignoring truncated last record in spool {}", path.display());
+                break;
+            }
+            Err(e) => return Err(e).context("read spool"),
+        }
+        total.add(&request_stats::Stats::deserialize(&buf)?);
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn stats(latencies_micros: &[u64]) -> request_stats::Stats {
+        let mut stats = request_stats::Stats::new();
+        for micros in latencies_micros {
+            stats.observe(Duration::from_micros(*micros)).unwrap();
+        }
+        stats
+    }
+
+    #[test]
+    fn read_aggregate_ignores_a_truncated_last_record() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool").into_std_path_buf();
+        let len = || std::fs::metadata(&path).unwrap().len();
+
+        let mut spool = Spool::create(&path).unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        spool.append(start, &stats(&[100, 200])).unwrap();
+        let one_record = len();
+        spool
+            .append(start + Duration::from_secs(1), &stats(&[300, 400, 500]))
+            .unwrap();
+        let two_records = len();
+        drop(spool);
+
+        assert_eq!(read_aggregate(&path).unwrap().request_count(), 5);
+
+        let truncated_count = |len: u64| {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(len)
+                .unwrap();
+            read_aggregate(&path).unwrap().request_count()
+        };
+        // in the histogram of the last record
+        assert_eq!(truncated_count(two_records - 1), 2);
+        // in the header of the last record
+        assert_eq!(truncated_count(one_record + 5), 2);
+        assert_eq!(truncated_count(one_record), 2);
+        assert_eq!(truncated_count(0), 0);
+    }
+}
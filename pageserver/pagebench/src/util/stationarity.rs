@@ -0,0 +1,246 @@
+//! Whether a benchmark run was in steady state, selected with `--stationarity-check`.
+//!
+//! The run is cut into intervals of `--interval-stats-interval`. For each metric, the median
+//! over the intervals of the first half of the run is compared to the median over the second
+//! half. A run that is still warming up, e.g., filling the page cache, or that degrades, e.g.,
+//! as layers pile up, shows up as a difference beyond `--stationarity-tolerance`: its totals
+//! describe neither state.
+//!
+//! Medians rather than the merged histograms of the halves, so that a single stall, e.g., a
+//! compaction, doesn't flag the run.
+
+use std::time::Duration;
+
+use crate::util::request_stats;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Check whether throughput and latency were in steady state over the run, and report it
+    /// as `stationarity` in the output.
+    #[clap(long)]
+    stationarity_check: bool,
+    /// Relative difference between the first and second half of the run above which a metric
+    /// counts as not stationary.
+    #[clap(long, default_value = "0.1")]
+    stationarity_tolerance: f64,
+}
+
+/// Below this many intervals, the halves are too short to compare.
+const MIN_INTERVALS: usize = 6;
+
+struct Interval {
+    rps: f64,
+    /// None if the interval has no requests.
+    latency_micros: Option<[f64; 2]>,
+}
+
+/// The metrics of [`Interval::latency_micros`].
+const LATENCY_METRICS: [(&str, f64); 2] = [("latency_p50_us", 50.0), ("latency_p99_us", 99.0)];
+
+pub(crate) struct Tracker {
+    tolerance: f64,
+    intervals: Vec<Interval>,
+}
+
+impl Tracker {
+    pub(crate) fn new(args: &Args) -> Option<Self> {
+        args.stationarity_check.then(|| Tracker {
+            tolerance: args.stationarity_tolerance,
+            intervals: Vec::new(),
+        })
+    }
+
+    pub(crate) fn observe(&mut self, elapsed: Duration, stats: &request_stats::Stats) {
+        let latency_micros = (stats.request_count() > 0).then(|| {
+            LATENCY_METRICS
+                .map(|(_, percentile)| stats.latency_percentile(percentile).as_micros() as f64)
+        });
+        self.intervals.push(Interval {
+            rps: stats.request_count() as f64 / elapsed.as_secs_f64(),
+            latency_micros,
+        });
+    }
+
+    pub(crate) fn output(&self) -> Output {
+        if self.intervals.len() < MIN_INTERVALS {
+            return Output {
+                steady_state: None,
+                intervals: self.intervals.len(),
+                metrics: Vec::new(),
+            };
+        }
+
+        let (first, second) = self.intervals.split_at(self.intervals.len() / 2);
+        let mut metrics = vec![self.metric("throughput_rps", first, second, |i| Some(i.rps))];
+        for (idx, (metric, _)) in LATENCY_METRICS.iter().enumerate() {
+            metrics.push(self.metric(metric, first, second, |i| i.latency_micros.map(|l| l[idx])));
+        }
+        Output {
+            steady_state: Some(metrics.iter().all(|m| m.stationary)),
+            intervals: self.intervals.len(),
+            metrics,
+        }
+    }
+
+    fn metric(
+        &self,
+        metric: &str,
+        first: &[Interval],
+        second: &[Interval],
+        value: impl Fn(&Interval) -> Option<f64>,
+    ) -> MetricOutput {
+        let first_half = median(first.iter().filter_map(&value).collect());
+        let second_half = median(second.iter().filter_map(&value).collect());
+        let relative_change = match (first_half, second_half) {
+            (Some(first), Some(second)) if first > 0.0 => Some((second - first) / first),
+            (Some(first), Some(second)) if first == second => Some(0.0),
+            _ => None,
+        };
+        MetricOutput {
+            metric: metric.to_owned(),
+            first_half,
+            second_half,
+            relative_change,
+            stationary: relative_change.is_some_and(|c| c.abs() <= self.tolerance),
+        }
+    }
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Output {
+    /// None if the run had too few intervals to tell.
+    steady_state: Option<bool>,
+    intervals: usize,
+    metrics: Vec<MetricOutput>,
+}
+
+impl Output {
+    pub(crate) fn steady_state(&self) -> Option<bool> {
+        self.steady_state
+    }
+}
+
+#[derive(serde::Serialize)]
+struct MetricOutput {
+    metric: String,
+    /// Medians over the intervals of each half, None if the half has no requests at all.
+    first_half: Option<f64>,
+    second_half: Option<f64>,
+    /// `(second_half - first_half) / first_half`.
+    relative_change: Option<f64>,
+    stationary: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(intervals: impl IntoIterator<Item = (f64, Option<[f64; 2]>)>) -> Tracker {
+        Tracker {
+            tolerance: 0.1,
+            intervals: intervals
+                .into_iter()
+                .map(|(rps, latency_micros)| Interval {
+                    rps,
+                    latency_micros,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(vec![]), None);
+        assert_eq!(median(vec![3.0]), Some(3.0));
+        assert_eq!(median(vec![5.0, 1.0, 3.0]), Some(3.0));
+        assert_eq!(median(vec![4.0, 1.0, 3.0, 2.0]), Some(2.5));
+    }
+
+    #[test]
+    fn too_few_intervals_to_tell() {
+        let output = tracker([(100.0, Some([10.0, 20.0])); MIN_INTERVALS - 1]).output();
+        assert_eq!(output.steady_state(), None);
+        assert_eq!(output.intervals, MIN_INTERVALS - 1);
+        assert!(output.metrics.is_empty());
+    }
+
+    #[test]
+    fn a_stall_is_steady_a_warmup_is_not() {
+        // one stalled interval in each half doesn't move the medians
+        let steady = tracker([
+            (100.0, Some([10.0, 20.0])),
+            (1.0, Some([1000.0, 2000.0])),
+            (104.0, Some([10.0, 21.0])),
+            (102.0, Some([10.0, 20.0])),
+            (98.0, Some([11.0, 20.0])),
+            (0.0, None),
+        ])
+        .output();
+        assert_eq!(steady.steady_state(), Some(true));
+        let throughput = &steady.metrics[0];
+        assert_eq!(throughput.metric, "throughput_rps");
+        assert_eq!(
+            (throughput.first_half, throughput.second_half),
+            (Some(100.0), Some(98.0))
+        );
+        assert_eq!(throughput.relative_change, Some(-0.02));
+
+        // twice the throughput in the second half
+        let warmup = tracker([
+            (50.0, Some([10.0, 20.0])),
+            (50.0, Some([10.0, 20.0])),
+            (50.0, Some([10.0, 20.0])),
+            (100.0, Some([10.0, 20.0])),
+            (100.0, Some([10.0, 20.0])),
+            (100.0, Some([10.0, 20.0])),
+        ])
+        .output();
+        assert_eq!(warmup.steady_state(), Some(false));
+        let stationary = warmup
+            .metrics
+            .iter()
+            .map(|m| (m.metric.as_str(), m.stationary))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stationary,
+            [
+                ("throughput_rps", false),
+                ("latency_p50_us", true),
+                ("latency_p99_us", true)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_half_without_requests_is_not_steady() {
+        let output = tracker([
+            (100.0, Some([10.0, 20.0])),
+            (100.0, Some([10.0, 20.0])),
+            (100.0, Some([10.0, 20.0])),
+            (0.0, None),
+            (0.0, None),
+            (0.0, None),
+        ])
+        .output();
+        assert_eq!(output.steady_state(), Some(false));
+        let latency = &output.metrics[1];
+        assert_eq!(
+            (latency.first_half, latency.second_half),
+            (Some(10.0), None)
+        );
+        assert_eq!(latency.relative_change, None);
+    }
+}
@@ -0,0 +1,234 @@
+//! Page content verification, selected with `--verify`, e.g., to validate a refactoring of the
+//! read path under load.
+//!
+//! Each page that the benchmark reads is checksummed and compared to a reference checksum of the
+//! same page, requested the same way: at the same LSN, and with the same `latest` flag. The
+//! reference is the page's checksum in `--verify-reference`, i.e., the `--verify-out` of a prior
+//! run, or else the first read of the page in this run. Either way, the pages must not change
+//! between the reads, so the timelines must not receive writes unless all requests are at a
+//! pinned LSN, e.g., with `--lsn-distribution=fixed=<lsn>`.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use pageserver_api::models::PagestreamGetPageRequest;
+use tracing::warn;
+use utils::id::TenantTimelineId;
+use utils::lsn::Lsn;
+
+#[derive(clap::Args)]
+pub(crate) struct Args {
+    /// Compare the content of every page read to a reference, and report the divergent pages
+    /// as `verification` in the output. The benchmark then fails if there are any.
+    #[clap(long)]
+    verify: bool,
+    /// The reference checksums, from the `--verify-out` of a prior run. Without it, the first
+    /// read of each page is the reference for the later reads.
+    #[clap(long, requires = "verify")]
+    verify_reference: Option<PathBuf>,
+    /// Write the checksum of every page read to this file, one JSON object per line, e.g., as
+    /// the `--verify-reference` of a later run.
+    #[clap(long, requires = "verify")]
+    verify_out: Option<PathBuf>,
+}
+
+/// The divergent pages beyond this many are only counted.
+const MAX_REPORTED_DIVERGENT_PAGES: usize = 100;
+
+/// A page, requested in one way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+struct Page {
+    timeline: TenantTimelineId,
+    spcnode: u32,
+    dbnode: u32,
+    relnode: u32,
+    forknum: u8,
+    blkno: u32,
+    lsn: Lsn,
+    latest: bool,
+}
+
+/// A line of `--verify-out`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    page: Page,
+    crc32c: u32,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Output {
+    /// The reads that were compared to a reference.
+    verified: u64,
+    /// The reads that became the reference, without `--verify-reference`.
+    references: u64,
+    /// With `--verify-reference`, the reads of pages that it doesn't have.
+    unreferenced: u64,
+    divergent: u64,
+    /// The first divergent reads, up to a limit.
+    divergent_pages: Vec<DivergentPage>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct DivergentPage {
+    #[serde(flatten)]
+    page: Page,
+    expected_crc32c: u32,
+    crc32c: u32,
+}
+
+pub(crate) struct Verifier {
+    /// Whether the references are from `--verify-reference`, rather than the first reads.
+    fixed_references: bool,
+    out: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    references: HashMap<Page, u32>,
+    /// The checksums of this run's reads, with `--verify-out`.
+    read: HashMap<Page, u32>,
+    verified: u64,
+    new_references: u64,
+    unreferenced: u64,
+    divergent: u64,
+    divergent_pages: Vec<DivergentPage>,
+}
+
+impl Output {
+    pub(crate) fn divergent(&self) -> u64 {
+        self.divergent
+    }
+}
+
+impl Verifier {
+    /// Loads the `--verify-reference`. None without `--verify`.
+    pub(crate) fn new(args: &Args) -> anyhow::Result<Option<Self>> {
+        if !args.verify {
+            return Ok(None);
+        }
+        let mut references = HashMap::new();
+        if let Some(path) = &args.verify_reference {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("open verify reference {}", path.display()))?;
+            for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+                let record: Record = serde_json::from_str(&line?).with_context(|| {
+                    format!(
+                        "parse line {} of verify reference {}",
+                        i + 1,
+                        path.display()
+                    )
+                })?;
+                references.insert(record.page, record.crc32c);
+            }
+        }
+        Ok(Some(Verifier {
+            fixed_references: args.verify_reference.is_some(),
+            out: args.verify_out.clone(),
+            inner: Mutex::new(Inner {
+                references,
+                ..Default::default()
+            }),
+        }))
+    }
+
+    /// Checks `content`, the response to `req` for `timeline`.
+    pub(crate) fn check(
+        &self,
+        timeline: TenantTimelineId,
+        req: &PagestreamGetPageRequest,
+        content: &[u8],
+    ) {
+        let page = Page {
+            timeline,
+            spcnode: req.rel.spcnode,
+            dbnode: req.rel.dbnode,
+            relnode: req.rel.relnode,
+            forknum: req.rel.forknum,
+            blkno: req.blkno,
+            lsn: req.lsn,
+            latest: req.latest,
+        };
+        let crc32c = crc32c::crc32c(content);
+
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        if self.out.is_some() {
+            inner.read.insert(page, crc32c);
+        }
+        let expected_crc32c = match inner.references.get(&page) {
+            Some(expected) => *expected,
+            None if self.fixed_references => {
+                inner.unreferenced += 1;
+                return;
+            }
+            None => {
+                inner.references.insert(page, crc32c);
+                inner.new_references += 1;
+                return;
+            }
+        };
+        inner.verified += 1;
+        if crc32c == expected_crc32c {
+            return;
+        }
+        inner.divergent += 1;
+        if inner.divergent_pages.len() < MAX_REPORTED_DIVERGENT_PAGES {
+            warn!(
+                ?page,
+                "divergent page: crc32c {crc32c:#010x}, expected {expected_crc32c:#010x}"
+            );
+            inner.divergent_pages.push(DivergentPage {
+                page,
+                expected_crc32c,
+                crc32c,
+            });
+        }
+    }
+
+    /// Writes the `--verify-out`.
+    pub(crate) fn finish(&self) -> anyhow::Result<Output> {
+        let inner = self.inner.lock().unwrap();
+        if let Some(path) = &self.out {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("create verify out {}", path.display()))?;
+            let mut file = BufWriter::new(file);
+            let mut records: Vec<Record> = inner
+                .read
+                .iter()
+                .map(|(page, crc32c)| Record {
+                    page: *page,
+                    crc32c: *crc32c,
+                })
+                .collect();
+            // for diffing the files of two runs
+            records.sort_by_key(|record| {
+                let page = &record.page;
+                (
+                    page.timeline,
+                    (page.spcnode, page.dbnode, page.relnode, page.forknum),
+                    page.blkno,
+                    page.lsn,
+                    page.latest,
+                )
+            });
+            for record in records {
+                serde_json::to_writer(&mut file, &record)?;
+                writeln!(file)?;
+            }
+            file.flush()
+                .with_context(|| format!("write verify out {}", path.display()))?;
+        }
+        Ok(Output {
+            verified: inner.verified,
+            references: inner.new_references,
+            unreferenced: inner.unreferenced,
+            divergent: inner.divergent,
+            divergent_pages: inner.divergent_pages.clone(),
+        })
+    }
+}
@@ -17,5 +17,8 @@ pub fn check_permission(claims: &Claims, tenant_id: Option<TenantId>) -> Result<
         (Scope::SafekeeperData, _) => Err(AuthError(
             "SafekeeperData scope makes no sense for Pageserver".into(),
         )),
+        (Scope::GenerationsApi | Scope::Admin, _) => Err(AuthError(
+            format!("{:?} scope makes no sense for Pageserver", claims.scope).into(),
+        )),
     }
 }
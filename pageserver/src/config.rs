@@ -123,6 +123,7 @@ pub mod defaults {
 #min_resident_size_override = .. # in bytes
 #evictions_low_residence_duration_metric_threshold = '{DEFAULT_EVICTIONS_LOW_RESIDENCE_DURATION_METRIC_THRESHOLD}'
 #gc_feedback = false
+#never_evict_below_min_resident_size = false
 
 #heatmap_upload_concurrency = {DEFAULT_HEATMAP_UPLOAD_CONCURRENCY}
 
@@ -233,6 +234,11 @@ pub struct PageServerConf {
     /// How many heatmap uploads may be done concurrency: lower values implicitly deprioritize
     /// heatmap uploads vs. other remote storage operations.
     pub heatmap_upload_concurrency: usize,
+
+    /// Whether getpage requests from hot standby replicas count as layer accesses for
+    /// the purpose of eviction. If false, layers that are only read by replicas look
+    /// idle to the eviction policies and may be evicted under disk pressure.
+    pub replica_reads_count_for_eviction: bool,
 }
 
 /// We do not want to store this in a PageServerConf because the latter may be logged
@@ -314,6 +320,8 @@ struct PageServerConfigBuilder {
     control_plane_emergency_mode: BuilderValue<bool>,
 
     heatmap_upload_concurrency: BuilderValue<usize>,
+
+    replica_reads_count_for_eviction: BuilderValue<bool>,
 }
 
 impl Default for PageServerConfigBuilder {
@@ -386,6 +394,8 @@ impl Default for PageServerConfigBuilder {
             control_plane_emergency_mode: Set(false),
 
             heatmap_upload_concurrency: Set(DEFAULT_HEATMAP_UPLOAD_CONCURRENCY),
+
+            replica_reads_count_for_eviction: Set(true),
         }
     }
 }
@@ -534,6 +544,10 @@ impl PageServerConfigBuilder {
         self.heatmap_upload_concurrency = BuilderValue::Set(value)
     }
 
+    pub fn replica_reads_count_for_eviction(&mut self, value: bool) {
+        self.replica_reads_count_for_eviction = BuilderValue::Set(value)
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         let concurrent_tenant_warmup = self
             .concurrent_tenant_warmup
@@ -636,6 +650,9 @@ impl PageServerConfigBuilder {
             heatmap_upload_concurrency: self
                 .heatmap_upload_concurrency
                 .ok_or(anyhow!("missing heatmap_upload_concurrency"))?,
+            replica_reads_count_for_eviction: self
+                .replica_reads_count_for_eviction
+                .ok_or(anyhow!("missing replica_reads_count_for_eviction"))?,
         })
     }
 }
@@ -878,6 +895,9 @@ impl PageServerConf {
                 "heatmap_upload_concurrency" => {
                     builder.heatmap_upload_concurrency(parse_toml_u64(key, item)? as usize)
                 },
+                "replica_reads_count_for_eviction" => {
+                    builder.replica_reads_count_for_eviction(parse_toml_bool(key, item)?)
+                },
                 _ => bail!("unrecognized pageserver option '{key}'"),
             }
         }
@@ -949,6 +969,7 @@ impl PageServerConf {
             control_plane_api_token: None,
             control_plane_emergency_mode: false,
             heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY,
+            replica_reads_count_for_eviction: true,
         }
     }
 }
@@ -1177,7 +1198,8 @@ background_task_maximum_delay = '334 s'
                 control_plane_api: None,
                 control_plane_api_token: None,
                 control_plane_emergency_mode: false,
-                heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY
+                heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY,
+                replica_reads_count_for_eviction: true,
             },
             "Correct defaults should be used when no config values are provided"
         );
@@ -1238,7 +1260,8 @@ background_task_maximum_delay = '334 s'
                 control_plane_api: None,
                 control_plane_api_token: None,
                 control_plane_emergency_mode: false,
-                heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY
+                heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY,
+                replica_reads_count_for_eviction: true,
             },
             "Should be able to parse all basic config values correctly"
         );
@@ -1465,10 +1488,14 @@ threshold = "20m"
             Some(DiskUsageEvictionTaskConfig {
                 max_usage_pct: Percent::new(80).unwrap(),
                 min_avail_bytes: 0,
+                wal_ingest_reserved_bytes: 0,
+                event_sink: None,
                 period: Duration::from_secs(10),
                 #[cfg(feature = "testing")]
                 mock_statvfs: None,
                 eviction_order: crate::disk_usage_eviction_task::EvictionOrder::AbsoluteAccessed,
+                working_set_penalty: Duration::ZERO,
+                adaptive_min_resident_size: None,
             })
         );
         match &conf.default_tenant_conf.eviction_policy {
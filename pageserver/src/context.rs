@@ -135,6 +135,11 @@ pub(crate) enum AccessStatsBehavior {
     /// perhaps because the requestor is a compaction routine that will soon cover
     /// this layer with another.
     Skip,
+
+    /// Record the access in the stats exposed via the management API, but
+    /// do not take it into account for eviction, e.g. reads from hot standby
+    /// replicas when `replica_reads_count_for_eviction` is disabled.
+    ObserveOnly,
 }
 
 pub struct RequestContextBuilder {
@@ -36,28 +36,39 @@
 //! during page reconstruction.
 //! An alternative default for all tenants can be specified in the `tenant_config` section of the config.
 //! Lastly, each tenant can have an override in their respective tenant config (`min_resident_size_override`).
+//! A tenant can also opt out of the weak part of the reservation (`never_evict_below_min_resident_size`):
+//! then its reserved layers are not evicted even if that means we cannot relieve the pressure.
+//! Tenants without an override can have their reservation learned instead of the default, see
+//! [`AdaptiveMinResidentSize`].
 
 // Implementation notes:
 // - The `#[allow(dead_code)]` above various structs are to suppress warnings about only the Debug impl
 //   reading these fields. We use the Debug impl for semi-structured logging, though.
 
 use std::{
-    sync::Arc,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 
 use anyhow::Context;
 use camino::Utf8Path;
+use pageserver_api::shard::TenantShardId;
 use remote_storage::GenericRemoteStorage;
 use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, instrument, warn, Instrument};
-use utils::completion;
 use utils::serde_percent::Percent;
+use utils::{completion, failpoint_support};
 
 use crate::{
     config::PageServerConf,
+    metrics::DISK_USAGE_EVICTION,
     task_mgr::{self, TaskKind, BACKGROUND_RUNTIME},
     tenant::{
         self,
@@ -66,17 +77,113 @@ use crate::{
     },
 };
 
+mod event_sink;
+use event_sink::EvictionOutcome;
+
+pub use event_sink::EvictionEventSinkConfig;
+pub use filesystem_level_usage::{FilesystemUsage, UsageSummary};
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DiskUsageEvictionTaskConfig {
     pub max_usage_pct: Percent,
     pub min_avail_bytes: u64,
     #[serde(with = "humantime_serde")]
     pub period: Duration,
+    /// Free space to keep available for WAL ingest, on top of what `max_usage_pct` and
+    /// `min_avail_bytes` require. Eviction treats this space as used, and while the free
+    /// space is below it, WAL ingest backpressures the computes, see
+    /// [`wal_ingest_headroom_is_low`].
+    #[serde(default)]
+    pub wal_ingest_reserved_bytes: u64,
+    /// Where to record each layer eviction decision, if anywhere.
+    #[serde(default)]
+    pub event_sink: Option<EvictionEventSinkConfig>,
     #[cfg(feature = "testing")]
     pub mock_statvfs: Option<crate::statvfs::mock::Behavior>,
     /// Select sorting for evicted layers
     #[serde(default)]
     pub eviction_order: EvictionOrder,
+    /// Layers that cover keys recently read by computes are considered accessed this much more
+    /// recently than they were, so that the layers backing the working set of a timeline
+    /// survive longer than otherwise similar cold layers. Zero disables it.
+    #[serde(default, with = "humantime_serde")]
+    pub working_set_penalty: Duration,
+    /// Learn the `min_resident_size` of tenants without an override, instead of using the default.
+    #[serde(default)]
+    pub adaptive_min_resident_size: Option<AdaptiveMinResidentSize>,
+}
+
+impl DiskUsageEvictionTaskConfig {
+    /// The config without the credentials it may contain, for the management API.
+    pub fn redacted(&self) -> Self {
+        DiskUsageEvictionTaskConfig {
+            event_sink: self
+                .event_sink
+                .as_ref()
+                .map(EvictionEventSinkConfig::redacted),
+            ..self.clone()
+        }
+    }
+}
+
+/// Bounds of the learned per-tenant `min_resident_size`.
+///
+/// Each iteration that collects eviction candidates grows a tenant's reservation by the bytes of
+/// its layers that were downloaded again after an eviction since the previous iteration, i.e., by
+/// how much the tenant thrashed. Tenants that did not re-download any layers have their
+/// reservation shrunk by an eighth instead. A tenant starts from the default reservation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveMinResidentSize {
+    pub min_bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl AdaptiveMinResidentSize {
+    fn bound(&self, bytes: u64) -> u64 {
+        bytes.min(self.max_bytes).max(self.min_bytes)
+    }
+}
+
+/// A tenant's `min_resident_size` as learned with [`AdaptiveMinResidentSize`].
+#[derive(Debug, Clone, Serialize)]
+pub struct LearnedMinResidentSize {
+    tenant_shard_id: TenantShardId,
+    bytes: u64,
+    /// Re-downloaded between the two most recent iterations.
+    redownloaded_bytes: u64,
+    /// The sum of the tenant's [`Timeline::redownloaded_after_eviction_bytes`] at the most recent
+    /// iteration.
+    #[serde(skip)]
+    redownloaded_bytes_total: u64,
+}
+
+impl LearnedMinResidentSize {
+    fn new(
+        config: &AdaptiveMinResidentSize,
+        tenant_shard_id: TenantShardId,
+        default_bytes: u64,
+        redownloaded_bytes_total: u64,
+    ) -> Self {
+        LearnedMinResidentSize {
+            tenant_shard_id,
+            bytes: config.bound(default_bytes),
+            redownloaded_bytes: 0,
+            redownloaded_bytes_total,
+        }
+    }
+
+    fn update(&mut self, config: &AdaptiveMinResidentSize, redownloaded_bytes_total: u64) {
+        // timelines deleted or reloaded since the previous iteration take their bytes with them
+        let redownloaded = redownloaded_bytes_total.saturating_sub(self.redownloaded_bytes_total);
+        self.redownloaded_bytes_total = redownloaded_bytes_total;
+        self.redownloaded_bytes = redownloaded;
+        let bytes = if redownloaded > 0 {
+            self.bytes.saturating_add(redownloaded)
+        } else {
+            self.bytes - self.bytes / 8
+        };
+        self.bytes = config.bound(bytes);
+    }
 }
 
 /// Selects the sort order for eviction candidates *after* per tenant `min_resident_size`
@@ -100,6 +207,77 @@ pub enum EvictionOrder {
         #[serde(default)]
         highest_layer_count_loses_first: bool,
     },
+
+    /// Like [`Self::AbsoluteAccessed`], but delta layers whose pages are costly to reconstruct
+    /// are evicted later: each delta layer that a read of the layer's pages visits counts as
+    /// `penalty_per_delta` more recent access, see
+    /// [`crate::tenant::layer_map::reconstruct_cost`].
+    ReconstructCost {
+        #[serde(with = "humantime_serde")]
+        penalty_per_delta: Duration,
+    },
+}
+
+/// Changes to apply to the running task's [`DiskUsageEvictionTaskConfig`], see
+/// `PUT /v1/disk_usage_eviction/config`. Unset fields keep their current value.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskUsageEvictionTaskConfigUpdate {
+    #[serde(default)]
+    pub max_usage_pct: Option<Percent>,
+    #[serde(default)]
+    pub min_avail_bytes: Option<u64>,
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    pub period: Option<Duration>,
+    #[serde(default)]
+    pub wal_ingest_reserved_bytes: Option<u64>,
+    #[serde(default)]
+    pub eviction_order: Option<EvictionOrder>,
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    pub working_set_penalty: Option<Duration>,
+}
+
+impl DiskUsageEvictionTaskConfigUpdate {
+    fn apply(
+        self,
+        current: &DiskUsageEvictionTaskConfig,
+    ) -> anyhow::Result<DiskUsageEvictionTaskConfig> {
+        let DiskUsageEvictionTaskConfigUpdate {
+            max_usage_pct,
+            min_avail_bytes,
+            period,
+            wal_ingest_reserved_bytes,
+            eviction_order,
+            working_set_penalty,
+        } = self;
+
+        if period == Some(Duration::ZERO) {
+            anyhow::bail!("period must be greater than zero");
+        }
+
+        let mut updated = current.clone();
+        if let Some(max_usage_pct) = max_usage_pct {
+            updated.max_usage_pct = max_usage_pct;
+        }
+        if let Some(min_avail_bytes) = min_avail_bytes {
+            updated.min_avail_bytes = min_avail_bytes;
+        }
+        if let Some(period) = period {
+            updated.period = period;
+        }
+        if let Some(wal_ingest_reserved_bytes) = wal_ingest_reserved_bytes {
+            updated.wal_ingest_reserved_bytes = wal_ingest_reserved_bytes;
+        }
+        if let Some(eviction_order) = eviction_order {
+            updated.eviction_order = eviction_order;
+        }
+        if let Some(working_set_penalty) = working_set_penalty {
+            updated.working_set_penalty = working_set_penalty;
+        }
+        Ok(updated)
+    }
 }
 
 impl EvictionOrder {
@@ -107,7 +285,7 @@ impl EvictionOrder {
     /// counts should be the first ones to have their layers evicted.
     fn highest_layer_count_loses_first(&self) -> bool {
         match self {
-            EvictionOrder::AbsoluteAccessed => false,
+            EvictionOrder::AbsoluteAccessed | EvictionOrder::ReconstructCost { .. } => false,
             EvictionOrder::RelativeAccessed {
                 highest_layer_count_loses_first,
             } => *highest_layer_count_loses_first,
@@ -119,6 +297,141 @@ impl EvictionOrder {
 pub struct State {
     /// Exclude http requests and background task from running at the same time.
     mutex: tokio::sync::Mutex<()>,
+    /// The outcome of the most recently completed iteration, background or http-triggered.
+    last_iteration: std::sync::Mutex<Option<IterationStatus>>,
+    /// The config of the background task, re-read on every iteration so that changes made via
+    /// `PUT /v1/disk_usage_eviction/config` are picked up without a restart.
+    ///
+    /// `None` if the background task is not running.
+    task_config: std::sync::Mutex<Option<Arc<DiskUsageEvictionTaskConfig>>>,
+    /// The filesystem usage as of the background task's most recent `statvfs`.
+    latest_usage: std::sync::Mutex<Option<UsageSummary>>,
+    /// Set from [`DiskUsageEvictionTaskConfig::event_sink`] when launching the background task.
+    event_sink: std::sync::Mutex<Option<Arc<event_sink::EventSink>>>,
+    /// Iterations do nothing until this time, see [`Self::pause`]. Checked by iterations once
+    /// they hold `mutex`, and cleared by [`Self::paused_until`] once expired.
+    paused_until: std::sync::Mutex<Option<SystemTime>>,
+    /// See [`AdaptiveMinResidentSize`]. Only changed while holding `mutex`.
+    learned_min_resident_sizes: std::sync::Mutex<HashMap<TenantShardId, LearnedMinResidentSize>>,
+}
+
+impl State {
+    /// Snapshot for the `GET /v1/disk_usage_eviction/status` endpoint.
+    pub(crate) fn status(&self) -> Status {
+        Status {
+            task_config: self.task_config().map(|config| config.redacted()),
+            latest_usage: self.latest_usage.lock().unwrap().clone(),
+            last_iteration: self.last_iteration.lock().unwrap().clone(),
+            paused_until: self.paused_until(),
+            learned_min_resident_sizes: {
+                let learned = self.learned_min_resident_sizes.lock().unwrap();
+                let mut learned = learned.values().cloned().collect::<Vec<_>>();
+                learned.sort_unstable_by_key(|l| l.tenant_shard_id);
+                learned
+            },
+        }
+    }
+
+    /// Stop evicting for `duration`, e.g., while an operator does maintenance on the disk or
+    /// on the layer files. Waits for a running iteration to finish, so that no evictions happen
+    /// after this returns. Pausing again replaces the previous pause.
+    pub(crate) async fn pause(&self, duration: Duration) -> anyhow::Result<SystemTime> {
+        let until = SystemTime::now()
+            .checked_add(duration)
+            .with_context(|| format!("pause duration {duration:?} is too long"))?;
+        *self.paused_until.lock().unwrap() = Some(until);
+        DISK_USAGE_EVICTION.paused.set(1);
+        info!(?duration, "disk usage based eviction paused");
+        // Iterations that start from now on see the pause, wait for one that started before.
+        // Only held for an instant, an iteration that fails to lock it meanwhile is skipped.
+        drop(self.mutex.lock().await);
+        Ok(until)
+    }
+
+    pub(crate) fn resume(&self) {
+        if self.paused_until.lock().unwrap().take().is_some() {
+            info!("disk usage based eviction resumed");
+        }
+        DISK_USAGE_EVICTION.paused.set(0);
+    }
+
+    /// `None` if not paused, or if the pause has expired.
+    pub(crate) fn paused_until(&self) -> Option<SystemTime> {
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.is_some_and(|until| until <= SystemTime::now()) {
+            *paused_until = None;
+            DISK_USAGE_EVICTION.paused.set(0);
+            info!("disk usage based eviction pause expired");
+        }
+        *paused_until
+    }
+
+    pub(crate) fn task_config(&self) -> Option<Arc<DiskUsageEvictionTaskConfig>> {
+        self.task_config.lock().unwrap().clone()
+    }
+
+    fn record_usage(&self, usage: &FilesystemUsage) {
+        update_wal_ingest_headroom(usage);
+        *self.latest_usage.lock().unwrap() = Some(UsageSummary::from(usage));
+    }
+
+    /// Apply `update` to the config of the running background task, taking effect on its next
+    /// iteration. Returns the resulting config.
+    pub(crate) fn update_task_config(
+        &self,
+        update: DiskUsageEvictionTaskConfigUpdate,
+    ) -> anyhow::Result<Arc<DiskUsageEvictionTaskConfig>> {
+        let mut guard = self.task_config.lock().unwrap();
+        let Some(current) = guard.as_ref() else {
+            anyhow::bail!("disk usage based eviction task is not running");
+        };
+        let updated = Arc::new(update.apply(current)?);
+        info!(?updated, "disk usage based eviction task config updated");
+        *guard = Some(Arc::clone(&updated));
+        Ok(updated)
+    }
+
+    fn record_outcome<U: Usage>(&self, outcome: &IterationOutcome<U>) {
+        let outcome = match serde_json::to_value(outcome) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                warn!("failed to serialize iteration outcome for status endpoint: {e:#}");
+                return;
+            }
+        };
+        *self.last_iteration.lock().unwrap() = Some(IterationStatus {
+            completed_at: SystemTime::now(),
+            outcome,
+        });
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    /// `None` if the background task is not running.
+    task_config: Option<DiskUsageEvictionTaskConfig>,
+    /// `None` until the background task has looked at the filesystem for the first time.
+    latest_usage: Option<UsageSummary>,
+    /// `None` until the first iteration has completed.
+    last_iteration: Option<IterationStatus>,
+    /// `None` unless paused.
+    #[serde(rename = "paused_until_millis_since_epoch")]
+    #[serde_as(as = "Option<serde_with::TimestampMilliSeconds>")]
+    paused_until: Option<SystemTime>,
+    /// Empty unless `adaptive_min_resident_size` is configured. Only tenants seen by an
+    /// iteration under pressure, sorted by tenant.
+    learned_min_resident_sizes: Vec<LearnedMinResidentSize>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct IterationStatus {
+    #[serde(rename = "completed_at_millis_since_epoch")]
+    #[serde_as(as = "serde_with::TimestampMilliSeconds")]
+    completed_at: SystemTime,
+    /// The serialized [`IterationOutcome`], type-erased over the [`Usage`] implementation.
+    outcome: serde_json::Value,
 }
 
 pub fn launch_disk_usage_global_eviction_task(
@@ -134,6 +447,12 @@ pub fn launch_disk_usage_global_eviction_task(
 
     info!("launching disk usage based eviction task");
 
+    *state.task_config.lock().unwrap() = Some(Arc::new(task_config.clone()));
+    *state.event_sink.lock().unwrap() = task_config
+        .event_sink
+        .clone()
+        .map(|sink| Arc::new(event_sink::EventSink::new(sink, conf.id)));
+
     task_mgr::spawn(
         BACKGROUND_RUNTIME.handle(),
         TaskKind::DiskUsageEviction,
@@ -150,8 +469,7 @@ pub fn launch_disk_usage_global_eviction_task(
                 _ = background_jobs_barrier.wait() => { }
             };
 
-            disk_usage_eviction_task(&state, task_config, &storage, &conf.tenants_path(), cancel)
-                .await;
+            disk_usage_eviction_task(&state, &storage, &conf.tenants_path(), cancel).await;
             Ok(())
         },
     );
@@ -162,7 +480,6 @@ pub fn launch_disk_usage_global_eviction_task(
 #[instrument(skip_all)]
 async fn disk_usage_eviction_task(
     state: &State,
-    task_config: &DiskUsageEvictionTaskConfig,
     storage: &GenericRemoteStorage,
     tenants_dir: &Utf8Path,
     cancel: CancellationToken,
@@ -171,6 +488,10 @@ async fn disk_usage_eviction_task(
         info!("disk usage based eviction task finishing");
     };
 
+    let task_config = state
+        .task_config()
+        .expect("set when launching the background task");
+
     use crate::tenant::tasks::random_init_delay;
     {
         if random_init_delay(task_config.period, &cancel)
@@ -186,10 +507,15 @@ async fn disk_usage_eviction_task(
         iteration_no += 1;
         let start = Instant::now();
 
+        // pick up changes made via the config update endpoint
+        let task_config = state
+            .task_config()
+            .expect("set when launching the background task");
+
         async {
             let res = disk_usage_eviction_task_iteration(
                 state,
-                task_config,
+                &task_config,
                 storage,
                 tenants_dir,
                 &cancel,
@@ -217,7 +543,33 @@ async fn disk_usage_eviction_task(
     }
 }
 
-pub trait Usage: Clone + Copy + std::fmt::Debug {
+/// Set while the free space is below [`DiskUsageEvictionTaskConfig::wal_ingest_reserved_bytes`],
+/// as of the most recent `statvfs` of the background task.
+static WAL_INGEST_HEADROOM_LOW: AtomicBool = AtomicBool::new(false);
+
+/// Whether WAL ingest is at risk of running out of disk space before eviction catches up.
+/// WAL receivers use this to backpressure the computes.
+pub(crate) fn wal_ingest_headroom_is_low() -> bool {
+    WAL_INGEST_HEADROOM_LOW.load(Ordering::Relaxed)
+}
+
+fn update_wal_ingest_headroom(usage: &FilesystemUsage) {
+    let low = usage.wal_ingest_headroom_is_low();
+    let was_low = WAL_INGEST_HEADROOM_LOW.swap(low, Ordering::Relaxed);
+    DISK_USAGE_EVICTION
+        .wal_ingest_headroom_low
+        .set(u64::from(low));
+    match (was_low, low) {
+        (false, true) => warn!(
+            ?usage,
+            "free space below wal_ingest_reserved_bytes, backpressuring WAL ingest"
+        ),
+        (true, false) => info!(?usage, "free space back above wal_ingest_reserved_bytes"),
+        _ => {}
+    }
+}
+
+pub trait Usage: Clone + Copy + std::fmt::Debug + Serialize {
     fn has_pressure(&self) -> bool;
     fn add_available_bytes(&mut self, bytes: u64);
 }
@@ -229,13 +581,15 @@ async fn disk_usage_eviction_task_iteration(
     tenants_dir: &Utf8Path,
     cancel: &CancellationToken,
 ) -> anyhow::Result<()> {
-    let usage_pre = filesystem_level_usage::get(tenants_dir, task_config)
+    let usage_pre = FilesystemUsage::get(tenants_dir, task_config)
         .context("get filesystem-level disk usage before evictions")?;
+    state.record_usage(&usage_pre);
     let res = disk_usage_eviction_task_iteration_impl(
         state,
         storage,
         usage_pre,
         task_config.eviction_order,
+        task_config.working_set_penalty,
         cancel,
     )
     .await;
@@ -243,14 +597,17 @@ async fn disk_usage_eviction_task_iteration(
         Ok(outcome) => {
             debug!(?outcome, "disk_usage_eviction_iteration finished");
             match outcome {
-                IterationOutcome::NoPressure | IterationOutcome::Cancelled => {
+                IterationOutcome::NoPressure
+                | IterationOutcome::Cancelled
+                | IterationOutcome::Paused => {
                     // nothing to do, select statement below will handle things
                 }
                 IterationOutcome::Finished(outcome) => {
                     // Verify with statvfs whether we made any real progress
-                    let after = filesystem_level_usage::get(tenants_dir, task_config)
+                    let after = FilesystemUsage::get(tenants_dir, task_config)
                         // It's quite unlikely to hit the error here. Keep the code simple and bail out.
                         .context("get filesystem-level disk usage after evictions")?;
+                    state.record_usage(&after);
 
                     debug!(?after, "disk usage");
 
@@ -280,6 +637,8 @@ async fn disk_usage_eviction_task_iteration(
 pub enum IterationOutcome<U> {
     NoPressure,
     Cancelled,
+    /// Nothing was done, see [`State::pause`].
+    Paused,
     Finished(IterationOutcomeFinished<U>),
 }
 
@@ -290,6 +649,9 @@ pub struct IterationOutcomeFinished<U> {
     before: U,
     /// The expected value for `after`, according to internal accounting, after phase 1.
     planned: PlannedUsage<U>,
+    /// The tenants whose `min_resident_size` reservation phase 1 planned to evict from, most
+    /// bytes first. Empty unless `planned.fallback_to_global_lru` is set.
+    reservation_violations: Vec<ReservationViolation>,
     /// The outcome of phase 2, where we actually do the evictions.
     ///
     /// If all layers that phase 1 planned to evict _can_ actually get evicted, this will
@@ -304,6 +666,8 @@ struct AssumedUsage<U> {
     projected_after: U,
     /// The layers we failed to evict during phase 2.
     failed: LayerCount,
+    /// The layers we did not evict during phase 2 because their tenant started shutting down.
+    skipped: LayerCount,
 }
 
 #[allow(dead_code)]
@@ -313,6 +677,14 @@ struct PlannedUsage<U> {
     fallback_to_global_lru: Option<U>,
 }
 
+#[allow(dead_code)]
+#[derive(Debug, Serialize)]
+struct ReservationViolation {
+    tenant_shard_id: TenantShardId,
+    /// Bytes planned for eviction from the `Below` partition of this tenant.
+    planned_bytes: u64,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Default, Serialize)]
 struct LayerCount {
@@ -322,17 +694,67 @@ struct LayerCount {
 
 pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
     state: &State,
-    _storage: &GenericRemoteStorage,
+    storage: &GenericRemoteStorage,
     usage_pre: U,
     eviction_order: EvictionOrder,
+    working_set_penalty: Duration,
     cancel: &CancellationToken,
 ) -> anyhow::Result<IterationOutcome<U>> {
     // use tokio's mutex to get a Sync guard (instead of std::sync::Mutex)
-    let _g = state
-        .mutex
-        .try_lock()
-        .map_err(|_| anyhow::anyhow!("iteration is already executing"))?;
+    let _g = match state.mutex.try_lock() {
+        Ok(g) => g,
+        // locked by a pause that waits for the previous iteration
+        Err(_) if state.paused_until().is_some() => return Ok(IterationOutcome::Paused),
+        Err(_) => anyhow::bail!("iteration is already executing"),
+    };
 
+    if let Some(until) = state.paused_until() {
+        debug!(?until, "skipping iteration, paused");
+        return Ok(IterationOutcome::Paused);
+    }
+
+    let event_sink = state.event_sink.lock().unwrap().clone();
+    // the background task's setting also applies to iterations triggered via http
+    let adaptive_min_resident_size = state
+        .task_config()
+        .and_then(|config| config.adaptive_min_resident_size.clone());
+    // a copy, so that the status shows the previous iteration's sizes while this one runs
+    let mut learned_min_resident_sizes = match adaptive_min_resident_size {
+        Some(_) => state.learned_min_resident_sizes.lock().unwrap().clone(),
+        None => HashMap::new(),
+    };
+
+    let res = disk_usage_eviction_task_iteration_locked(
+        storage,
+        event_sink.as_ref(),
+        usage_pre,
+        eviction_order,
+        working_set_penalty,
+        adaptive_min_resident_size.as_ref(),
+        &mut learned_min_resident_sizes,
+        cancel,
+    )
+    .await;
+    *state.learned_min_resident_sizes.lock().unwrap() = learned_min_resident_sizes;
+    let outcome = res?;
+
+    state.record_outcome(&outcome);
+
+    Ok(outcome)
+}
+
+/// The body of [`disk_usage_eviction_task_iteration_impl`], to be called with `State::mutex` held.
+#[allow(clippy::too_many_arguments)]
+async fn disk_usage_eviction_task_iteration_locked<U: Usage>(
+    storage: &GenericRemoteStorage,
+    event_sink: Option<&Arc<event_sink::EventSink>>,
+    usage_pre: U,
+    eviction_order: EvictionOrder,
+    working_set_penalty: Duration,
+    adaptive_min_resident_size: Option<&AdaptiveMinResidentSize>,
+    learned_min_resident_sizes: &mut HashMap<TenantShardId, LearnedMinResidentSize>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<IterationOutcome<U>> {
     debug!(?usage_pre, "disk usage");
 
     if !usage_pre.has_pressure() {
@@ -344,7 +766,19 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
         "running disk usage based eviction due to pressure"
     );
 
-    let candidates = match collect_eviction_candidates(eviction_order, cancel).await? {
+    let bytes_needed = bytes_needed_to_relieve_pressure(usage_pre);
+    debug!(?bytes_needed, "bytes needed to relieve pressure");
+
+    let candidates = match collect_eviction_candidates(
+        eviction_order,
+        working_set_penalty,
+        adaptive_min_resident_size,
+        learned_min_resident_sizes,
+        bytes_needed,
+        cancel,
+    )
+    .await?
+    {
         EvictionCandidates::Cancelled => {
             return Ok(IterationOutcome::Cancelled);
         }
@@ -383,6 +817,7 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
     let mut warned = None;
     let mut usage_planned = usage_pre;
     let mut evicted_amount = 0;
+    let mut reservation_violations = HashMap::<TenantShardId, u64>::new();
 
     for (i, (partition, candidate)) in candidates.iter().enumerate() {
         if !usage_planned.has_pressure() {
@@ -398,10 +833,21 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
             warned = Some(usage_planned);
         }
 
-        usage_planned.add_available_bytes(candidate.layer.layer_desc().file_size);
+        let desc = candidate.layer.layer_desc();
+        if partition == &MinResidentSizePartition::Below {
+            *reservation_violations
+                .entry(desc.tenant_shard_id)
+                .or_default() += desc.file_size;
+        }
+
+        usage_planned.add_available_bytes(desc.file_size);
         evicted_amount += 1;
     }
 
+    let reservation_violations = summarize_reservation_violations(reservation_violations);
+
+    let events = event_sink.map(|sink| sink.events(candidates.iter().take(evicted_amount)));
+
     let usage_planned = match warned {
         Some(respecting_tenant_min_resident_size) => PlannedUsage {
             respecting_tenant_min_resident_size,
@@ -414,18 +860,28 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
     };
     debug!(?usage_planned, "usage planned");
 
+    failpoint_support::sleep_millis_async!("disk-usage-eviction-before-evictions");
+
     // phase2: evict layers
 
     let mut js = tokio::task::JoinSet::new();
     let limit = 1000;
 
-    let mut evicted = candidates.into_iter().take(evicted_amount).fuse();
+    let mut evicted = candidates
+        .into_iter()
+        .take(evicted_amount)
+        .enumerate()
+        .fuse();
     let mut consumed_all = false;
 
     // After the evictions, `usage_assumed` is the post-eviction usage,
     // according to internal accounting.
     let mut usage_assumed = usage_pre;
     let mut evictions_failed = LayerCount::default();
+    let mut evictions_skipped = LayerCount::default();
+    let mut failures = EvictionFailures::default();
+    // reported to the event sink, None if the eviction task panicked
+    let mut outcomes = vec![None; evicted_amount];
 
     let evict_layers = async move {
         loop {
@@ -440,12 +896,20 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
 
             if let Some(next) = next {
                 match next {
-                    Ok(Ok(file_size)) => {
-                        usage_assumed.add_available_bytes(file_size);
+                    Ok((i, layer, Some(Ok(())))) => {
+                        usage_assumed.add_available_bytes(layer.layer_desc().file_size);
+                        outcomes[i] = Some(EvictionOutcome::Evicted);
                     }
-                    Ok(Err((file_size, EvictionError::NotFound | EvictionError::Downloaded))) => {
-                        evictions_failed.file_sizes += file_size;
+                    Ok((i, layer, Some(Err(e)))) => {
+                        evictions_failed.file_sizes += layer.layer_desc().file_size;
                         evictions_failed.count += 1;
+                        failures.record(&layer, &e);
+                        outcomes[i] = Some(EvictionOutcome::from(&e));
+                    }
+                    Ok((i, layer, None)) => {
+                        evictions_skipped.file_sizes += layer.layer_desc().file_size;
+                        evictions_skipped.count += 1;
+                        outcomes[i] = Some(EvictionOutcome::Skipped);
                     }
                     Err(je) if je.is_cancelled() => unreachable!("not used"),
                     Err(je) if je.is_panic() => { /* already logged */ }
@@ -458,31 +922,43 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
             }
 
             // calling again when consumed_all is fine as evicted is fused.
-            let Some((_partition, candidate)) = evicted.next() else {
+            let Some((i, (_partition, candidate))) = evicted.next() else {
                 consumed_all = true;
                 continue;
             };
 
+            let file_size = candidate.layer.layer_desc().file_size;
+
+            // The tenant may have started shutting down since we collected the candidates.
+            // Its layers will go away with it, so there is no point in evicting them, and
+            // doing so would only fail noisily.
+            if candidate.tenant_cancel.is_cancelled() {
+                evictions_skipped.file_sizes += file_size;
+                evictions_skipped.count += 1;
+                outcomes[i] = Some(EvictionOutcome::Skipped);
+                continue;
+            }
+
             js.spawn(async move {
                 let rtc = candidate.timeline.remote_client.as_ref().expect(
                     "holding the witness, all timelines must have a remote timeline client",
                 );
-                let file_size = candidate.layer.layer_desc().file_size;
-                candidate
-                    .layer
-                    .evict_and_wait(rtc)
-                    .await
-                    .map(|()| file_size)
-                    .map_err(|e| (file_size, e))
+                let res = tokio::select! {
+                    biased;
+                    _ = candidate.tenant_cancel.cancelled() => None,
+                    res = candidate.layer.evict_and_wait(rtc) => Some(res),
+                };
+                (i, candidate.layer, res)
             });
 
             tokio::task::yield_now().await;
         }
 
-        (usage_assumed, evictions_failed)
+        failures.log_summary();
+        (usage_assumed, evictions_failed, evictions_skipped, outcomes)
     };
 
-    let (usage_assumed, evictions_failed) = tokio::select! {
+    let (usage_assumed, evictions_failed, evictions_skipped, outcomes) = tokio::select! {
         tuple = evict_layers => { tuple },
         _ = cancel.cancelled() => {
             // dropping joinset will abort all pending evict_and_waits and that is fine, our
@@ -491,25 +967,146 @@ pub(crate) async fn disk_usage_eviction_task_iteration_impl<U: Usage>(
         }
     };
 
+    // deliver only now, to not delay the evictions, and in the background, to not hold
+    // `State::mutex` while delivering
+    if let (Some(sink), Some(events)) = (event_sink, events) {
+        sink.deliver(events, outcomes, storage);
+    }
+
     Ok(IterationOutcome::Finished(IterationOutcomeFinished {
         before: usage_pre,
         planned: usage_planned,
+        reservation_violations,
         assumed: AssumedUsage {
             projected_after: usage_assumed,
             failed: evictions_failed,
+            skipped: evictions_skipped,
         },
     }))
 }
 
+/// The eviction failures of one iteration. The first [`DETAILED_EVICTION_FAILURES`] are logged
+/// one by one, the rest only as one summary at the end of the iteration: when e.g. a tenant shuts
+/// down mid-iteration, hundreds of its layers can fail to evict. The event sink, if configured,
+/// gets the outcome of every eviction.
+#[derive(Default)]
+struct EvictionFailures {
+    count: usize,
+    /// The failures that were not logged one by one, by kind and tenant.
+    unlogged: HashMap<(&'static str, TenantShardId), LayerCount>,
+}
+
+const DETAILED_EVICTION_FAILURES: usize = 10;
+
+impl EvictionFailures {
+    fn record(&mut self, layer: &Layer, error: &EvictionError) {
+        let desc = layer.layer_desc();
+        self.count += 1;
+        if self.count <= DETAILED_EVICTION_FAILURES {
+            warn!(
+                tenant_id = %desc.tenant_shard_id.tenant_id,
+                shard_id = %desc.tenant_shard_id.shard_slug(),
+                timeline_id = %desc.timeline_id,
+                layer = %desc.filename(),
+                "failed to evict layer: {error}"
+            );
+            return;
+        }
+        let kind = match error {
+            EvictionError::NotFound => "already_evicted",
+            EvictionError::Downloaded => "downloaded",
+        };
+        let unlogged = self
+            .unlogged
+            .entry((kind, desc.tenant_shard_id))
+            .or_default();
+        unlogged.count += 1;
+        unlogged.file_sizes += desc.file_size;
+    }
+
+    fn log_summary(&self) {
+        if self.unlogged.is_empty() {
+            return;
+        }
+        let mut groups = self.unlogged.iter().collect::<Vec<_>>();
+        groups.sort_unstable_by_key(|((kind, tenant_shard_id), layers)| {
+            (std::cmp::Reverse(layers.count), *kind, *tenant_shard_id)
+        });
+
+        // like the failures, the groups can be many, only log the largest
+        const MAX_LOGGED: usize = 10;
+        let largest = groups
+            .iter()
+            .take(MAX_LOGGED)
+            .map(|((kind, tenant_shard_id), layers)| {
+                format!("{kind}/{tenant_shard_id}={}", layers.count)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        warn!(
+            failures = self.count,
+            groups = groups.len(),
+            "{} more layers failed to evict; by kind and tenant, most layers first: {largest}",
+            self.count - DETAILED_EVICTION_FAILURES
+        );
+    }
+}
+
+/// Sorts the per-tenant bytes planned for eviction from the `Below` partition, and reports them
+/// via metrics and, if there are any, a warning log line.
+fn summarize_reservation_violations(
+    violations: HashMap<TenantShardId, u64>,
+) -> Vec<ReservationViolation> {
+    let mut violations = violations
+        .into_iter()
+        .map(|(tenant_shard_id, planned_bytes)| ReservationViolation {
+            tenant_shard_id,
+            planned_bytes,
+        })
+        .collect::<Vec<_>>();
+    violations.sort_unstable_by_key(|v| (std::cmp::Reverse(v.planned_bytes), v.tenant_shard_id));
+
+    let total_bytes = violations.iter().map(|v| v.planned_bytes).sum::<u64>();
+    DISK_USAGE_EVICTION
+        .reservation_violated_tenants
+        .set(violations.len() as u64);
+    DISK_USAGE_EVICTION
+        .reservation_violated_bytes
+        .set(total_bytes);
+
+    if !violations.is_empty() {
+        DISK_USAGE_EVICTION.global_lru_fallbacks.inc();
+
+        // the list can be long on large pageservers, only log the worst offenders
+        const MAX_LOGGED: usize = 10;
+        let worst = violations
+            .iter()
+            .take(MAX_LOGGED)
+            .map(|v| format!("{}={}", v.tenant_shard_id, v.planned_bytes))
+            .collect::<Vec<_>>()
+            .join(", ");
+        warn!(
+            tenants = violations.len(),
+            total_bytes,
+            "evicting from tenants' min_resident_size reservations, the node may be undersized; most bytes first: {worst}"
+        );
+    }
+
+    violations
+}
+
 #[derive(Clone)]
 struct EvictionCandidate {
     timeline: Arc<Timeline>,
+    /// The owning tenant's cancellation token, so that phase 2 can stop evicting from a
+    /// tenant which is shutting down.
+    tenant_cancel: CancellationToken,
     layer: Layer,
     last_activity_ts: SystemTime,
     relative_last_activity: finite_f32::FiniteF32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 enum MinResidentSizePartition {
     Above,
     Below,
@@ -622,8 +1219,24 @@ enum EvictionCandidates {
 /// - tenant A 14 layers
 /// - tenant B 1 layer
 /// - tenant C 8 layers
+///
+/// # Memory usage
+///
+/// The candidates are collected tenant by tenant into a [`top_k::CandidateHeap`], which only
+/// retains the candidates that come first in eviction order and add up to `bytes_needed`.
+/// This bounds the memory used on pageservers with thousands of tenants to what is actually
+/// needed to relieve pressure. With `bytes_needed = None`, all candidates are retained.
+///
+/// # Learned `min_resident_size`
+///
+/// With `adaptive_min_resident_size`, `learned_min_resident_sizes` is updated for the tenants
+/// without an override, see [`AdaptiveMinResidentSize`]. It is left unchanged if cancelled.
 async fn collect_eviction_candidates(
     eviction_order: EvictionOrder,
+    working_set_penalty: Duration,
+    adaptive_min_resident_size: Option<&AdaptiveMinResidentSize>,
+    learned_min_resident_sizes: &mut HashMap<TenantShardId, LearnedMinResidentSize>,
+    bytes_needed: Option<u64>,
     cancel: &CancellationToken,
 ) -> anyhow::Result<EvictionCandidates> {
     // get a snapshot of the list of tenants
@@ -631,9 +1244,11 @@ async fn collect_eviction_candidates(
         .await
         .context("get list of tenants")?;
 
-    let mut candidates = Vec::new();
+    let mut candidates = top_k::CandidateHeap::new(bytes_needed);
+    // tenants that went away since the previous iteration are dropped
+    let mut next_learned_min_resident_sizes = HashMap::new();
 
-    for (tenant_id, _state) in &tenants {
+    for (tenant_id, _state, _gen) in &tenants {
         if cancel.is_cancelled() {
             return Ok(EvictionCandidates::Cancelled);
         }
@@ -659,18 +1274,43 @@ async fn collect_eviction_candidates(
         // a little unfair to tenants during shutdown in such a situation is tolerable.
         let mut tenant_candidates = Vec::new();
         let mut max_layer_size = 0;
+        let mut redownloaded_bytes_total = 0;
         for tl in tenant.list_timelines() {
             if !tl.is_active() {
                 continue;
             }
-            let info = tl.get_local_layers_for_disk_usage_eviction().await;
+            let info = tl
+                .get_local_layers_for_disk_usage_eviction(matches!(
+                    eviction_order,
+                    EvictionOrder::ReconstructCost { .. }
+                ))
+                .await;
             debug!(tenant_id=%tl.tenant_shard_id.tenant_id, shard_id=%tl.tenant_shard_id.shard_slug(), timeline_id=%tl.timeline_id, "timeline resident layers count: {}", info.resident_layers.len());
-            tenant_candidates.extend(
-                info.resident_layers
-                    .into_iter()
-                    .map(|layer_infos| (tl.clone(), layer_infos)),
-            );
+            tenant_candidates.extend(info.resident_layers.into_iter().map(|layer_info| {
+                let sort_ts = if !working_set_penalty.is_zero()
+                    && tl
+                        .working_set
+                        .overlaps(&layer_info.layer.layer_desc().key_range)
+                {
+                    layer_info
+                        .last_activity_ts
+                        .checked_add(working_set_penalty)
+                        .unwrap_or(layer_info.last_activity_ts)
+                } else {
+                    layer_info.last_activity_ts
+                };
+                let sort_ts = match eviction_order {
+                    EvictionOrder::ReconstructCost { penalty_per_delta } => penalty_per_delta
+                        .checked_mul(layer_info.reconstruct_cost.delta_depth)
+                        .and_then(|penalty| sort_ts.checked_add(penalty))
+                        .unwrap_or(sort_ts),
+                    _ => sort_ts,
+                };
+                (tl.clone(), layer_info, sort_ts)
+            }));
             max_layer_size = max_layer_size.max(info.max_layer_size.unwrap_or(0));
+            redownloaded_bytes_total +=
+                tl.redownloaded_after_eviction_bytes.load(Ordering::Relaxed);
 
             if cancel.is_cancelled() {
                 return Ok(EvictionCandidates::Cancelled);
@@ -695,6 +1335,29 @@ async fn collect_eviction_candidates(
                 "using overridden min resident size for tenant"
             );
             s
+        } else if let Some(config) = adaptive_min_resident_size {
+            let tenant_shard_id = *tenant_id;
+            let learned = match learned_min_resident_sizes.get(&tenant_shard_id).cloned() {
+                Some(mut learned) => {
+                    learned.update(config, redownloaded_bytes_total);
+                    learned
+                }
+                None => LearnedMinResidentSize::new(
+                    config,
+                    tenant_shard_id,
+                    max_layer_size,
+                    redownloaded_bytes_total,
+                ),
+            };
+            debug!(
+                tenant_id=%tenant.tenant_id(),
+                learned_size=learned.bytes,
+                redownloaded_bytes=learned.redownloaded_bytes,
+                "using learned min resident size for tenant"
+            );
+            let bytes = learned.bytes;
+            next_learned_min_resident_sizes.insert(tenant_shard_id, learned);
+            bytes
         } else {
             debug!(
                 tenant_id=%tenant.tenant_id(),
@@ -705,9 +1368,8 @@ async fn collect_eviction_candidates(
         };
 
         // Sort layers most-recently-used first, then partition by
-        // cumsum above/below min_resident_size.
-        tenant_candidates
-            .sort_unstable_by_key(|(_, layer_info)| std::cmp::Reverse(layer_info.last_activity_ts));
+        // cumsum above/below min_resident_size. The working set penalty applies to both.
+        tenant_candidates.sort_unstable_by_key(|(_, _, sort_ts)| std::cmp::Reverse(*sort_ts));
         let mut cumsum: i128 = 0;
 
         // keeping the -1 or not decides if every tenant should lose their least recently accessed
@@ -741,9 +1403,22 @@ async fn collect_eviction_candidates(
             .unwrap_or(1);
         let divider = total as f32;
 
-        for (i, (timeline, layer_info)) in tenant_candidates.into_iter().enumerate() {
+        let never_evict_below = tenant.get_never_evict_below_min_resident_size();
+
+        for (i, (timeline, layer_info, sort_ts)) in tenant_candidates.into_iter().enumerate() {
             let file_size = layer_info.file_size();
 
+            let partition = if cumsum > min_resident_size as i128 {
+                MinResidentSizePartition::Above
+            } else {
+                MinResidentSizePartition::Below
+            };
+            cumsum += i128::from(file_size);
+            if partition == MinResidentSizePartition::Below && never_evict_below {
+                // the tenant opted out of the global LRU fallback: not even a candidate
+                continue;
+            }
+
             // as we iterate this reverse sorted list, the most recently accessed layer will always
             // be 1.0; this is for us to evict it last.
             let relative_last_activity = if matches!(
@@ -763,37 +1438,185 @@ async fn collect_eviction_candidates(
 
             let candidate = EvictionCandidate {
                 timeline,
+                tenant_cancel: tenant.cancel.clone(),
                 last_activity_ts: layer_info.last_activity_ts,
                 layer: layer_info.layer,
                 relative_last_activity,
             };
-            let partition = if cumsum > min_resident_size as i128 {
-                MinResidentSizePartition::Above
-            } else {
-                MinResidentSizePartition::Below
+            let sort_key = match eviction_order {
+                EvictionOrder::AbsoluteAccessed | EvictionOrder::ReconstructCost { .. } => {
+                    EvictionSortKey::Absolute(sort_ts)
+                }
+                EvictionOrder::RelativeAccessed { .. } => {
+                    EvictionSortKey::Relative(candidate.relative_last_activity)
+                }
             };
-            candidates.push((partition, candidate));
-            cumsum += i128::from(file_size);
+            candidates.push((partition, sort_key), file_size, (partition, candidate));
         }
     }
 
     debug_assert!(MinResidentSizePartition::Above < MinResidentSizePartition::Below,
         "as explained in the function's doc comment, layers that aren't in the tenant's min_resident_size are evicted first");
 
-    match eviction_order {
-        EvictionOrder::AbsoluteAccessed => {
-            candidates.sort_unstable_by_key(|(partition, candidate)| {
-                (*partition, candidate.last_activity_ts)
-            });
+    *learned_min_resident_sizes = next_learned_min_resident_sizes;
+
+    Ok(EvictionCandidates::Finished(candidates.into_sorted_vec()))
+}
+
+/// Eviction order within a [`MinResidentSizePartition`]. All candidates of an iteration use the
+/// same variant, as selected by [`EvictionOrder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EvictionSortKey {
+    Absolute(SystemTime),
+    Relative(finite_f32::FiniteF32),
+}
+
+/// The amount of bytes that need to be freed for `usage` to no longer have pressure.
+///
+/// Found by searching over [`Usage::add_available_bytes`], so it works for any [`Usage`]
+/// implementation. Returns `None` if freeing any reasonable amount of bytes would not relieve the
+/// pressure.
+fn bytes_needed_to_relieve_pressure<U: Usage>(usage: U) -> Option<u64> {
+    // 1 EiB, far beyond any filesystem we run on
+    const MAX_BYTES: u64 = 1 << 60;
+
+    let relieved_by = |bytes: u64| {
+        let mut usage = usage;
+        usage.add_available_bytes(bytes);
+        !usage.has_pressure()
+    };
+
+    if relieved_by(0) {
+        return Some(0);
+    }
+
+    // exponential search for an upper bound, then bisect; `lo` never relieves, `hi` always does
+    let mut hi = 1;
+    while !relieved_by(hi) {
+        if hi >= MAX_BYTES {
+            return None;
         }
-        EvictionOrder::RelativeAccessed { .. } => {
-            candidates.sort_unstable_by_key(|(partition, candidate)| {
-                (*partition, candidate.relative_last_activity)
-            });
+        hi *= 2;
+    }
+    let mut lo = hi / 2;
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if relieved_by(mid) {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    Some(hi)
+}
+
+/// Bounded collection of eviction candidates, see [`collect_eviction_candidates`].
+mod top_k {
+    use std::collections::BinaryHeap;
+
+    /// Retains the shortest prefix, in ascending key order, of the pushed items whose sizes add
+    /// up to at least `bytes_needed`. Pushing items in any order yields the same prefix as
+    /// sorting all of them and truncating.
+    pub(super) struct CandidateHeap<K, T> {
+        heap: BinaryHeap<Entry<K, T>>,
+        /// Sum of the sizes of the items in `heap`.
+        total_bytes: u64,
+        /// `None` to retain all items.
+        bytes_needed: Option<u64>,
+    }
+
+    struct Entry<K, T> {
+        key: K,
+        size: u64,
+        item: T,
+    }
+
+    impl<K: Ord, T> PartialEq for Entry<K, T> {
+        fn eq(&self, other: &Self) -> bool {
+            self.key == other.key
         }
     }
 
-    Ok(EvictionCandidates::Finished(candidates))
+    impl<K: Ord, T> Eq for Entry<K, T> {}
+
+    impl<K: Ord, T> PartialOrd for Entry<K, T> {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl<K: Ord, T> Ord for Entry<K, T> {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    impl<K: Ord, T> CandidateHeap<K, T> {
+        pub(super) fn new(bytes_needed: Option<u64>) -> Self {
+            CandidateHeap {
+                heap: BinaryHeap::new(),
+                total_bytes: 0,
+                bytes_needed,
+            }
+        }
+
+        pub(super) fn push(&mut self, key: K, size: u64, item: T) {
+            if let Some(bytes_needed) = self.bytes_needed {
+                if self.total_bytes >= bytes_needed
+                    && self.heap.peek().is_some_and(|last| key >= last.key)
+                {
+                    // we already have enough bytes of items that come first
+                    return;
+                }
+            }
+
+            self.heap.push(Entry { key, size, item });
+            self.total_bytes += size;
+
+            let Some(bytes_needed) = self.bytes_needed else {
+                return;
+            };
+            // drop the items coming last for as long as the remaining ones still suffice
+            while let Some(last) = self.heap.peek() {
+                if self.total_bytes - last.size < bytes_needed {
+                    break;
+                }
+                self.total_bytes -= last.size;
+                self.heap.pop();
+            }
+        }
+
+        /// The retained items in ascending key order.
+        pub(super) fn into_sorted_vec(self) -> Vec<T> {
+            self.heap
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Entry { item, .. }| item)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn retains_shortest_sufficient_prefix() {
+        let items = [(5, 10), (1, 10), (4, 30), (2, 10), (3, 10), (0, 5)];
+
+        let collect = |bytes_needed| {
+            let mut heap = CandidateHeap::new(bytes_needed);
+            for (key, size) in items {
+                heap.push(key, size, key);
+            }
+            heap.into_sorted_vec()
+        };
+
+        assert_eq!(collect(None), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(collect(Some(0)), Vec::<i32>::new());
+        assert_eq!(collect(Some(5)), vec![0]);
+        assert_eq!(collect(Some(6)), vec![0, 1]);
+        assert_eq!(collect(Some(25)), vec![0, 1, 2]);
+        assert_eq!(collect(Some(36)), vec![0, 1, 2, 3, 4]);
+        // not enough bytes in total: retain everything
+        assert_eq!(collect(Some(1000)), vec![0, 1, 2, 3, 4, 5]);
+    }
 }
 
 struct TimelineKey(Arc<Timeline>);
@@ -883,15 +1706,29 @@ mod finite_f32 {
 mod filesystem_level_usage {
     use anyhow::Context;
     use camino::Utf8Path;
+    use utils::serde_percent::Percent;
 
     use crate::statvfs::Statvfs;
 
-    use super::DiskUsageEvictionTaskConfig;
+    use super::{DiskUsageEvictionTaskConfig, Usage as _};
 
+    /// The parts of [`DiskUsageEvictionTaskConfig`] which define pressure.
     #[derive(Debug, Clone, Copy)]
-    #[allow(dead_code)]
-    pub struct Usage<'a> {
-        config: &'a DiskUsageEvictionTaskConfig,
+    struct Thresholds {
+        max_usage_pct: Percent,
+        min_avail_bytes: u64,
+        wal_ingest_reserved_bytes: u64,
+    }
+
+    /// Filesystem-level disk usage, judged against the thresholds of a
+    /// [`DiskUsageEvictionTaskConfig`].
+    ///
+    /// This is what the background task uses, but it is just as usable for http-triggered
+    /// eviction and tests which want the same pressure math.
+    #[derive(Debug, Clone, Copy, serde::Serialize)]
+    pub struct FilesystemUsage {
+        #[serde(skip)]
+        thresholds: Thresholds,
 
         /// Filesystem capacity
         total_bytes: u64,
@@ -899,19 +1736,106 @@ mod filesystem_level_usage {
         avail_bytes: u64,
     }
 
-    impl super::Usage for Usage<'_> {
-        fn has_pressure(&self) -> bool {
-            let usage_pct =
-                (100.0 * (1.0 - ((self.avail_bytes as f64) / (self.total_bytes as f64)))) as u64;
+    /// A serializable summary of a [`FilesystemUsage`], for API responses.
+    #[derive(Debug, Clone, serde::Serialize)]
+    pub struct UsageSummary {
+        pub total_bytes: u64,
+        pub avail_bytes: u64,
+        /// Usage including `wal_ingest_reserved_bytes`, as compared against `max_usage_pct`.
+        pub usage_pct: u64,
+        pub has_pressure: bool,
+        /// How many bytes would need to be evicted to relieve the pressure, if any.
+        pub bytes_needed: Option<u64>,
+    }
+
+    impl From<&FilesystemUsage> for UsageSummary {
+        fn from(usage: &FilesystemUsage) -> Self {
+            UsageSummary {
+                total_bytes: usage.total_bytes,
+                avail_bytes: usage.avail_bytes,
+                usage_pct: usage.usage_pct(),
+                has_pressure: usage.has_pressure(),
+                bytes_needed: super::bytes_needed_to_relieve_pressure(*usage),
+            }
+        }
+    }
 
+    impl FilesystemUsage {
+        pub fn new(
+            total_bytes: u64,
+            avail_bytes: u64,
+            config: &DiskUsageEvictionTaskConfig,
+        ) -> Self {
+            FilesystemUsage {
+                thresholds: Thresholds {
+                    max_usage_pct: config.max_usage_pct,
+                    min_avail_bytes: config.min_avail_bytes,
+                    wal_ingest_reserved_bytes: config.wal_ingest_reserved_bytes,
+                },
+                total_bytes,
+                avail_bytes,
+            }
+        }
+
+        /// Take a `statvfs` of the filesystem holding `tenants_dir`.
+        pub fn get(
+            tenants_dir: &Utf8Path,
+            config: &DiskUsageEvictionTaskConfig,
+        ) -> anyhow::Result<Self> {
+            let mock_config = {
+                #[cfg(feature = "testing")]
+                {
+                    config.mock_statvfs.as_ref()
+                }
+                #[cfg(not(feature = "testing"))]
+                {
+                    None
+                }
+            };
+
+            let stat = Statvfs::get(tenants_dir, mock_config)
+                .context("statvfs failed, presumably directory got unlinked")?;
+
+            // https://unix.stackexchange.com/a/703650
+            let blocksize = if stat.fragment_size() > 0 {
+                stat.fragment_size()
+            } else {
+                stat.block_size()
+            };
+
+            // use blocks_available (b_avail) since, pageserver runs as unprivileged user
+            let avail_bytes = stat.blocks_available() * blocksize;
+            let total_bytes = stat.blocks() * blocksize;
+
+            Ok(FilesystemUsage::new(total_bytes, avail_bytes, config))
+        }
+
+        /// The free space which is not reserved for WAL ingest.
+        fn usable_avail_bytes(&self) -> u64 {
+            self.avail_bytes
+                .saturating_sub(self.thresholds.wal_ingest_reserved_bytes)
+        }
+
+        pub fn usage_pct(&self) -> u64 {
+            (100.0 * (1.0 - ((self.usable_avail_bytes() as f64) / (self.total_bytes as f64))))
+                as u64
+        }
+
+        pub(super) fn wal_ingest_headroom_is_low(&self) -> bool {
+            self.avail_bytes < self.thresholds.wal_ingest_reserved_bytes
+        }
+    }
+
+    impl super::Usage for FilesystemUsage {
+        fn has_pressure(&self) -> bool {
             let pressures = [
                 (
                     "min_avail_bytes",
-                    self.avail_bytes < self.config.min_avail_bytes,
+                    self.usable_avail_bytes() < self.thresholds.min_avail_bytes,
                 ),
                 (
                     "max_usage_pct",
-                    usage_pct >= self.config.max_usage_pct.get() as u64,
+                    self.usage_pct() >= self.thresholds.max_usage_pct.get() as u64,
                 ),
             ];
 
@@ -923,61 +1847,28 @@ mod filesystem_level_usage {
         }
     }
 
-    pub fn get<'a>(
-        tenants_dir: &Utf8Path,
-        config: &'a DiskUsageEvictionTaskConfig,
-    ) -> anyhow::Result<Usage<'a>> {
-        let mock_config = {
-            #[cfg(feature = "testing")]
-            {
-                config.mock_statvfs.as_ref()
-            }
-            #[cfg(not(feature = "testing"))]
-            {
-                None
-            }
-        };
-
-        let stat = Statvfs::get(tenants_dir, mock_config)
-            .context("statvfs failed, presumably directory got unlinked")?;
-
-        // https://unix.stackexchange.com/a/703650
-        let blocksize = if stat.fragment_size() > 0 {
-            stat.fragment_size()
-        } else {
-            stat.block_size()
-        };
-
-        // use blocks_available (b_avail) since, pageserver runs as unprivileged user
-        let avail_bytes = stat.blocks_available() * blocksize;
-        let total_bytes = stat.blocks() * blocksize;
-
-        Ok(Usage {
-            config,
-            total_bytes,
-            avail_bytes,
-        })
-    }
-
     #[test]
     fn max_usage_pct_pressure() {
         use super::EvictionOrder;
-        use super::Usage as _;
         use std::time::Duration;
         use utils::serde_percent::Percent;
 
-        let mut usage = Usage {
-            config: &DiskUsageEvictionTaskConfig {
+        let mut usage = FilesystemUsage::new(
+            100_000,
+            0,
+            &DiskUsageEvictionTaskConfig {
                 max_usage_pct: Percent::new(85).unwrap(),
                 min_avail_bytes: 0,
                 period: Duration::MAX,
+                wal_ingest_reserved_bytes: 0,
+                event_sink: None,
                 #[cfg(feature = "testing")]
                 mock_statvfs: None,
                 eviction_order: EvictionOrder::default(),
+                working_set_penalty: Duration::ZERO,
+                adaptive_min_resident_size: None,
             },
-            total_bytes: 100_000,
-            avail_bytes: 0,
-        };
+        );
 
         assert!(usage.has_pressure(), "expected pressure at 100%");
 
@@ -999,4 +1890,114 @@ mod filesystem_level_usage {
         usage.add_available_bytes(16_000);
         assert!(!usage.has_pressure());
     }
+    #[test]
+    fn wal_ingest_reservation_counts_as_used() {
+        use super::EvictionOrder;
+        use std::time::Duration;
+        use utils::serde_percent::Percent;
+
+        let mut usage = FilesystemUsage::new(
+            100_000,
+            4_000,
+            &DiskUsageEvictionTaskConfig {
+                max_usage_pct: Percent::new(85).unwrap(),
+                min_avail_bytes: 1_000,
+                period: Duration::MAX,
+                wal_ingest_reserved_bytes: 5_000,
+                event_sink: None,
+                #[cfg(feature = "testing")]
+                mock_statvfs: None,
+                eviction_order: EvictionOrder::default(),
+                working_set_penalty: Duration::ZERO,
+                adaptive_min_resident_size: None,
+            },
+        );
+
+        assert!(usage.has_pressure());
+        assert!(usage.wal_ingest_headroom_is_low());
+
+        // 6k available, of which only 1k outside the reservation: 99% usage
+        usage.add_available_bytes(2_000);
+        assert!(usage.has_pressure(), "expected pressure at 99%");
+        assert!(!usage.wal_ingest_headroom_is_low());
+
+        // 21k available, 16k outside the reservation: 84% usage
+        usage.add_available_bytes(15_000);
+        assert!(!usage.has_pressure(), "no pressure at 84%");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::id::TenantId;
+
+    use super::*;
+
+    #[test]
+    fn learned_min_resident_size_follows_redownloads() {
+        let config = AdaptiveMinResidentSize {
+            min_bytes: 100,
+            max_bytes: 10_000,
+        };
+        let tenant_shard_id = TenantShardId::unsharded(TenantId::generate());
+
+        // starts from the default, within bounds
+        let mut learned = LearnedMinResidentSize::new(&config, tenant_shard_id, 50, 1_000);
+        assert_eq!(learned.bytes, 100);
+
+        // thrashing grows the reservation by the re-downloaded bytes
+        learned.update(&config, 3_000);
+        assert_eq!((learned.bytes, learned.redownloaded_bytes), (2_100, 2_000));
+        learned.update(&config, 30_000);
+        assert_eq!(learned.bytes, 10_000);
+
+        // no re-downloads shrink it
+        learned.update(&config, 30_000);
+        assert_eq!((learned.bytes, learned.redownloaded_bytes), (8_750, 0));
+        for _ in 0..100 {
+            learned.update(&config, 30_000);
+        }
+        assert_eq!(learned.bytes, 100);
+
+        // the timelines went away, which is not a re-download
+        learned.update(&config, 0);
+        assert_eq!((learned.bytes, learned.redownloaded_bytes), (100, 0));
+    }
+
+    #[test]
+    fn task_config_update() {
+        let current = DiskUsageEvictionTaskConfig {
+            max_usage_pct: Percent::new(85).unwrap(),
+            min_avail_bytes: 0,
+            period: Duration::from_secs(60),
+            wal_ingest_reserved_bytes: 0,
+            event_sink: None,
+            #[cfg(feature = "testing")]
+            mock_statvfs: None,
+            eviction_order: EvictionOrder::default(),
+            working_set_penalty: Duration::ZERO,
+            adaptive_min_resident_size: None,
+        };
+
+        let update: DiskUsageEvictionTaskConfigUpdate =
+            serde_json::from_str(r#"{"min_avail_bytes": 1000, "period": "10s"}"#).unwrap();
+        let updated = update.apply(&current).unwrap();
+        assert_eq!(
+            updated,
+            DiskUsageEvictionTaskConfig {
+                min_avail_bytes: 1000,
+                period: Duration::from_secs(10),
+                ..current.clone()
+            }
+        );
+
+        let noop = DiskUsageEvictionTaskConfigUpdate::default();
+        assert_eq!(noop.apply(&current).unwrap(), current);
+
+        let zero_period = DiskUsageEvictionTaskConfigUpdate {
+            period: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        zero_period.apply(&current).unwrap_err();
+    }
 }
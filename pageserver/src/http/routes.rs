@@ -4,7 +4,7 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
 use enumset::EnumSet;
@@ -410,7 +410,21 @@ async fn status_handler(
 ) -> Result<Response<Body>, ApiError> {
     check_permission(&request, None)?;
     let config = get_config(&request);
-    json_response(StatusCode::OK, StatusResponse { id: config.id })
+    let state = get_state(&request);
+    let disk_usage_eviction_paused_until_millis_since_epoch =
+        state.disk_usage_eviction_state.paused_until().map(|until| {
+            until
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+    json_response(
+        StatusCode::OK,
+        StatusResponse {
+            id: config.id,
+            disk_usage_eviction_paused_until_millis_since_epoch,
+        },
+    )
 }
 
 async fn reload_auth_validation_keys_handler(
@@ -829,11 +843,12 @@ async fn tenant_list_handler(
             ApiError::ResourceUnavailable("Tenant map is initializing or shutting down".into())
         })?
         .iter()
-        .map(|(id, state)| TenantInfo {
+        .map(|(id, state, gen)| TenantInfo {
             id: *id,
             state: state.clone(),
             current_physical_size: None,
             attachment_status: state.attachment_status(),
+            generation: (*gen).into(),
         })
         .collect::<Vec<TenantInfo>>();
 
@@ -863,6 +878,7 @@ async fn tenant_status(
                 state: state.clone(),
                 current_physical_size: Some(current_physical_size),
                 attachment_status: state.attachment_status(),
+                generation: tenant.get_generation().into(),
             },
             timelines: tenant.list_timeline_ids(),
         })
@@ -995,11 +1011,14 @@ async fn layer_map_info_handler(
     let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
     let reset: LayerAccessStatsReset =
         parse_query_param(&request, "reset")?.unwrap_or(LayerAccessStatsReset::NoReset);
+    let reconstruct_cost: Option<bool> = parse_query_param(&request, "reconstruct_cost")?;
 
     check_permission(&request, Some(tenant_shard_id.tenant_id))?;
 
     let timeline = active_timeline_of_active_tenant(tenant_shard_id, timeline_id).await?;
-    let layer_map_info = timeline.layer_map_info(reset).await;
+    let layer_map_info = timeline
+        .layer_map_info(reset, reconstruct_cost.unwrap_or(false))
+        .await;
 
     json_response(StatusCode::OK, layer_map_info)
 }
@@ -1538,10 +1557,17 @@ async fn disk_usage_eviction_run(
     #[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
     struct Config {
         /// How many bytes to evict before reporting that pressure is relieved.
-        evict_bytes: u64,
+        ///
+        /// If not set, evict until the actual filesystem usage is below the thresholds of the
+        /// background task's config, like the background task would.
+        #[serde(default)]
+        evict_bytes: Option<u64>,
 
         #[serde(default)]
         eviction_order: crate::disk_usage_eviction_task::EvictionOrder,
+
+        #[serde(default, with = "humantime_serde")]
+        working_set_penalty: Duration,
     }
 
     #[derive(Debug, Clone, Copy, serde::Serialize)]
@@ -1564,11 +1590,6 @@ async fn disk_usage_eviction_run(
 
     let config = json_request::<Config>(&mut r).await?;
 
-    let usage = Usage {
-        evict_bytes: config.evict_bytes,
-        freed_bytes: 0,
-    };
-
     let state = get_state(&r);
 
     let Some(storage) = state.remote_storage.as_ref() else {
@@ -1577,22 +1598,139 @@ async fn disk_usage_eviction_run(
         )));
     };
 
-    let state = state.disk_usage_eviction_state.clone();
+    let eviction_state = state.disk_usage_eviction_state.clone();
 
-    let res = crate::disk_usage_eviction_task::disk_usage_eviction_task_iteration_impl(
-        &state,
-        storage,
-        usage,
-        config.eviction_order,
-        &cancel,
-    )
-    .await;
+    async fn run<U: crate::disk_usage_eviction_task::Usage>(
+        state: &crate::disk_usage_eviction_task::State,
+        storage: &GenericRemoteStorage,
+        usage: U,
+        eviction_order: crate::disk_usage_eviction_task::EvictionOrder,
+        working_set_penalty: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<Response<Body>, ApiError> {
+        let res = crate::disk_usage_eviction_task::disk_usage_eviction_task_iteration_impl(
+            state,
+            storage,
+            usage,
+            eviction_order,
+            working_set_penalty,
+            cancel,
+        )
+        .await;
+
+        info!(?res, "disk_usage_eviction_task_iteration_impl finished");
+
+        let res = res.map_err(ApiError::InternalServerError)?;
+
+        json_response(StatusCode::OK, res)
+    }
+
+    match config.evict_bytes {
+        Some(evict_bytes) => {
+            let usage = Usage {
+                evict_bytes,
+                freed_bytes: 0,
+            };
+            run(
+                &eviction_state,
+                storage,
+                usage,
+                config.eviction_order,
+                config.working_set_penalty,
+                &cancel,
+            )
+            .await
+        }
+        None => {
+            let Some(task_config) = eviction_state.task_config() else {
+                return Err(ApiError::BadRequest(anyhow::anyhow!(
+                    "evict_bytes is required when the disk usage based eviction task is not configured"
+                )));
+            };
+            let usage = crate::disk_usage_eviction_task::FilesystemUsage::get(
+                &state.conf.tenants_path(),
+                &task_config,
+            )
+            .map_err(ApiError::InternalServerError)?;
+            run(
+                &eviction_state,
+                storage,
+                usage,
+                config.eviction_order,
+                config.working_set_penalty,
+                &cancel,
+            )
+            .await
+        }
+    }
+}
+
+async fn disk_usage_eviction_status(
+    r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
+
+    let state = get_state(&r);
 
-    info!(?res, "disk_usage_eviction_task_iteration_impl finished");
+    json_response(StatusCode::OK, state.disk_usage_eviction_state.status())
+}
+
+async fn disk_usage_eviction_pause(
+    r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
+
+    let duration: humantime::Duration = parse_query_param(&r, "duration")?.ok_or_else(|| {
+        ApiError::BadRequest(anyhow::anyhow!("no duration specified in query parameters"))
+    })?;
+    if duration.is_zero() {
+        return Err(ApiError::BadRequest(anyhow::anyhow!(
+            "duration must be greater than zero, use /v1/disk_usage_eviction/resume to resume"
+        )));
+    }
+
+    let state = get_state(&r);
+    state
+        .disk_usage_eviction_state
+        .pause(duration.into())
+        .await
+        .map_err(ApiError::BadRequest)?;
+
+    json_response(StatusCode::OK, state.disk_usage_eviction_state.status())
+}
+
+async fn disk_usage_eviction_resume(
+    r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
 
-    let res = res.map_err(ApiError::InternalServerError)?;
+    let state = get_state(&r);
+    state.disk_usage_eviction_state.resume();
 
-    json_response(StatusCode::OK, res)
+    json_response(StatusCode::OK, state.disk_usage_eviction_state.status())
+}
+
+async fn disk_usage_eviction_config_update(
+    mut r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
+
+    let update =
+        json_request::<crate::disk_usage_eviction_task::DiskUsageEvictionTaskConfigUpdate>(&mut r)
+            .await?;
+
+    let state = get_state(&r);
+
+    let updated = state
+        .disk_usage_eviction_state
+        .update_task_config(update)
+        .map_err(ApiError::BadRequest)?;
+
+    json_response(StatusCode::OK, updated.redacted())
 }
 
 async fn secondary_upload_handler(
@@ -1875,6 +2013,18 @@ pub fn make_router(
         .put("/v1/disk_usage_eviction/run", |r| {
             api_handler(r, disk_usage_eviction_run)
         })
+        .get("/v1/disk_usage_eviction/status", |r| {
+            api_handler(r, disk_usage_eviction_status)
+        })
+        .put("/v1/disk_usage_eviction/config", |r| {
+            api_handler(r, disk_usage_eviction_config_update)
+        })
+        .put("/v1/disk_usage_eviction/pause", |r| {
+            api_handler(r, disk_usage_eviction_pause)
+        })
+        .put("/v1/disk_usage_eviction/resume", |r| {
+            api_handler(r, disk_usage_eviction_resume)
+        })
         .put("/v1/deletion_queue/flush", |r| {
             api_handler(r, deletion_queue_flush)
         })
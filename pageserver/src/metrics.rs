@@ -660,6 +660,44 @@ static EVICTIONS_WITH_LOW_RESIDENCE_DURATION: Lazy<IntCounterVec> = Lazy::new(||
     .expect("failed to define a metric")
 });
 
+pub(crate) struct DiskUsageEvictionMetrics {
+    pub(crate) global_lru_fallbacks: IntCounter,
+    pub(crate) reservation_violated_tenants: UIntGauge,
+    pub(crate) reservation_violated_bytes: UIntGauge,
+    pub(crate) wal_ingest_headroom_low: UIntGauge,
+    pub(crate) paused: UIntGauge,
+}
+
+pub(crate) static DISK_USAGE_EVICTION: Lazy<DiskUsageEvictionMetrics> = Lazy::new(|| {
+    DiskUsageEvictionMetrics {
+        global_lru_fallbacks: register_int_counter!(
+            "pageserver_disk_usage_eviction_global_lru_fallbacks_total",
+            "Number of disk usage based eviction iterations which had to evict from the tenants' min_resident_size reservations. Repeated occurrences indicate that the node is undersized."
+        )
+        .expect("failed to define a metric"),
+        reservation_violated_tenants: register_uint_gauge!(
+            "pageserver_disk_usage_eviction_reservation_violated_tenants",
+            "Number of tenants whose min_resident_size reservation the most recent disk usage based eviction under pressure planned to evict from"
+        )
+        .expect("failed to define a metric"),
+        reservation_violated_bytes: register_uint_gauge!(
+            "pageserver_disk_usage_eviction_reservation_violated_bytes",
+            "Bytes the most recent disk usage based eviction under pressure planned to evict from tenants' min_resident_size reservations"
+        )
+        .expect("failed to define a metric"),
+        wal_ingest_headroom_low: register_uint_gauge!(
+            "pageserver_disk_usage_eviction_wal_ingest_headroom_low",
+            "1 while the free disk space is below wal_ingest_reserved_bytes and WAL ingest backpressures the computes, 0 otherwise"
+        )
+        .expect("failed to define a metric"),
+        paused: register_uint_gauge!(
+            "pageserver_disk_usage_eviction_paused",
+            "1 while disk usage based eviction is paused through the management API, 0 otherwise"
+        )
+        .expect("failed to define a metric"),
+    }
+});
+
 pub(crate) static UNEXPECTED_ONDEMAND_DOWNLOADS: Lazy<IntCounter> = Lazy::new(|| {
     register_int_counter!(
         "pageserver_unexpected_ondemand_downloads_count",
@@ -2307,6 +2345,9 @@ pub fn preinitialize_metrics() {
     // Deletion queue stats
     Lazy::force(&DELETION_QUEUE);
 
+    // Disk usage based eviction stats
+    Lazy::force(&DISK_USAGE_EVICTION);
+
     // Tenant stats
     Lazy::force(&TENANT);
 
@@ -49,7 +49,9 @@ use utils::{
 use crate::auth::check_permission;
 use crate::basebackup;
 use crate::config::PageServerConf;
-use crate::context::{DownloadBehavior, RequestContext};
+use crate::context::{
+    AccessStatsBehavior, DownloadBehavior, RequestContext, RequestContextBuilder,
+};
 use crate::import_datadir::import_wal_from_tar;
 use crate::metrics;
 use crate::metrics::LIVE_CONNECTIONS_COUNT;
@@ -271,7 +273,7 @@ async fn page_service_conn_main(
 }
 
 struct PageServerHandler {
-    _conf: &'static PageServerConf,
+    conf: &'static PageServerConf,
     broker_client: storage_broker::BrokerClientChannel,
     auth: Option<Arc<SwappableJwtAuth>>,
     claims: Option<Claims>,
@@ -291,7 +293,7 @@ impl PageServerHandler {
         connection_ctx: RequestContext,
     ) -> Self {
         PageServerHandler {
-            _conf: conf,
+            conf,
             broker_client,
             auth,
             claims: None,
@@ -501,6 +503,7 @@ impl PageServerHandler {
                 PagestreamFeMessage::GetPage(req) => {
                     let _timer = metrics.start_timer(metrics::SmgrQueryType::GetPageAtLsn);
                     let span = tracing::info_span!("handle_get_page_at_lsn_request", rel = %req.rel, blkno = %req.blkno, req_lsn = %req.lsn);
+                    let ctx = self.getpage_ctx(&req, &ctx);
                     (
                         self.handle_get_page_at_lsn_request(&timeline, &req, &ctx)
                             .instrument(span.clone())
@@ -674,6 +677,20 @@ impl PageServerHandler {
         Ok(())
     }
 
+    /// Requests that do not ask for the latest page version come from hot standby
+    /// replicas (see [`Self::wait_or_get_last_lsn`]). Whether their layer accesses
+    /// count toward eviction recency is configurable.
+    fn getpage_ctx(&self, req: &PagestreamGetPageRequest, ctx: &RequestContext) -> RequestContext {
+        let access_stats_behavior = if req.latest || self.conf.replica_reads_count_for_eviction {
+            AccessStatsBehavior::Update
+        } else {
+            AccessStatsBehavior::ObserveOnly
+        };
+        RequestContextBuilder::extend(ctx)
+            .access_stats_behavior(access_stats_behavior)
+            .build()
+    }
+
     /// Helper function to handle the LSN from client request.
     ///
     /// Each GetPage (and Exists and Nblocks) request includes information about
@@ -815,6 +832,7 @@ impl PageServerHandler {
 
         let key = rel_block_to_key(req.rel, req.blkno);
         let page = if timeline.get_shard_identity().is_key_local(&key) {
+            timeline.working_set.record(&key);
             timeline
                 .get_rel_page_at_lsn(req.rel, req.blkno, lsn, req.latest, ctx)
                 .await?
@@ -852,6 +870,7 @@ impl PageServerHandler {
             // Take a GateGuard for the duration of this request.  If we were using our main Timeline object,
             // the GateGuard was already held over the whole connection.
             let _timeline_guard = timeline.gate.enter().map_err(|_| QueryError::Shutdown)?;
+            timeline.working_set.record(&key);
             timeline
                 .get_rel_page_at_lsn(req.rel, req.blkno, lsn, req.latest, ctx)
                 .await?
@@ -56,6 +56,10 @@ impl Statvfs {
 }
 
 pub mod mock {
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
     use anyhow::Context;
     use camino::Utf8Path;
     use regex::Regex;
@@ -72,6 +76,62 @@ pub mod mock {
         Failure {
             mocked_error: MockedError,
         },
+        /// Script the behavior of consecutive calls, e.g. to report pressure for a few
+        /// calls and then report it as relieved. Once all steps have been used up, the
+        /// last step applies to all further calls.
+        ///
+        /// The position in the sequence is shared by the clones of the behavior, and a
+        /// config update which replaces the behavior starts over from the first step.
+        Sequence {
+            steps: Vec<SequenceStep>,
+            #[serde(skip)]
+            calls: SequenceCalls,
+        },
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    pub struct SequenceStep {
+        /// The number of consecutive calls this step applies to.
+        #[serde(default = "SequenceStep::default_times")]
+        pub times: NonZeroUsize,
+        pub behavior: Behavior,
+    }
+
+    impl SequenceStep {
+        fn default_times() -> NonZeroUsize {
+            NonZeroUsize::new(1).unwrap()
+        }
+    }
+
+    /// The number of calls made with a [`Behavior::Sequence`] so far.
+    #[derive(Debug, Clone, Default)]
+    pub struct SequenceCalls(Arc<AtomicUsize>);
+
+    impl SequenceCalls {
+        fn next(&self) -> usize {
+            self.0.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    /// The position isn't part of the configured behavior.
+    impl PartialEq for SequenceCalls {
+        fn eq(&self, _other: &Self) -> bool {
+            true
+        }
+    }
+
+    impl Eq for SequenceCalls {}
+
+    /// Selects the step of the sequence which applies to the zero-based `call`.
+    fn sequence_step(steps: &[SequenceStep], call: usize) -> Option<&Behavior> {
+        let mut remaining = call;
+        for step in steps {
+            match remaining.checked_sub(step.times.get()) {
+                Some(rest) => remaining = rest,
+                None => return Some(&step.behavior),
+            }
+        }
+        steps.last().map(|step| &step.behavior)
     }
 
     #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -118,6 +178,16 @@ pub mod mock {
                 })
             }
             Behavior::Failure { mocked_error } => Err((*mocked_error).into()),
+            Behavior::Sequence { steps, calls } => {
+                let call = calls.next();
+                let behavior = sequence_step(steps, call)
+                    .unwrap_or_else(|| panic!("mocking error: empty sequence"));
+                if matches!(behavior, Behavior::Sequence { .. }) {
+                    panic!("mocking error: nested sequences are not supported");
+                }
+                info!("using step for call {call} of mocked statvfs sequence");
+                get(tenants_dir, behavior)
+            }
         }
     }
 
@@ -149,4 +219,67 @@ pub mod mock {
         pub fragment_size: u64,
         pub block_size: u64,
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn sequence_steps_are_used_in_order_and_last_one_repeats() {
+            let failure = Behavior::Failure {
+                mocked_error: MockedError::EIO,
+            };
+            let success = Behavior::Success {
+                blocksize: 4096,
+                total_blocks: 10,
+                name_filter: None,
+            };
+
+            let sequence: Behavior = serde_json::from_value(serde_json::json!({
+                "type": "Sequence",
+                "steps": [
+                    { "times": 3, "behavior": failure },
+                    { "behavior": success },
+                ]
+            }))
+            .unwrap();
+            let Behavior::Sequence { steps, .. } = sequence else {
+                panic!("expected a sequence");
+            };
+
+            let selected = (0..6)
+                .map(|call| sequence_step(&steps, call).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                selected,
+                [&failure, &failure, &failure, &success, &success, &success]
+            );
+
+            assert!(sequence_step(&[], 0).is_none());
+        }
+
+        #[test]
+        fn sequence_position_is_shared_by_clones_only() {
+            let parse = || -> Behavior {
+                serde_json::from_value(serde_json::json!({
+                    "type": "Sequence",
+                    "steps": [{ "behavior": { "type": "Failure", "mocked_error": "EIO" } }]
+                }))
+                .unwrap()
+            };
+            let calls = |behavior: &Behavior| match behavior {
+                Behavior::Sequence { calls, .. } => calls.clone(),
+                _ => unreachable!(),
+            };
+
+            let first = parse();
+            let clone = first.clone();
+            assert_eq!(calls(&first).next(), 0);
+            assert_eq!(calls(&clone).next(), 1);
+
+            let reparsed = parse();
+            assert_eq!(reparsed, first, "the position is not part of the behavior");
+            assert_eq!(calls(&reparsed).next(), 0);
+        }
+    }
 }
@@ -2443,6 +2443,15 @@ impl Tenant {
             .or(self.conf.default_tenant_conf.min_resident_size_override)
     }
 
+    pub fn get_never_evict_below_min_resident_size(&self) -> bool {
+        let tenant_conf = self.tenant_conf.read().unwrap().tenant_conf;
+        tenant_conf.never_evict_below_min_resident_size.unwrap_or(
+            self.conf
+                .default_tenant_conf
+                .never_evict_below_min_resident_size,
+        )
+    }
+
     pub fn get_heatmap_period(&self) -> Option<Duration> {
         let tenant_conf = self.tenant_conf.read().unwrap().tenant_conf;
         let heatmap_period = tenant_conf
@@ -3926,6 +3935,9 @@ pub(crate) mod harness {
                 ),
                 gc_feedback: Some(tenant_conf.gc_feedback),
                 heatmap_period: Some(tenant_conf.heatmap_period),
+                never_evict_below_min_resident_size: Some(
+                    tenant_conf.never_evict_below_min_resident_size,
+                ),
             }
         }
     }
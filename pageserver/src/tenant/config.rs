@@ -339,6 +339,11 @@ pub struct TenantConf {
     /// may be disabled if a Tenant will not have secondary locations: only secondary
     /// locations will use the heatmap uploaded by attached locations.
     pub heatmap_period: Duration,
+
+    /// Under disk pressure, never evict layers of this tenant that are within its
+    /// `min_resident_size`, not even when the disk usage based eviction has to fall back
+    /// to global LRU.
+    pub never_evict_below_min_resident_size: bool,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -424,6 +429,10 @@ pub struct TenantConfOpt {
     #[serde(with = "humantime_serde")]
     #[serde(default)]
     pub heatmap_period: Option<Duration>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub never_evict_below_min_resident_size: Option<bool>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -493,6 +502,9 @@ impl TenantConfOpt {
                 .unwrap_or(global_conf.evictions_low_residence_duration_metric_threshold),
             gc_feedback: self.gc_feedback.unwrap_or(global_conf.gc_feedback),
             heatmap_period: self.heatmap_period.unwrap_or(global_conf.heatmap_period),
+            never_evict_below_min_resident_size: self
+                .never_evict_below_min_resident_size
+                .unwrap_or(global_conf.never_evict_below_min_resident_size),
         }
     }
 }
@@ -531,6 +543,7 @@ impl Default for TenantConf {
             .expect("cannot parse default evictions_low_residence_duration_metric_threshold"),
             gc_feedback: false,
             heatmap_period: Duration::ZERO,
+            never_evict_below_min_resident_size: false,
         }
     }
 }
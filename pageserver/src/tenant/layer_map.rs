@@ -51,6 +51,7 @@ use crate::keyspace::KeyPartitioning;
 use crate::repository::Key;
 use crate::tenant::storage_layer::InMemoryLayer;
 use anyhow::Result;
+use pageserver_api::models::LayerReconstructCost;
 use std::collections::VecDeque;
 use std::ops::Range;
 use std::sync::Arc;
@@ -646,3 +647,100 @@ impl LayerMap {
         Ok(())
     }
 }
+
+/// Estimate the cost of reading the pages of a historic layer: the delta layers that the reads
+/// visit, from `layer` down to the newest image layer beneath it, among the `historic` layers
+/// of a snapshot of [`LayerMap::iter_historic_layers`].
+///
+/// This is an upper bound, as it counts every delta layer that overlaps any part of the key
+/// range of `layer`, whether or not it holds records for the keys that are read. It visits all
+/// of `historic`, so computing it for every layer is quadratic in their number: take the
+/// snapshot and release the layers lock first.
+pub fn reconstruct_cost(
+    historic: &[Arc<PersistentLayerDesc>],
+    layer: &PersistentLayerDesc,
+) -> LayerReconstructCost {
+    if !layer.is_delta {
+        return LayerReconstructCost::default();
+    }
+
+    let overlaps = |other: &PersistentLayerDesc| {
+        other.key_range.start < layer.key_range.end && layer.key_range.start < other.key_range.end
+    };
+    let beneath = |other: &PersistentLayerDesc| {
+        overlaps(other) && other.lsn_range.end <= layer.lsn_range.start
+    };
+
+    let image_lsn = historic
+        .iter()
+        .filter(|l| !l.is_delta && beneath(l))
+        .map(|l| l.image_layer_lsn())
+        .max();
+
+    let mut cost = LayerReconstructCost {
+        delta_depth: 1,
+        wal_redo_bytes: layer.file_size,
+    };
+    for l in historic {
+        if l.is_delta && beneath(l) && image_lsn.map_or(true, |lsn| l.lsn_range.end > lsn) {
+            cost.delta_depth += 1;
+            cost.wal_redo_bytes += l.file_size;
+        }
+    }
+    cost
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pageserver_api::shard::TenantShardId;
+    use utils::id::{TenantId, TimelineId};
+
+    #[test]
+    fn reconstruct_cost() {
+        let tenant_shard_id = TenantShardId::unsharded(TenantId::generate());
+        let timeline_id = TimelineId::generate();
+        let keys = |keys: Range<i128>| Key::from_i128(keys.start)..Key::from_i128(keys.end);
+        let delta = |k: Range<i128>, lsns: Range<u64>, file_size| {
+            PersistentLayerDesc::new_delta(
+                tenant_shard_id,
+                timeline_id,
+                keys(k),
+                Lsn(lsns.start)..Lsn(lsns.end),
+                file_size,
+            )
+        };
+        let image = |k: Range<i128>, lsn: u64, file_size| {
+            PersistentLayerDesc::new_img(tenant_shard_id, timeline_id, keys(k), Lsn(lsn), file_size)
+        };
+
+        let layers = [
+            // beneath the image layer, reads above it don't visit it
+            delta(0..100, 0x08..0x10, 1),
+            image(0..100, 0x10, 10),
+            delta(0..100, 0x11..0x20, 2),
+            delta(0..50, 0x20..0x30, 4),
+            // doesn't overlap the key range of the next one
+            delta(50..100, 0x20..0x30, 8),
+            delta(0..50, 0x30..0x40, 16),
+        ];
+        let mut layer_map = LayerMap::default();
+        let mut updates = layer_map.batch_update();
+        for layer in &layers {
+            updates.insert_historic(layer.clone());
+        }
+        updates.flush();
+
+        let historic = layer_map.iter_historic_layers().collect::<Vec<_>>();
+
+        let cost = |delta_depth, wal_redo_bytes| LayerReconstructCost {
+            delta_depth,
+            wal_redo_bytes,
+        };
+        assert_eq!(reconstruct_cost(&historic, &layers[0]), cost(1, 1));
+        assert_eq!(reconstruct_cost(&historic, &layers[1]), cost(0, 0));
+        assert_eq!(reconstruct_cost(&historic, &layers[2]), cost(1, 2));
+        assert_eq!(reconstruct_cost(&historic, &layers[4]), cost(2, 8 + 2));
+        assert_eq!(reconstruct_cost(&historic, &layers[5]), cost(3, 16 + 4 + 2));
+    }
+}
@@ -86,7 +86,9 @@ pub enum ValueReconstructResult {
 pub struct LayerAccessStats(Mutex<LayerAccessStatsLocked>);
 
 /// This struct holds two instances of [`LayerAccessStatsInner`].
-/// Accesses are recorded to both instances.
+/// Accesses are recorded to both instances, except those of requests that only observe the
+/// stats, e.g. replica reads with `replica_reads_count_for_eviction = false`, which are only
+/// recorded to `for_scraping_api`.
 /// The `for_scraping_api`instance can be reset from the management API via [`LayerAccessStatsReset`].
 /// The `for_eviction_policy` is never reset.
 #[derive(Debug, Default, Clone)]
@@ -200,9 +202,11 @@ impl LayerAccessStats {
     }
 
     fn record_access(&self, access_kind: LayerAccessKind, ctx: &RequestContext) {
-        if ctx.access_stats_behavior() == AccessStatsBehavior::Skip {
-            return;
-        }
+        let for_eviction_policy = match ctx.access_stats_behavior() {
+            AccessStatsBehavior::Update => true,
+            AccessStatsBehavior::ObserveOnly => false,
+            AccessStatsBehavior::Skip => return,
+        };
 
         let this_access = LayerAccessStatFullDetails {
             when: SystemTime::now(),
@@ -210,13 +214,18 @@ impl LayerAccessStats {
             access_kind,
         };
 
-        let mut locked = self.0.lock().unwrap();
-        locked.iter_mut().for_each(|inner| {
+        let record = |inner: &mut LayerAccessStatsInner| {
             inner.first_access.get_or_insert(this_access);
             inner.count_by_access_kind[access_kind] += 1;
             inner.task_kind_flag |= ctx.task_kind();
             inner.last_accesses.write(this_access);
-        })
+        };
+
+        let mut locked = self.0.lock().unwrap();
+        record(&mut locked.for_scraping_api);
+        if for_eviction_policy {
+            record(&mut locked.for_eviction_policy);
+        }
     }
 
     fn as_api_model(
@@ -1,7 +1,8 @@
 use anyhow::Context;
 use camino::{Utf8Path, Utf8PathBuf};
 use pageserver_api::models::{
-    HistoricLayerInfo, LayerAccessKind, LayerResidenceEventReason, LayerResidenceStatus,
+    HistoricLayerInfo, LayerAccessKind, LayerReconstructCost, LayerResidenceEventReason,
+    LayerResidenceStatus,
 };
 use pageserver_api::shard::ShardIndex;
 use std::ops::Range;
@@ -302,8 +303,12 @@ impl Layer {
         })
     }
 
-    pub(crate) fn info(&self, reset: LayerAccessStatsReset) -> HistoricLayerInfo {
-        self.0.info(reset)
+    pub(crate) fn info(
+        &self,
+        reset: LayerAccessStatsReset,
+        reconstruct_cost: Option<LayerReconstructCost>,
+    ) -> HistoricLayerInfo {
+        self.0.info(reset, reconstruct_cost)
     }
 
     pub(crate) fn access_stats(&self) -> &LayerAccessStats {
@@ -717,7 +722,15 @@ impl LayerInner {
 
                         tracing::info!(%reason, "downloading on-demand");
 
-                        self.spawn_download_and_wait(timeline, permit).await?
+                        let permit = self
+                            .spawn_download_and_wait(Arc::clone(&timeline), permit)
+                            .await?;
+                        if self.last_evicted_at.lock().unwrap().is_some() {
+                            timeline
+                                .redownloaded_after_eviction_bytes
+                                .fetch_add(self.desc.file_size, Ordering::Relaxed);
+                        }
+                        permit
                     } else {
                         // the file is present locally, probably by a previous but cancelled call to
                         // get_or_maybe_download. alternatively we might be running without remote storage.
@@ -982,7 +995,11 @@ impl LayerInner {
         }
     }
 
-    fn info(&self, reset: LayerAccessStatsReset) -> HistoricLayerInfo {
+    fn info(
+        &self,
+        reset: LayerAccessStatsReset,
+        reconstruct_cost: Option<LayerReconstructCost>,
+    ) -> HistoricLayerInfo {
         let layer_file_name = self.desc.filename().file_name();
 
         // this is not accurate: we could have the file locally but there was a cancellation
@@ -1001,6 +1018,7 @@ impl LayerInner {
                 lsn_end: lsn_range.end,
                 remote,
                 access_stats,
+                reconstruct_cost,
             }
         } else {
             let lsn = self.desc.image_layer_lsn();
@@ -1011,6 +1029,7 @@ impl LayerInner {
                 lsn_start: lsn,
                 remote,
                 access_stats,
+                reconstruct_cost,
             }
         }
     }
@@ -6,6 +6,7 @@ pub(crate) mod logical_size;
 pub mod span;
 pub mod uninit;
 mod walreceiver;
+pub(crate) mod working_set;
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use bytes::Bytes;
@@ -16,7 +17,7 @@ use itertools::Itertools;
 use pageserver_api::{
     models::{
         DownloadRemoteLayersTaskInfo, DownloadRemoteLayersTaskSpawnRequest, LayerMapInfo,
-        TimelineState,
+        LayerReconstructCost, TimelineState,
     },
     shard::{ShardIdentity, TenantShardId},
 };
@@ -34,7 +35,7 @@ use utils::sync::gate::Gate;
 use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::ops::{Deref, Range};
 use std::pin::pin;
-use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::time::{Duration, Instant, SystemTime};
 use std::{
@@ -48,13 +49,13 @@ use crate::context::{
 use crate::tenant::storage_layer::delta_layer::DeltaEntry;
 use crate::tenant::storage_layer::{
     AsLayerDesc, DeltaLayerWriter, EvictionError, ImageLayerWriter, InMemoryLayer, Layer,
-    LayerAccessStatsReset, LayerFileName, ResidentLayer, ValueReconstructResult,
-    ValueReconstructState,
+    LayerAccessStatsReset, LayerFileName, PersistentLayerDesc, ResidentLayer,
+    ValueReconstructResult, ValueReconstructState,
 };
 use crate::tenant::tasks::BackgroundLoopKind;
 use crate::tenant::timeline::logical_size::CurrentLogicalSize;
 use crate::tenant::{
-    layer_map::{LayerMap, SearchResult},
+    layer_map::{reconstruct_cost, LayerMap, SearchResult},
     metadata::{save_metadata, TimelineMetadata},
     par_fsync,
 };
@@ -96,6 +97,7 @@ use self::eviction_task::EvictionTaskTimelineState;
 use self::layer_manager::LayerManager;
 use self::logical_size::LogicalSize;
 use self::walreceiver::{WalReceiver, WalReceiverConf};
+use self::working_set::WorkingSet;
 
 use super::config::TenantConf;
 use super::remote_timeline_client::index::{IndexLayerMetadata, IndexPart};
@@ -318,6 +320,14 @@ pub struct Timeline {
     /// to the timeline should drop out when this token fires.
     pub(crate) cancel: CancellationToken,
 
+    /// The keys that computes have recently read through the page service, a hint for disk
+    /// usage based eviction.
+    pub(crate) working_set: WorkingSet,
+
+    /// Bytes of layers that were downloaded again after having been evicted, since the timeline
+    /// was loaded. The disk usage based eviction learns `min_resident_size` from it.
+    pub(crate) redownloaded_after_eviction_bytes: AtomicU64,
+
     /// Make sure we only have one running compaction at a time in tests.
     ///
     /// Must only be taken in two places:
@@ -1076,7 +1086,13 @@ impl Timeline {
         }
     }
 
-    pub async fn layer_map_info(&self, reset: LayerAccessStatsReset) -> LayerMapInfo {
+    /// With the [`reconstruct_cost`] of the historic layers if `with_reconstruct_cost`, which is
+    /// computed after releasing the layers lock.
+    pub async fn layer_map_info(
+        &self,
+        reset: LayerAccessStatsReset,
+        with_reconstruct_cost: bool,
+    ) -> LayerMapInfo {
         let guard = self.layers.read().await;
         let layer_map = guard.layer_map();
         let mut in_memory_layers = Vec::with_capacity(layer_map.frozen_layers.len() + 1);
@@ -1086,12 +1102,27 @@ impl Timeline {
         for frozen_layer in &layer_map.frozen_layers {
             in_memory_layers.push(frozen_layer.info());
         }
+        let descs = layer_map.iter_historic_layers().collect::<Vec<_>>();
+        let layers = descs
+            .iter()
+            .map(|desc| guard.get_from_desc(desc))
+            .collect::<Vec<_>>();
+        drop(guard);
 
-        let mut historic_layers = Vec::new();
-        for historic_layer in layer_map.iter_historic_layers() {
-            let historic_layer = guard.get_from_desc(&historic_layer);
-            historic_layers.push(historic_layer.info(reset));
-        }
+        let costs = if with_reconstruct_cost {
+            reconstruct_costs(descs)
+                .await
+                .into_iter()
+                .map(Some)
+                .collect()
+        } else {
+            vec![None; layers.len()]
+        };
+        let historic_layers = layers
+            .iter()
+            .zip(costs)
+            .map(|(layer, reconstruct_cost)| layer.info(reset, reconstruct_cost))
+            .collect();
 
         LayerMapInfo {
             in_memory_layers,
@@ -1349,6 +1380,8 @@ impl Timeline {
 
                 cancel,
                 gate: Gate::new(format!("Timeline<{tenant_shard_id}/{timeline_id}>")),
+                working_set: WorkingSet::new(),
+                redownloaded_after_eviction_bytes: AtomicU64::new(0),
 
                 compaction_lock: tokio::sync::Mutex::default(),
                 gc_lock: tokio::sync::Mutex::default(),
@@ -2085,7 +2118,7 @@ impl Timeline {
     /// should treat this as a cue to simply skip doing any heatmap uploading
     /// for this timeline.
     pub(crate) async fn generate_heatmap(&self) -> Option<HeatMapTimeline> {
-        let eviction_info = self.get_local_layers_for_disk_usage_eviction().await;
+        let eviction_info = self.get_local_layers_for_disk_usage_eviction(false).await;
 
         let remote_client = match &self.remote_client {
             Some(c) => c,
@@ -4387,6 +4420,8 @@ pub(crate) struct DiskUsageEvictionInfo {
 pub(crate) struct LocalLayerInfoForDiskUsageEviction {
     pub layer: Layer,
     pub last_activity_ts: SystemTime,
+    /// Only if requested, see [`Timeline::get_local_layers_for_disk_usage_eviction`].
+    pub reconstruct_cost: LayerReconstructCost,
 }
 
 impl std::fmt::Debug for LocalLayerInfoForDiskUsageEviction {
@@ -4414,20 +4449,40 @@ impl LocalLayerInfoForDiskUsageEviction {
     }
 }
 
+/// The [`reconstruct_cost`] of each of the `historic` layers of a snapshot, in their order. It is
+/// quadratic in their number, so it is computed on a blocking thread, without the layers lock.
+async fn reconstruct_costs(historic: Vec<Arc<PersistentLayerDesc>>) -> Vec<LayerReconstructCost> {
+    tokio::task::spawn_blocking(move || {
+        historic
+            .iter()
+            .map(|layer| reconstruct_cost(&historic, layer))
+            .collect()
+    })
+    .await
+    .expect("reconstruct_cost doesn't panic")
+}
+
 impl Timeline {
-    /// Returns non-remote layers for eviction.
-    pub(crate) async fn get_local_layers_for_disk_usage_eviction(&self) -> DiskUsageEvictionInfo {
+    /// Returns non-remote layers for eviction, with their [`reconstruct_cost`] if
+    /// `with_reconstruct_cost`, which is computed after releasing the layers lock.
+    pub(crate) async fn get_local_layers_for_disk_usage_eviction(
+        &self,
+        with_reconstruct_cost: bool,
+    ) -> DiskUsageEvictionInfo {
         let guard = self.layers.read().await;
         let layers = guard.layer_map();
+        let descs = layers.iter_historic_layers().collect::<Vec<_>>();
 
         let mut max_layer_size: Option<u64> = None;
         let mut resident_layers = Vec::new();
+        // the positions of the resident layers in `descs`
+        let mut resident_descs = Vec::new();
 
-        for l in layers.iter_historic_layers() {
+        for (i, l) in descs.iter().enumerate() {
             let file_size = l.file_size();
             max_layer_size = max_layer_size.map_or(Some(file_size), |m| Some(m.max(file_size)));
 
-            let l = guard.get_from_desc(&l);
+            let l = guard.get_from_desc(l);
 
             let l = match l.keep_resident().await {
                 Ok(Some(l)) => l,
@@ -4450,7 +4505,17 @@ impl Timeline {
             resident_layers.push(LocalLayerInfoForDiskUsageEviction {
                 layer: l.drop_eviction_guard(),
                 last_activity_ts,
+                reconstruct_cost: LayerReconstructCost::default(),
             });
+            resident_descs.push(i);
+        }
+        drop(guard);
+
+        if with_reconstruct_cost {
+            let costs = reconstruct_costs(descs).await;
+            for (layer, i) in resident_layers.iter_mut().zip(resident_descs) {
+                layer.reconstruct_cost = costs[i];
+            }
         }
 
         DiskUsageEvictionInfo {
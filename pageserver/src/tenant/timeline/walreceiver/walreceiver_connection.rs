@@ -374,10 +374,18 @@ pub(super) async fn handle_walreceiver_connection(
                 .get_remote_consistent_lsn_visible()
                 .unwrap_or(Lsn(0));
 
-            // The last LSN we processed. It is not guaranteed to survive pageserver crash.
-            let last_received_lsn = last_lsn;
             // `disk_consistent_lsn` is the LSN at which page server guarantees local persistence of all received data
             let disk_consistent_lsn = timeline.get_disk_consistent_lsn();
+            // The last LSN we processed. It is not guaranteed to survive pageserver crash.
+            //
+            // While we are short on disk space for ingest, hold it back at `disk_consistent_lsn`:
+            // the growing write lag makes the computes throttle until eviction has freed up space.
+            let last_received_lsn = if crate::disk_usage_eviction_task::wal_ingest_headroom_is_low()
+            {
+                std::cmp::min(last_lsn, disk_consistent_lsn)
+            } else {
+                last_lsn
+            };
             // The last LSN that is synced to remote storage and is guaranteed to survive pageserver crash
             // Used by safekeepers to remove WAL preceding `remote_consistent_lsn`.
             let remote_consistent_lsn = timeline_remote_consistent_lsn;
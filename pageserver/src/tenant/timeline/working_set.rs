@@ -0,0 +1,125 @@
+//! Approximation of the keys that computes have recently read from a timeline.
+//!
+//! The disk usage based eviction uses it to penalize evicting layers that back the working set,
+//! which their access timestamps alone don't capture well: a layer that was last read a while ago
+//! can still cover keys that the compute reads all the time, from other layers.
+//!
+//! We track chunks of consecutive keys in two generations: a chunk is in the working set if it
+//! was read in the current or previous generation. Each generation has a bounded number of chunks,
+//! and recording an access is lossy under contention: this is only a hint.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use pageserver_api::key::Key;
+
+/// Number of consecutive keys in a chunk, as a power of two: 1024 relation blocks are 8MiB.
+const CHUNK_SHIFT: u32 = 10;
+
+const GENERATION_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Bounds the memory use per timeline.
+const MAX_CHUNKS_PER_GENERATION: usize = 16 * 1024;
+
+pub(crate) struct WorkingSet {
+    inner: Mutex<Generations>,
+}
+
+struct Generations {
+    current: BTreeSet<i128>,
+    previous: BTreeSet<i128>,
+    current_started_at: Instant,
+}
+
+impl Generations {
+    fn rotate_if_needed(&mut self, now: Instant) {
+        let age = now.duration_since(self.current_started_at);
+        if age < GENERATION_PERIOD {
+            return;
+        }
+        if age < 2 * GENERATION_PERIOD {
+            self.previous = std::mem::take(&mut self.current);
+        } else {
+            // no accesses for a whole generation
+            self.previous.clear();
+            self.current.clear();
+        }
+        self.current_started_at = now;
+    }
+}
+
+impl WorkingSet {
+    pub(crate) fn new() -> Self {
+        WorkingSet {
+            inner: Mutex::new(Generations {
+                current: BTreeSet::new(),
+                previous: BTreeSet::new(),
+                current_started_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Called for each page a compute reads.
+    pub(crate) fn record(&self, key: &Key) {
+        // Contention only means that someone else is recording an access right now: the chunk
+        // is likely in the working set already.
+        let Ok(mut inner) = self.inner.try_lock() else {
+            return;
+        };
+        inner.rotate_if_needed(Instant::now());
+        if inner.current.len() < MAX_CHUNKS_PER_GENERATION {
+            inner.current.insert(key.to_i128() >> CHUNK_SHIFT);
+        }
+    }
+
+    /// Whether any key in `key_range` is in the working set.
+    pub(crate) fn overlaps(&self, key_range: &Range<Key>) -> bool {
+        if key_range.start >= key_range.end {
+            return false;
+        }
+        let chunks = (key_range.start.to_i128() >> CHUNK_SHIFT)
+            ..=((key_range.end.to_i128() - 1) >> CHUNK_SHIFT);
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.rotate_if_needed(Instant::now());
+        inner.current.range(chunks.clone()).next().is_some()
+            || inner.previous.range(chunks).next().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlaps_recorded_chunks_only() {
+        let ws = WorkingSet::new();
+        let key = |i: i128| Key::from_i128(i);
+
+        ws.record(&key(5 << CHUNK_SHIFT));
+
+        assert!(ws.overlaps(&(key(0)..key(6 << CHUNK_SHIFT))));
+        assert!(ws.overlaps(&(key((5 << CHUNK_SHIFT) + 100)..key((5 << CHUNK_SHIFT) + 101))));
+        assert!(!ws.overlaps(&(key(0)..key(5 << CHUNK_SHIFT))));
+        assert!(!ws.overlaps(&(key(6 << CHUNK_SHIFT)..key(100 << CHUNK_SHIFT))));
+    }
+
+    #[test]
+    fn generations_expire() {
+        let ws = WorkingSet::new();
+        let range = Key::from_i128(0)..Key::from_i128(1);
+        ws.record(&range.start);
+
+        let mut inner = ws.inner.lock().unwrap();
+        let started = inner.current_started_at;
+
+        inner.rotate_if_needed(started + GENERATION_PERIOD);
+        assert!(inner.current.is_empty());
+        assert_eq!(inner.previous.len(), 1, "still in the working set");
+
+        inner.rotate_if_needed(started + 3 * GENERATION_PERIOD);
+        assert!(inner.previous.is_empty());
+    }
+}
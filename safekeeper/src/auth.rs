@@ -16,5 +16,8 @@ pub fn check_permission(claims: &Claims, tenant_id: Option<TenantId>) -> Result<
             "PageServerApi scope makes no sense for Safekeeper".into(),
         )),
         (Scope::SafekeeperData, _) => Ok(()),
+        (Scope::GenerationsApi | Scope::Admin, _) => Err(AuthError(
+            format!("{:?} scope makes no sense for Safekeeper", claims.scope).into(),
+        )),
     }
 }
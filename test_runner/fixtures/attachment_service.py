@@ -0,0 +1,62 @@
+import subprocess
+from pathlib import Path
+from typing import Any, List, Optional
+
+import requests
+
+from fixtures.types import TenantId
+from fixtures.utils import wait_until
+
+
+class AttachmentService:
+    """
+    An attachment service run on its own, with arguments that `neon_local attachment_service`
+    doesn't pass, e.g. another storage than its JSON file or shorter heartbeat intervals.
+    """
+
+    def __init__(self, neon_binpath: Path, port: int, args: List[str], test_output_dir: Path):
+        self.bin = str(neon_binpath / "attachment_service")
+        self.port = port
+        self.args = args
+        self.log_path = test_output_dir / "attachment_service.log"
+        self._popen: Optional[subprocess.Popen[bytes]] = None
+
+    def start(self) -> "AttachmentService":
+        assert self._popen is None
+        log_file = open(self.log_path, "a")
+        self._popen = subprocess.Popen(
+            [self.bin, "--listen", f"127.0.0.1:{self.port}", *self.args],
+            stdout=log_file,
+            stderr=subprocess.STDOUT,
+        )
+        wait_until(50, 0.1, lambda: self.get("tenants"))
+        return self
+
+    def stop(self):
+        if self._popen is not None:
+            self._popen.terminate()
+            self._popen.wait(timeout=10)
+            self._popen = None
+
+    def state(self, *args: str) -> subprocess.CompletedProcess[str]:
+        """Runs a `state` subcommand on the storage, while the service is stopped."""
+        return subprocess.run(
+            [self.bin, *self.args, "state", *args],
+            capture_output=True,
+            text=True,
+        )
+
+    def get(self, path: str) -> Any:
+        response = requests.get(f"http://127.0.0.1:{self.port}/{path}")
+        response.raise_for_status()
+        return response.json()
+
+    def attach_hook(self, tenant_id: TenantId, node_id: int) -> int:
+        response = requests.post(
+            f"http://127.0.0.1:{self.port}/attach-hook",
+            json={"tenant_id": str(tenant_id), "node_id": node_id},
+        )
+        response.raise_for_status()
+        gen = response.json()["gen"]
+        assert isinstance(gen, int)
+        return gen
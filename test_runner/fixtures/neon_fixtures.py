@@ -1631,6 +1631,44 @@ class NeonAttachmentService:
         else:
             return None
 
+    def tenant_list(self) -> List[Dict[str, Any]]:
+        response = requests.get(f"{self.env.control_plane_api}/tenants")
+        response.raise_for_status()
+        tenants = response.json()
+        assert isinstance(tenants, list)
+        return tenants
+
+    def tenant_delete(self, tenant_id: TenantId):
+        response = requests.delete(f"{self.env.control_plane_api}/tenant/{tenant_id}")
+        response.raise_for_status()
+
+    def tenant_migrate(
+        self, tenant_id: TenantId, pageserver_id: int, detach_origin: bool = False
+    ) -> Dict[str, Any]:
+        # Returns the new generation, and the pageserver that the tenant was attached to before
+        response = requests.put(
+            f"{self.env.control_plane_api}/tenant/{tenant_id}/migrate",
+            json={"node_id": pageserver_id, "detach_origin": detach_origin},
+        )
+        response.raise_for_status()
+        migrated = response.json()
+        assert isinstance(migrated, dict)
+        return migrated
+
+    def node_drain(self, node_id: int) -> Dict[str, Any]:
+        response = requests.put(f"{self.env.control_plane_api}/node/{node_id}/drain")
+        response.raise_for_status()
+        progress = response.json()
+        assert isinstance(progress, dict)
+        return progress
+
+    def node_drain_progress(self, node_id: int) -> Dict[str, Any]:
+        response = requests.get(f"{self.env.control_plane_api}/node/{node_id}/drain")
+        response.raise_for_status()
+        progress = response.json()
+        assert isinstance(progress, dict)
+        return progress
+
     def __enter__(self) -> "NeonAttachmentService":
         return self
 
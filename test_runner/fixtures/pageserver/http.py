@@ -58,6 +58,9 @@ class HistoricLayerInfo:
     lsn_start: str
     lsn_end: Optional[str]
     remote: bool
+    # delta layers visited by reads of this layer's pages, down to the newest image layer,
+    # only with layer_map_info(reconstruct_cost=True)
+    reconstruct_delta_depth: Optional[int]
 
     @classmethod
     def from_json(cls, d: Dict[str, Any]) -> HistoricLayerInfo:
@@ -68,6 +71,9 @@ class HistoricLayerInfo:
             lsn_start=d["lsn_start"],
             lsn_end=d.get("lsn_end"),
             remote=d["remote"],
+            reconstruct_delta_depth=d["reconstruct_cost"]["delta_depth"]
+            if "reconstruct_cost" in d
+            else None,
         )
 
 
@@ -686,9 +692,11 @@ class PageserverHttpClient(requests.Session):
         self,
         tenant_id: TenantId,
         timeline_id: TimelineId,
+        reconstruct_cost: bool = False,
     ) -> LayerMapInfo:
         res = self.get(
             f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/layer/",
+            params={"reconstruct_cost": "true"} if reconstruct_cost else None,
         )
         self.verbose_error(res)
         return LayerMapInfo.from_json(res.json())
@@ -729,6 +737,19 @@ class PageserverHttpClient(requests.Session):
         self.verbose_error(res)
         return res.json()
 
+    def disk_usage_eviction_pause(self, duration: str):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/disk_usage_eviction/pause",
+            params={"duration": duration},
+        )
+        self.verbose_error(res)
+        return res.json()
+
+    def disk_usage_eviction_resume(self):
+        res = self.put(f"http://localhost:{self.port}/v1/disk_usage_eviction/resume")
+        self.verbose_error(res)
+        return res.json()
+
     def tenant_break(self, tenant_id: TenantId):
         res = self.put(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/break")
         self.verbose_error(res)
@@ -175,6 +175,7 @@ def test_fully_custom_config(positive_env: NeonEnv):
         "lagging_wal_timeout": "23m",
         "max_lsn_wal_lag": 230000,
         "min_resident_size_override": 23,
+        "never_evict_below_min_resident_size": True,
         "trace_read_requests": True,
         "walreceiver_connect_timeout": "13m",
     }
@@ -0,0 +1,171 @@
+from pathlib import Path
+from typing import Any, Dict, List
+from urllib.parse import urlparse
+
+import pytest
+from fixtures.attachment_service import AttachmentService
+from fixtures.log_helper import log
+from fixtures.neon_fixtures import NeonEnv, NeonEnvBuilder
+from fixtures.pageserver.http import PageserverApiException
+from fixtures.remote_storage import RemoteStorageKind
+from fixtures.types import TenantId
+from fixtures.utils import wait_until
+from fixtures.workload import Workload
+
+
+def init_start(neon_env_builder: NeonEnvBuilder) -> NeonEnv:
+    """Two pageservers on the same remote storage, that tenants may move between."""
+    neon_env_builder.num_pageservers = 2
+    neon_env_builder.enable_pageserver_remote_storage(RemoteStorageKind.LOCAL_FS)
+    env = neon_env_builder.init_start()
+
+    for ps in env.pageservers:
+        ps.allowed_errors.extend(
+            [
+                # the origin of a migration is stale once the destination attached the tenant
+                ".*Dropped remote consistent LSN updates.*",
+                ".*Dropping stale deletions.*",
+                # computes that still query the origin
+                ".*query handler.*Tenant.*not found.*",
+                ".*query handler.*Tenant.*not active.*",
+            ]
+        )
+    return env
+
+
+def shards(env: NeonEnv, tenant_id: TenantId) -> List[Dict[str, Any]]:
+    """The tenant's shards, as GET /tenants lists them."""
+    for tenant in env.attachment_service.tenant_list():
+        if tenant["tenant_id"] == str(tenant_id):
+            shards = tenant["shards"]
+            assert isinstance(shards, list)
+            return shards
+    raise AssertionError(f"tenant {tenant_id} is not listed")
+
+
+def assert_active(env: NeonEnv, tenant_id: TenantId, pageserver_id: int):
+    status = env.get_pageserver(pageserver_id).http_client().tenant_status(tenant_id)
+    assert status["state"]["slug"] == "Active"
+
+
+def test_attachment_service_migrate(neon_env_builder: NeonEnvBuilder):
+    """
+    A migration attaches the tenant to the destination in a new generation, and leaves a
+    secondary location on the origin, or none with `detach_origin`.
+    """
+    env = init_start(neon_env_builder)
+    tenant_id, timeline_id = env.initial_tenant, env.initial_timeline
+    gen = shards(env, tenant_id)[0]["gen"]
+
+    workload = Workload(env, tenant_id, timeline_id)
+    workload.init(1)
+    workload.write_rows(100, 1)
+
+    migrated = env.attachment_service.tenant_migrate(tenant_id, 2)
+    assert migrated["gen"] == gen + 1
+    assert migrated["origin"] == 1
+    assert shards(env, tenant_id)[0]["node_id"] == 2
+    assert "Secondary" in env.pageservers[0].read_tenant_location_conf(tenant_id)["mode"]
+    assert_active(env, tenant_id, 2)
+    workload.validate(2)
+
+    migrated = env.attachment_service.tenant_migrate(tenant_id, 1, detach_origin=True)
+    assert migrated["gen"] == gen + 2
+    assert migrated["origin"] == 2
+    assert_active(env, tenant_id, 1)
+    with pytest.raises(PageserverApiException):
+        env.pageservers[1].http_client().tenant_status(tenant_id)
+    workload.validate(1)
+
+
+def test_attachment_service_drain(neon_env_builder: NeonEnvBuilder):
+    """
+    A drain migrates all the tenant shards of a pageserver to the others, and reports its
+    progress until it is complete.
+    """
+    env = init_start(neon_env_builder)
+    tenant_ids = [env.initial_tenant]
+    for _ in range(2):
+        tenant_id, _ = env.neon_cli.create_tenant()
+        tenant_ids.append(tenant_id)
+
+    progress = env.attachment_service.node_drain(1)
+    assert progress["total"] == len(tenant_ids)
+
+    def drained():
+        progress = env.attachment_service.node_drain_progress(1)
+        log.info(f"drain progress: {progress}")
+        assert progress["status"] == "complete"
+        return progress
+
+    progress = wait_until(30, 1, drained)
+    assert progress["migrated"] == len(tenant_ids)
+    assert progress["failed"] == []
+
+    for tenant_id in tenant_ids:
+        assert [shard["node_id"] for shard in shards(env, tenant_id)] == [2]
+        assert_active(env, tenant_id, 2)
+
+
+def test_attachment_service_failover(neon_env_builder: NeonEnvBuilder, test_output_dir: Path):
+    """
+    Once a pageserver stopped answering heartbeats for the grace period, its tenant shards are
+    attached to another pageserver in a new generation.
+    """
+    env = init_start(neon_env_builder)
+    tenant_id, timeline_id = env.initial_tenant, env.initial_timeline
+    gen = shards(env, tenant_id)[0]["gen"]
+
+    workload = Workload(env, tenant_id, timeline_id)
+    workload.init(1)
+    workload.write_rows(100, 1)
+
+    # neon_local doesn't pass the failover arguments: run the service itself, on the same
+    # port and state
+    env.attachment_service.stop()
+    port = urlparse(env.control_plane_api).port
+    assert port is not None
+    service = AttachmentService(
+        env.neon_binpath,
+        port,
+        [
+            "--path",
+            str(env.repo_dir / "attachments.json"),
+            "--heartbeat-interval-ms",
+            "200",
+            "--heartbeat-max-failures",
+            "2",
+            "--failover-grace-ms",
+            "1000",
+        ],
+        test_output_dir,
+    )
+    service.start()
+    try:
+        env.pageservers[0].stop(immediate=True)
+
+        def failed_over():
+            [shard] = shards(env, tenant_id)
+            assert shard["node_id"] == 2
+            assert shard["gen"] == gen + 1
+            assert_active(env, tenant_id, 2)
+
+        wait_until(30, 1, failed_over)
+        workload.validate(2)
+    finally:
+        service.stop()
+
+
+def test_attachment_service_delete(neon_env_builder: NeonEnvBuilder):
+    """
+    Deleting a tenant deletes it on its pageserver, then forgets it.
+    """
+    env = init_start(neon_env_builder)
+    tenant_id, _ = env.neon_cli.create_tenant()
+    assert_active(env, tenant_id, 1)
+
+    env.attachment_service.tenant_delete(tenant_id)
+    listed = [tenant["tenant_id"] for tenant in env.attachment_service.tenant_list()]
+    assert str(tenant_id) not in listed
+    with pytest.raises(PageserverApiException):
+        env.pageservers[0].http_client().tenant_status(tenant_id)
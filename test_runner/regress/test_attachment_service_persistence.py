@@ -0,0 +1,111 @@
+import json
+import sqlite3
+from pathlib import Path
+from typing import List
+
+import pytest
+from fixtures.attachment_service import AttachmentService
+from fixtures.log_helper import log
+from fixtures.neon_fixtures import VanillaPostgres
+from fixtures.port_distributor import PortDistributor
+from fixtures.types import TenantId
+
+
+@pytest.fixture
+def storage_args(request: pytest.FixtureRequest, test_output_dir: Path) -> List[str]:
+    """The arguments of the attachment service for the backend under test."""
+    if request.param == "sqlite":
+        return ["--path", str(test_output_dir / "attachments.db"), "--sqlite"]
+
+    vanilla_pg: VanillaPostgres = request.getfixturevalue("vanilla_pg")
+    vanilla_pg.start()
+    vanilla_pg.safe_psql("CREATE ROLE attachment_service LOGIN")
+    vanilla_pg.safe_psql("CREATE DATABASE attachments OWNER attachment_service")
+    url = vanilla_pg.connstr(user="attachment_service", dbname="attachments")
+    return ["--database-url", url]
+
+
+@pytest.mark.parametrize("storage_args", ["postgres", "sqlite"], indirect=True)
+def test_attachment_service_persistence(
+    neon_binpath: Path,
+    port_distributor: PortDistributor,
+    storage_args: List[str],
+    test_output_dir: Path,
+):
+    """
+    The state outlives restarts of the attachment service: the generations go on from
+    where they were, and the tenant's history has the attachments of all runs.
+    """
+    service = AttachmentService(
+        neon_binpath, port_distributor.get_port(), storage_args, test_output_dir
+    )
+    tenant_id = TenantId.generate()
+
+    service.start()
+    try:
+        first = service.attach_hook(tenant_id, 1)
+        assert service.attach_hook(tenant_id, 2) == first + 1
+    finally:
+        service.stop()
+
+    # a second start finds the tables migrated already
+    service.start()
+    try:
+        assert service.attach_hook(tenant_id, 1) == first + 2
+        history = service.get(f"tenant/{tenant_id}/history")
+        log.info(f"history: {history}")
+        assert [entry["new_node_id"] for entry in history] == [1, 2, 1]
+    finally:
+        service.stop()
+
+    # the subcommands use the same storage
+    state = service.state("get", str(tenant_id))
+    assert state.returncode == 0, state.stderr
+    assert json.loads(state.stdout)["generation"] == first + 2
+
+
+@pytest.mark.parametrize("storage_args", ["postgres", "sqlite"], indirect=True)
+def test_attachment_service_refuses_an_old_copy(
+    neon_binpath: Path,
+    port_distributor: PortDistributor,
+    storage_args: List[str],
+    test_output_dir: Path,
+    request: pytest.FixtureRequest,
+):
+    """
+    A state older than the last epoch the database stored, e.g. restored from an old dump, is
+    refused: it could hand out generations that the pageservers have seen already.
+    """
+    service = AttachmentService(
+        neon_binpath, port_distributor.get_port(), storage_args, test_output_dir
+    )
+    tenant_id = TenantId.generate()
+
+    assigned = service.state("assign", str(tenant_id), "1")
+    assert assigned.returncode == 0, assigned.stderr
+    bumped = service.state("bump-epoch")
+    assert bumped.returncode == 0, bumped.stderr
+    assert int(bumped.stdout) == 1
+
+    # the epoch of the state rows goes back, like with a restored dump of them
+    rewind = "UPDATE state_version SET epoch = 0"
+    if "--sqlite" in storage_args:
+        with sqlite3.connect(test_output_dir / "attachments.db") as db:
+            db.execute(rewind)
+    else:
+        vanilla_pg: VanillaPostgres = request.getfixturevalue("vanilla_pg")
+        vanilla_pg.safe_psql(rewind, dbname="attachments")
+
+    refused = service.state("assign", str(tenant_id), "2")
+    assert refused.returncode != 0
+    assert "old copy" in refused.stderr, refused.stderr
+
+    # a new epoch, after the last one, lets it start again
+    bumped = service.state("bump-epoch")
+    assert bumped.returncode == 0, bumped.stderr
+    assert int(bumped.stdout) == 2
+    service.start()
+    try:
+        assert service.attach_hook(tenant_id, 2) > 0
+    finally:
+        service.stop()
@@ -1,3 +1,4 @@
+import concurrent.futures
 import enum
 import time
 from dataclasses import dataclass
@@ -12,7 +13,7 @@ from fixtures.neon_fixtures import (
     PgBin,
     wait_for_last_flush_lsn,
 )
-from fixtures.pageserver.http import PageserverHttpClient
+from fixtures.pageserver.http import PageserverApiException, PageserverHttpClient
 from fixtures.pageserver.utils import wait_for_upload_queue_empty
 from fixtures.remote_storage import RemoteStorageKind
 from fixtures.types import Lsn, TenantId, TimelineId
@@ -70,6 +71,7 @@ class EvictionOrder(str, enum.Enum):
     ABSOLUTE_ORDER = "absolute"
     RELATIVE_ORDER_EQUAL = "relative_equal"
     RELATIVE_ORDER_SPARE = "relative_spare"
+    RECONSTRUCT_COST = "reconstruct_cost"
 
     def config(self) -> Dict[str, Any]:
         if self == EvictionOrder.ABSOLUTE_ORDER:
@@ -78,6 +80,8 @@ class EvictionOrder(str, enum.Enum):
             return {"type": "RelativeAccessed", "args": {"highest_layer_count_loses_first": False}}
         elif self == EvictionOrder.RELATIVE_ORDER_SPARE:
             return {"type": "RelativeAccessed", "args": {"highest_layer_count_loses_first": True}}
+        elif self == EvictionOrder.RECONSTRUCT_COST:
+            return {"type": "ReconstructCost", "args": {"penalty_per_delta": "1m"}}
         else:
             raise RuntimeError(f"not implemented: {self}")
 
@@ -289,9 +293,49 @@ def test_broken_tenants_are_skipped(eviction_env: EvictionEnv):
     env.neon_env.pageserver.allowed_errors.append(".*" + GLOBAL_LRU_LOG_LINE)
 
 
+def test_pause_and_resume(eviction_env: EvictionEnv):
+    """
+    While paused, eviction iterations do nothing, until resumed.
+    """
+    env = eviction_env
+    ps_http = env.pageserver_http
+
+    (total_on_disk, _, _) = env.timelines_du()
+
+    with pytest.raises(PageserverApiException, match="too long") as e:
+        ps_http.disk_usage_eviction_pause("400000000000years")
+    assert e.value.status_code == 400
+
+    status = ps_http.disk_usage_eviction_pause("10m")
+    assert status["paused_until_millis_since_epoch"] is not None
+    ps_status = ps_http.get(f"http://localhost:{ps_http.port}/v1/status").json()
+    assert (
+        ps_status["disk_usage_eviction_paused_until_millis_since_epoch"]
+        == status["paused_until_millis_since_epoch"]
+    )
+
+    response = ps_http.disk_usage_eviction_run({"evict_bytes": total_on_disk})
+    assert response == "Paused"
+    (later_total_on_disk, _, _) = env.timelines_du()
+    assert later_total_on_disk == total_on_disk
+
+    status = ps_http.disk_usage_eviction_resume()
+    assert status["paused_until_millis_since_epoch"] is None
+
+    response = ps_http.disk_usage_eviction_run({"evict_bytes": total_on_disk})
+    assert response != "Paused"
+    (later_total_on_disk, _, _) = env.timelines_du()
+    assert later_total_on_disk < total_on_disk
+    env.neon_env.pageserver.allowed_errors.append(".*" + GLOBAL_LRU_LOG_LINE)
+
+
 @pytest.mark.parametrize(
     "order",
-    [EvictionOrder.ABSOLUTE_ORDER, EvictionOrder.RELATIVE_ORDER_EQUAL],
+    [
+        EvictionOrder.ABSOLUTE_ORDER,
+        EvictionOrder.RELATIVE_ORDER_EQUAL,
+        EvictionOrder.RECONSTRUCT_COST,
+    ],
 )
 def test_pageserver_evicts_until_pressure_is_relieved(
     eviction_env: EvictionEnv, order: EvictionOrder
@@ -321,6 +365,46 @@ def test_pageserver_evicts_until_pressure_is_relieved(
         response["Finished"]["assumed"]["projected_after"]["freed_bytes"] >= actual_change
     ), "report accurately evicted bytes"
     assert response["Finished"]["assumed"]["failed"]["count"] == 0, "zero failures expected"
+    assert response["Finished"]["assumed"]["skipped"]["count"] == 0, "zero skips expected"
+
+
+def test_layers_of_shutting_down_tenant_are_skipped(eviction_env: EvictionEnv):
+    """
+    The layers of a tenant that starts shutting down after the candidates were
+    collected are counted as skipped, not as evicted or failed.
+    """
+    env = eviction_env
+    ps_http = env.pageserver_http
+
+    (total_on_disk, _, _) = env.timelines_du()
+    detached_tenant_id, detached_timeline_id = env.timelines[0]
+    detached_size, _, _ = poor_mans_du(
+        env.neon_env, [(detached_tenant_id, detached_timeline_id)], verbose=True
+    )
+
+    failpoint = "disk-usage-eviction-before-evictions"
+    ps_http.configure_failpoints((failpoint, "return(5000)"))
+
+    def at_failpoint():
+        assert env.neon_env.pageserver.log_contains(f'failpoint "{failpoint}": sleeping')
+
+    with concurrent.futures.ThreadPoolExecutor() as executor:
+        # evict everything, so that the detached tenant's layers are among the candidates
+        run = executor.submit(ps_http.disk_usage_eviction_run, {"evict_bytes": total_on_disk})
+        wait_until(20, 0.5, at_failpoint)
+        ps_http.tenant_detach(detached_tenant_id)
+        response = run.result(timeout=30)
+    log.info(f"{response}")
+
+    assumed = response["Finished"]["assumed"]
+    assert assumed["failed"]["count"] == 0, "zero failures expected"
+    assert assumed["skipped"]["count"] > 0, "the detached tenant's layers are skipped"
+    assert assumed["skipped"]["file_sizes"] <= detached_size
+    assert (
+        assumed["projected_after"]["freed_bytes"]
+        <= total_on_disk - assumed["skipped"]["file_sizes"]
+    ), "skipped layers are not counted as freed"
+    env.neon_env.pageserver.allowed_errors.append(".*" + GLOBAL_LRU_LOG_LINE)
 
 
 @pytest.mark.parametrize(
@@ -430,6 +514,44 @@ def test_pageserver_falls_back_to_global_lru(eviction_env: EvictionEnv, order: E
     env.neon_env.pageserver.allowed_errors.append(".*" + GLOBAL_LRU_LOG_LINE)
 
 
+def test_never_evict_below_min_resident_size(eviction_env: EvictionEnv):
+    """
+    A tenant with `never_evict_below_min_resident_size` keeps its min_resident_size even when
+    the global LRU fallback evicts everything else.
+    """
+    env = eviction_env
+    ps_http = env.pageserver_http
+
+    (total_on_disk, _, _) = env.timelines_du()
+    du_by_timeline = env.du_by_timeline()
+    assert len(du_by_timeline) == 2, "this test assumes two tenants"
+    protected_tenant = min(du_by_timeline, key=du_by_timeline.__getitem__)
+    other_tenant = max(du_by_timeline, key=du_by_timeline.__getitem__)
+
+    ps_http.patch_tenant_config_client_side(
+        protected_tenant[0],
+        {
+            "min_resident_size_override": du_by_timeline[protected_tenant],
+            "never_evict_below_min_resident_size": True,
+        },
+    )
+
+    response = ps_http.disk_usage_eviction_run({"evict_bytes": total_on_disk})
+    log.info(f"{response}")
+
+    later_du_by_timeline = env.du_by_timeline()
+    log.info("later_du_by_timeline: %s", later_du_by_timeline)
+    assert (
+        later_du_by_timeline[protected_tenant] == du_by_timeline[protected_tenant]
+    ), "protected tenant sees no haircut"
+    assert (
+        later_du_by_timeline[other_tenant] < du_by_timeline[other_tenant]
+    ), "other tenant absorbs the deficit"
+
+    time.sleep(1)  # give log time to flush
+    env.neon_env.pageserver.allowed_errors.append(".*" + GLOBAL_LRU_LOG_LINE)
+
+
 @pytest.mark.parametrize(
     "order",
     [
@@ -602,6 +724,61 @@ def test_statvfs_pressure_usage(eviction_env: EvictionEnv):
     assert post_eviction_total_size <= 0.33 * total_size, "we requested max 33% usage"
 
 
+def test_statvfs_pressure_still_high_after_eviction(eviction_env: EvictionEnv):
+    """
+    If the statvfs after the evictions still shows pressure, the eviction task
+    warns and evicts more on its next iteration.
+    """
+    env = eviction_env
+
+    env.neon_env.pageserver.stop()
+
+    # make it seem like we're at 100% utilization by setting total bytes to the used bytes
+    total_size, _, _ = env.timelines_du()
+    blocksize = 512
+    total_blocks = (total_size + (blocksize - 1)) // blocksize
+
+    def mock_success(total_blocks: int):
+        return {
+            "type": "Success",
+            "blocksize": blocksize,
+            "total_blocks": total_blocks,
+            # Only count layer files towards used bytes in the mock_statvfs.
+            # This avoids accounting for metadata files & tenant conf in the tests.
+            "name_filter": ".*__.*",
+        }
+
+    # The first iteration evicts down to 33% of the total, but the statvfs that verifies
+    # it then reports only half of the total, making it ~66% usage. The next iteration
+    # evicts down to 33% of that half.
+    env.pageserver_start_with_disk_usage_eviction(
+        period="1s",
+        max_usage_pct=33,
+        min_avail_bytes=0,
+        mock_behavior={
+            "type": "Sequence",
+            "steps": [
+                {"times": 1, "behavior": mock_success(total_blocks)},
+                {"behavior": mock_success(total_blocks // 2)},
+            ],
+        },
+        eviction_order=EvictionOrder.ABSOLUTE_ORDER,
+    )
+
+    def relieved_log_message():
+        assert env.neon_env.pageserver.log_contains(".*disk usage pressure relieved")
+
+    wait_until(10, 1, relieved_log_message)
+
+    assert env.neon_env.pageserver.log_contains(".*disk usage still high")
+
+    post_eviction_total_size, _, _ = env.timelines_du()
+
+    assert (
+        post_eviction_total_size <= 0.33 * (total_blocks // 2) * blocksize
+    ), "the second iteration evicted down to 33% of the smaller total"
+
+
 def test_statvfs_pressure_min_avail_bytes(eviction_env: EvictionEnv):
     """
     If statvfs data shows 100% usage, the eviction task will drive it down to